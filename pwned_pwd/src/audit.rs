@@ -0,0 +1,310 @@
+//! Audits of exported password hashes
+//!
+//! An organization can check the hashes of its users (e.g. exported from a user database)
+//! against a store without knowing the passwords. An [Auditor] looks them up in batches
+//! with [ReadStore::exists_count_many] (or [ReadStore::exists_many] without counts),
+//! writes a verdict per hash and sums the results up
+//! in an [AuditReport]. SHA-1 and NTLM hashes are audited against the stores of their kind
+
+use std::{
+    cmp::Reverse,
+    fmt::Display,
+    io,
+    pin::pin,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use futures::{
+    future, io::BufReader, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt,
+    Stream, TryStreamExt,
+};
+use pwned_pwd_core::{ParseError, PwnedPwd, SHA1_LEN};
+use pwned_pwd_store::ReadStore;
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuditError<E> {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Store error: {0}")]
+    Store(E),
+}
+
+/// A hash to audit, with an optional id of its owner
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry<const N: usize = SHA1_LEN> {
+    pub id: Option<String>,
+    pub hash: [u8; N],
+}
+
+impl<const N: usize> AuditEntry<N> {
+    /// Parses a `HASH` or `ID:HASH` line, the hash is hex in either case
+    pub fn parse(line: &str) -> Result<Self, ParseError> {
+        let (id, hash) = match line.rsplit_once(':') {
+            Some((id, hash)) => (Some(id.to_owned()), hash),
+            None => (None, line),
+        };
+        Ok(Self {
+            id,
+            hash: PwnedPwd::<N>::try_from_hex(hash.trim(), 0)?.hash,
+        })
+    }
+}
+
+/// A compromised hash with its count
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding<const N: usize = SHA1_LEN> {
+    pub entry: AuditEntry<N>,
+    pub count: u32,
+}
+
+/// Summary of an audit
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditReport<const N: usize = SHA1_LEN> {
+    /// Audited hashes
+    pub checked: u64,
+
+    /// Hashes found in the store
+    pub compromised: u64,
+
+    /// Lines which aren't hashes, they are skipped
+    pub invalid: u64,
+
+    /// The most breached hashes, the most breached first. Empty if counts aren't looked up
+    pub top: Vec<Finding<N>>,
+}
+
+impl<const N: usize> AuditReport<N> {
+    fn push_top(&mut self, finding: Finding<N>, top: usize) {
+        self.top.push(finding);
+        if self.top.len() > top.max(1) * 2 {
+            self.truncate_top(top);
+        }
+    }
+
+    fn truncate_top(&mut self, top: usize) {
+        self.top.sort_by_key(|finding| Reverse(finding.count));
+        self.top.truncate(top);
+    }
+}
+
+/// Configuration of audits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Auditor {
+    batch_size: usize,
+    top: usize,
+    counts: bool,
+    only_compromised: bool,
+}
+
+impl Default for Auditor {
+    fn default() -> Self {
+        Self {
+            batch_size: 1000,
+            top: 10,
+            counts: true,
+            only_compromised: false,
+        }
+    }
+}
+
+impl Auditor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hashes passed to a single [ReadStore::exists_count_many] or [ReadStore::exists_many]
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Size of [AuditReport::top]
+    pub fn with_top(mut self, top: usize) -> Self {
+        self.top = top;
+        self
+    }
+
+    /// Look up the counts of compromised hashes (the default). Stores which don't keep
+    /// counts need it disabled
+    pub fn with_counts(mut self, counts: bool) -> Self {
+        self.counts = counts;
+        self
+    }
+
+    /// Write verdicts of compromised hashes only
+    pub fn with_only_compromised(mut self, only_compromised: bool) -> Self {
+        self.only_compromised = only_compromised;
+        self
+    }
+
+    /// Audits the entries and writes a line per entry to `out`: `[ID:]HASH:ok`,
+    /// `[ID:]HASH:pwned` or `[ID:]HASH:pwned:COUNT`. `futures::io::sink()` drops them
+    pub async fn audit<S, St, W, const N: usize>(
+        &self,
+        store: &S,
+        entries: St,
+        mut out: W,
+    ) -> Result<AuditReport<N>, AuditError<S::Error>>
+    where
+        S: ReadStore<N> + Sync,
+        St: Stream<Item = Result<AuditEntry<N>, io::Error>>,
+        W: AsyncWrite + Unpin,
+    {
+        let mut report = AuditReport::default();
+        let mut batches = pin!(entries.try_chunks(self.batch_size));
+        while let Some(batch) = batches.try_next().await.map_err(|e| e.1)? {
+            let hashes = batch.iter().map(|entry| entry.hash).collect::<Vec<_>>();
+            let found = match self.counts {
+                true => store.exists_count_many(&hashes).await,
+                false => store
+                    .exists_many(&hashes)
+                    .await
+                    .map(|found| found.into_iter().map(|found| found.then_some(0)).collect()),
+            }
+            .map_err(AuditError::Store)?;
+
+            for (entry, count) in batch.into_iter().zip(found) {
+                report.checked += 1;
+                let found = count.is_some();
+                let count = count.filter(|_| self.counts);
+
+                let verdict = match (found, count) {
+                    (false, _) => "ok".to_owned(),
+                    (true, None) => "pwned".to_owned(),
+                    (true, Some(count)) => format!("pwned:{}", count),
+                };
+                let hash = PwnedPwd {
+                    hash: entry.hash,
+                    count: 0,
+                };
+                let line = match &entry.id {
+                    Some(id) => format!("{}:{:X}:{}\n", id, hash, verdict),
+                    None => format!("{:X}:{}\n", hash, verdict),
+                };
+                if found || !self.only_compromised {
+                    out.write_all(line.as_bytes()).await?;
+                }
+
+                if found {
+                    report.compromised += 1;
+                }
+                if let Some(count) = count {
+                    report.push_top(Finding { entry, count }, self.top);
+                }
+            }
+        }
+        out.flush().await?;
+
+        report.truncate_top(self.top);
+        Ok(report)
+    }
+
+    /// Audits lines of [AuditEntry::parse]. Empty lines are skipped,
+    /// invalid ones are counted in [AuditReport::invalid]
+    pub async fn audit_lines<S, R, W, const N: usize>(
+        &self,
+        store: &S,
+        reader: R,
+        out: W,
+    ) -> Result<AuditReport<N>, AuditError<S::Error>>
+    where
+        S: ReadStore<N> + Sync,
+        R: AsyncBufRead,
+        W: AsyncWrite + Unpin,
+    {
+        self.audit_parsed(store, reader, out, |line| AuditEntry::parse(line).map(Some))
+            .await
+    }
+
+    /// Audits lines of the reader, `parse` returns None for lines to skip
+    pub(crate) async fn audit_parsed<S, R, W, E, const N: usize>(
+        &self,
+        store: &S,
+        reader: R,
+        out: W,
+        parse: impl Fn(&str) -> Result<Option<AuditEntry<N>>, E>,
+    ) -> Result<AuditReport<N>, AuditError<S::Error>>
+    where
+        S: ReadStore<N> + Sync,
+        R: AsyncBufRead,
+        W: AsyncWrite + Unpin,
+        E: Display,
+    {
+        let invalid = AtomicU64::new(0);
+        let entries = reader.lines().try_filter_map(|line| {
+            let line = line.trim();
+            let entry = match line.is_empty() {
+                true => None,
+                false => parse(line)
+                    .inspect_err(|e| {
+                        tracing::warn!("Invalid line is skipped: {}", e);
+                        invalid.fetch_add(1, Ordering::Relaxed);
+                    })
+                    .ok()
+                    .flatten(),
+            };
+            future::ready(Ok(entry))
+        });
+
+        let mut report = self.audit(store, entries, out).await?;
+        report.invalid = invalid.load(Ordering::Relaxed);
+        Ok(report)
+    }
+
+    /// [Auditor::audit_lines] of a file or any other reader
+    pub async fn audit_reader<S, R, W, const N: usize>(
+        &self,
+        store: &S,
+        reader: R,
+        out: W,
+    ) -> Result<AuditReport<N>, AuditError<S::Error>>
+    where
+        S: ReadStore<N> + Sync,
+        R: AsyncRead,
+        W: AsyncWrite + Unpin,
+    {
+        self.audit_lines(store, BufReader::new(reader), out).await
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use pwned_pwd_core::NTLM_LEN;
+    use pwned_pwd_test_utils::MockStore;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn audit() {
+        let store = MockStore::new().with_passwords([("password", 100), ("qwerty", 20), ("123456", 300)]);
+        let hex = |password: &str| format!("{:x}", PwnedPwd { hash: PwnedPwd::hash_password(password), count: 0 });
+        let input = format!("alice:{}\nbob:{}\n\n{}\nnot a hash\neve:{}\n", hex("password"), hex("correct horse"), hex("123456"), hex("qwerty"));
+
+        let mut out = Vec::new();
+        let report = Auditor::new().with_batch_size(2).with_top(2).audit_reader(&store, input.as_bytes(), &mut out).await.unwrap();
+
+        assert_eq!((4, 3, 1), (report.checked, report.compromised, report.invalid));
+        assert_eq!(2, store.calls());
+        assert_eq!(vec![(None, 300), (Some("alice".to_owned()), 100)], report.top.into_iter().map(|f| (f.entry.id, f.count)).collect::<Vec<_>>());
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(4, lines.len());
+        assert_eq!(format!("alice:{}:pwned:100", hex("password").to_uppercase()), lines[0]);
+        assert_eq!(format!("bob:{}:ok", hex("correct horse").to_uppercase()), lines[1]);
+    }
+
+    #[tokio::test]
+    async fn ntlm_without_counts() {
+        let hash = [0xAB; NTLM_LEN];
+        let store = MockStore::<NTLM_LEN>::default().with_records([PwnedPwd { hash, count: 5 }]);
+        let input = format!("{}\n{}\n", "AB".repeat(NTLM_LEN), "CD".repeat(NTLM_LEN));
+
+        let mut out = Vec::new();
+        let report = Auditor::new().with_counts(false).audit_reader(&store, input.as_bytes(), &mut out).await.unwrap();
+        assert_eq!((2, 1, 0), (report.checked, report.compromised, report.invalid));
+        assert!(report.top.is_empty());
+        assert_eq!(format!("{}:pwned\n{}:ok\n", "AB".repeat(NTLM_LEN), "CD".repeat(NTLM_LEN)), String::from_utf8(out).unwrap());
+    }
+}
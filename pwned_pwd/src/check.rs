@@ -0,0 +1,86 @@
+//! Checks of plaintext passwords
+//!
+//! Most applications need a single call: is the password a user has just typed pwned?
+//! [check_password] hashes it and asks the store, the plaintext never leaves the process.
+//! A [crate::PwnedPwdClient] answers with a [CheckResult], which tells how the answer was got
+
+use std::time::Duration;
+
+use pwned_pwd_core::PwnedPwd;
+use pwned_pwd_store::ReadStore;
+
+/// What answered a check of a [crate::PwnedPwdClient]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// A store by its index: 0 is the primary one, then the fallbacks in the order they were added
+    Store(usize),
+
+    /// The cache of answers
+    Cache,
+}
+
+/// The answer of a check with its provenance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckResult {
+    /// Is the password pwned at least the minimal count of times
+    pub found: bool,
+
+    /// How many times the password was pwned, also below the minimal count. None if it
+    /// isn't pwned at all or the count wasn't needed to answer (the minimal count is 1)
+    pub count: Option<u32>,
+
+    pub backend: Backend,
+
+    /// [pwned_pwd_store::StoreMetadata::generation] of the data set which answered,
+    /// None for cached answers and stores which don't track it
+    pub generation: Option<u64>,
+
+    pub elapsed: Duration,
+}
+
+/// How many times the password was pwned, None if it wasn't.
+/// Bytes which aren't UTF-8 are hashed as is
+pub async fn check_password<S>(
+    store: &S,
+    password: impl AsRef<[u8]>,
+) -> Result<Option<u32>, S::Error>
+where
+    S: ReadStore + Sync,
+{
+    store.exists_count(PwnedPwd::hash_password(password)).await
+}
+
+/// Whether the password was pwned at all, for stores which don't keep counts
+pub async fn is_pwned<S>(store: &S, password: impl AsRef<[u8]>) -> Result<bool, S::Error>
+where
+    S: ReadStore + Sync,
+{
+    store.exists(PwnedPwd::hash_password(password)).await
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use pwned_pwd_core::{Chunk, Prefix};
+    use pwned_pwd_store::WriteStore;
+    use pwned_pwd_store_local::{format::RecordFormat, LocalStore};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn check() {
+        let path = temp_dir().join("pwned_pwd_check");
+        let _ = std::fs::remove_file(&path);
+        let store = LocalStore::builder(&path).with_format(RecordFormat::HashesWithCounts).build().unwrap();
+        let hash = PwnedPwd::hash_password("password");
+        store.save(futures::stream::iter(vec![Chunk { prefix: Prefix::from_sha1(&hash), passwords: vec![PwnedPwd { hash, count: 42 }] }])).await.unwrap();
+
+        assert_eq!(Some(42), check_password(&store, "password").await.unwrap());
+        assert_eq!(Some(42), check_password(&store, b"password".to_vec()).await.unwrap());
+        assert_eq!(None, check_password(&store, "correct horse battery staple").await.unwrap());
+        assert!(is_pwned(&store, String::from("password")).await.unwrap());
+        let _ = std::fs::remove_file(path);
+    }
+}
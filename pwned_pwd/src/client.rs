@@ -0,0 +1,428 @@
+//! A ready-made client for applications
+//!
+//! [PwnedPwdClient] composes the store wrappers most deployments need — fallback stores,
+//! a cache of answers and a count threshold — into a single cheap-to-clone object,
+//! which can be kept in the state of a web application
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{future::BoxFuture, Stream};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+use pwned_pwd_store::{
+    cached::{Cached, CachedStore},
+    dyn_store::{DynError, DynReadStore},
+    ReadStore, StoreMetadata,
+};
+
+use crate::check::{Backend, CheckResult};
+
+/// How long the generation of a store's data set is reused by [CheckResult]s
+const GENERATION_TTL: Duration = Duration::from_secs(60);
+
+/// When the generation of a store was asked and its value
+type AskedGeneration = (Instant, Option<u64>);
+
+/// Stores which are asked in order until one of them answers
+struct Fallbacks {
+    stores: Vec<Box<dyn DynReadStore>>,
+    generations: Vec<Mutex<Option<AskedGeneration>>>,
+}
+
+impl Fallbacks {
+    fn new(stores: Vec<Box<dyn DynReadStore>>) -> Self {
+        let generations = stores.iter().map(|_| Mutex::new(None)).collect();
+        Self {
+            stores,
+            generations,
+        }
+    }
+
+    async fn read<'a, T>(
+        &'a self,
+        read: impl Fn(&'a dyn DynReadStore) -> BoxFuture<'a, Result<T, DynError>>,
+    ) -> Result<T, DynError> {
+        self.read_indexed(read).await.map(|(res, _)| res)
+    }
+
+    /// The answer with the index of the store which gave it
+    async fn read_indexed<'a, T>(
+        &'a self,
+        read: impl Fn(&'a dyn DynReadStore) -> BoxFuture<'a, Result<T, DynError>>,
+    ) -> Result<(T, usize), DynError> {
+        let mut error = None;
+        for (i, store) in self.stores.iter().enumerate() {
+            match read(store.as_ref()).await {
+                Ok(res) => return Ok((res, i)),
+                Err(e) => {
+                    tracing::warn!("Store {} failed: {}", i, e);
+                    error = Some(e);
+                }
+            }
+        }
+        Err(error.expect("No stores"))
+    }
+
+    /// The generation of the store's data set, asked at most once per [GENERATION_TTL]
+    async fn generation(&self, i: usize) -> Option<u64> {
+        if let Some((asked, generation)) = *self.generations[i].lock().unwrap() {
+            if asked.elapsed() < GENERATION_TTL {
+                return generation;
+            }
+        }
+
+        let generation = match self.stores[i].metadata().await {
+            Ok(metadata) => metadata.generation,
+            Err(e) => {
+                tracing::warn!("Metadata of store {} failed: {}", i, e);
+                None
+            }
+        };
+        *self.generations[i].lock().unwrap() = Some((Instant::now(), generation));
+        generation
+    }
+}
+
+impl ReadStore for Fallbacks {
+    type Error = DynError;
+
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        self.read(|store| store.exists(val)).await
+    }
+
+    /// Streams the primary store
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        self.stores[0].iter_all()
+    }
+
+    async fn exists_many(&self, vals: &[[u8; 20]]) -> Result<Vec<bool>, Self::Error> {
+        self.read(|store| store.exists_many(vals)).await
+    }
+
+    async fn exists_count(&self, val: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        self.read(|store| store.exists_count(val)).await
+    }
+
+    async fn exists_count_many(&self, vals: &[[u8; 20]]) -> Result<Vec<Option<u32>>, Self::Error> {
+        self.read(|store| store.exists_count_many(vals)).await
+    }
+
+    async fn exists_with_min_count(
+        &self,
+        val: [u8; 20],
+        min_count: u32,
+    ) -> Result<Option<u32>, Self::Error> {
+        self.read(|store| store.exists_with_min_count(val, min_count))
+            .await
+    }
+
+    async fn range(&self, prefix: Prefix) -> Result<Chunk, Self::Error> {
+        self.read(|store| store.range(prefix)).await
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        self.read(|store| store.metadata()).await
+    }
+
+    async fn max_prefix(&self) -> Result<Option<Prefix>, Self::Error> {
+        self.read(|store| store.max_prefix()).await
+    }
+
+    async fn healthy(&self) -> Result<bool, Self::Error> {
+        self.read(|store| store.healthy()).await
+    }
+}
+
+/// Configuration of a [PwnedPwdClient]
+pub struct PwnedPwdClientBuilder {
+    stores: Vec<Box<dyn DynReadStore>>,
+    cache: Option<usize>,
+    cache_ttl: Option<(Duration, Duration)>,
+    min_count: u32,
+}
+
+impl PwnedPwdClientBuilder {
+    /// A store which is asked if all the previous ones failed, e.g. the online API
+    /// behind a local file
+    pub fn with_fallback(mut self, store: impl DynReadStore + 'static) -> Self {
+        self.stores.push(Box::new(store));
+        self
+    }
+
+    /// Cache answers of at most `max_entries` hashes, see [CachedStore]
+    pub fn with_cache(mut self, max_entries: usize) -> Self {
+        self.cache = Some(max_entries);
+        self
+    }
+
+    /// Lifetimes of cached answers about found and missing hashes,
+    /// [CachedStore] defaults are used without it. Has no effect without [Self::with_cache]
+    pub fn with_cache_ttl(mut self, positive: Duration, negative: Duration) -> Self {
+        self.cache_ttl = Some((positive, negative));
+        self
+    }
+
+    /// Passwords seen fewer than `min_count` times aren't compromised. The stores
+    /// must keep counts if it is greater than 1
+    pub fn with_min_count(mut self, min_count: u32) -> Self {
+        self.min_count = min_count;
+        self
+    }
+
+    pub fn build(self) -> PwnedPwdClient {
+        let fallbacks = Fallbacks::new(self.stores);
+
+        let stores = match self.cache {
+            Some(max_entries) => {
+                let mut cached = CachedStore::new(fallbacks, max_entries);
+                if let Some((positive, negative)) = self.cache_ttl {
+                    cached = cached
+                        .with_positive_ttl(positive)
+                        .with_negative_ttl(negative);
+                }
+                Stores::Cached(cached)
+            }
+            None => Stores::Direct(fallbacks),
+        };
+
+        PwnedPwdClient {
+            stores: Arc::new(stores),
+            min_count: self.min_count,
+        }
+    }
+}
+
+enum Stores {
+    Direct(Fallbacks),
+    Cached(CachedStore<Fallbacks>),
+}
+
+impl Stores {
+    fn store(&self) -> &dyn DynReadStore {
+        match self {
+            Stores::Direct(fallbacks) => fallbacks,
+            Stores::Cached(cached) => cached,
+        }
+    }
+
+    fn fallbacks(&self) -> &Fallbacks {
+        match self {
+            Stores::Direct(fallbacks) => fallbacks,
+            Stores::Cached(cached) => cached.store(),
+        }
+    }
+
+    fn cache(&self) -> Option<&CachedStore<Fallbacks>> {
+        match self {
+            Stores::Direct(_) => None,
+            Stores::Cached(cached) => Some(cached),
+        }
+    }
+}
+
+/// Checks of passwords against a primary store and its fallbacks.
+/// Clones share the stores and the cache
+#[derive(Clone)]
+pub struct PwnedPwdClient {
+    stores: Arc<Stores>,
+    min_count: u32,
+}
+
+impl PwnedPwdClient {
+    pub fn builder(primary: impl DynReadStore + 'static) -> PwnedPwdClientBuilder {
+        PwnedPwdClientBuilder {
+            stores: vec![Box::new(primary)],
+            cache: None,
+            cache_ttl: None,
+            min_count: 1,
+        }
+    }
+
+    pub fn min_count(&self) -> u32 {
+        self.min_count
+    }
+
+    /// Is the plaintext password pwned at least [PwnedPwdClientBuilder::with_min_count] times.
+    /// An error is returned only if all the stores failed
+    pub async fn is_compromised(&self, password: impl AsRef<[u8]>) -> Result<bool, DynError> {
+        self.is_hash_compromised(PwnedPwd::hash_password(password))
+            .await
+    }
+
+    /// [PwnedPwdClient::is_compromised] of a SHA-1 hash. Stores without counts
+    /// can answer it, if the threshold is 1
+    pub async fn is_hash_compromised(&self, hash: [u8; 20]) -> Result<bool, DynError> {
+        match self.min_count {
+            0 | 1 => self.stores.store().exists(hash).await,
+            _ => Ok(self.check(hash).await?.is_some()),
+        }
+    }
+
+    /// How many times the SHA-1 hash is pwned, None if it isn't pwned at least
+    /// [PwnedPwdClientBuilder::with_min_count] times
+    pub async fn check(&self, hash: [u8; 20]) -> Result<Option<u32>, DynError> {
+        self.stores
+            .store()
+            .exists_with_min_count(hash, self.min_count)
+            .await
+    }
+
+    /// [PwnedPwdClient::check_hash] of the plaintext password
+    pub async fn check_password(
+        &self,
+        password: impl AsRef<[u8]>,
+    ) -> Result<CheckResult, DynError> {
+        self.check_hash(PwnedPwd::hash_password(password)).await
+    }
+
+    /// Checks the SHA-1 hash as [PwnedPwdClient::is_hash_compromised] does and tells
+    /// which store answered it, with the generation of its data set and the time taken.
+    /// An error is returned only if all the stores failed
+    pub async fn check_hash(&self, hash: [u8; 20]) -> Result<CheckResult, DynError> {
+        let started = Instant::now();
+        let counted = self.min_count > 1;
+
+        let cached = self
+            .stores
+            .cache()
+            .and_then(|cache| cache.cached(&hash, counted));
+        let (answer, backend) = match cached {
+            Some(answer) => (answer, Backend::Cache),
+            None => {
+                let fallbacks = self.stores.fallbacks();
+                let (answer, i) = match counted {
+                    true => {
+                        let (count, i) = fallbacks
+                            .read_indexed(|store| store.exists_count(hash))
+                            .await?;
+                        (
+                            count.map_or(Cached::NotPwned, |count| Cached::Pwned(Some(count))),
+                            i,
+                        )
+                    }
+                    false => {
+                        let (found, i) = fallbacks.read_indexed(|store| store.exists(hash)).await?;
+                        (
+                            if found {
+                                Cached::Pwned(None)
+                            } else {
+                                Cached::NotPwned
+                            },
+                            i,
+                        )
+                    }
+                };
+                if let Some(cache) = self.stores.cache() {
+                    cache.remember(hash, answer);
+                }
+                (answer, Backend::Store(i))
+            }
+        };
+        let elapsed = started.elapsed();
+
+        let (found, count) = match answer {
+            Cached::NotPwned => (false, None),
+            Cached::Pwned(count) => (count.is_none_or(|count| count >= self.min_count), count),
+        };
+        let generation = match backend {
+            Backend::Store(i) => self.stores.fallbacks().generation(i).await,
+            Backend::Cache => None,
+        };
+
+        Ok(CheckResult {
+            found,
+            count,
+            backend,
+            generation,
+            elapsed,
+        })
+    }
+
+    /// Health of the first store which answers
+    pub async fn healthy(&self) -> Result<bool, DynError> {
+        self.stores.store().healthy().await
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::{env::temp_dir, io};
+
+    use pwned_pwd_core::Chunk;
+    use pwned_pwd_store::WriteStore;
+    use pwned_pwd_store_local::{format::RecordFormat, LocalStore};
+    use pwned_pwd_test_utils::{MockStore, Scripted};
+
+    use super::*;
+
+    struct Unavailable;
+
+    impl ReadStore for Unavailable {
+        type Error = io::Error;
+
+        async fn exists(&self, _: [u8; 20]) -> Result<bool, Self::Error> {
+            Err(io::ErrorKind::ConnectionRefused.into())
+        }
+
+        fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+            futures::stream::empty()
+        }
+
+        async fn exists_count(&self, _: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+            Err(io::ErrorKind::ConnectionRefused.into())
+        }
+    }
+
+    async fn local(name: &str) -> LocalStore {
+        let path = temp_dir().join(name);
+        let _ = std::fs::remove_file(&path);
+        let store = LocalStore::builder(&path).with_format(RecordFormat::HashesWithCounts).build().unwrap();
+
+        let mut passwords = vec![
+            PwnedPwd { hash: PwnedPwd::hash_password("password"), count: 100 },
+            PwnedPwd { hash: PwnedPwd::hash_password("qwerty"), count: 2 },
+        ];
+        passwords.sort_by_key(|pwd| pwd.hash);
+        let chunks = passwords.into_iter().map(|pwd| Chunk { prefix: Prefix::from_sha1(&pwd.hash), passwords: vec![pwd] });
+        store.save(futures::stream::iter(chunks.collect::<Vec<_>>())).await.unwrap();
+        store
+    }
+
+    #[tokio::test]
+    async fn fallback() {
+        let client = PwnedPwdClient::builder(Unavailable).with_fallback(local("pwned_pwd_client_fallback").await).with_cache(10).build();
+
+        assert!(client.is_compromised("password").await.unwrap());
+        assert!(!client.clone().is_compromised("correct horse battery staple").await.unwrap());
+        assert_eq!(Some(2), client.check(PwnedPwd::hash_password("qwerty")).await.unwrap());
+        assert!(PwnedPwdClient::builder(Unavailable).build().is_compromised("password").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn min_count() {
+        let client = PwnedPwdClient::builder(local("pwned_pwd_client_min_count").await).with_min_count(10).build();
+
+        assert!(client.is_compromised("password").await.unwrap());
+        assert!(!client.is_compromised("qwerty").await.unwrap());
+        assert_eq!(None, client.check(PwnedPwd::hash_password("qwerty")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn check_result() {
+        let primary = MockStore::new().with_passwords([("password", 100)]);
+        primary.push(Scripted::Fail);
+        let fallback = MockStore::new().with_passwords([("password", 100), ("qwerty", 2)]);
+        fallback.merge(futures::stream::empty()).await.unwrap();
+        let client = PwnedPwdClient::builder(primary).with_fallback(fallback).with_cache(10).with_min_count(10).build();
+
+        let res = client.check_password("password").await.unwrap();
+        assert_eq!((true, Some(100), Backend::Store(1), Some(1)), (res.found, res.count, res.backend, res.generation));
+        let res = client.check_password("password").await.unwrap();
+        assert_eq!((true, Some(100), Backend::Cache, None), (res.found, res.count, res.backend, res.generation));
+        let res = client.check_password("qwerty").await.unwrap();
+        assert_eq!((false, None, Backend::Store(0), Some(0)), (res.found, res.count, res.backend, res.generation));
+    }
+}
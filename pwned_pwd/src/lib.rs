@@ -0,0 +1,24 @@
+//! A single entry point to the pwned passwords crates: the core types, the stores
+//! and the downloader, with the high-level operations built on top of them
+
+pub use pwned_pwd_core as core;
+pub use pwned_pwd_downloader as downloader;
+pub use pwned_pwd_store as store;
+
+pub mod audit;
+pub mod check;
+pub mod client;
+pub mod ntds;
+pub mod policy;
+#[cfg(feature = "tower")]
+pub mod service;
+#[cfg(feature = "zxcvbn")]
+pub mod strength;
+pub mod sync;
+pub mod telemetry;
+
+pub use audit::{AuditReport, Auditor};
+pub use check::{check_password, is_pwned, Backend, CheckResult};
+pub use client::{PwnedPwdClient, PwnedPwdClientBuilder};
+pub use policy::{Action, PasswordPolicy, PolicyVerdict};
+pub use sync::{sync, sync_observed, sync_prefixes, SyncError};
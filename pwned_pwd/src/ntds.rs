@@ -0,0 +1,159 @@
+//! Audits of Active Directory accounts
+//!
+//! The NTLM data set is mostly used to find domain accounts with breached passwords.
+//! [NtdsAuditor] reads an `ntds.dit` export in the secretsdump format
+//! (`DOMAIN\user:RID:LMHASH:NTHASH:::`), checks the NT hashes against an NTLM store
+//! and writes the accounts whose passwords are breached
+
+use futures::{io::BufReader, AsyncRead, AsyncWrite};
+use pwned_pwd_core::{ParseError, PwnedPwd, NTLM_LEN};
+use pwned_pwd_store::ReadStore;
+
+use crate::audit::{AuditEntry, AuditError, AuditReport, Auditor};
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum NtdsParseError {
+    #[error("Expected 'account:rid:lmhash:nthash:::'")]
+    Format,
+
+    #[error("Invalid NT hash: {0}")]
+    Hash(#[from] ParseError),
+}
+
+/// Audits secretsdump exports. Password histories and machine accounts
+/// (their passwords are random) are skipped by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NtdsAuditor {
+    auditor: Auditor,
+    history: bool,
+    machine_accounts: bool,
+}
+
+impl Default for NtdsAuditor {
+    fn default() -> Self {
+        Self {
+            auditor: Auditor::new().with_only_compromised(true),
+            history: false,
+            machine_accounts: false,
+        }
+    }
+}
+
+impl NtdsAuditor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Batches, counts and verdicts of the audit. Only compromised accounts are
+    /// written by default
+    pub fn with_auditor(mut self, auditor: Auditor) -> Self {
+        self.auditor = auditor;
+        self
+    }
+
+    /// Audit the `user_historyN` entries of exports made with `-history`
+    pub fn with_history(mut self, history: bool) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Audit accounts which end with `$`
+    pub fn with_machine_accounts(mut self, machine_accounts: bool) -> Self {
+        self.machine_accounts = machine_accounts;
+        self
+    }
+
+    /// The account and its NT hash, None if the account is skipped.
+    /// Anything after the NT hash (e.g. ` (status=Enabled)`) is ignored
+    pub fn parse(&self, line: &str) -> Result<Option<AuditEntry<NTLM_LEN>>, NtdsParseError> {
+        let mut fields = line.split(':');
+        let (Some(account), Some(rid), Some(_lm), Some(nt)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(NtdsParseError::Format);
+        };
+        if account.is_empty() || rid.parse::<u32>().is_err() {
+            return Err(NtdsParseError::Format);
+        }
+
+        if (!self.machine_accounts && account.ends_with('$'))
+            || (!self.history && is_history(account))
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(AuditEntry {
+            id: Some(account.to_owned()),
+            hash: PwnedPwd::<NTLM_LEN>::try_from_hex(nt.trim(), 0)?.hash,
+        }))
+    }
+
+    /// Audits the export. Verdicts are written as `ACCOUNT:NTHASH:pwned[:COUNT]`,
+    /// see [Auditor::audit]
+    pub async fn audit<S, R, W>(
+        &self,
+        store: &S,
+        reader: R,
+        out: W,
+    ) -> Result<AuditReport<NTLM_LEN>, AuditError<S::Error>>
+    where
+        S: ReadStore<NTLM_LEN> + Sync,
+        R: AsyncRead,
+        W: AsyncWrite + Unpin,
+    {
+        self.auditor
+            .audit_parsed(store, BufReader::new(reader), out, |line| self.parse(line))
+            .await
+    }
+}
+
+/// `user_history0` and so on
+fn is_history(account: &str) -> bool {
+    account
+        .trim_end_matches(|c: char| c.is_ascii_digit())
+        .ends_with("_history")
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use pwned_pwd_test_utils::MockStore;
+
+    use super::*;
+
+    const EMPTY_LM: &str = "aad3b435b51404eeaad3b435b51404ee";
+    const BREACHED: &str = "8846f7eaee8fb117ad06bdd830b7586c";
+    const STRONG: &str = "0cb6948805f797bf2a82807973b89537";
+
+    #[test]
+    fn parse() {
+        let auditor = NtdsAuditor::new();
+        let entry = auditor.parse(&format!("CORP\\alice:1104:{}:{}::: (status=Enabled)", EMPTY_LM, BREACHED)).unwrap().unwrap();
+        assert_eq!(Some("CORP\\alice".to_owned()), entry.id);
+        assert_eq!(PwnedPwd::<NTLM_LEN>::try_from_hex(BREACHED, 0).unwrap().hash, entry.hash);
+
+        assert_eq!(Ok(None), auditor.parse(&format!("CORP\\alice_history0:1104:{}:{}:::", EMPTY_LM, BREACHED)));
+        assert_eq!(Ok(None), auditor.parse(&format!("CORP\\DC01$:1000:{}:{}:::", EMPTY_LM, BREACHED)));
+        assert!(auditor.with_history(true).parse(&format!("CORP\\alice_history0:1104:{}:{}:::", EMPTY_LM, BREACHED)).unwrap().is_some());
+        assert_eq!(Err(NtdsParseError::Format), auditor.parse("[*] Dumping Domain Credentials (domain\\uid:rid:lmhash:nthash)"));
+        assert!(matches!(auditor.parse("CORP\\bob:1105:aad3:zz:::"), Err(NtdsParseError::Hash(_))));
+    }
+
+    #[tokio::test]
+    async fn audit() {
+        let breached = PwnedPwd::<NTLM_LEN>::try_from_hex(BREACHED, 52_000).unwrap();
+        let store = MockStore::<NTLM_LEN>::default().with_records([breached]);
+        let export = [
+            "[*] Using the DRSUAPI method to get NTDS.DIT secrets".to_owned(),
+            format!("CORP\\alice:1104:{}:{}:::", EMPTY_LM, BREACHED),
+            format!("CORP\\bob:1105:{}:{}:::", EMPTY_LM, STRONG),
+            format!("CORP\\alice_history0:1104:{}:{}:::", EMPTY_LM, BREACHED),
+            format!("CORP\\WS01$:1106:{}:{}:::", EMPTY_LM, BREACHED),
+        ].join("\n");
+
+        let mut out = Vec::new();
+        let report = NtdsAuditor::new().audit(&store, export.as_bytes(), &mut out).await.unwrap();
+        assert_eq!((2, 1, 1), (report.checked, report.compromised, report.invalid));
+        assert_eq!(format!("CORP\\alice:{}:pwned:52000\n", BREACHED.to_uppercase()), String::from_utf8(out).unwrap());
+    }
+}
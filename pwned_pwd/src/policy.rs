@@ -0,0 +1,171 @@
+//! What an application does about a pwned password
+//!
+//! A store only tells how many times a password was seen. A [PasswordPolicy] maps the
+//! count to an [Action] with rules like "warn if it was seen at all, reject it if it
+//! was seen more than 100 times", and a check returns a [PolicyVerdict]
+
+use pwned_pwd_core::PwnedPwd;
+use pwned_pwd_store::ReadStore;
+
+/// Actions from the mildest to the strictest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Action {
+    #[default]
+    Allow,
+    Warn,
+    RequireMfa,
+    Reject,
+}
+
+/// The action is taken if a password was seen more than `max_count` times
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rule {
+    pub max_count: u32,
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyVerdict {
+    pub action: Action,
+
+    /// How many times the password was seen, None if it wasn't or the check failed
+    pub count: Option<u32>,
+
+    /// The rule which decided the action
+    pub rule: Option<Rule>,
+
+    /// The store failed, the action is [PasswordPolicy::with_on_error]
+    pub failed: bool,
+}
+
+impl PolicyVerdict {
+    pub fn is_rejected(&self) -> bool {
+        self.action == Action::Reject
+    }
+}
+
+/// Rules of a policy, the strictest matching one wins. Without rules every password is allowed
+#[derive(Debug, Clone, Default)]
+pub struct PasswordPolicy {
+    rules: Vec<Rule>,
+    on_error: Action,
+}
+
+impl PasswordPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take the action for passwords seen more than `max_count` times
+    pub fn with_rule(mut self, max_count: u32, action: Action) -> Self {
+        self.rules.push(Rule { max_count, action });
+        self
+    }
+
+    /// The action if the store fails, [Action::Allow] by default
+    pub fn with_on_error(mut self, action: Action) -> Self {
+        self.on_error = action;
+        self
+    }
+
+    /// The verdict for a password seen `count` times
+    pub fn verdict(&self, count: Option<u32>) -> PolicyVerdict {
+        let rule = count.and_then(|count| {
+            self.rules
+                .iter()
+                .filter(|rule| count > rule.max_count)
+                .max_by_key(|rule| rule.action)
+                .copied()
+        });
+
+        PolicyVerdict {
+            action: rule.map(|rule| rule.action).unwrap_or_default(),
+            count,
+            rule,
+            failed: false,
+        }
+    }
+
+    /// Checks the password in a store which keeps counts
+    pub async fn check<S>(&self, store: &S, password: impl AsRef<[u8]>) -> PolicyVerdict
+    where
+        S: ReadStore + Sync,
+        S::Error: std::fmt::Display,
+    {
+        self.check_hash(store, PwnedPwd::hash_password(password))
+            .await
+    }
+
+    /// [PasswordPolicy::check] of a SHA-1 hash
+    pub async fn check_hash<S>(&self, store: &S, hash: [u8; 20]) -> PolicyVerdict
+    where
+        S: ReadStore + Sync,
+        S::Error: std::fmt::Display,
+    {
+        match store.exists_count(hash).await {
+            Ok(count) => self.verdict(count),
+            Err(e) => {
+                tracing::warn!("Password can't be checked: {}", e);
+                PolicyVerdict {
+                    action: self.on_error,
+                    count: None,
+                    rule: None,
+                    failed: true,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use pwned_pwd_core::{Chunk, Prefix};
+    use pwned_pwd_store::WriteStore;
+    use pwned_pwd_store_local::{format::RecordFormat, LocalStore};
+
+    use super::*;
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy::new()
+            .with_rule(100, Action::Reject)
+            .with_rule(0, Action::Warn)
+            .with_rule(10, Action::RequireMfa)
+    }
+
+    #[test]
+    fn verdict() {
+        let policy = policy();
+        assert_eq!(Action::Allow, policy.verdict(None).action);
+        assert_eq!(Action::Warn, policy.verdict(Some(1)).action);
+        assert_eq!(Action::RequireMfa, policy.verdict(Some(100)).action);
+
+        let verdict = policy.verdict(Some(101));
+        assert!(verdict.is_rejected());
+        assert_eq!(Some(Rule { max_count: 100, action: Action::Reject }), verdict.rule);
+        assert_eq!(Action::Allow, PasswordPolicy::new().verdict(Some(1_000_000)).action);
+    }
+
+    #[tokio::test]
+    async fn check() {
+        let path = temp_dir().join("pwned_pwd_policy");
+        let _ = std::fs::remove_file(&path);
+        let store = LocalStore::builder(&path).with_format(RecordFormat::HashesWithCounts).build().unwrap();
+        let hash = PwnedPwd::hash_password("password");
+        store.save(futures::stream::iter(vec![Chunk { prefix: Prefix::from_sha1(&hash), passwords: vec![PwnedPwd { hash, count: 42 }] }])).await.unwrap();
+
+        let verdict = policy().check(&store, "password").await;
+        assert_eq!((Action::RequireMfa, Some(42), false), (verdict.action, verdict.count, verdict.failed));
+        assert_eq!(Action::Allow, policy().check(&store, "correct horse battery staple").await.action);
+
+        let _ = std::fs::remove_file(&path);
+
+        // The store fails, it doesn't keep counts
+        let store = LocalStore::builder(&path).build().unwrap();
+        let verdict = policy().with_on_error(Action::RequireMfa).check(&store, "password").await;
+        assert_eq!((Action::RequireMfa, None, true), (verdict.action, verdict.count, verdict.failed));
+        let _ = std::fs::remove_file(path);
+    }
+}
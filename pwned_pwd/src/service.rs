@@ -0,0 +1,177 @@
+//! Password checks as tower services
+//!
+//! [CheckService] answers [CheckRequest]s with a [PwnedPwdClient], [CheckLayer] checks
+//! a password taken from requests of any inner service before calling it, so checks
+//! can be composed into any tower stack, e.g. of tonic or hyper
+
+use std::task::{Context, Poll};
+
+use futures::{future::BoxFuture, FutureExt};
+use pwned_pwd_core::PwnedPwd;
+use pwned_pwd_store::dyn_store::DynError;
+use tower::{Layer, Service};
+
+use crate::{CheckResult, PwnedPwdClient};
+
+/// A SHA-1 hash to check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckRequest {
+    pub hash: [u8; 20],
+}
+
+impl CheckRequest {
+    pub fn hash(hash: [u8; 20]) -> Self {
+        Self { hash }
+    }
+
+    pub fn password(password: impl AsRef<[u8]>) -> Self {
+        Self::hash(PwnedPwd::hash_password(password))
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CheckError {
+    #[error("The password is pwned")]
+    Pwned,
+
+    #[error("The password can't be checked: {0}")]
+    Unavailable(DynError),
+}
+
+/// Answers whether the hash is compromised, see [PwnedPwdClient::check_hash]
+#[derive(Clone)]
+pub struct CheckService {
+    client: PwnedPwdClient,
+}
+
+impl CheckService {
+    pub fn new(client: PwnedPwdClient) -> Self {
+        Self { client }
+    }
+}
+
+impl Service<CheckRequest> for CheckService {
+    type Response = CheckResult;
+    type Error = DynError;
+    type Future = BoxFuture<'static, Result<CheckResult, DynError>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: CheckRequest) -> Self::Future {
+        let client = self.client.clone();
+        async move { client.check_hash(req.hash).await }.boxed()
+    }
+}
+
+/// Checks the password of a request before the inner service gets it. `extract` returns
+/// None for requests without a password, they aren't checked
+#[derive(Clone)]
+pub struct CheckLayer<F> {
+    client: PwnedPwdClient,
+    extract: F,
+}
+
+impl<F> CheckLayer<F> {
+    pub fn new(client: PwnedPwdClient, extract: F) -> Self {
+        Self { client, extract }
+    }
+}
+
+impl<S, F: Clone> Layer<S> for CheckLayer<F> {
+    type Service = Checked<S, F>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Checked {
+            inner,
+            check: CheckService::new(self.client.clone()),
+            extract: self.extract.clone(),
+        }
+    }
+}
+
+/// A service behind a [CheckLayer]. Pwned passwords and failed checks are [CheckError]s,
+/// which can be downcast from the boxed errors
+#[derive(Clone)]
+pub struct Checked<S, F> {
+    inner: S,
+    check: CheckService,
+    extract: F,
+}
+
+impl<S, F, R> Service<R> for Checked<S, F>
+where
+    S: Service<R> + Clone + Send + 'static,
+    S::Response: Send + 'static,
+    S::Error: Into<DynError>,
+    S::Future: Send,
+    F: Fn(&R) -> Option<CheckRequest>,
+    R: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = DynError;
+    type Future = BoxFuture<'static, Result<S::Response, DynError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        let check = (self.extract)(&req).map(|check_req| self.check.call(check_req));
+        // The ready service is taken, its clone is left for the next call
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        async move {
+            if let Some(check) = check {
+                match check.await {
+                    Ok(res) if !res.found => {}
+                    Ok(_) => return Err(CheckError::Pwned.into()),
+                    Err(e) => return Err(CheckError::Unavailable(e).into()),
+                }
+            }
+            inner.call(req).await.map_err(Into::into)
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::{convert::Infallible, env::temp_dir};
+
+    use pwned_pwd_core::{Chunk, Prefix};
+    use pwned_pwd_store::WriteStore;
+    use pwned_pwd_store_local::{format::RecordFormat, LocalStore};
+    use tower::{service_fn, ServiceBuilder, ServiceExt};
+
+    use super::*;
+
+    async fn client() -> PwnedPwdClient {
+        let path = temp_dir().join("pwned_pwd_service");
+        let _ = std::fs::remove_file(&path);
+        let store = LocalStore::builder(&path).with_format(RecordFormat::HashesWithCounts).build().unwrap();
+        let pwd = PwnedPwd { hash: PwnedPwd::hash_password("password"), count: 3 };
+        store.save(futures::stream::iter([Chunk { prefix: Prefix::from_sha1(&pwd.hash), passwords: vec![pwd] }])).await.unwrap();
+        PwnedPwdClient::builder(store).build()
+    }
+
+    #[tokio::test]
+    async fn check_and_layer() {
+        let client = client().await;
+        let check = CheckService::new(client.clone());
+        assert!(check.clone().oneshot(CheckRequest::password("password")).await.unwrap().found);
+        assert!(!check.oneshot(CheckRequest::password("correct horse")).await.unwrap().found);
+
+        let signup = ServiceBuilder::new()
+            .layer(CheckLayer::new(client, |req: &(&'static str, Option<&'static str>)| req.1.map(CheckRequest::password)))
+            .service(service_fn(|req: (&'static str, Option<&'static str>)| async move { Ok::<_, Infallible>(req.0) }));
+
+        assert_eq!("alice", signup.clone().oneshot(("alice", Some("correct horse"))).await.unwrap());
+        assert_eq!("bob", signup.clone().oneshot(("bob", None)).await.unwrap());
+        let err = signup.oneshot(("eve", Some("password"))).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<CheckError>(), Some(CheckError::Pwned)));
+    }
+}
@@ -0,0 +1,165 @@
+//! Strength estimation combined with the pwned check
+//!
+//! Signup forms need both answers: is the password guessable and was it leaked.
+//! [StrengthEvaluator] runs zxcvbn and a store lookup in one call and merges them
+//! into a single score, a pwned password is as weak as the weakest one
+
+use pwned_pwd_core::PwnedPwd;
+use pwned_pwd_store::ReadStore;
+use zxcvbn::Score;
+
+/// The warning of a pwned password
+pub const PWNED_WARNING: &str = "This password has appeared in a data breach";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StrengthVerdict {
+    /// 0 (guessable in 10^3 guesses) to 4 (more than 10^10 guesses), 0 if the password is pwned
+    pub score: u8,
+
+    /// The zxcvbn score without the pwned check
+    pub strength: u8,
+
+    /// How many times the password was seen, None if it wasn't or the check failed
+    pub count: Option<u32>,
+
+    /// The store failed, only the strength is estimated
+    pub check_failed: bool,
+
+    /// The score is at least [StrengthEvaluator::with_min_score]
+    pub acceptable: bool,
+
+    /// What's wrong with the password, e.g. [PWNED_WARNING]
+    pub warning: Option<String>,
+
+    /// How to choose a better password
+    pub suggestions: Vec<String>,
+}
+
+/// Evaluates passwords against a store which keeps counts
+#[derive(Debug, Clone)]
+pub struct StrengthEvaluator {
+    min_score: u8,
+    max_count: u32,
+}
+
+impl Default for StrengthEvaluator {
+    fn default() -> Self {
+        Self {
+            min_score: 3,
+            max_count: 0,
+        }
+    }
+}
+
+impl StrengthEvaluator {
+    /// Passwords are acceptable with a score of at least 3 and if they aren't pwned
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The lowest acceptable score, at most 4
+    pub fn with_min_score(mut self, min_score: u8) -> Self {
+        self.min_score = min_score.min(u8::from(Score::Four));
+        self
+    }
+
+    /// Passwords seen at most `max_count` times aren't considered pwned
+    pub fn with_max_count(mut self, max_count: u32) -> Self {
+        self.max_count = max_count;
+        self
+    }
+
+    /// Estimates the strength and checks the password. `user_inputs` are words an attacker
+    /// would try first, e.g. the login and the name of the user
+    pub async fn evaluate<S>(
+        &self,
+        store: &S,
+        password: &str,
+        user_inputs: &[&str],
+    ) -> StrengthVerdict
+    where
+        S: ReadStore + Sync,
+        S::Error: std::fmt::Display,
+    {
+        let entropy = zxcvbn::zxcvbn(password, user_inputs);
+        let strength = u8::from(entropy.score());
+        let (count, check_failed) =
+            match store.exists_count(PwnedPwd::hash_password(password)).await {
+                Ok(count) => (count, false),
+                Err(e) => {
+                    tracing::warn!("Password can't be checked: {}", e);
+                    (None, true)
+                }
+            };
+
+        let pwned = count.is_some_and(|count| count > self.max_count);
+        let score = if pwned { 0 } else { strength };
+        let warning = match pwned {
+            true => Some(PWNED_WARNING.to_owned()),
+            false => entropy
+                .feedback()
+                .and_then(|feedback| feedback.warning())
+                .map(|warning| warning.to_string()),
+        };
+        let suggestions = entropy
+            .feedback()
+            .map(|feedback| {
+                feedback
+                    .suggestions()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        StrengthVerdict {
+            score,
+            strength,
+            count,
+            check_failed,
+            acceptable: score >= self.min_score,
+            warning,
+            suggestions,
+        }
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use pwned_pwd_core::{Chunk, Prefix};
+    use pwned_pwd_store::WriteStore;
+    use pwned_pwd_store_local::{format::RecordFormat, LocalStore};
+
+    use super::*;
+
+    /// A strong password, which is pwned
+    const LEAKED: &str = "correct horse battery staple";
+
+    #[tokio::test]
+    async fn evaluate() {
+        let path = temp_dir().join("pwned_pwd_strength");
+        let _ = std::fs::remove_file(&path);
+        let store = LocalStore::builder(&path).with_format(RecordFormat::HashesWithCounts).build().unwrap();
+        let hash = PwnedPwd::hash_password(LEAKED);
+        store.save(futures::stream::iter(vec![Chunk { prefix: Prefix::from_sha1(&hash), passwords: vec![PwnedPwd { hash, count: 5 }] }])).await.unwrap();
+        let evaluator = StrengthEvaluator::new();
+
+        let leaked = evaluator.evaluate(&store, LEAKED, &[]).await;
+        assert_eq!((0, 4, Some(5), false), (leaked.score, leaked.strength, leaked.count, leaked.acceptable));
+        assert_eq!(Some(PWNED_WARNING), leaked.warning.as_deref());
+        assert!(evaluator.clone().with_max_count(5).evaluate(&store, LEAKED, &[]).await.acceptable);
+
+        let weak = evaluator.evaluate(&store, "alice1990", &["alice"]).await;
+        assert!(!weak.acceptable);
+        assert_eq!(None, weak.count);
+        assert!(weak.score < 3);
+
+        let strong = evaluator.evaluate(&store, "plum tractor velvet nebula 42", &[]).await;
+        assert!(strong.acceptable);
+        assert!(!strong.check_failed);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,194 @@
+//! Synchronization of a store with the range API
+//!
+//! [sync] downloads every prefix, orders the chunks if the store needs it
+//! and saves them, so a store is refreshed by a single call
+
+use std::{pin::pin, sync::Mutex};
+
+use futures::{
+    channel::oneshot,
+    future::{self, Either},
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use pwned_pwd_core::{Chunk, Prefix};
+use pwned_pwd_downloader::{ChunkSource, DownloadError, Downloader};
+use pwned_pwd_store::{
+    progress::{SaveObserver, SaveProgress},
+    OrderRequirement, WriteStore,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum SyncError<E> {
+    #[error("Download error")]
+    Download(#[from] DownloadError),
+
+    #[error("Store error: {0}")]
+    Store(E),
+}
+
+/// Replaces the data of the store with all the ranges of the downloader
+pub async fn sync<S, const N: usize>(
+    downloader: &Downloader<N>,
+    store: &S,
+) -> Result<SaveProgress, SyncError<S::Error>>
+where
+    S: WriteStore<N> + Sync,
+{
+    sync_observed(downloader, store, &()).await
+}
+
+/// [sync] which reports the progress of the save to the observer
+pub async fn sync_observed<S, O, const N: usize>(
+    downloader: &Downloader<N>,
+    store: &S,
+    observer: &O,
+) -> Result<SaveProgress, SyncError<S::Error>>
+where
+    S: WriteStore<N> + Sync,
+    O: SaveObserver,
+{
+    sync_prefixes(downloader, store, Prefix::default().into_iter(), observer).await
+}
+
+/// Replaces the data of the store with the given ranges and returns the final progress.
+///
+/// The save is dropped as soon as a download fails, so the store is left as documented
+/// by [WriteStore::save] for a cancelled save and never completes with partial data
+#[tracing::instrument(name = "sync", skip_all)]
+pub async fn sync_prefixes<S, O, P, const N: usize>(
+    downloader: &Downloader<N>,
+    store: &S,
+    prefixes: P,
+    observer: &O,
+) -> Result<SaveProgress, SyncError<S::Error>>
+where
+    S: WriteStore<N> + Sync,
+    O: SaveObserver,
+    P: Iterator<Item = Prefix> + Send + 'static,
+{
+    let chunks: BoxStream<'static, Result<Chunk<N>, DownloadError>> = match S::order_requirement() {
+        OrderRequirement::Ordered => downloader.download_ordered(prefixes).boxed(),
+        OrderRequirement::Unordered => downloader.chunks(prefixes),
+    };
+
+    let (error_tx, error_rx) = oneshot::channel();
+    let chunks = stream::unfold(
+        (chunks, Some(error_tx)),
+        |(mut chunks, error_tx)| async move {
+            match chunks.next().await? {
+                Ok(chunk) => Some((chunk, (chunks, error_tx))),
+                Err(e) => {
+                    if let Some(error_tx) = error_tx {
+                        let _ = error_tx.send(e);
+                    }
+                    // The save must not see the end of the stream
+                    future::pending().await
+                }
+            }
+        },
+    )
+    .boxed();
+
+    let progress = Mutex::new(SaveProgress::default());
+    let recording = |current: &SaveProgress| {
+        *progress.lock().unwrap() = *current;
+        observer.on_progress(current);
+    };
+
+    let save = pin!(store.save_observed(chunks, &recording));
+    match future::select(save, error_rx).await {
+        Either::Left((res, _)) => res.map_err(SyncError::Store)?,
+        Either::Right((Ok(e), _)) => {
+            tracing::warn!("Sync is aborted: {}", e);
+            return Err(e.into());
+        }
+        // The stream has ended without errors
+        Either::Right((Err(_), save)) => save.await.map_err(SyncError::Store)?,
+    }
+
+    let progress = *progress.lock().unwrap();
+    tracing::info!(
+        "Synced {} chunks with {} records",
+        progress.chunks,
+        progress.records
+    );
+    Ok(progress)
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::{env::temp_dir, time::Duration};
+
+    use hex_literal::hex;
+    use pwned_pwd_store::ReadStore;
+    use pwned_pwd_store_local::LocalStore;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    use super::*;
+
+    /// Serves a single hash in every range, later ranges answer faster. The range `00007` is broken
+    async fn serve() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/range/", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            while let Ok((mut connection, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let n = connection.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..n]);
+                    }
+
+                    let request = String::from_utf8_lossy(&request);
+                    let prefix = u32::from_str_radix(&request["GET /range/".len()..][..5], 16).unwrap();
+                    tokio::time::sleep(Duration::from_millis(20 - prefix as u64)).await;
+
+                    let body = match prefix {
+                        7 => "broken",
+                        _ => "004DDDC80AE4683948C5A1C5903584D8087:3\r\n",
+                    };
+                    let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len());
+                    connection.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+        url
+    }
+
+    fn prefixes(range: std::ops::Range<u32>) -> impl Iterator<Item = Prefix> + Send + 'static {
+        range.map(|prefix| Prefix::create(prefix).unwrap())
+    }
+
+    #[tokio::test]
+    async fn sync_ordered() {
+        let downloader = Downloader::new(serve().await.parse().unwrap(), 4);
+        let path = temp_dir().join("pwned_pwd_sync");
+        let store = LocalStore::builder(&path).build().unwrap();
+
+        let observed = Mutex::new(0);
+        let progress = sync_prefixes(&downloader, &store, prefixes(0..7), &|_: &SaveProgress| *observed.lock().unwrap() += 1).await.unwrap();
+
+        assert_eq!(7, progress.chunks);
+        assert_eq!(7, progress.records);
+        assert_eq!(Some(Prefix::create(6).unwrap()), progress.last_prefix);
+        assert_eq!(7, *observed.lock().unwrap());
+        assert!(store.exists(hex!("00006004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn download_error() {
+        let downloader = Downloader::new(serve().await.parse().unwrap(), 4);
+        let path = temp_dir().join("pwned_pwd_sync_error");
+        let store = LocalStore::builder(&path).build().unwrap();
+
+        let err = sync_prefixes(&downloader, &store, prefixes(0..10), &()).await.unwrap_err();
+        let SyncError::Download(err) = err else { panic!("Download error expected") };
+        assert_eq!(Prefix::create(7).unwrap(), err.prefix());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,21 @@
+//! The signals of the crates in one place
+//!
+//! Spans (`tracing`):
+//! - `sync` — a [crate::sync()] of a store
+//! - `download_worker{worker}`, `download_prefix{prefix}` and `download_watchdog` —
+//!   see [pwned_pwd_downloader::telemetry]
+//! - `store{store, op}` — an operation of a [pwned_pwd_store::traced::TracedStore]
+//!
+//! Metrics (the `metrics` feature, through the [metrics](https://docs.rs/metrics) facade):
+//! - downloads, errors and the reorder buffer — the constants of
+//!   [pwned_pwd_downloader::telemetry]
+//! - lookups and writes of a `MeteredStore` — the constants of `pwned_pwd_store::metered`
+//!
+//! Failures of downloads and store operations are `warn` events
+
+/// Describes all the metrics to the installed recorder
+#[cfg(feature = "metrics")]
+pub fn describe() {
+    pwned_pwd_downloader::telemetry::describe();
+    pwned_pwd_store::metered::describe();
+}
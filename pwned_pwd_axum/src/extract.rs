@@ -0,0 +1,90 @@
+//! An extractor of bodies with passwords which aren't pwned
+
+use axum::{
+    extract::{FromRef, FromRequest, Request},
+    response::{IntoResponse, Response},
+    Form, Json,
+};
+use serde::de::DeserializeOwned;
+
+use crate::{layer::BodyKind, PasswordCheck};
+
+/// A body with a password to check
+pub trait PasswordField {
+    fn password(&self) -> &str;
+}
+
+/// A JSON or urlencoded form body whose [PasswordField] isn't pwned. The [PasswordCheck]
+/// is taken from the state of the router, its rejection is returned for pwned passwords
+#[derive(Debug, Clone)]
+pub struct NotPwned<T>(pub T);
+
+impl<T, S> FromRequest<S> for NotPwned<T>
+where
+    T: PasswordField + DeserializeOwned + Send,
+    S: Send + Sync,
+    PasswordCheck: FromRef<S>,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let value = match BodyKind::of(req.headers()) {
+            Some(BodyKind::Form) => {
+                let Form(value) = Form::<T>::from_request(req, state)
+                    .await
+                    .map_err(IntoResponse::into_response)?;
+                value
+            }
+            _ => {
+                let Json(value) = Json::<T>::from_request(req, state)
+                    .await
+                    .map_err(IntoResponse::into_response)?;
+                value
+            }
+        };
+
+        PasswordCheck::from_ref(state)
+            .verify(value.password())
+            .await?;
+        Ok(Self(value))
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use axum::{body::Body, http::{header, StatusCode}, routing::post, Router};
+    use serde::Deserialize;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_client::client;
+
+    #[derive(Deserialize)]
+    struct Signup {
+        login: String,
+        password: String,
+    }
+
+    impl PasswordField for Signup {
+        fn password(&self) -> &str {
+            &self.password
+        }
+    }
+
+    async fn send(content_type: &str, body: &'static str) -> StatusCode {
+        let app = Router::new()
+            .route("/signup", post(|NotPwned(signup): NotPwned<Signup>| async move { signup.login }))
+            .with_state(PasswordCheck::new(client()));
+        let req = Request::post("/signup").header(header::CONTENT_TYPE, content_type).body(Body::from(body)).unwrap();
+        app.oneshot(req).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn not_pwned() {
+        assert_eq!(StatusCode::OK, send("application/json", r#"{"login":"a","password":"correct horse"}"#).await);
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, send("application/json", r#"{"login":"a","password":"password"}"#).await);
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, send("application/x-www-form-urlencoded", "login=a&password=password").await);
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, send("application/json", r#"{"login":"a"}"#).await);
+    }
+}
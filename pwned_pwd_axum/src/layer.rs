@@ -0,0 +1,181 @@
+//! A layer checking a field of request bodies
+//!
+//! JSON and urlencoded form bodies are buffered, the configured field is checked and
+//! the body is passed on unchanged. Other requests and bodies without the field pass as is
+
+use std::{
+    convert::Infallible,
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::{self, Body},
+    extract::Request,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures::{future::BoxFuture, FutureExt};
+use tower::{Layer, Service};
+
+use crate::PasswordCheck;
+
+#[derive(Clone)]
+pub struct PasswordCheckLayer {
+    check: PasswordCheck,
+}
+
+impl PasswordCheckLayer {
+    pub fn new(check: PasswordCheck) -> Self {
+        Self { check }
+    }
+}
+
+impl<S> Layer<S> for PasswordCheckLayer {
+    type Service = PasswordCheckService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PasswordCheckService {
+            inner,
+            check: self.check.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PasswordCheckService<S> {
+    inner: S,
+    check: PasswordCheck,
+}
+
+impl<S> Service<Request> for PasswordCheckService<S>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let check = self.check.clone();
+        // The ready service is taken, its clone is left for the next call
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        async move {
+            let Some(kind) = BodyKind::of(req.headers()) else {
+                return inner.call(req).await;
+            };
+
+            let (parts, body) = req.into_parts();
+            let Ok(bytes) = body::to_bytes(body, check.body_limit).await else {
+                return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+            };
+
+            if let Some(password) = kind.field(&bytes, &check.field) {
+                if let Err(rejection) = check.verify(&password).await {
+                    return Ok(rejection);
+                }
+            }
+            inner
+                .call(Request::from_parts(parts, Body::from(bytes)))
+                .await
+        }
+        .boxed()
+    }
+}
+
+/// Bodies with fields
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BodyKind {
+    Json,
+    Form,
+}
+
+impl BodyKind {
+    pub(crate) fn of(headers: &HeaderMap) -> Option<Self> {
+        let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+        let mime = content_type.split(';').next()?.trim();
+        if mime.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            Some(Self::Form)
+        } else if mime.eq_ignore_ascii_case("application/json") || mime.ends_with("+json") {
+            Some(Self::Json)
+        } else {
+            None
+        }
+    }
+
+    /// A string field, a JSON pointer if it starts with `/`
+    fn field(self, body: &[u8], field: &str) -> Option<String> {
+        match self {
+            Self::Form => url::form_urlencoded::parse(body)
+                .find(|(name, _)| name == field)
+                .map(|(_, value)| value.into_owned()),
+            Self::Json => {
+                let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+                let field = match field.starts_with('/') {
+                    true => value.pointer(field),
+                    false => value.get(field),
+                };
+                field?.as_str().map(str::to_owned)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use axum::{routing::post, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test_client::{client, failing_client};
+
+    fn app(check: PasswordCheck) -> Router {
+        Router::new()
+            .route("/signup", post(|body: String| async move { body }))
+            .layer(check.layer())
+    }
+
+    async fn send(app: Router, content_type: &str, body: &'static str) -> (StatusCode, String) {
+        let req = Request::post("/signup").header(header::CONTENT_TYPE, content_type).body(Body::from(body)).unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        let status = response.status();
+        let body = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn checks_fields() {
+        let app = app(PasswordCheck::new(client()));
+
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, send(app.clone(), "application/json", r#"{"password":"password"}"#).await.0);
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, send(app.clone(), "application/x-www-form-urlencoded", "login=a&password=password").await.0);
+        assert_eq!(
+            (StatusCode::OK, r#"{"password":"correct horse"}"#.to_owned()),
+            send(app.clone(), "application/json; charset=utf-8", r#"{"password":"correct horse"}"#).await,
+        );
+        assert_eq!(StatusCode::OK, send(app.clone(), "text/plain", "password=password").await.0);
+        assert_eq!(StatusCode::OK, send(app, "application/json", r#"{"login":"password"}"#).await.0);
+    }
+
+    #[tokio::test]
+    async fn configured() {
+        let check = PasswordCheck::new(client())
+            .with_field("/user/secret")
+            .with_rejection(|| (StatusCode::BAD_REQUEST, "pwned").into_response());
+        assert_eq!((StatusCode::BAD_REQUEST, "pwned".to_owned()), send(app(check), "application/json", r#"{"user":{"secret":"password"}}"#).await);
+
+        assert_eq!(StatusCode::OK, send(app(PasswordCheck::new(failing_client())), "application/json", r#"{"password":"password"}"#).await.0);
+        let closed = PasswordCheck::new(failing_client()).with_fail_closed();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, send(app(closed), "application/json", r#"{"password":"password"}"#).await.0);
+
+        let limited = PasswordCheck::new(client()).with_body_limit(8);
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, send(app(limited), "application/json", r#"{"password":"correct horse"}"#).await.0);
+    }
+}
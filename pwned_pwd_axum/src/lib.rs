@@ -0,0 +1,102 @@
+//! Rejection of pwned passwords in axum applications
+//!
+//! A [PasswordCheck] is configured once and used either as a layer, which checks a field of
+//! JSON and form bodies before a route sees them, or through the [NotPwned] extractor
+
+use std::sync::Arc;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use pwned_pwd::PwnedPwdClient;
+
+pub mod extract;
+pub mod layer;
+#[cfg(test)]
+mod test_client;
+
+pub use extract::{NotPwned, PasswordField};
+pub use layer::{PasswordCheckLayer, PasswordCheckService};
+
+/// Bodies are read up to this size by the layer by default
+pub const DEFAULT_BODY_LIMIT: usize = 64 * 1024;
+
+type Rejection = Arc<dyn Fn() -> Response + Send + Sync>;
+
+/// Where and how passwords are checked, cheap to clone
+#[derive(Clone)]
+pub struct PasswordCheck {
+    client: PwnedPwdClient,
+    field: Arc<str>,
+    rejection: Rejection,
+    fail_open: bool,
+    body_limit: usize,
+}
+
+impl PasswordCheck {
+    /// Checks the `password` field with the client. Passwords are accepted, if the client fails
+    pub fn new(client: PwnedPwdClient) -> Self {
+        Self {
+            client,
+            field: "password".into(),
+            rejection: Arc::new(|| {
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "The password has appeared in a data breach, choose another one",
+                )
+                    .into_response()
+            }),
+            fail_open: true,
+            body_limit: DEFAULT_BODY_LIMIT,
+        }
+    }
+
+    /// The field checked by the layer: a name of a form or a top-level JSON field,
+    /// or a JSON pointer like `/user/password`
+    pub fn with_field(mut self, field: impl Into<Arc<str>>) -> Self {
+        self.field = field.into();
+        self
+    }
+
+    /// The response to a pwned password, 422 with a plain text message by default
+    pub fn with_rejection(
+        mut self,
+        rejection: impl Fn() -> Response + Send + Sync + 'static,
+    ) -> Self {
+        self.rejection = Arc::new(rejection);
+        self
+    }
+
+    /// Reject requests with 503, if the client fails, instead of accepting the password
+    pub fn with_fail_closed(mut self) -> Self {
+        self.fail_open = false;
+        self
+    }
+
+    /// Maximum size of a body read by the layer, larger bodies are rejected with 413
+    pub fn with_body_limit(mut self, body_limit: usize) -> Self {
+        self.body_limit = body_limit;
+        self
+    }
+
+    pub fn layer(self) -> PasswordCheckLayer {
+        PasswordCheckLayer::new(self)
+    }
+
+    /// The rejection if the password is pwned
+    pub async fn verify(&self, password: &str) -> Result<(), Response> {
+        match self.client.is_compromised(password).await {
+            Ok(false) => Ok(()),
+            Ok(true) => Err((self.rejection)()),
+            Err(e) if self.fail_open => {
+                tracing::warn!("Password is accepted unchecked: {}", e);
+                Ok(())
+            }
+            Err(e) => {
+                tracing::error!("Password can't be checked: {}", e);
+                Err(StatusCode::SERVICE_UNAVAILABLE.into_response())
+            }
+        }
+    }
+}
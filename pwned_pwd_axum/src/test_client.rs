@@ -0,0 +1,36 @@
+//! Clients for unit tests
+
+use futures::Stream;
+use pwned_pwd::{core::PwnedPwd, store::ReadStore, PwnedPwdClient};
+
+/// Only `password` is pwned, unless the store fails
+pub(crate) struct TestStore {
+    fails: bool,
+}
+
+impl ReadStore for TestStore {
+    type Error = &'static str;
+
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        match self.fails {
+            true => Err("Unavailable"),
+            false => Ok(val == PwnedPwd::hash_password("password")),
+        }
+    }
+
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        futures::stream::empty()
+    }
+
+    async fn exists_count(&self, val: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        Ok(self.exists(val).await?.then_some(1))
+    }
+}
+
+pub(crate) fn client() -> PwnedPwdClient {
+    PwnedPwdClient::builder(TestStore { fails: false }).build()
+}
+
+pub(crate) fn failing_client() -> PwnedPwdClient {
+    PwnedPwdClient::builder(TestStore { fails: true }).build()
+}
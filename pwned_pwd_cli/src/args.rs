@@ -0,0 +1,126 @@
+//! Values of command line arguments
+
+use std::{path::PathBuf, str::FromStr};
+
+use pwned_pwd::core::{Prefix, PrefixIterator};
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ArgError {
+    #[error("Unknown store '{0}', expected local:<path>, sqlite:<path>, sled:<path> or hibp")]
+    UnknownStore(String),
+
+    #[error("Invalid prefix '{0}', expected 5 hex characters")]
+    InvalidPrefix(String),
+
+    #[error("Empty prefix range '{0}'")]
+    EmptyRange(String),
+
+    #[error("Invalid hash '{0}', expected 40 hex characters of SHA-1")]
+    InvalidHash(String),
+}
+
+/// A store given as `<backend>:<location>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreSpec {
+    Local(PathBuf),
+    Sqlite(PathBuf),
+    Sled(PathBuf),
+
+    /// The online range API, read-only
+    Hibp,
+}
+
+impl FromStr for StoreSpec {
+    type Err = ArgError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "hibp" {
+            return Ok(Self::Hibp);
+        }
+
+        match s.split_once(':') {
+            Some(("local", path)) if !path.is_empty() => Ok(Self::Local(path.into())),
+            Some(("sqlite", path)) if !path.is_empty() => Ok(Self::Sqlite(path.into())),
+            Some(("sled", path)) if !path.is_empty() => Ok(Self::Sled(path.into())),
+            _ => Err(ArgError::UnknownStore(s.to_owned())),
+        }
+    }
+}
+
+/// An inclusive range of prefixes given as `21BD4` or `00000-0FFFF`, all prefixes by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefixRange {
+    pub first: Prefix,
+    pub last: Prefix,
+}
+
+impl PrefixRange {
+    pub fn iter(&self) -> PrefixIterator {
+        self.first.up_to(self.last)
+    }
+}
+
+impl Default for PrefixRange {
+    fn default() -> Self {
+        Self {
+            first: Prefix::default(),
+            last: Prefix::max(),
+        }
+    }
+}
+
+impl FromStr for PrefixRange {
+    type Err = ArgError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let prefix = |p: &str| p.parse().map_err(|_| ArgError::InvalidPrefix(p.to_owned()));
+        let (first, last) = match s.split_once('-') {
+            Some((first, last)) => (prefix(first)?, prefix(last)?),
+            None => (prefix(s)?, prefix(s)?),
+        };
+
+        match first <= last {
+            true => Ok(Self { first, last }),
+            false => Err(ArgError::EmptyRange(s.to_owned())),
+        }
+    }
+}
+
+/// A SHA-1 hash in hex
+pub fn parse_hash(s: &str) -> Result<[u8; 20], ArgError> {
+    let mut hash = [0; 20];
+    hex::decode_to_slice(s, &mut hash).map_err(|_| ArgError::InvalidHash(s.to_owned()))?;
+    Ok(hash)
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_spec() {
+        assert_eq!(Ok(StoreSpec::Local("/var/lib/pwned".into())), "local:/var/lib/pwned".parse());
+        assert_eq!(Ok(StoreSpec::Sqlite("pwned.db".into())), "sqlite:pwned.db".parse());
+        assert_eq!(Ok(StoreSpec::Hibp), "hibp".parse());
+        assert_eq!(Err(ArgError::UnknownStore("local:".into())), "local:".parse::<StoreSpec>());
+        assert_eq!(Err(ArgError::UnknownStore("redis://localhost".into())), "redis://localhost".parse::<StoreSpec>());
+    }
+
+    #[test]
+    fn prefix_range() {
+        let range: PrefixRange = "00000-0000F".parse().unwrap();
+        assert_eq!(16, range.iter().len());
+        assert_eq!(1, "21BD4".parse::<PrefixRange>().unwrap().iter().len());
+        assert_eq!(Prefix::count() as usize + 1, PrefixRange::default().iter().len());
+
+        assert_eq!(Err(ArgError::EmptyRange("0000F-00000".into())), "0000F-00000".parse::<PrefixRange>());
+        assert_eq!(Err(ArgError::InvalidPrefix("0000".into())), "0000-00001".parse::<PrefixRange>());
+    }
+
+    #[test]
+    fn hash() {
+        assert_eq!(0x5B, parse_hash("5baa61e4c9b93f3f0682250b6cf8331b7ee68fd8").unwrap()[0]);
+        assert!(parse_hash("5BAA6").is_err());
+    }
+}
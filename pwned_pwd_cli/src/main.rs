@@ -0,0 +1,242 @@
+//! `pwned-pwd`: management of the pwned passwords data set without writing code
+//!
+//! `download` syncs the ranges into a store, `check` checks a password or its hash
+//! and exits with 1 if it is pwned, so it can be used in scripts
+
+use std::{
+    io::{self, BufRead, IsTerminal},
+    process::ExitCode,
+};
+
+use args::{parse_hash, PrefixRange, StoreSpec};
+use clap::{Parser, Subcommand};
+use pwned_pwd::{
+    core::PwnedPwd,
+    downloader::Downloader,
+    store::{dyn_store::DynError, progress::SaveProgress, WriteStore},
+    PwnedPwdClient, PwnedPwdClientBuilder,
+};
+use pwned_pwd_store_hibp::HibpStore;
+use pwned_pwd_store_local::{format::RecordFormat, LocalStore};
+use pwned_pwd_store_sled::SledStore;
+use pwned_pwd_store_sqlite::SqliteStore;
+use url::Url;
+
+mod args;
+
+/// Chunks between progress lines of a download
+const PROGRESS_EVERY: u64 = 4096;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "pwned-pwd",
+    version,
+    about = "Downloads and checks pwned passwords"
+)]
+struct Cli {
+    /// Log debug messages
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Downloads the ranges into a store, replacing its data
+    Download {
+        /// local:<path>, sqlite:<path> or sled:<path>
+        #[arg(long)]
+        store: StoreSpec,
+
+        /// Prefixes to download, e.g. `21BD4` or `00000-0FFFF`, all of them by default
+        #[arg(long)]
+        prefixes: Option<PrefixRange>,
+
+        /// Keep counts of hashes in a local store
+        #[arg(long)]
+        counts: bool,
+
+        /// The range API
+        #[arg(long, default_value = Downloader::DEFAULT_BASE_URL)]
+        url: Url,
+
+        /// Parallel downloads
+        #[arg(long, default_value_t = 16)]
+        spawns: u32,
+    },
+
+    /// Checks a password, exits with 1 if it is pwned
+    Check {
+        /// local:<path>, sqlite:<path>, sled:<path> or hibp (the online API)
+        #[arg(long, default_value = "hibp")]
+        store: StoreSpec,
+
+        /// SHA-1 of the password in hex, otherwise the password is read from stdin
+        /// (without echo on a terminal)
+        #[arg(long)]
+        hash: Option<String>,
+
+        /// The password is pwned, if it was seen at least this many times
+        #[arg(long, default_value_t = 1)]
+        min_count: u32,
+    },
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    tracing_subscriber::fmt()
+        .with_writer(io::stderr)
+        .with_max_level(match cli.verbose {
+            true => tracing::Level::DEBUG,
+            false => tracing::Level::WARN,
+        })
+        .init();
+
+    let res = match cli.command {
+        Command::Download {
+            store,
+            prefixes,
+            counts,
+            url,
+            spawns,
+        } => download(store, prefixes.unwrap_or_default(), counts, url, spawns)
+            .await
+            .map(|_| ExitCode::SUCCESS),
+        Command::Check {
+            store,
+            hash,
+            min_count,
+        } => check(store, hash, min_count)
+            .await
+            .map(|pwned| match pwned {
+                true => ExitCode::from(1),
+                false => ExitCode::SUCCESS,
+            }),
+    };
+
+    res.unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        ExitCode::from(2)
+    })
+}
+
+async fn download(
+    store: StoreSpec,
+    prefixes: PrefixRange,
+    counts: bool,
+    url: Url,
+    spawns: u32,
+) -> Result<(), DynError> {
+    let downloader = Downloader::new(url, spawns);
+    match store {
+        StoreSpec::Local(path) => {
+            let format = match counts {
+                true => RecordFormat::HashesWithCounts,
+                false => RecordFormat::Hashes,
+            };
+            let store = LocalStore::builder(path).with_format(format).build()?;
+            sync(&downloader, &store, prefixes).await
+        }
+        StoreSpec::Sqlite(path) => sync(&downloader, &SqliteStore::open(path)?, prefixes).await,
+        StoreSpec::Sled(path) => sync(&downloader, &SledStore::open(path)?, prefixes).await,
+        StoreSpec::Hibp => Err("The online API is read-only".into()),
+    }
+}
+
+async fn sync<S>(downloader: &Downloader, store: &S, prefixes: PrefixRange) -> Result<(), DynError>
+where
+    S: WriteStore + Sync,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    let total = prefixes.iter().len();
+    let report = |progress: &SaveProgress| {
+        if progress.chunks.is_multiple_of(PROGRESS_EVERY) {
+            eprintln!(
+                "{}/{} prefixes, {} records",
+                progress.chunks, total, progress.records
+            );
+        }
+    };
+
+    let progress = pwned_pwd::sync_prefixes(downloader, store, prefixes.iter(), &report).await?;
+    eprintln!(
+        "Downloaded {} prefixes, {} records",
+        progress.chunks, progress.records
+    );
+    Ok(())
+}
+
+async fn check(store: StoreSpec, hash: Option<String>, min_count: u32) -> Result<bool, DynError> {
+    let hash = match hash {
+        Some(hash) => parse_hash(&hash)?,
+        None => PwnedPwd::hash_password(read_password()?),
+    };
+
+    let client = client(store)?.with_min_count(min_count).build();
+    let pwned = client.is_hash_compromised(hash).await?;
+    println!("{}", if pwned { "Pwned" } else { "Not pwned" });
+    Ok(pwned)
+}
+
+fn client(store: StoreSpec) -> Result<PwnedPwdClientBuilder, DynError> {
+    Ok(match store {
+        StoreSpec::Local(path) => PwnedPwdClient::builder(LocalStore::open_read_only(path)?),
+        StoreSpec::Sqlite(path) => PwnedPwdClient::builder(SqliteStore::open(path)?),
+        StoreSpec::Sled(path) => PwnedPwdClient::builder(SledStore::open(path)?),
+        StoreSpec::Hibp => PwnedPwdClient::builder(HibpStore::new()),
+    })
+}
+
+/// Prompts on a terminal, otherwise takes the first line, e.g. of a pipe
+fn read_password() -> io::Result<String> {
+    if io::stdin().is_terminal() {
+        return rpassword::prompt_password("Password: ");
+    }
+
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use clap::CommandFactory;
+    use pwned_pwd_test_utils::{serve_ranges, MockStore};
+
+    use super::*;
+
+    #[test]
+    fn cli() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::parse_from(["pwned-pwd", "download", "--store", "local:pwned.bin", "--prefixes", "00000-000FF"]);
+        let Command::Download { store, prefixes, counts, spawns, .. } = cli.command else { panic!("Download expected") };
+        assert_eq!(StoreSpec::Local("pwned.bin".into()), store);
+        assert_eq!(Some(256), prefixes.map(|prefixes| prefixes.iter().len()));
+        assert!(!counts);
+        assert_eq!(16, spawns);
+
+        let cli = Cli::parse_from(["pwned-pwd", "check"]);
+        assert!(matches!(cli.command, Command::Check { store: StoreSpec::Hibp, hash: None, min_count: 1 }));
+        assert!(Cli::try_parse_from(["pwned-pwd", "download", "--store", "hibp:"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn download_with_counts_then_check() {
+        let url = serve_ranges(MockStore::new().with_passwords([("password", 10)])).await.unwrap();
+        let path = temp_dir().join("pwned_pwd_cli_counts");
+        let hash = format!("{:X}", PwnedPwd { hash: PwnedPwd::hash_password("password"), count: 0 });
+
+        download(StoreSpec::Local(path.clone()), hash[..5].parse().unwrap(), true, url, 1).await.unwrap();
+
+        assert!(check(StoreSpec::Local(path.clone()), Some(hash.clone()), 10).await.unwrap());
+        assert!(!check(StoreSpec::Local(path.clone()), Some(hash), 11).await.unwrap());
+        assert!(!check(StoreSpec::Local(path), Some("0".repeat(40)), 1).await.unwrap());
+    }
+}
@@ -0,0 +1,298 @@
+//! Declarative configuration of a sync service
+//!
+//! A [Config] describes the downloader, the store backend and the schedule of syncs.
+//! It is read from TOML or YAML and environment variables override it, so operators
+//! tune a deployment without recompiling. [Pipeline::from_config] assembles it
+
+use std::{collections::HashMap, fmt::Display, path::PathBuf, str::FromStr};
+
+use pwned_pwd::downloader::Downloader;
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+use url::Url;
+
+pub mod pipeline;
+
+pub use pipeline::{ConfiguredStore, Pipeline};
+
+/// Prefix of environment variables. Nested fields are separated by `__`,
+/// e.g. `PWNED_PWD__DOWNLOADER__SPAWNS=32`
+pub const ENV_PREFIX: &str = "PWNED_PWD__";
+
+/// The field which selects the variant of a table, e.g. [StoreConfig]
+const TAG: &str = "backend";
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("IO error")]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid TOML: {0}")]
+    Toml(#[from] toml::de::Error),
+
+    #[error("Invalid YAML: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+
+    #[error("Invalid config: {0}")]
+    Invalid(#[from] serde_json::Error),
+
+    #[error("Unknown config format of '{0}', expected .toml, .yaml or .yml")]
+    UnknownFormat(PathBuf),
+
+    #[error("Store can't be opened: {0}")]
+    Store(pwned_pwd::store::dyn_store::DynError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub downloader: DownloaderConfig,
+
+    pub store: StoreConfig,
+
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownloaderConfig {
+    /// The range API, [Downloader::DEFAULT_BASE_URL] by default
+    pub base_url: Url,
+
+    /// Parallel downloads
+    #[serde(deserialize_with = "parsed")]
+    pub spawns: u32,
+
+    /// Seconds without progress after which a download is cancelled, see
+    /// [pwned_pwd::downloader::watchdog::Watchdog]. No watchdog by default
+    #[serde(deserialize_with = "parsed_option")]
+    pub stall_timeout_secs: Option<u64>,
+
+    /// Retries of a stalled download
+    #[serde(deserialize_with = "parsed")]
+    pub retries: u32,
+}
+
+impl Default for DownloaderConfig {
+    fn default() -> Self {
+        Self {
+            base_url: Downloader::DEFAULT_BASE_URL
+                .parse()
+                .expect("Invalid default url"),
+            spawns: 16,
+            stall_timeout_secs: None,
+            retries: 0,
+        }
+    }
+}
+
+/// A backend, selected by the `backend` field
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase", deny_unknown_fields)]
+pub enum StoreConfig {
+    Local {
+        path: PathBuf,
+
+        /// Keep counts of hashes
+        #[serde(default, deserialize_with = "parsed")]
+        counts: bool,
+    },
+    Sqlite {
+        path: PathBuf,
+    },
+    Sled {
+        path: PathBuf,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// Seconds between syncs, a single sync without it
+    #[serde(deserialize_with = "parsed_option")]
+    pub interval_secs: Option<u64>,
+
+    /// Sync at start, otherwise after the first interval
+    #[serde(deserialize_with = "parsed")]
+    pub run_on_start: bool,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: None,
+            run_on_start: true,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_toml(s: &str) -> Result<Self, ConfigError> {
+        Self::from_value(toml::from_str(s)?, HashMap::new())
+    }
+
+    pub fn from_yaml(s: &str) -> Result<Self, ConfigError> {
+        Self::from_value(serde_yaml::from_str(s)?, HashMap::new())
+    }
+
+    /// Only the [ENV_PREFIX] variables of the process
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_value(Value::Object(Map::new()), std::env::vars().collect())
+    }
+
+    /// Reads a TOML or YAML file (by its extension) and applies the [ENV_PREFIX]
+    /// variables of the process over it
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, ConfigError> {
+        let path = path.into();
+        let content = std::fs::read_to_string(&path)?;
+        let value = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&content)?,
+            Some("yaml" | "yml") => serde_yaml::from_str(&content)?,
+            _ => return Err(ConfigError::UnknownFormat(path)),
+        };
+        Self::from_value(value, std::env::vars().collect())
+    }
+
+    /// Overrides fields of the value with the variables and deserializes it.
+    /// A variable is a string, numeric and bool fields parse it.
+    /// A `backend` variable which selects another backend replaces the whole table
+    fn from_value(mut value: Value, vars: HashMap<String, String>) -> Result<Self, ConfigError> {
+        let mut vars = vars
+            .into_iter()
+            .filter_map(|(name, var)| {
+                let path = name.strip_prefix(ENV_PREFIX)?.to_lowercase();
+                Some((path.split("__").map(str::to_owned).collect::<Vec<_>>(), var))
+            })
+            .collect::<Vec<_>>();
+        // The other fields of the table of a tag are applied over the selected variant
+        vars.sort_by_key(|(path, _)| path.last().map(String::as_str) != Some(TAG));
+
+        for (path, var) in vars {
+            let (key, tables) = path.split_last().expect("Split always has a part");
+            let mut table = &mut value;
+            for name in tables {
+                table = object(table).entry(name).or_insert(Value::Null);
+            }
+
+            let table = object(table);
+            if key == TAG && table.get(TAG).and_then(Value::as_str) != Some(&var) {
+                table.clear();
+            }
+            table.insert(key.clone(), Value::String(var));
+        }
+
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+/// The value as an object, anything else is replaced with an empty one
+fn object(value: &mut Value) -> &mut Map<String, Value> {
+    if !value.is_object() {
+        *value = Value::Object(Map::new());
+    }
+    value.as_object_mut().expect("Object")
+}
+
+/// The value of a field or its string form, which an environment variable sets
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Parsed<T> {
+    Value(T),
+    Str(String),
+}
+
+impl<T: FromStr<Err: Display>> Parsed<T> {
+    fn parse<E: serde::de::Error>(self) -> Result<T, E> {
+        match self {
+            Parsed::Value(value) => Ok(value),
+            Parsed::Str(s) => s
+                .parse()
+                .map_err(|e| E::custom(format_args!("invalid value '{s}': {e}"))),
+        }
+    }
+}
+
+fn parsed<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + FromStr<Err: Display>,
+{
+    Parsed::deserialize(deserializer)?.parse()
+}
+
+fn parsed_option<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + FromStr<Err: Display>,
+{
+    Option::<Parsed<T>>::deserialize(deserializer)?
+        .map(Parsed::parse)
+        .transpose()
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+        [downloader]
+        spawns = 4
+        stall_timeout_secs = 30
+
+        [store]
+        backend = "local"
+        path = "/var/lib/pwned/pwned.bin"
+        counts = true
+
+        [schedule]
+        interval_secs = 86400
+    "#;
+
+    #[test]
+    fn toml_and_yaml() {
+        let config = Config::from_toml(TOML).unwrap();
+        assert_eq!(4, config.downloader.spawns);
+        assert_eq!(Some(30), config.downloader.stall_timeout_secs);
+        assert_eq!(Downloader::DEFAULT_BASE_URL, config.downloader.base_url.as_str());
+        assert_eq!(StoreConfig::Local { path: "/var/lib/pwned/pwned.bin".into(), counts: true }, config.store);
+        assert_eq!(ScheduleConfig { interval_secs: Some(86400), run_on_start: true }, config.schedule);
+
+        let config = Config::from_yaml("store:\n  backend: sqlite\n  path: pwned.db\n").unwrap();
+        assert_eq!(StoreConfig::Sqlite { path: "pwned.db".into() }, config.store);
+        assert_eq!(DownloaderConfig::default(), config.downloader);
+
+        assert!(matches!(Config::from_toml("[store]\nbackend = \"redis\""), Err(ConfigError::Invalid(_))));
+        assert!(matches!(Config::from_toml("[store]\nbackend = \"sled\"\npath = \"db\"\nspawns = 1"), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn env() {
+        let vars = HashMap::from([
+            ("PWNED_PWD__DOWNLOADER__SPAWNS".to_owned(), "32".to_owned()),
+            ("PWNED_PWD__STORE__BACKEND".to_owned(), "sled".to_owned()),
+            ("PWNED_PWD__STORE__PATH".to_owned(), "/data/sled".to_owned()),
+            ("PWNED_PWD__SCHEDULE__RUN_ON_START".to_owned(), "false".to_owned()),
+            ("HOME".to_owned(), "/root".to_owned()),
+        ]);
+        let config = Config::from_value(toml::from_str(TOML).unwrap(), vars).unwrap();
+        assert_eq!(32, config.downloader.spawns);
+        assert_eq!(Some(30), config.downloader.stall_timeout_secs);
+        assert_eq!(StoreConfig::Sled { path: "/data/sled".into() }, config.store);
+        assert!(!config.schedule.run_on_start);
+
+        let vars = HashMap::from([
+            ("PWNED_PWD__STORE__BACKEND".to_owned(), "local".to_owned()),
+            ("PWNED_PWD__STORE__PATH".to_owned(), "12345".to_owned()),
+            ("PWNED_PWD__SCHEDULE__INTERVAL_SECS".to_owned(), "60".to_owned()),
+        ]);
+        let config = Config::from_value(toml::from_str(TOML).unwrap(), vars).unwrap();
+        assert_eq!(StoreConfig::Local { path: "12345".into(), counts: true }, config.store);
+        assert_eq!(Some(60), config.schedule.interval_secs);
+
+        let vars = HashMap::from([("PWNED_PWD__DOWNLOADER__SPAWNS".to_owned(), "many".to_owned())]);
+        assert!(matches!(Config::from_value(toml::from_str(TOML).unwrap(), vars), Err(ConfigError::Invalid(_))));
+    }
+}
@@ -0,0 +1,133 @@
+//! A downloader, a store and a schedule assembled from a [Config]
+
+use std::time::Duration;
+
+use pwned_pwd::{
+    downloader::{watchdog::Watchdog, Downloader},
+    store::{dyn_store::DynError, progress::SaveProgress, WriteStore},
+};
+use pwned_pwd_store_local::{format::RecordFormat, LocalStore};
+use pwned_pwd_store_sled::SledStore;
+use pwned_pwd_store_sqlite::SqliteStore;
+
+use crate::{Config, ConfigError, ScheduleConfig, StoreConfig};
+
+/// The store selected by [StoreConfig]
+pub enum ConfiguredStore {
+    Local(LocalStore),
+    Sqlite(SqliteStore),
+    Sled(SledStore),
+}
+
+impl ConfiguredStore {
+    pub fn open(config: &StoreConfig) -> Result<Self, ConfigError> {
+        let store = match config {
+            StoreConfig::Local { path, counts } => {
+                let format = match counts {
+                    true => RecordFormat::HashesWithCounts,
+                    false => RecordFormat::Hashes,
+                };
+                LocalStore::builder(path)
+                    .with_format(format)
+                    .build()
+                    .map(Self::Local)
+                    .map_err(|e| ConfigError::Store(e.into()))?
+            }
+            StoreConfig::Sqlite { path } => SqliteStore::open(path)
+                .map(Self::Sqlite)
+                .map_err(|e| ConfigError::Store(e.into()))?,
+            StoreConfig::Sled { path } => SledStore::open(path)
+                .map(Self::Sled)
+                .map_err(|e| ConfigError::Store(e.into()))?,
+        };
+        Ok(store)
+    }
+}
+
+/// Syncs the store with the range API on the schedule
+pub struct Pipeline {
+    downloader: Downloader,
+    store: ConfiguredStore,
+    schedule: ScheduleConfig,
+}
+
+impl Pipeline {
+    /// Opens the store and configures the downloader
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let mut downloader =
+            Downloader::new(config.downloader.base_url.clone(), config.downloader.spawns);
+        if let Some(stall_timeout) = config.downloader.stall_timeout_secs {
+            downloader = downloader.with_watchdog(
+                Watchdog::new(Duration::from_secs(stall_timeout))
+                    .with_retries(config.downloader.retries),
+            );
+        }
+
+        Ok(Self {
+            downloader,
+            store: ConfiguredStore::open(&config.store)?,
+            schedule: config.schedule.clone(),
+        })
+    }
+
+    pub fn store(&self) -> &ConfiguredStore {
+        &self.store
+    }
+
+    /// A single sync of all the ranges
+    pub async fn sync_once(&self) -> Result<SaveProgress, DynError> {
+        match &self.store {
+            ConfiguredStore::Local(store) => self.sync(store).await,
+            ConfiguredStore::Sqlite(store) => self.sync(store).await,
+            ConfiguredStore::Sled(store) => self.sync(store).await,
+        }
+    }
+
+    async fn sync<S>(&self, store: &S) -> Result<SaveProgress, DynError>
+    where
+        S: WriteStore + Sync,
+        S::Error: std::error::Error + Send + Sync + 'static,
+    {
+        Ok(pwned_pwd::sync(&self.downloader, store).await?)
+    }
+
+    /// Syncs on the schedule. Without an interval returns the result of a single sync,
+    /// otherwise runs forever and a failed sync is retried at the next interval
+    pub async fn run(&self) -> Result<(), DynError> {
+        let Some(interval) = self.schedule.interval_secs.map(Duration::from_secs) else {
+            return self.sync_once().await.map(|_| ());
+        };
+
+        if !self.schedule.run_on_start {
+            tokio::time::sleep(interval).await;
+        }
+        loop {
+            if let Err(e) = self.sync_once().await {
+                tracing::error!("Sync failed: {}", e);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use super::*;
+
+    #[test]
+    fn from_config() {
+        let path = temp_dir().join("pwned_pwd_config_pipeline");
+        let _ = std::fs::remove_file(&path);
+        let config = Config::from_toml(&format!("[store]\nbackend = \"local\"\npath = {:?}\n[downloader]\nstall_timeout_secs = 5", path)).unwrap();
+
+        let pipeline = Pipeline::from_config(&config).unwrap();
+        assert!(matches!(pipeline.store(), ConfiguredStore::Local(_)));
+
+        let broken = Config::from_toml("[store]\nbackend = \"sqlite\"\npath = \"/nonexistent/dir/pwned.db\"").unwrap();
+        assert!(matches!(Pipeline::from_config(&broken), Err(ConfigError::Store(_))));
+        let _ = std::fs::remove_file(path);
+    }
+}
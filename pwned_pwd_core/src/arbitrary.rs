@@ -0,0 +1,79 @@
+//! proptest strategies for property tests of stores
+//!
+//! Generated chunks are well-formed: every hash has the chunk prefix,
+//! hashes are unique and sorted, counts are positive
+
+use proptest::{
+    arbitrary::{any, Arbitrary},
+    collection::vec,
+    strategy::{BoxedStrategy, Strategy},
+};
+
+use crate::{Chunk, Prefix, PwnedPwd};
+
+impl Arbitrary for Prefix {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Prefix>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        (0..=Prefix::MAX_PREFIX).prop_map(Prefix).boxed()
+    }
+}
+
+impl<const N: usize> Arbitrary for PwnedPwd<N> {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<PwnedPwd<N>>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        (proptest::array::uniform::<_, N>(any::<u8>()), 1..=u32::MAX)
+            .prop_map(|(hash, count)| PwnedPwd { hash, count })
+            .boxed()
+    }
+}
+
+impl<const N: usize> Arbitrary for Chunk<N> {
+    /// Max count of passwords in a chunk, 0 means 64
+    type Parameters = usize;
+    type Strategy = BoxedStrategy<Chunk<N>>;
+
+    fn arbitrary_with(max_len: Self::Parameters) -> Self::Strategy {
+        let max_len = if max_len == 0 { 64 } else { max_len };
+
+        (any::<Prefix>(), vec(any::<PwnedPwd<N>>(), 0..=max_len))
+            .prop_map(|(prefix, mut passwords)| {
+                for pwd in passwords.iter_mut() {
+                    let low_nibble = pwd.hash[2] & 0x0F;
+                    prefix.write_prefix(&mut pwd.hash);
+                    pwd.hash[2] |= low_nibble;
+                }
+
+                let mut chunk = Chunk { prefix, passwords };
+                chunk.dedup();
+                chunk
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use proptest::proptest;
+
+    use super::*;
+    use crate::NtlmChunk;
+
+    proptest! {
+        #[test]
+        fn chunk_is_well_formed(chunk in any::<Chunk>(), ntlm in any::<NtlmChunk>()) {
+            assert_eq!(Ok(()), chunk.validate());
+            assert_eq!(Ok(()), ntlm.validate());
+            assert!(chunk.passwords.iter().all(|p| p.count > 0));
+        }
+
+        #[test]
+        fn prefix_is_valid(prefix in any::<Prefix>()) {
+            assert_eq!(Some(prefix), Prefix::create(u32::from(prefix)));
+        }
+    }
+}
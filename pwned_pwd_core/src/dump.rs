@@ -0,0 +1,151 @@
+//! Reader of the downloadable dumps
+//!
+//! The official downloader writes the whole data set into one text file of
+//! `HASH:COUNT` lines ordered by hash. [DumpReader] streams such a file as
+//! [Chunk]s of consecutive prefixes, so a store can be filled without the API
+
+use std::io::{self, BufRead};
+
+use crate::{Chunk, ParseError, Prefix, PwnedPwd, SHA1_LEN};
+
+#[derive(thiserror::Error, Debug)]
+pub enum DumpError {
+    #[error("Io error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid line {line}: {error}")]
+    Parse { line: u64, error: ParseError },
+
+    #[error("Line {line} is not greater than the previous one, the dump must be ordered by hash")]
+    Unordered { line: u64 },
+}
+
+/// Chunks of a dump of `HASH:COUNT` lines ordered by hash.
+/// Blank lines, `\r\n` terminators and a UTF-8 BOM are skipped.
+/// `N` is a hash length: [SHA1_LEN] (default) or [crate::NTLM_LEN]
+pub struct DumpReader<R, const N: usize = SHA1_LEN> {
+    data: R,
+    buf: String,
+    line: u64,
+
+    /// The first password of the next chunk
+    next: Option<PwnedPwd<N>>,
+}
+
+impl<R: BufRead, const N: usize> DumpReader<R, N> {
+    pub fn new(data: R) -> Self {
+        Self {
+            data,
+            buf: String::new(),
+            line: 0,
+            next: None,
+        }
+    }
+
+    /// Count of the lines read
+    pub fn lines(&self) -> u64 {
+        self.line
+    }
+
+    /// Reads the next password or None at the end of the dump
+    fn read(&mut self) -> Result<Option<PwnedPwd<N>>, DumpError> {
+        loop {
+            self.buf.clear();
+            if self.data.read_line(&mut self.buf)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+
+            let line = match self.line {
+                1 => self.buf.trim_start_matches('\u{feff}'),
+                _ => &self.buf,
+            };
+
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+
+            return line.parse().map(Some).map_err(|error| DumpError::Parse {
+                line: self.line,
+                error,
+            });
+        }
+    }
+}
+
+impl<R: BufRead, const N: usize> Iterator for DumpReader<R, N> {
+    type Item = Result<Chunk<N>, DumpError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = match self.next.take() {
+            Some(first) => first,
+            None => match self.read() {
+                Ok(first) => first?,
+                Err(e) => return Some(Err(e)),
+            },
+        };
+
+        let prefix = Prefix::from_hash(&first.hash);
+        let mut passwords = vec![first];
+
+        loop {
+            let pwd = match self.read() {
+                Ok(Some(pwd)) => pwd,
+                Ok(None) => break,
+                Err(e) => return Some(Err(e)),
+            };
+
+            if pwd.hash <= passwords[passwords.len() - 1].hash {
+                return Some(Err(DumpError::Unordered { line: self.line }));
+            }
+
+            if Prefix::from_hash(&pwd.hash) != prefix {
+                self.next = Some(pwd);
+                break;
+            }
+            passwords.push(pwd);
+        }
+
+        Some(Ok(Chunk { prefix, passwords }))
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::NTLM_LEN;
+
+    #[test]
+    fn chunks() {
+        let dump = "\u{feff}0000000A0E3B9F25FF41DE4B5AC238C2D545C7A8:15\r\n\
+                    0000000A1D4B746FAA3FD526FF6D5BC8052FDB38:16\r\n\
+                    \r\n\
+                    21BD4004DDDC80AE4683948C5A1C5903584D8087:3\r\n";
+
+        let chunks = DumpReader::new(Cursor::new(dump)).collect::<Result<Vec<Chunk>, _>>().unwrap();
+        assert_eq!(2, chunks.len());
+        assert_eq!(Prefix::create(0x00000).unwrap(), chunks[0].prefix);
+        assert_eq!(vec![15, 16], chunks[0].passwords.iter().map(|pwd| pwd.count).collect::<Vec<_>>());
+        assert_eq!(Prefix::create(0x21BD4).unwrap(), chunks[1].prefix);
+        assert!(chunks.iter().all(|chunk| chunk.validate().is_ok()));
+
+        let ntlm = DumpReader::<_, NTLM_LEN>::new(Cursor::new("00000001BE6A4E48F8ABAE29E1E9BFF1:2\n")).next().unwrap().unwrap();
+        assert_eq!(2, ntlm.passwords[0].count);
+    }
+
+    #[test]
+    fn errors() {
+        let unordered = "21BD4004DDDC80AE4683948C5A1C5903584D8087:3\n\
+                         0000000A0E3B9F25FF41DE4B5AC238C2D545C7A8:15\n";
+        let err = DumpReader::<_>::new(Cursor::new(unordered)).next().unwrap().unwrap_err();
+        assert!(matches!(err, DumpError::Unordered { line: 2 }));
+
+        let invalid = "0000000A0E3B9F25FF41DE4B5AC238C2D545C7A8:15\n\
+                       0000000A0E3B9F25FF41DE4B5AC238C2D545C7A9\n";
+        let err = DumpReader::<_>::new(Cursor::new(invalid)).next().unwrap().unwrap_err();
+        assert!(matches!(err, DumpError::Parse { line: 2, .. }));
+    }
+}
@@ -1,46 +1,182 @@
 use std::{
-    fmt::{Debug, Display},
+    fmt::{Debug, Display, LowerHex, UpperHex},
     hash::Hash,
-    str::from_utf8_unchecked,
+    str::{from_utf8_unchecked, FromStr},
 };
 
-use hex::ToHex;
+#[cfg(feature = "test-arbitrary")]
+mod arbitrary;
+pub mod dump;
+pub mod query;
+#[cfg(feature = "serde")]
+mod ser;
+
+/// Length of a SHA-1 hash in bytes
+pub const SHA1_LEN: usize = 20;
+
+/// Length of a NTLM hash in bytes
+pub const NTLM_LEN: usize = 16;
+
+/// Hash algorithm of a data set
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum HashKind {
+    #[default]
+    Sha1,
+    Ntlm,
+}
+
+impl HashKind {
+    /// Hash kind by its length in bytes
+    pub const fn of_len(len: usize) -> Option<Self> {
+        match len {
+            SHA1_LEN => Some(Self::Sha1),
+            NTLM_LEN => Some(Self::Ntlm),
+            _ => None,
+        }
+    }
+
+    /// Hash length in bytes
+    pub const fn hash_len(&self) -> usize {
+        match self {
+            Self::Sha1 => SHA1_LEN,
+            Self::Ntlm => NTLM_LEN,
+        }
+    }
+
+    /// Value of the haveibeenpwned `mode` query parameter
+    pub const fn mode(&self) -> &'static str {
+        match self {
+            Self::Sha1 => "sha1",
+            Self::Ntlm => "ntlm",
+        }
+    }
+}
 
 /// Representetion of a pwned password
+/// `N` is a hash length: [SHA1_LEN] (default) or [NTLM_LEN]
 #[derive(Debug, PartialEq, Eq, Clone)]
-pub struct PwnedPwd {
-    /// password SHA-1
-    pub sha1: [u8; 20],
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub struct PwnedPwd<const N: usize = SHA1_LEN> {
+    /// password hash. It was `sha1` before the hash length became generic,
+    /// serde still reads the old name
+    #[cfg_attr(feature = "serde", serde(with = "ser::hex_upper", alias = "sha1"))]
+    pub hash: [u8; N],
 
     /// how many times it appears in the data set
     pub count: u32,
 }
 
-/// Prefix for downloading from haveibeenpwned with k-anonimity
-#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Hash)]
-pub struct Prefix(u32);
+/// A pwned password from the NTLM data set
+pub type NtlmPwd = PwnedPwd<NTLM_LEN>;
 
-/// String representation of a [Prefix]
-#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
-pub struct PrefixStr([u8; 5]);
+impl<const N: usize> PwnedPwd<N> {
+    /// Hash kind of the password or None, if `N` is not a known hash length
+    pub const fn kind() -> Option<HashKind> {
+        HashKind::of_len(N)
+    }
+}
+
+impl<const N: usize> PwnedPwd<N> {
+    /// Creates a password from a hex hash in either case
+    pub fn try_from_hex(hash: &str, count: u32) -> Result<Self, ParseError> {
+        let mut res = [0; N];
+        hex::decode_to_slice(hash, &mut res)?;
+        Ok(PwnedPwd { hash: res, count })
+    }
+}
+
+impl PwnedPwd {
+    /// SHA-1 of the password, the field before it became [PwnedPwd::hash]
+    #[deprecated(note = "use the `hash` field")]
+    pub fn sha1(&self) -> &[u8; 20] {
+        &self.hash
+    }
 
-impl FromIterator<char> for PrefixStr {
-    fn from_iter<T: IntoIterator<Item = char>>(iter: T) -> Self {
-        let mut iter = iter.into_iter();
-        for _ in 0..3 {
-            iter.next().expect("Invalid iterator len");
+    /// Upper case hex of the hash, like haveibeenpwned renders it
+    pub fn sha1_hex(&self) -> String {
+        format!("{:X}", self)
+    }
+
+    /// SHA-1 of a password, the lookup key of the data set.
+    /// A password which isn't UTF-8 (e.g. from a legacy system) is hashed as is
+    #[cfg(feature = "sha1")]
+    pub fn hash_password(password: impl AsRef<[u8]>) -> [u8; 20] {
+        use sha1::Digest;
+
+        sha1::Sha1::digest(password.as_ref()).into()
+    }
+}
+
+/// Formats the hash without the count
+impl<const N: usize> LowerHex for PwnedPwd<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_hex(f, &self.hash, HEX_LOWER)
+    }
+}
+
+/// Formats the hash without the count
+impl<const N: usize> UpperHex for PwnedPwd<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_hex(f, &self.hash, HEX_UPPER)
+    }
+}
+
+const HEX_LOWER: &[u8; 16] = b"0123456789abcdef";
+const HEX_UPPER: &[u8; 16] = b"0123456789ABCDEF";
+
+fn write_hex(f: &mut std::fmt::Formatter<'_>, bytes: &[u8], table: &[u8; 16]) -> std::fmt::Result {
+    use std::fmt::Write;
+
+    for b in bytes {
+        f.write_char(table[(b >> 4) as usize] as char)?;
+        f.write_char(table[(b & 0x0F) as usize] as char)?;
+    }
+    Ok(())
+}
+
+impl<const N: usize> FromStr for PwnedPwd<N> {
+    type Err = ParseError;
+
+    /// Parses a full hash line `HASH:COUNT` of the downloadable dumps,
+    /// like `21BD4004DDDC80AE4683948C5A1C5903584D8087:13`. Surrounding whitespace is ignored
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim_ascii();
+        let hash_len = N * 2;
+
+        if value.len() < hash_len + 2 {
+            return Err(ParseError::InvalidStringLength);
         }
 
-        let mut res = [0u8; 5];
-        for i in 0..5 {
-            let value = iter.next().expect("Invalid iterator len");
-            res[i] = value as u8;
+        if value.as_bytes()[hash_len] != b':' {
+            return Err(ParseError::InvalidFullString);
         }
 
-        PrefixStr(res)
+        let mut hash = [0; N];
+        hex::decode_to_slice(&value[..hash_len], &mut hash)?;
+
+        Ok(PwnedPwd {
+            hash,
+            count: value[hash_len + 1..].parse()?,
+        })
     }
 }
 
+/// Prefix for downloading from haveibeenpwned with k-anonimity
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub struct Prefix(u32);
+
+/// String representation of a [Prefix]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct PrefixStr([u8; 5]);
+
 impl From<&Prefix> for PrefixStr {
     fn from(value: &Prefix) -> Self {
         value.as_prefix_str()
@@ -49,8 +185,8 @@ impl From<&Prefix> for PrefixStr {
 
 impl AsRef<str> for PrefixStr {
     fn as_ref(&self) -> &str {
-        // PrefixStr may be created ONLY from Prefix with `encode_hex_upper`
-        // so, while `hex` crate returns us good data, we can be sure that is valid utf8 bytes
+        // PrefixStr may be created ONLY by `Prefix::as_prefix_str` from `HEX_UPPER` chars
+        // so we can be sure that is valid utf8 bytes
         unsafe { from_utf8_unchecked(&self.0) }
     }
 }
@@ -94,10 +230,53 @@ impl Prefix {
         Self::create(self.0 + v)
     }
 
+    /// Get a previous prefix or None, if self is zero
+    pub fn prev(&self) -> Option<Self> {
+        self.checked_sub(1)
+    }
+
+    /// Get a prefix `v` steps back or None, if self - v is less than zero
+    pub fn checked_sub(&self, v: u32) -> Option<Self> {
+        self.0.checked_sub(v).map(Prefix)
+    }
+
+    /// Iterate from self to `last` inclusive. Empty, if `last` is less than self
+    pub fn up_to(&self, last: Prefix) -> PrefixIterator {
+        PrefixIterator {
+            range: Some((*self, last)).filter(|(first, last)| first.0 <= last.0),
+        }
+    }
+
+    /// Every `step`th prefix starting from zero
+    pub fn sample_every(step: u32) -> std::iter::StepBy<PrefixIterator> {
+        Prefix::default().into_iter().step_by(step.max(1) as usize)
+    }
+
+    /// A uniformly distributed random prefix
+    #[cfg(feature = "rand")]
+    pub fn random(rng: &mut (impl rand::Rng + ?Sized)) -> Self {
+        Prefix(rng.gen_range(0..=Self::MAX_PREFIX))
+    }
+
+    /// `amount` distinct random prefixes in ascending order (or all the prefixes, if `amount` is greater)
+    #[cfg(feature = "rand")]
+    pub fn sample_random(rng: &mut (impl rand::Rng + ?Sized), amount: usize) -> Vec<Self> {
+        let total = Self::MAX_PREFIX as usize + 1;
+        let mut res = rand::seq::index::sample(rng, total, amount.min(total))
+            .into_iter()
+            .map(|i| Prefix(i as u32))
+            .collect::<Vec<_>>();
+        res.sort_unstable_by_key(|p| p.0);
+        res
+    }
+
     /// Get string representation
     pub fn as_prefix_str(&self) -> PrefixStr {
-        let bytes = self.0.to_be_bytes();
-        bytes.encode_hex_upper()
+        let mut res = [0u8; 5];
+        for (i, char) in res.iter_mut().enumerate() {
+            *char = HEX_UPPER[(self.0 >> ((4 - i) * 4)) as usize & 0x0F];
+        }
+        PrefixStr(res)
     }
 
     /// Write prefix into slice. Slice length must be greater or equal 3
@@ -105,8 +284,110 @@ impl Prefix {
         dst[0..3].copy_from_slice(&(self.0 << 4).to_be_bytes()[1..])
     }
 
+    /// The first bytes of the hashes of the prefix. A store of ordered hashes has the hashes
+    /// of the prefix from these bytes up to the bytes of the next prefix
+    pub fn to_bytes(&self) -> [u8; 3] {
+        let mut res = [0; 3];
+        self.write_prefix(&mut res);
+        res
+    }
+
     pub fn parser(&self) -> Parser {
-        self.clone().into()
+        (*self).into()
+    }
+
+    /// Get a prefix of the hash
+    pub fn from_sha1(sha1: &[u8; 20]) -> Self {
+        Self::from_hash(sha1)
+    }
+
+    /// Get a prefix of a hash of any kind. Hash length must be greater or equal 3
+    pub fn from_hash(hash: &[u8]) -> Self {
+        Prefix(u32::from_be_bytes([0, hash[0], hash[1], hash[2]]) >> 4)
+    }
+
+    /// Get a prefix of the password SHA-1
+    #[cfg(feature = "sha1")]
+    pub fn of_password(password: &str) -> Self {
+        Self::from_sha1(&PwnedPwd::hash_password(password))
+    }
+
+    /// Compose a full hash from the prefix and the suffix
+    pub fn with_suffix(&self, suffix: &Suffix) -> [u8; 20] {
+        let mut res = [0; 20];
+        self.write_prefix(&mut res);
+        suffix.write_suffix(&mut res);
+        res
+    }
+}
+
+impl std::ops::Add<Suffix> for Prefix {
+    type Output = [u8; 20];
+
+    fn add(self, rhs: Suffix) -> Self::Output {
+        self.with_suffix(&rhs)
+    }
+}
+
+/// The rest of a SHA-1 hash after a [Prefix]: 35 hex characters or 140 bits
+/// The first byte contains the lowest 4 bits of the third hash byte
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+pub struct Suffix([u8; 18]);
+
+impl Suffix {
+    /// Get a suffix of the hash
+    pub fn from_sha1(sha1: &[u8; 20]) -> Self {
+        let mut res = [0; 18];
+        res[0] = sha1[2] & 0x0F;
+        res[1..].copy_from_slice(&sha1[3..]);
+        Suffix(res)
+    }
+
+    /// Create a suffix from its bytes or None, if the first byte is greater than 0x0F
+    pub fn from_bytes(bytes: [u8; 18]) -> Option<Self> {
+        if bytes[0] > 0x0F {
+            None
+        } else {
+            Some(Suffix(bytes))
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 18] {
+        &self.0
+    }
+
+    /// Write suffix into the last 140 bits of a slice, keeping the prefix bits.
+    /// Slice length must be 20
+    pub fn write_suffix(&self, dst: &mut [u8]) {
+        dst[2] = (dst[2] & 0xF0) | self.0[0];
+        dst[3..20].copy_from_slice(&self.0[1..]);
+    }
+}
+
+impl FromStr for Suffix {
+    type Err = ParseError;
+
+    /// Parses 35 hex characters, like `004DDDC80AE4683948C5A1C5903584D8087`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.len() != 35 {
+            return Err(ParseError::InvalidStringLength);
+        }
+
+        let mut res = [0; 18];
+        res[0] = val(value.as_bytes()[0], 0)?;
+        hex::decode_to_slice(&value[1..], &mut res[1..])?;
+
+        Ok(Suffix(res))
+    }
+}
+
+impl Display for Suffix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:X}", self.0[0])?;
+        for b in &self.0[1..] {
+            write!(f, "{:02X}", b)?;
+        }
+        Ok(())
     }
 }
 
@@ -122,39 +403,145 @@ impl TryFrom<u32> for Prefix {
     }
 }
 
+impl From<Prefix> for u32 {
+    fn from(value: Prefix) -> Self {
+        value.0
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = PrefixError;
+
+    /// Parses 5 hex characters, like `21BD4`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 5 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(PrefixError::InvalidString);
+        }
+
+        u32::from_str_radix(s, 16)
+            .map_err(|_| PrefixError::InvalidString)
+            .and_then(Self::try_from)
+    }
+}
+
 impl IntoIterator for Prefix {
     type Item = Prefix;
 
     type IntoIter = PrefixIterator;
 
     fn into_iter(self) -> Self::IntoIter {
-        PrefixIterator { next: Some(self) }
+        self.up_to(Prefix::max())
     }
 }
 
+/// Iterator over an inclusive range of prefixes, from both ends
 pub struct PrefixIterator {
-    next: Option<Prefix>,
+    range: Option<(Prefix, Prefix)>,
 }
 
 impl Iterator for PrefixIterator {
     type Item = Prefix;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let current = self.next.clone();
-        self.next = self.next.and_then(|v| v.next());
-        current
+        let (first, last) = self.range?;
+        self.range = first
+            .next()
+            .filter(|_| first != last)
+            .map(|next| (next, last));
+        Some(first)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self
+            .range
+            .map_or(0, |(first, last)| (last.0 - first.0) as usize + 1);
+        (len, Some(len))
+    }
+}
+
+impl DoubleEndedIterator for PrefixIterator {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (first, last) = self.range?;
+        self.range = last
+            .prev()
+            .filter(|_| first != last)
+            .map(|prev| (first, prev));
+        Some(last)
     }
 }
 
-pub struct Chunk {
+impl ExactSizeIterator for PrefixIterator {}
+
+/// Passwords of a prefix
+#[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub struct Chunk<const N: usize = SHA1_LEN> {
     pub prefix: Prefix,
-    pub passwords: Vec<PwnedPwd>,
+    pub passwords: Vec<PwnedPwd<N>>,
 }
 
-impl IntoIterator for Chunk {
-    type Item = PwnedPwd;
+/// Passwords of a prefix from the NTLM data set
+pub type NtlmChunk = Chunk<NTLM_LEN>;
 
-    type IntoIter = std::vec::IntoIter<PwnedPwd>;
+impl<const N: usize> Chunk<N> {
+    /// Checks that all the passwords have the chunk prefix and are strictly sorted
+    pub fn validate(&self) -> Result<(), ChunkError> {
+        let mut prev: Option<&[u8; N]> = None;
+
+        for (index, pwd) in self.passwords.iter().enumerate() {
+            if Prefix::from_hash(&pwd.hash) != self.prefix {
+                return Err(ChunkError::PrefixMismatch {
+                    index,
+                    prefix: self.prefix,
+                });
+            }
+
+            if prev.is_some_and(|prev| prev >= &pwd.hash) {
+                return Err(ChunkError::Unordered { index });
+            }
+
+            prev = Some(&pwd.hash);
+        }
+
+        Ok(())
+    }
+
+    /// Sorts the passwords and combines duplicate hashes, summing their counts
+    pub fn dedup(&mut self) {
+        self.passwords.sort_unstable_by_key(|pwd| pwd.hash);
+        self.passwords.dedup_by(|next, prev| {
+            let duplicate = next.hash == prev.hash;
+            if duplicate {
+                prev.count = prev.count.saturating_add(next.count);
+            }
+            duplicate
+        });
+    }
+
+    /// Combines passwords of two chunks of the same prefix (e.g. an old dump and a fresh download).
+    /// The result is sorted, and counts of duplicate hashes are summed
+    pub fn merge(mut self, other: Chunk<N>) -> Result<Chunk<N>, ChunkError> {
+        if self.prefix != other.prefix {
+            return Err(ChunkError::DifferentPrefixes {
+                left: self.prefix,
+                right: other.prefix,
+            });
+        }
+
+        self.passwords.extend(other.passwords);
+        self.dedup();
+        Ok(self)
+    }
+}
+
+impl<const N: usize> IntoIterator for Chunk<N> {
+    type Item = PwnedPwd<N>;
+
+    type IntoIter = std::vec::IntoIter<PwnedPwd<N>>;
 
     fn into_iter(self) -> Self::IntoIter {
         self.passwords.into_iter()
@@ -165,6 +552,21 @@ impl IntoIterator for Chunk {
 pub enum PrefixError {
     #[error("Prefix is out of range, it must be from 0x00000 to 0xfffff")]
     OutOfRange,
+
+    #[error("Prefix must contain exactly 5 hex characters")]
+    InvalidString,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ChunkError {
+    #[error("Password {index} doesn't start with the chunk prefix '{prefix}'")]
+    PrefixMismatch { index: usize, prefix: Prefix },
+
+    #[error("Password {index} is not greater than the previous one")]
+    Unordered { index: usize },
+
+    #[error("Chunks of different prefixes '{left}' and '{right}' can't be merged")]
+    DifferentPrefixes { left: Prefix, right: Prefix },
 }
 
 #[derive(thiserror::Error, Debug, PartialEq)]
@@ -178,17 +580,21 @@ pub enum ParseError {
     #[error("Invalid string lenght")]
     InvalidStringLength,
 
-    #[error("String must contain 35 hex characters, then a ':' char and then a positive or zero integer")]
+    #[error("String must contain 35 (27 for NTLM) hex characters, then a ':' char and then a positive or zero integer")]
     InvalidString,
+
+    #[error("String must contain 40 (32 for NTLM) hex characters, then a ':' char and then a positive or zero integer")]
+    InvalidFullString,
 }
 
 /// Haveibeenpwned result lines parser
+/// `N` is a hash length: [SHA1_LEN] (default) or [NTLM_LEN]
 #[derive(Debug, Default, PartialEq, Eq)]
-pub struct Parser {
+pub struct Parser<const N: usize = SHA1_LEN> {
     prefix: Prefix,
 }
 
-impl From<Prefix> for Parser {
+impl<const N: usize> From<Prefix> for Parser<N> {
     fn from(value: Prefix) -> Self {
         Self { prefix: value }
     }
@@ -198,28 +604,42 @@ impl Parser {
     pub fn new(prefix: Prefix) -> Self {
         Self { prefix }
     }
+}
 
-    pub fn parse(&self, value: impl AsRef<str>) -> Result<PwnedPwd, ParseError> {
-        let value = value.as_ref();
+impl Parser<NTLM_LEN> {
+    /// Parser of NTLM range lines
+    pub fn ntlm(prefix: Prefix) -> Self {
+        Self { prefix }
+    }
+}
 
-        if value.len() < 37 {
+impl<const N: usize> Parser<N> {
+    /// Count of hex characters after a prefix
+    const SUFFIX_LEN: usize = N * 2 - 5;
+
+    /// Parses a range line like `004DDDC80AE4683948C5A1C5903584D8087:13`.
+    /// Surrounding whitespace and line terminators (`\r\n` included) are ignored
+    pub fn parse(&self, value: impl AsRef<str>) -> Result<PwnedPwd<N>, ParseError> {
+        let value = value.as_ref().trim_ascii();
+
+        if value.len() < Self::SUFFIX_LEN + 2 {
             return Err(ParseError::InvalidStringLength);
         }
 
-        if value.as_bytes()[35] != b':' {
+        if value.as_bytes()[Self::SUFFIX_LEN] != b':' {
             return Err(ParseError::InvalidString);
         }
 
-        let mut res = [0; 20];
-        self.prefix.write_prefix(&mut res);
+        let mut hash = [0; N];
+        self.prefix.write_prefix(&mut hash);
 
-        res[2] = res[2] | val(value.as_bytes()[0], 0)?;
+        hash[2] |= val(value.as_bytes()[0], 0)?;
 
-        hex::decode_to_slice(&value[1..35], &mut res[3..])?;
+        hex::decode_to_slice(&value[1..Self::SUFFIX_LEN], &mut hash[3..])?;
 
         Ok(PwnedPwd {
-            sha1: res,
-            count: value[36..].parse()?,
+            hash,
+            count: value[Self::SUFFIX_LEN + 1..].parse()?,
         })
     }
 }
@@ -238,7 +658,7 @@ fn val(char: u8, idx: usize) -> Result<u8, hex::FromHexError> {
 
 impl Display for Prefix {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.as_prefix_str().fmt(f)
+        f.write_str(self.as_prefix_str().as_ref())
     }
 }
 
@@ -299,17 +719,30 @@ mod tests {
         assert_eq!(None, prefix.next());
     }
 
+    #[test]
+    fn prefix_from_str() {
+        assert_eq!("021BD", Prefix(0x021BD).to_string());
+        assert_eq!(Ok(Prefix(0x21BD4)), "21BD4".parse());
+        assert_eq!(Ok(Prefix(0x21BD4)), "21bd4".parse());
+        assert_eq!(Ok(Prefix(0x00000)), "00000".parse());
+        assert_eq!(Ok(Prefix(0xFFFFF)), "FFFFF".parse());
+        assert_eq!(Err::<Prefix, PrefixError>(PrefixError::InvalidString), "1BD4".parse());
+        assert_eq!(Err::<Prefix, PrefixError>(PrefixError::InvalidString), "21BD40".parse());
+        assert_eq!(Err::<Prefix, PrefixError>(PrefixError::InvalidString), "+1BD4".parse());
+        assert_eq!(Err::<Prefix, PrefixError>(PrefixError::InvalidString), "21BQ4".parse());
+    }
+
     #[test]
     fn parse() {
 
         let parser = Parser::new(Prefix(0x21BD4));
 
-        assert_eq!(PwnedPwd { sha1: hex::decode("21BD4004DDDC80AE4683948C5A1C5903584D8087").unwrap().try_into().unwrap(), count: 13 }, parser.parse("004DDDC80AE4683948C5A1C5903584D8087:13").unwrap());
-        assert_eq!(PwnedPwd { sha1: hex::decode("21BD4FFF08998514E6E8F28DBB4CA9F74EA5CAFA").unwrap().try_into().unwrap(), count: 3 }, parser.parse("FFF08998514E6E8F28DBB4CA9F74EA5CAFA:3").unwrap());
+        assert_eq!(PwnedPwd { hash: hex::decode("21BD4004DDDC80AE4683948C5A1C5903584D8087").unwrap().try_into().unwrap(), count: 13 }, parser.parse("004DDDC80AE4683948C5A1C5903584D8087:13").unwrap());
+        assert_eq!(PwnedPwd { hash: hex::decode("21BD4FFF08998514E6E8F28DBB4CA9F74EA5CAFA").unwrap().try_into().unwrap(), count: 3 }, parser.parse("FFF08998514E6E8F28DBB4CA9F74EA5CAFA:3").unwrap());
 
         let parser = Parser { prefix: Prefix(0x00000) };
-        assert_eq!(PwnedPwd { sha1: hex::decode("00000004DDDC80AE4683948C5A1C5903584D8087").unwrap().try_into().unwrap(), count: 0 }, parser.parse("004DDDC80AE4683948C5A1C5903584D8087:0").unwrap());
-        assert_eq!(PwnedPwd { sha1: hex::decode("00000FFF08998514E6E8F28DBB4CA9F74EA5CAFA").unwrap().try_into().unwrap(), count: 999999 }, parser.parse("FFF08998514E6E8F28DBB4CA9F74EA5CAFA:999999").unwrap());
+        assert_eq!(PwnedPwd { hash: hex::decode("00000004DDDC80AE4683948C5A1C5903584D8087").unwrap().try_into().unwrap(), count: 0 }, parser.parse("004DDDC80AE4683948C5A1C5903584D8087:0").unwrap());
+        assert_eq!(PwnedPwd { hash: hex::decode("00000FFF08998514E6E8F28DBB4CA9F74EA5CAFA").unwrap().try_into().unwrap(), count: 999999 }, parser.parse("FFF08998514E6E8F28DBB4CA9F74EA5CAFA:999999").unwrap());
 
         assert_eq!(Err::<PwnedPwd, ParseError>(ParseError::FromHexError(hex::FromHexError::InvalidHexCharacter { c: 'Q', index: 0 })), parser.parse("QFF08998514E6E8F28DBB4CA9F74EA5CAFA:999999"));
         assert_eq!(Err::<PwnedPwd, ParseError>(ParseError::FromHexError(hex::FromHexError::InvalidHexCharacter { c: ':', index: 33 })), parser.parse("AFF08998514E6E8F28DBB4CA9F74EA5CAF::999999"));
@@ -317,6 +750,141 @@ mod tests {
         assert_eq!(Err::<PwnedPwd, ParseError>(ParseError::InvalidString), parser.parse("FF08998514E6E8F28DBB4CA9F74EA5CAFA|999999"));
     }
 
+    #[test]
+    fn parse_tolerant() {
+        let parser = Parser::new(Prefix(0x21BD4));
+        let expected = PwnedPwd { hash: hex::decode("21BD4004DDDC80AE4683948C5A1C5903584D8087").unwrap().try_into().unwrap(), count: 13 };
+
+        assert_eq!(expected, parser.parse("004DDDC80AE4683948C5A1C5903584D8087:13\r\n").unwrap());
+        assert_eq!(expected, parser.parse("004DDDC80AE4683948C5A1C5903584D8087:13\r").unwrap());
+        assert_eq!(expected, parser.parse(" 004DDDC80AE4683948C5A1C5903584D8087:13 \t").unwrap());
+        assert_eq!(expected, "21BD4004DDDC80AE4683948C5A1C5903584D8087:13\r\n".parse().unwrap());
+
+        assert_eq!(Err::<PwnedPwd, ParseError>(ParseError::InvalidStringLength), parser.parse("\r\n"));
+        assert!(matches!(parser.parse("004DDDC80AE4683948C5A1C5903584D8087: 13"), Err(ParseError::ParseIntError(_))));
+    }
+
+    #[test]
+    fn parse_ntlm() {
+        let parser = Parser::ntlm(Prefix(0x00000));
+
+        assert_eq!(NtlmPwd { hash: hex::decode("000001A1FE92F7AB1B2CF1B7F3A9C2B3").unwrap().try_into().unwrap(), count: 4 }, parser.parse("1A1FE92F7AB1B2CF1B7F3A9C2B3:4").unwrap());
+        assert_eq!(Err::<NtlmPwd, ParseError>(ParseError::InvalidString), parser.parse("004DDDC80AE4683948C5A1C5903584D8087:13"));
+        assert_eq!(Err::<NtlmPwd, ParseError>(ParseError::InvalidStringLength), parser.parse("1A1FE92F7AB1B2CF1B7F3A9C2B3"));
+
+        assert_eq!(NtlmPwd { hash: hex::decode("000001A1FE92F7AB1B2CF1B7F3A9C2B3").unwrap().try_into().unwrap(), count: 4 }, "000001A1FE92F7AB1B2CF1B7F3A9C2B3:4".parse().unwrap());
+        assert_eq!(Some(HashKind::Ntlm), NtlmPwd::kind());
+        assert_eq!(Some(HashKind::Sha1), <PwnedPwd>::kind());
+    }
+
+    #[test]
+    fn pwned_pwd_hex() {
+        let pwd = <PwnedPwd>::try_from_hex("21bd4004DDDC80AE4683948C5A1C5903584D8087", 13).unwrap();
+
+        assert_eq!(13, pwd.count);
+        assert_eq!("21BD4004DDDC80AE4683948C5A1C5903584D8087", pwd.sha1_hex());
+        assert_eq!("21bd4004dddc80ae4683948c5a1c5903584d8087", format!("{:x}", pwd));
+        assert_eq!("21BD4004DDDC80AE4683948C5A1C5903584D8087", format!("{:X}", pwd));
+        assert_eq!("1a1fe92f7ab1b2cf1b7f3a9c2b3f0d2c", format!("{:x}", NtlmPwd::try_from_hex("1A1FE92F7AB1B2CF1B7F3A9C2B3F0D2C", 1).unwrap()));
+
+        assert!(<PwnedPwd>::try_from_hex("21BD4004DDDC80AE4683948C5A1C5903584D80", 1).is_err());
+        assert!(<PwnedPwd>::try_from_hex("21BD4004DDDC80AE4683948C5A1C5903584D808G", 1).is_err());
+    }
+
+    #[test]
+    fn pwned_pwd_from_str() {
+        assert_eq!(PwnedPwd { hash: hex::decode("21BD4004DDDC80AE4683948C5A1C5903584D8087").unwrap().try_into().unwrap(), count: 13 }, "21BD4004DDDC80AE4683948C5A1C5903584D8087:13".parse::<PwnedPwd>().unwrap());
+        assert_eq!(PwnedPwd { hash: hex::decode("00000FFF08998514E6E8F28DBB4CA9F74EA5CAFA").unwrap().try_into().unwrap(), count: 0 }, "00000fff08998514e6e8f28dbb4ca9f74ea5cafa:0".parse::<PwnedPwd>().unwrap());
+
+        assert_eq!(Err::<PwnedPwd, ParseError>(ParseError::InvalidStringLength), "004DDDC80AE4683948C5A1C5903584D8087:13".parse());
+        assert_eq!(Err::<PwnedPwd, ParseError>(ParseError::InvalidFullString), "21BD4004DDDC80AE4683948C5A1C5903584D8087|13".parse());
+        assert_eq!(Err::<PwnedPwd, ParseError>(ParseError::FromHexError(hex::FromHexError::InvalidHexCharacter { c: 'Q', index: 0 })), "QFF08998514E6E8F28DBB4CA9F74EA5CAFA21BD4:13".parse());
+        assert!(matches!("21BD4004DDDC80AE4683948C5A1C5903584D8087:-1".parse::<PwnedPwd>(), Err(ParseError::ParseIntError(_))));
+    }
+
+    #[test]
+    fn suffix() {
+        let sha1: [u8; 20] = hex::decode("21BD4004DDDC80AE4683948C5A1C5903584D8087").unwrap().try_into().unwrap();
+        let suffix: Suffix = "004DDDC80AE4683948C5A1C5903584D8087".parse().unwrap();
+
+        assert_eq!(suffix, Suffix::from_sha1(&sha1));
+        assert_eq!(Prefix(0x21BD4), Prefix::from_sha1(&sha1));
+        assert_eq!(sha1, Prefix(0x21BD4) + suffix);
+        assert_eq!([0x21, 0xBD, 0x40], Prefix(0x21BD4).to_bytes());
+        assert_eq!("004DDDC80AE4683948C5A1C5903584D8087", suffix.to_string());
+        assert_eq!("F04DDDC80AE4683948C5A1C5903584D8087", "f04dddc80ae4683948c5a1c5903584d8087".parse::<Suffix>().unwrap().to_string());
+
+        assert_eq!(None, Suffix::from_bytes([0x10; 18]));
+        assert_eq!(Some(Suffix([0x0F; 18])), Suffix::from_bytes([0x0F; 18]));
+
+        assert_eq!(Err::<Suffix, ParseError>(ParseError::InvalidStringLength), "004DDDC80AE4683948C5A1C5903584D808".parse());
+        assert_eq!(Err::<Suffix, ParseError>(ParseError::FromHexError(hex::FromHexError::InvalidHexCharacter { c: 'Q', index: 0 })), "Q04DDDC80AE4683948C5A1C5903584D8087".parse());
+    }
+
+    #[cfg(feature = "sha1")]
+    #[test]
+    fn hash_password() {
+        assert_eq!(hex::decode("5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8").unwrap().as_slice(), PwnedPwd::hash_password("password"));
+        assert_eq!(PwnedPwd::hash_password("password"), PwnedPwd::hash_password(b"password"));
+        assert_eq!(Prefix(0x5BAA6), Prefix::of_password("password"));
+    }
+
+    #[test]
+    fn chunk_validate() {
+        let pwd = |hash: &str| -> PwnedPwd { PwnedPwd { hash: hex::decode(hash).unwrap().try_into().unwrap(), count: 1 } };
+        let chunk = |passwords| -> Chunk { Chunk { prefix: Prefix(0x21BD4), passwords } };
+
+        assert_eq!(Ok(()), chunk(vec![]).validate());
+        assert_eq!(Ok(()), chunk(vec![
+            pwd("21BD4004DDDC80AE4683948C5A1C5903584D8087"),
+            pwd("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"),
+            pwd("21BD4FFF08998514E6E8F28DBB4CA9F74EA5CAFA"),
+        ]).validate());
+
+        assert_eq!(Err(ChunkError::PrefixMismatch { index: 1, prefix: Prefix(0x21BD4) }), chunk(vec![
+            pwd("21BD4004DDDC80AE4683948C5A1C5903584D8087"),
+            pwd("21BD5004DDDC80AE4683948C5A1C5903584D8087"),
+        ]).validate());
+
+        assert_eq!(Err(ChunkError::Unordered { index: 2 }), chunk(vec![
+            pwd("21BD4004DDDC80AE4683948C5A1C5903584D8087"),
+            pwd("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"),
+            pwd("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"),
+        ]).validate());
+
+        assert_eq!(Err(ChunkError::Unordered { index: 1 }), chunk(vec![
+            pwd("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"),
+            pwd("21BD4004DDDC80AE4683948C5A1C5903584D8087"),
+        ]).validate());
+    }
+
+    #[test]
+    fn chunk_merge() {
+        let pwd = |hash: &str, count| -> PwnedPwd { PwnedPwd { hash: hex::decode(hash).unwrap().try_into().unwrap(), count } };
+        let chunk = |passwords| -> Chunk { Chunk { prefix: Prefix(0x21BD4), passwords } };
+
+        let merged = chunk(vec![
+            pwd("21BD4004DDDC80AE4683948C5A1C5903584D8087", 1),
+            pwd("21BD4FFF08998514E6E8F28DBB4CA9F74EA5CAFA", 2),
+        ]).merge(chunk(vec![
+            pwd("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED", 3),
+            pwd("21BD4004DDDC80AE4683948C5A1C5903584D8087", 4),
+            pwd("21BD4FFF08998514E6E8F28DBB4CA9F74EA5CAFA", u32::MAX),
+        ])).unwrap();
+
+        assert_eq!(chunk(vec![
+            pwd("21BD4004DDDC80AE4683948C5A1C5903584D8087", 5),
+            pwd("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED", 3),
+            pwd("21BD4FFF08998514E6E8F28DBB4CA9F74EA5CAFA", u32::MAX),
+        ]), merged);
+        assert_eq!(Ok(()), merged.validate());
+
+        assert_eq!(
+            Err(ChunkError::DifferentPrefixes { left: Prefix(0x21BD4), right: Prefix(0x21BD5) }),
+            chunk(vec![]).merge(Chunk { prefix: Prefix(0x21BD5), passwords: vec![] }),
+        );
+    }
+
     #[test]
     fn iterator() {
         let mut iterator = Prefix(0x0000).into_iter();
@@ -326,4 +894,99 @@ mod tests {
 
         assert_eq!(None, iterator.next())
     }
+
+    #[test]
+    fn iterator_back() {
+        let mut iterator = Prefix(0xFFFFD).into_iter();
+        assert_eq!(3, iterator.len());
+        assert_eq!(Some(Prefix(0xFFFFF)), iterator.next_back());
+        assert_eq!(Some(Prefix(0xFFFFD)), iterator.next());
+        assert_eq!(Some(Prefix(0xFFFFE)), iterator.next_back());
+        assert_eq!(None, iterator.next());
+        assert_eq!(None, iterator.next_back());
+
+        let watermark = Prefix(0x21BD4);
+        let last = watermark.checked_sub(2).unwrap().up_to(watermark).rev().collect::<Vec<_>>();
+        assert_eq!(vec![Prefix(0x21BD4), Prefix(0x21BD3), Prefix(0x21BD2)], last);
+
+        assert_eq!(vec![Prefix(0x00000)], Prefix(0).up_to(Prefix(0)).rev().collect::<Vec<_>>());
+        assert_eq!(0, Prefix(2).up_to(Prefix(1)).count());
+        assert_eq!(0x100000, Prefix::default().into_iter().rev().count());
+    }
+
+    #[test]
+    fn prefix_sample_every() {
+        let sample = Prefix::sample_every(0x40000).collect::<Vec<_>>();
+        assert_eq!(vec![Prefix(0x00000), Prefix(0x40000), Prefix(0x80000), Prefix(0xC0000)], sample);
+        assert_eq!(0x100000, Prefix::sample_every(0).count());
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn prefix_random() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(1);
+        assert!(Prefix::random(&mut rng).0 <= Prefix::MAX_PREFIX);
+
+        let sample = Prefix::sample_random(&mut rng, 100);
+        assert_eq!(100, sample.len());
+        assert!(sample.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn prefix_prev() {
+        assert_eq!(Some(Prefix(0x21BD3)), Prefix(0x21BD4).prev());
+        assert_eq!(None, Prefix(0).prev());
+        assert_eq!(Some(Prefix(0)), Prefix(0x21BD4).checked_sub(0x21BD4));
+        assert_eq!(None, Prefix(0x21BD4).checked_sub(0x21BD5));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let chunk = Chunk {
+            prefix: Prefix(0x21BD4),
+            passwords: vec![PwnedPwd { hash: hex::decode("21BD4004DDDC80AE4683948C5A1C5903584D8087").unwrap().try_into().unwrap(), count: 13 }],
+        };
+
+        let json = serde_json::to_string(&chunk).unwrap();
+        assert_eq!(r#"{"prefix":"21BD4","passwords":[{"hash":"21BD4004DDDC80AE4683948C5A1C5903584D8087","count":13}]}"#, json);
+
+        let parsed: Chunk = serde_json::from_str(&json).unwrap();
+        assert_eq!(chunk.prefix, parsed.prefix);
+        assert_eq!(chunk.passwords, parsed.passwords);
+
+        let parsed: PwnedPwd = serde_json::from_str(r#"{"hash":"21bd4004dddc80ae4683948c5a1c5903584d8087","count":13}"#).unwrap();
+        assert_eq!(chunk.passwords[0], parsed);
+        let parsed: PwnedPwd = serde_json::from_str(r#"{"sha1":"21BD4004DDDC80AE4683948C5A1C5903584D8087","count":13}"#).unwrap();
+        assert_eq!(chunk.passwords[0], parsed);
+
+        assert!(serde_json::from_str::<Prefix>(r#""21BD""#).is_err());
+        assert!(serde_json::from_str::<PwnedPwd>(r#"{"hash":"21BD4004","count":13}"#).is_err());
+    }
+
+    #[cfg(feature = "rkyv")]
+    #[test]
+    fn rkyv() {
+        let chunk = Chunk {
+            prefix: Prefix(0x21BD4),
+            passwords: vec![
+                PwnedPwd { hash: hex::decode("21BD4004DDDC80AE4683948C5A1C5903584D8087").unwrap().try_into().unwrap(), count: 13 },
+                PwnedPwd { hash: hex::decode("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED").unwrap().try_into().unwrap(), count: 2 },
+            ],
+        };
+
+        let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&chunk).unwrap();
+
+        let archived = rkyv::access::<ArchivedChunk, rkyv::rancor::Error>(&bytes).unwrap();
+        assert_eq!(2, archived.passwords.len());
+        assert_eq!(chunk.passwords[1].hash, archived.passwords[1].hash);
+        assert_eq!(13, archived.passwords[0].count.to_native());
+
+        let deserialized: Chunk = rkyv::deserialize::<Chunk, rkyv::rancor::Error>(archived).unwrap();
+        assert_eq!(chunk, deserialized);
+
+        assert!(rkyv::access::<ArchivedChunk, rkyv::rancor::Error>(&bytes[1..]).is_err());
+    }
 }
@@ -0,0 +1,132 @@
+//! Integration point for password hashing front-ends
+//!
+//! An application which keeps only bcrypt/argon2 verifiers sees the plaintext
+//! once — when the password is set or changed. [QueryHash] computes the SHA-1
+//! at that moment, and [CheckHash] carries it to the check without the ability
+//! to be cloned, logged or serialized, so it isn't stored by accident.
+//! With the `zeroize` feature the hash is also wiped from memory on drop
+
+use std::fmt;
+
+use crate::Prefix;
+
+/// SHA-1 of a password which is only good for a pwned check.
+///
+/// It is neither `Clone` nor serializable and its `Debug` hides the hash,
+/// the hash is handed over by value with [CheckHash::into_sha1]
+pub struct CheckHash([u8; 20]);
+
+#[cfg(feature = "zeroize")]
+impl Drop for CheckHash {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.0);
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl zeroize::ZeroizeOnDrop for CheckHash {}
+
+impl CheckHash {
+    /// Wraps a SHA-1 computed elsewhere (e.g. by a client)
+    pub fn from_sha1(sha1: [u8; 20]) -> Self {
+        Self(sha1)
+    }
+
+    /// Hashes the plaintext
+    #[cfg(feature = "sha1")]
+    pub fn of_password(password: &str) -> Self {
+        Self(crate::PwnedPwd::hash_password(password))
+    }
+
+    /// The prefix of a range request, it is safe to share
+    pub fn prefix(&self) -> Prefix {
+        Prefix::from_sha1(&self.0)
+    }
+
+    /// Consumes the hash to pass it to a store
+    pub fn into_sha1(self) -> [u8; 20] {
+        self.0
+    }
+}
+
+impl fmt::Debug for CheckHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CheckHash({}, <redacted>)", self.prefix())
+    }
+}
+
+/// Something a pwned check can be made for.
+///
+/// Implement it for a password-change form or event of a framework,
+/// so the check is made while the plaintext is still available
+pub trait QueryHash {
+    fn query_hash(&self) -> CheckHash;
+}
+
+impl QueryHash for CheckHash {
+    fn query_hash(&self) -> CheckHash {
+        CheckHash(self.0)
+    }
+}
+
+#[cfg(feature = "sha1")]
+impl QueryHash for str {
+    fn query_hash(&self) -> CheckHash {
+        CheckHash::of_password(self)
+    }
+}
+
+#[cfg(feature = "sha1")]
+impl QueryHash for String {
+    fn query_hash(&self) -> CheckHash {
+        CheckHash::of_password(self)
+    }
+}
+
+/// A plaintext which is wiped after the check
+#[cfg(all(feature = "sha1", feature = "zeroize"))]
+impl QueryHash for zeroize::Zeroizing<String> {
+    fn query_hash(&self) -> CheckHash {
+        CheckHash::of_password(self)
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_is_redacted() {
+        let hash = CheckHash::from_sha1(hex::decode("21BD4004DDDC80AE4683948C5A1C5903584D8087").unwrap().try_into().unwrap());
+        assert_eq!("CheckHash(21BD4, <redacted>)", format!("{:?}", hash));
+    }
+
+    #[cfg(feature = "zeroize")]
+    #[test]
+    fn zeroize() {
+        let mut hash = std::mem::ManuallyDrop::new(CheckHash::from_sha1([0xAB; 20]));
+        unsafe { std::mem::ManuallyDrop::drop(&mut hash) };
+        assert_eq!([0; 20], hash.0);
+
+        assert_eq!([0xAB; 20], CheckHash::from_sha1([0xAB; 20]).into_sha1());
+    }
+
+    #[cfg(feature = "sha1")]
+    #[test]
+    fn query_hash() {
+        struct PasswordChanged { new_password: String }
+
+        impl QueryHash for PasswordChanged {
+            fn query_hash(&self) -> CheckHash {
+                self.new_password.query_hash()
+            }
+        }
+
+        let event = PasswordChanged { new_password: "password".to_string() };
+        let hash = event.query_hash();
+
+        assert_eq!(Prefix::create(0x5BAA6).unwrap(), hash.prefix());
+        assert_eq!(hex::decode("5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8").unwrap(), hash.into_sha1());
+    }
+}
@@ -0,0 +1,37 @@
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::Prefix;
+
+impl Serialize for Prefix {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_prefix_str().as_ref())
+    }
+}
+
+impl<'de> Deserialize<'de> for Prefix {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        value.parse().map_err(D::Error::custom)
+    }
+}
+
+/// Hashes are (de)serialized as hex strings. Serialized in upper case like haveibeenpwned does
+pub(crate) mod hex_upper {
+    use super::*;
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        value: &[u8; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode_upper(value))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[u8; N], D::Error> {
+        let value = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        let mut res = [0u8; N];
+        hex::decode_to_slice(value.as_ref(), &mut res).map_err(D::Error::custom)?;
+        Ok(res)
+    }
+}
@@ -0,0 +1,181 @@
+//! In-memory cache of downloaded ranges
+//!
+//! Checks of passwords which share a prefix (common in audits) may reuse
+//! one range request while it is fresh
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use pwned_pwd_core::{Chunk, Prefix, SHA1_LEN};
+
+/// A cache of parsed range responses keyed by [Prefix]
+#[derive(Debug)]
+pub struct ChunkCache<const N: usize = SHA1_LEN> {
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<Prefix, (Instant, Arc<Chunk<N>>)>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// Snapshot of [ChunkCache] counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheMetrics {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+}
+
+impl<const N: usize> ChunkCache<N> {
+    /// Entries live `ttl` and there are at most `max_entries` of them
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            entries: Default::default(),
+            hits: Default::default(),
+            misses: Default::default(),
+            evictions: Default::default(),
+        }
+    }
+
+    /// Get a fresh chunk
+    pub fn get(&self, prefix: Prefix) -> Option<Arc<Chunk<N>>> {
+        let mut entries = self.entries.lock().expect("Poisoned cache");
+
+        let chunk = match entries.get(&prefix) {
+            Some((inserted, chunk)) if inserted.elapsed() < self.ttl => Some(chunk.clone()),
+            Some(_) => {
+                entries.remove(&prefix);
+                self.evictions.fetch_add(1, Relaxed);
+                None
+            }
+            None => None,
+        };
+
+        match chunk {
+            Some(_) => self.hits.fetch_add(1, Relaxed),
+            None => self.misses.fetch_add(1, Relaxed),
+        };
+
+        chunk
+    }
+
+    /// Put a chunk into the cache. If the cache is full, expired
+    /// and then the oldest entries are evicted
+    pub fn insert(&self, chunk: Chunk<N>) -> Arc<Chunk<N>> {
+        let chunk = Arc::new(chunk);
+        if self.max_entries == 0 {
+            return chunk;
+        }
+
+        let mut entries = self.entries.lock().expect("Poisoned cache");
+
+        if entries.len() >= self.max_entries && !entries.contains_key(&chunk.prefix) {
+            let before = entries.len();
+            entries.retain(|_, (inserted, _)| inserted.elapsed() < self.ttl);
+
+            if entries.len() >= self.max_entries {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, (inserted, _))| *inserted)
+                    .map(|(prefix, _)| *prefix);
+
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+
+            self.evictions
+                .fetch_add((before - entries.len()) as u64, Relaxed);
+        }
+
+        entries.insert(chunk.prefix, (Instant::now(), chunk.clone()));
+        chunk
+    }
+
+    /// Remove a prefix from the cache
+    pub fn invalidate(&self, prefix: Prefix) {
+        self.entries.lock().expect("Poisoned cache").remove(&prefix);
+    }
+
+    /// Remove all the entries
+    pub fn invalidate_all(&self) {
+        self.entries.lock().expect("Poisoned cache").clear();
+    }
+
+    pub fn metrics(&self) -> CacheMetrics {
+        CacheMetrics {
+            hits: self.hits.load(Relaxed),
+            misses: self.misses.load(Relaxed),
+            evictions: self.evictions.load(Relaxed),
+            entries: self.entries.lock().expect("Poisoned cache").len(),
+        }
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    fn chunk(prefix: u32) -> Chunk {
+        Chunk { prefix: Prefix::create(prefix).unwrap(), passwords: Vec::new() }
+    }
+
+    #[test]
+    fn get_insert() {
+        let cache = ChunkCache::new(Duration::from_secs(60), 10);
+        let prefix = Prefix::create(1).unwrap();
+
+        assert!(cache.get(prefix).is_none());
+        cache.insert(chunk(1));
+        assert_eq!(prefix, cache.get(prefix).unwrap().prefix);
+
+        assert_eq!(CacheMetrics { hits: 1, misses: 1, evictions: 0, entries: 1 }, cache.metrics());
+    }
+
+    #[test]
+    fn expiration() {
+        let cache = ChunkCache::new(Duration::ZERO, 10);
+        cache.insert(chunk(1));
+
+        assert!(cache.get(Prefix::create(1).unwrap()).is_none());
+        assert_eq!(CacheMetrics { hits: 0, misses: 1, evictions: 1, entries: 0 }, cache.metrics());
+    }
+
+    #[test]
+    fn max_entries() {
+        let cache = ChunkCache::new(Duration::from_secs(60), 2);
+        cache.insert(chunk(1));
+        cache.insert(chunk(2));
+        cache.insert(chunk(3));
+
+        assert!(cache.get(Prefix::create(1).unwrap()).is_none());
+        assert!(cache.get(Prefix::create(2).unwrap()).is_some());
+        assert!(cache.get(Prefix::create(3).unwrap()).is_some());
+        assert_eq!(1, cache.metrics().evictions);
+    }
+
+    #[test]
+    fn invalidate() {
+        let cache = ChunkCache::new(Duration::from_secs(60), 10);
+        cache.insert(chunk(1));
+        cache.insert(chunk(2));
+
+        cache.invalidate(Prefix::create(1).unwrap());
+        assert!(cache.get(Prefix::create(1).unwrap()).is_none());
+        assert_eq!(1, cache.metrics().entries);
+
+        cache.invalidate_all();
+        assert_eq!(0, cache.metrics().entries);
+    }
+}
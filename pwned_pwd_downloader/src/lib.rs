@@ -1,20 +1,56 @@
-use std::sync::{
-    atomic::{AtomicU16, AtomicU32, AtomicU64, Ordering::SeqCst},
-    Arc,
+use std::{
+    marker::PhantomData,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU16, AtomicU32, AtomicU64, Ordering::SeqCst},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use futures::{
     channel::mpsc::{self},
-    SinkExt, Stream,
+    stream::BoxStream,
+    SinkExt, Stream, StreamExt,
 };
 use pwned_pwd_core::*;
 use tracing::Instrument;
 use url::Url;
 
+pub mod cache;
+pub mod ordered;
+pub mod simulation;
+pub mod spill;
+pub mod telemetry;
+pub mod watchdog;
+
+use cache::ChunkCache;
+use ordered::{OrderedStream, ReorderGate, ReorderLimit};
+use spill::{Spill, SpillBuffer, SpillError};
+use watchdog::{Activity, Watchdog};
+
+/// A source of chunks for the given prefixes
+/// The main implementation is a [Downloader], but it may be replaced
+/// with any other source (for example [simulation::SimulatedSource])
+/// `N` is a hash length: [SHA1_LEN] (default) or [NTLM_LEN]
+pub trait ChunkSource<const N: usize = SHA1_LEN> {
+    type Error;
+
+    fn chunks<Prefixes: Iterator<Item = Prefix> + Send + 'static>(
+        &self,
+        prefixes: Prefixes,
+    ) -> BoxStream<'static, Result<Chunk<N>, Self::Error>>;
+}
+
+/// Downloads ranges of the SHA-1 (default) or the NTLM data set
 #[derive(Debug)]
-pub struct Downloader {
+pub struct Downloader<const N: usize = SHA1_LEN> {
     base_url: Url,
     max_spawns: u32,
+    watchdog: Option<Watchdog>,
+    reorder_limit: Option<ReorderLimit>,
+    reorder_spill: Option<(PathBuf, usize)>,
+    kind: PhantomData<[u8; N]>,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -27,6 +63,12 @@ pub enum DownloadErrorKind {
 
     #[error("Channel send error")]
     SendError(#[from] mpsc::SendError),
+
+    #[error("Download stalled for {0:?}")]
+    Stalled(Duration),
+
+    #[error("Reorder buffer spill error: {0}")]
+    Spill(std::io::Error),
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -37,6 +79,26 @@ pub struct DownloadError {
     kind: DownloadErrorKind,
 }
 
+impl DownloadError {
+    /// The prefix whose range failed
+    pub fn prefix(&self) -> Prefix {
+        self.prefix
+    }
+
+    pub fn kind(&self) -> &DownloadErrorKind {
+        &self.kind
+    }
+}
+
+impl From<SpillError<Prefix>> for DownloadError {
+    fn from(e: SpillError<Prefix>) -> Self {
+        DownloadError {
+            prefix: e.key,
+            kind: DownloadErrorKind::Spill(e.source),
+        }
+    }
+}
+
 trait IntoDownloadError<T> {
     fn into_download_error(self, prefix: &Prefix) -> Result<T, DownloadError>;
 }
@@ -44,20 +106,96 @@ trait IntoDownloadError<T> {
 impl<T, E: Into<DownloadErrorKind>> IntoDownloadError<T> for Result<T, E> {
     fn into_download_error(self, prefix: &Prefix) -> Result<T, DownloadError> {
         self.map_err(|e| DownloadError {
-            prefix: prefix.clone(),
+            prefix: *prefix,
             kind: e.into(),
         })
     }
 }
 
 impl Downloader {
-    async fn download_by_prefix(base_url: &Url, prefix: Prefix) -> Result<Chunk, DownloadError> {
+    /// Haveibeenpwned range api url
+    pub const DEFAULT_BASE_URL: &'static str = "https://api.pwnedpasswords.com/range/";
+
+    /// Creates a downloader of SHA-1 hashes which requests `base_url` with `max_spawns` parallel workers
+    pub fn new(base_url: Url, max_spawns: u32) -> Self {
+        Self {
+            base_url,
+            max_spawns,
+            watchdog: None,
+            reorder_limit: None,
+            reorder_spill: None,
+            kind: PhantomData,
+        }
+    }
+}
+
+impl Downloader<NTLM_LEN> {
+    /// Creates a downloader of NTLM hashes which requests `base_url` with `max_spawns` parallel workers
+    pub fn ntlm(base_url: Url, max_spawns: u32) -> Self {
+        Self {
+            base_url,
+            max_spawns,
+            watchdog: None,
+            reorder_limit: None,
+            reorder_spill: None,
+            kind: PhantomData,
+        }
+    }
+}
+
+impl<const N: usize> Downloader<N> {
+    const KIND: HashKind = match HashKind::of_len(N) {
+        Some(kind) => kind,
+        None => panic!("Unknown hash length"),
+    };
+
+    /// Cancel (and optionally retry) stalled prefix downloads and log workers without progress
+    pub fn with_watchdog(mut self, watchdog: Watchdog) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    /// Bound the chunks [Downloader::download_ordered] holds back: workers don't take
+    /// new prefixes while a slow one keeps the limit reached. Unbounded by default
+    pub fn with_reorder_limit(mut self, limit: ReorderLimit) -> Self {
+        self.reorder_limit = Some(limit);
+        self
+    }
+
+    /// [Downloader::download_ordered] holds back chunks of at most `threshold` bytes
+    /// in memory and spills the following ones to a temporary file in `dir`
+    pub fn with_reorder_spill(mut self, dir: impl Into<PathBuf>, threshold: usize) -> Self {
+        self.reorder_spill = Some((dir.into(), threshold));
+        self
+    }
+
+    /// Downloads a range, it is stalled if no part of the response arrives for `stall_timeout`
+    async fn download_by_prefix(
+        base_url: &Url,
+        prefix: Prefix,
+        stall_timeout: Option<Duration>,
+    ) -> Result<Chunk<N>, DownloadError> {
         let str_prefix = prefix.as_prefix_str();
-        async move {
-            let url = base_url.join(str_prefix.as_ref()).expect("Invalid url");
-            let response = reqwest::get(url).await.into_download_error(&prefix)?;
-            let content = response.text().await.into_download_error(&prefix)?;
-            let parser = prefix.parser();
+        let span = tracing::info_span!("download_prefix", prefix = str_prefix.as_ref());
+        let started = Instant::now();
+        let res: Result<Chunk<N>, DownloadError> = async move {
+            let mut url = base_url.join(str_prefix.as_ref()).expect("Invalid url");
+            if Self::KIND != HashKind::Sha1 {
+                url.query_pairs_mut().append_pair("mode", Self::KIND.mode());
+            }
+
+            let mut response = progress(reqwest::get(url), stall_timeout)
+                .await
+                .into_download_error(&prefix)?;
+            let mut body = Vec::new();
+            while let Some(bytes) = progress(response.chunk(), stall_timeout)
+                .await
+                .into_download_error(&prefix)?
+            {
+                body.extend_from_slice(&bytes);
+            }
+            let content = String::from_utf8_lossy(&body);
+            let parser: Parser<N> = prefix.into();
 
             let passwords = content
                 .lines()
@@ -67,14 +205,113 @@ impl Downloader {
 
             Ok(Chunk { prefix, passwords })
         }
-        .instrument(tracing::info_span!("download_by_prefix"))
-        .await
+        .instrument(span)
+        .await;
+
+        match &res {
+            Ok(chunk) => telemetry::downloaded(chunk.passwords.len(), started.elapsed()),
+            Err(e) => telemetry::failed(&e.kind),
+        }
+        res
+    }
+
+    async fn download_with_watchdog(
+        base_url: &Url,
+        prefix: Prefix,
+        watchdog: &Watchdog,
+    ) -> Result<Chunk<N>, DownloadError> {
+        let mut attempt = 0;
+        loop {
+            match Self::download_by_prefix(base_url, prefix, Some(watchdog.stall_timeout())).await {
+                Err(DownloadError {
+                    kind: DownloadErrorKind::Stalled(_),
+                    ..
+                }) if attempt < watchdog.retries() => {
+                    attempt += 1;
+                    tracing::warn!(
+                        "Prefix '{}' download stalled, retry {}",
+                        prefix.as_prefix_str().as_ref(),
+                        attempt
+                    );
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Download a single range
+    pub async fn download_prefix(&self, prefix: Prefix) -> Result<Chunk<N>, DownloadError> {
+        match &self.watchdog {
+            Some(watchdog) => Self::download_with_watchdog(&self.base_url, prefix, watchdog).await,
+            None => Self::download_by_prefix(&self.base_url, prefix, None).await,
+        }
+    }
+
+    /// Download a single range or take it from the cache
+    pub async fn download_prefix_cached(
+        &self,
+        prefix: Prefix,
+        cache: &ChunkCache<N>,
+    ) -> Result<Arc<Chunk<N>>, DownloadError> {
+        if let Some(chunk) = cache.get(prefix) {
+            return Ok(chunk);
+        }
+
+        let chunk = self.download_prefix(prefix).await?;
+        Ok(cache.insert(chunk))
     }
 
     pub async fn download<Prefixes: Iterator<Item = Prefix> + Send + 'static>(
         &self,
         prefixes: Prefixes,
-    ) -> impl Stream<Item = Result<Chunk, DownloadError>> {
+    ) -> impl Stream<Item = Result<Chunk<N>, DownloadError>> {
+        self.spawn_download(prefixes, None)
+    }
+
+    /// [Downloader::download] which streams chunks in the order of the prefixes,
+    /// e.g. for stores which need ordered saves. A chunk which is downloaded early
+    /// is kept in memory until the chunks of the preceding prefixes arrive,
+    /// see [Downloader::with_reorder_limit]
+    pub fn download_ordered<Prefixes: Iterator<Item = Prefix> + Send + 'static>(
+        &self,
+        prefixes: Prefixes,
+    ) -> impl Stream<Item = Result<Chunk<N>, DownloadError>> + Send + 'static {
+        let gate = self.reorder_limit.map(ReorderGate::new);
+        let (taken, prefixes) = ordered::recording(prefixes);
+        let chunks = self.spawn_download(prefixes, gate.clone());
+        let key = |chunk: &Chunk<N>| chunk.prefix;
+
+        match &self.reorder_spill {
+            Some((dir, threshold)) => {
+                let buffer = SpillBuffer::new(dir.clone(), *threshold);
+                Self::gated(
+                    OrderedStream::new_buffered(chunks, taken, key, buffer),
+                    gate,
+                )
+                .boxed()
+            }
+            None => Self::gated(OrderedStream::new(chunks, taken, key), gate).boxed(),
+        }
+    }
+
+    fn gated<St, F, B>(
+        chunks: OrderedStream<St, Chunk<N>, Prefix, F, B>,
+        gate: Option<Arc<ReorderGate>>,
+    ) -> OrderedStream<St, Chunk<N>, Prefix, F, B>
+    where
+        F: FnMut(&Chunk<N>) -> Prefix,
+    {
+        match gate {
+            Some(gate) => chunks.with_gate(gate, Chunk::<N>::weight),
+            None => chunks,
+        }
+    }
+
+    fn spawn_download<Prefixes: Iterator<Item = Prefix> + Send + 'static>(
+        &self,
+        prefixes: Prefixes,
+        gate: Option<Arc<ReorderGate>>,
+    ) -> mpsc::UnboundedReceiver<Result<Chunk<N>, DownloadError>> {
         let (sender, pwd_stream) = mpsc::unbounded();
 
         let prefixes_processed = Arc::new(AtomicU32::new(0));
@@ -83,6 +320,7 @@ impl Downloader {
         let sender = Arc::new(futures::lock::Mutex::new(sender));
 
         let max_spawns = self.max_spawns;
+        let activity = Activity::new(max_spawns);
 
         let prefixes = Arc::new(futures::lock::Mutex::new(prefixes));
 
@@ -94,8 +332,11 @@ impl Downloader {
             let prefixes_processed = prefixes_processed.clone();
             let passwords_processed = pawwsords_processed.clone();
             let running_tasks = running_tasks.clone();
+            let activity = activity.clone();
+            let watchdog = self.watchdog.clone();
 
             let prefixes = prefixes.clone();
+            let gate = gate.clone();
 
             futures.push(
                 async move {
@@ -103,7 +344,12 @@ impl Downloader {
                     loop {
                         let prefix = {
                             let mut prefixes_guard = prefixes.lock().await;
-                            prefixes_guard.next()
+                            let prefix = prefixes_guard.next();
+                            // The lock is held, so the preceding prefixes have passed the gate
+                            if let (Some(_), Some(gate)) = (prefix, &gate) {
+                                gate.take().await;
+                            }
+                            prefix
                         };
 
                         let prefix = match prefix {
@@ -119,7 +365,12 @@ impl Downloader {
                             prefix.as_prefix_str().as_ref()
                         );
 
-                        let res = Self::download_by_prefix(&url, prefix).await;
+                        let res = match &watchdog {
+                            Some(watchdog) => {
+                                Self::download_with_watchdog(&url, prefix, watchdog).await
+                            }
+                            None => Self::download_by_prefix(&url, prefix, None).await,
+                        };
 
                         tracing::debug!("Prefix '{}' downloaded", prefix.as_prefix_str().as_ref());
 
@@ -143,9 +394,9 @@ impl Downloader {
 
                                 prefixes_processed.fetch_add(1, SeqCst);
                                 passwords_processed.fetch_add(len as u64, SeqCst);
+                                activity.progress(i);
                             }
                             Err(e) => {
-                                tracing::info!("DownloadErr");
                                 let mut sender = sender.lock().await;
                                 let _ = sender.send(Err(e)).await;
                                 sender.close_channel();
@@ -154,13 +405,23 @@ impl Downloader {
                         }
                     }
 
+                    activity.finished(i);
                     running_tasks.fetch_sub(1, SeqCst);
                     let mut sender = sender.lock().await;
                     if running_tasks.load(SeqCst) == 0 {
                         let _ = sender.close().await;
                     }
                 }
-                .instrument(tracing::info_span!("downloader", i = i)),
+                .instrument(tracing::info_span!("download_worker", worker = i)),
+            );
+        }
+
+        if let Some(watchdog) = &self.watchdog {
+            let sender = Arc::downgrade(&sender);
+            tokio::spawn(
+                activity
+                    .monitor(watchdog.stall_timeout(), move || sender.strong_count() == 0)
+                    .instrument(tracing::info_span!("download_watchdog")),
             );
         }
 
@@ -172,6 +433,40 @@ impl Downloader {
     }
 }
 
+/// A step of a download, which is stalled if it doesn't complete within `stall_timeout`
+async fn progress<T, E: Into<DownloadErrorKind>>(
+    step: impl std::future::Future<Output = Result<T, E>>,
+    stall_timeout: Option<Duration>,
+) -> Result<T, DownloadErrorKind> {
+    match stall_timeout {
+        Some(stall_timeout) => match tokio::time::timeout(stall_timeout, step).await {
+            Ok(res) => res.map_err(Into::into),
+            Err(_) => Err(DownloadErrorKind::Stalled(stall_timeout)),
+        },
+        None => step.await.map_err(Into::into),
+    }
+}
+
+impl Default for Downloader {
+    fn default() -> Self {
+        Self::new(
+            Self::DEFAULT_BASE_URL.parse().expect("Invalid default url"),
+            16,
+        )
+    }
+}
+
+impl<const N: usize> ChunkSource<N> for Downloader<N> {
+    type Error = DownloadError;
+
+    fn chunks<Prefixes: Iterator<Item = Prefix> + Send + 'static>(
+        &self,
+        prefixes: Prefixes,
+    ) -> BoxStream<'static, Result<Chunk<N>, Self::Error>> {
+        self.spawn_download(prefixes, None).boxed()
+    }
+}
+
 #[cfg(test)]
 #[rustfmt::skip]
 mod tests {
@@ -190,10 +485,7 @@ mod tests {
         .with_max_level(Level::INFO)
         .try_init();
 
-        let downloader = Downloader {
-            base_url: "https://api.pwnedpasswords.com/range/".parse().unwrap(),
-            max_spawns: 4,
-        };
+        let downloader = Downloader::new("https://api.pwnedpasswords.com/range/".parse().unwrap(), 4);
 
         let stream = downloader.download([
             Prefix::create(0x00000),
@@ -207,7 +499,7 @@ mod tests {
             Prefix::create(0xFFFFF),
         ].into_iter().map(|v| v.unwrap())).await;
 
-        let res = stream.map(|r| r.unwrap()).collect::<Vec<_>>().await.into_iter().flat_map(|a| a.passwords).map(|v| hex::encode_upper(v.sha1)).collect::<HashSet<_>>();
+        let res = stream.map(|r| r.unwrap()).collect::<Vec<_>>().await.into_iter().flat_map(|a| a.passwords).map(|v| hex::encode_upper(v.hash)).collect::<HashSet<_>>();
 
         assert!(!res.is_empty());
 
@@ -223,4 +515,99 @@ mod tests {
 
         
     }
+
+    #[tokio::test]
+    async fn watchdog_cancels_stalled_download() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        });
+
+        let downloader = Downloader::new(format!("http://{}/range/", addr).parse().unwrap(), 1)
+            .with_watchdog(Watchdog::new(Duration::from_millis(50)).with_retries(1));
+
+        let res = downloader.download(Prefix::max().into_iter()).await.collect::<Vec<_>>().await;
+
+        assert_eq!(1, res.len());
+        let Some(Err(err)) = res.into_iter().next() else { panic!("Error expected") };
+        assert_eq!(Prefix::max(), err.prefix);
+        assert!(matches!(err.kind, DownloadErrorKind::Stalled(_)));
+    }
+
+    #[tokio::test]
+    async fn watchdog_allows_slow_progressing_download() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut connection, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = connection.read(&mut buf).await;
+            let records = ["0018A45C4D1DEF81644B54AB7F969B88D65:3\r\n"; 4];
+            let head = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n", records.concat().len());
+            connection.write_all(head.as_bytes()).await.unwrap();
+            for record in records {
+                tokio::time::sleep(Duration::from_millis(30)).await;
+                connection.write_all(record.as_bytes()).await.unwrap();
+            }
+        });
+
+        let downloader = Downloader::new(format!("http://{}/range/", addr).parse().unwrap(), 1)
+            .with_watchdog(Watchdog::new(Duration::from_millis(80)));
+
+        let chunk = downloader.download_prefix(Prefix::default()).await.unwrap();
+        assert_eq!(4, chunk.passwords.len());
+    }
+
+    /// Serves a record for every prefix, the range of 00000 comes late
+    async fn serve_ranges() -> Url {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut connection, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    while let Ok(1..) = connection.read(&mut buf).await {
+                        if String::from_utf8_lossy(&buf).contains("/range/00000 ") {
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                        }
+                        let body = "0018A45C4D1DEF81644B54AB7F969B88D65:3";
+                        let response = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{}", body.len(), body);
+                        let _ = connection.write_all(response.as_bytes()).await;
+                    }
+                });
+            }
+        });
+        format!("http://{}/range/", addr).parse().unwrap()
+    }
+
+    async fn ordered_prefixes(downloader: Downloader) -> Vec<u32> {
+        downloader.download_ordered(Prefix::default().up_to(Prefix::create(20).unwrap()))
+            .map(|chunk| u32::from(chunk.unwrap().prefix))
+            .collect::<Vec<_>>().await
+    }
+
+    #[tokio::test]
+    async fn reorder_limit() {
+        let downloader = Downloader::new(serve_ranges().await, 8).with_reorder_limit(ReorderLimit::Items(2));
+        assert_eq!((0..=20).collect::<Vec<_>>(), ordered_prefixes(downloader).await);
+    }
+
+    #[tokio::test]
+    async fn reorder_spill() {
+        let dir = std::env::temp_dir().join("pwned_pwd_reorder_spill");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+
+        let downloader = Downloader::new(serve_ranges().await, 8).with_reorder_spill(&dir, 0);
+        assert_eq!((0..=20).collect::<Vec<_>>(), ordered_prefixes(downloader).await);
+        assert_eq!(0, std::fs::read_dir(&dir).unwrap().count());
+    }
 }
@@ -0,0 +1,405 @@
+//! Ordering of parallel downloads
+//!
+//! Workers of a [crate::Downloader] take prefixes in order, but finish them in any order.
+//! Ordered stores (e.g. the local store) need chunks in the order of their prefixes,
+//! so [OrderedStream] holds early chunks back until the preceding ones arrive.
+//! It reorders items of any key, so custom downloaders and sources can reuse it.
+//!
+//! A slow key makes the stream hold back everything taken after it. A [ReorderGate]
+//! bounds it: producers wait for the gate before taking the next key
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::{Stream, StreamExt};
+use pwned_pwd_core::Prefix;
+use tokio::sync::Notify;
+
+use crate::telemetry;
+
+/// Keys in the order they were taken by the workers, the order of an [OrderedStream]
+pub type Taken<K = Prefix> = Arc<Mutex<VecDeque<K>>>;
+
+/// Records the keys to [Taken] as the workers take them
+pub fn recording<K: Clone, I: Iterator<Item = K>>(keys: I) -> (Taken<K>, impl Iterator<Item = K>) {
+    let taken = Taken::default();
+    let recorded = taken.clone();
+    let keys = keys.inspect(move |key| recorded.lock().unwrap().push_back(key.clone()));
+    (taken, keys)
+}
+
+/// How much an [OrderedStream] with a [ReorderGate] holds back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReorderLimit {
+    /// Keys taken and not streamed yet, in flight or held back
+    Items(usize),
+
+    /// Bytes of held back items, as weighed by the stream. Items in flight
+    /// (at most one per producer) come on top of it
+    Bytes(usize),
+}
+
+#[derive(Debug, Default)]
+struct GateState {
+    taken: usize,
+    bytes: usize,
+    closed: bool,
+}
+
+/// Backpressure of an [OrderedStream]: a taken key may be processed only while
+/// the stream is below the limit. Keys must pass the gate in the order they are taken,
+/// then the key the stream waits for has always passed it and can't block the stream
+#[derive(Debug)]
+pub struct ReorderGate {
+    limit: ReorderLimit,
+    state: Mutex<GateState>,
+    notify: Notify,
+}
+
+impl ReorderGate {
+    /// A limit of 0 is treated as 1
+    pub fn new(limit: ReorderLimit) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            state: Mutex::new(GateState::default()),
+            notify: Notify::new(),
+        })
+    }
+
+    /// Waits until the key which was just taken may be processed and counts it.
+    /// The gate is open for good once its stream is dropped
+    pub async fn take(&self) {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                let open = match self.limit {
+                    ReorderLimit::Items(max) => state.taken < max.max(1),
+                    ReorderLimit::Bytes(max) => state.bytes < max.max(1),
+                };
+                if open || state.closed {
+                    state.taken += 1;
+                    return;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    fn held(&self, bytes: usize) {
+        self.state.lock().unwrap().bytes += bytes;
+    }
+
+    fn streamed(&self, bytes: usize) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.taken = state.taken.saturating_sub(1);
+            state.bytes = state.bytes.saturating_sub(bytes);
+        }
+        self.notify.notify_waiters();
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.notify_waiters();
+    }
+}
+
+/// Storage of the items an [OrderedStream] holds back, `E` is the error of the stream.
+/// A `BTreeMap` keeps them in memory, [crate::spill::SpillBuffer] spills them to disk
+pub trait ReorderBuffer<K, T, E> {
+    fn hold(&mut self, key: K, item: T) -> Result<(), E>;
+
+    /// Removes the item of the key, if it is held
+    fn release(&mut self, key: &K) -> Option<Result<T, E>>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Ord, T, E> ReorderBuffer<K, T, E> for BTreeMap<K, T> {
+    fn hold(&mut self, key: K, item: T) -> Result<(), E> {
+        self.insert(key, item);
+        Ok(())
+    }
+
+    fn release(&mut self, key: &K) -> Option<Result<T, E>> {
+        self.remove(key).map(Ok)
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+}
+
+/// A gate, the weight of an item and the weights of the held back items
+struct Gated<K, T> {
+    gate: Arc<ReorderGate>,
+    weigh: fn(&T) -> usize,
+    held: BTreeMap<K, usize>,
+}
+
+impl<K: Ord, T> Gated<K, T> {
+    fn held(&mut self, key: K, item: &T) {
+        let bytes = (self.weigh)(item);
+        self.gate.held(bytes);
+        self.held.insert(key, bytes);
+    }
+
+    /// The item of the key is streamed or failed
+    fn streamed(&mut self, key: &K) {
+        self.gate.streamed(self.held.remove(key).unwrap_or(0));
+    }
+}
+
+/// Items of a stream in the order of their [Taken] keys, `key` extracts the key of an item
+/// (e.g. the prefix of a chunk). An error is passed at once, an item which can't be held back
+/// or released is passed as an error in its place. Items whose keys weren't taken are held
+/// until the end of the stream and then dropped
+pub struct OrderedStream<St, T, K, F, B = BTreeMap<K, T>> {
+    items: St,
+    taken: Taken<K>,
+    key: F,
+    early: B,
+    gate: Option<Gated<K, T>>,
+}
+
+impl<St, T, K, F> OrderedStream<St, T, K, F>
+where
+    K: Ord,
+    F: FnMut(&T) -> K,
+{
+    /// Holds items back in memory
+    pub fn new(items: St, taken: Taken<K>, key: F) -> Self {
+        Self::new_buffered(items, taken, key, BTreeMap::new())
+    }
+}
+
+impl<St, T, K, F, B> OrderedStream<St, T, K, F, B>
+where
+    K: Ord,
+    F: FnMut(&T) -> K,
+{
+    /// Holds items back in the buffer
+    pub fn new_buffered(items: St, taken: Taken<K>, key: F, early: B) -> Self {
+        Self {
+            items,
+            taken,
+            key,
+            early,
+            gate: None,
+        }
+    }
+
+    /// Tells the gate how much is held back, `weigh` is the size of an item
+    /// for [ReorderLimit::Bytes]
+    pub fn with_gate(mut self, gate: Arc<ReorderGate>, weigh: fn(&T) -> usize) -> Self {
+        self.gate = Some(Gated {
+            gate,
+            weigh,
+            held: BTreeMap::new(),
+        });
+        self
+    }
+
+    /// The item of the key is lost, the following ones don't wait for it
+    fn skip(&mut self, key: &K) {
+        let mut taken = self.taken.lock().unwrap();
+        let Some(i) = taken.iter().position(|taken| taken == key) else {
+            return;
+        };
+        taken.remove(i);
+        if let Some(gated) = &mut self.gate {
+            gated.streamed(key);
+        }
+    }
+
+    /// The item of the next taken key, if it has arrived
+    fn next_ready<E>(&mut self) -> Option<Result<T, E>>
+    where
+        B: ReorderBuffer<K, T, E>,
+    {
+        let mut taken = self.taken.lock().unwrap();
+        let item = self.early.release(taken.front()?)?;
+        let key = taken.pop_front().expect("Released key");
+        telemetry::buffered(self.early.len());
+        if let Some(gated) = &mut self.gate {
+            gated.streamed(&key);
+        }
+        Some(item)
+    }
+}
+
+impl<St, T, K, F, B> Drop for OrderedStream<St, T, K, F, B> {
+    fn drop(&mut self) {
+        if let Some(gated) = &self.gate {
+            gated.gate.close();
+        }
+    }
+}
+
+impl<St, T, E, K, F, B> Stream for OrderedStream<St, T, K, F, B>
+where
+    St: Stream<Item = Result<T, E>> + Unpin,
+    K: Ord + Clone,
+    F: FnMut(&T) -> K + Unpin,
+    B: ReorderBuffer<K, T, E> + Unpin,
+{
+    type Item = Result<T, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(item) = this.next_ready() {
+                return Poll::Ready(Some(item));
+            }
+
+            match this.items.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(item))) => {
+                    let key = (this.key)(&item);
+                    if let Some(gated) = &mut this.gate {
+                        gated.held(key.clone(), &item);
+                    }
+                    if let Err(e) = this.early.hold(key.clone(), item) {
+                        this.skip(&key);
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                    telemetry::buffered(this.early.len());
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => {
+                    if !this.early.is_empty() {
+                        tracing::warn!(
+                            "{} items are dropped, the preceding ones are missing",
+                            this.early.len()
+                        );
+                    }
+                    return Poll::Ready(None);
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{channel::mpsc, SinkExt};
+    use pwned_pwd_core::Chunk;
+
+    use super::*;
+
+    fn chunk(prefix: u32) -> Chunk {
+        Chunk { prefix: Prefix::create(prefix).unwrap(), passwords: Vec::new() }
+    }
+
+    #[tokio::test]
+    async fn reorders() {
+        let (mut tx, rx) = mpsc::unbounded();
+        let taken = Taken::default();
+        taken.lock().unwrap().extend([3, 1, 2].map(|prefix| Prefix::create(prefix).unwrap()));
+
+        let mut ordered = OrderedStream::new(rx, taken.clone(), |chunk: &Chunk| chunk.prefix);
+        tx.send(Ok::<_, ()>(chunk(1))).await.unwrap();
+        tx.send(Ok(chunk(2))).await.unwrap();
+        tx.send(Ok(chunk(3))).await.unwrap();
+        tx.close_channel();
+
+        let prefixes = ordered.by_ref().map(|chunk| u32::from(chunk.unwrap().prefix)).collect::<Vec<_>>().await;
+        assert_eq!(vec![3, 1, 2], prefixes);
+        assert!(taken.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn generic() {
+        let (order, keys) = recording(["b", "a"].into_iter());
+        assert_eq!(vec!["b", "a"], keys.collect::<Vec<_>>());
+
+        let items = futures::stream::iter([Ok((1, "a")), Err("failed"), Ok((2, "b")), Ok((3, "c"))]);
+        let ordered = OrderedStream::new(items, order, |item: &(u32, &str)| item.1).collect::<Vec<_>>().await;
+        assert_eq!(vec![Err("failed"), Ok((2, "b")), Ok((1, "a"))], ordered);
+    }
+
+    #[tokio::test]
+    async fn gate() {
+        let gate = ReorderGate::new(ReorderLimit::Items(2));
+        let (mut tx, rx) = mpsc::unbounded();
+        let (order, mut keys) = recording(1..=3);
+        let mut ordered = OrderedStream::new(rx, order, |item: &u32| *item).with_gate(gate.clone(), |_| 1);
+
+        gate.take().await;
+        gate.take().await;
+        let (first, second) = (keys.next().unwrap(), keys.next().unwrap());
+        assert!(tokio::time::timeout(Duration::from_millis(10), gate.take()).await.is_err());
+
+        tx.send(Ok::<_, ()>(second)).await.unwrap();
+        tx.send(Ok(first)).await.unwrap();
+        assert_eq!(Some(Ok(1)), ordered.next().await);
+        gate.take().await;
+        assert_eq!(Some(Ok(2)), ordered.next().await);
+
+        drop(ordered);
+        gate.take().await;
+        gate.take().await;
+    }
+
+    /// Fails to hold back 2 and to release 1
+    struct Failing(BTreeMap<u32, u32>);
+
+    impl ReorderBuffer<u32, u32, ()> for Failing {
+        fn hold(&mut self, key: u32, item: u32) -> Result<(), ()> {
+            match key {
+                2 => Err(()),
+                _ => ReorderBuffer::<_, _, ()>::hold(&mut self.0, key, item),
+            }
+        }
+
+        fn release(&mut self, key: &u32) -> Option<Result<u32, ()>> {
+            let item = self.0.remove(key)?;
+            Some(if *key == 1 { Err(()) } else { Ok(item) })
+        }
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    #[tokio::test]
+    async fn gate_errors() {
+        let gate = ReorderGate::new(ReorderLimit::Bytes(100));
+        let order = Arc::new(Mutex::new(VecDeque::from([1, 2, 3])));
+        let items = futures::stream::iter([Ok(3), Ok(2), Ok(1)]).chain(futures::stream::pending());
+        let mut ordered = OrderedStream::new_buffered(items, order, |item: &u32| *item, Failing(BTreeMap::new())).with_gate(gate.clone(), |_| 60);
+
+        for _ in 0..3 {
+            gate.take().await;
+        }
+        assert_eq!(Some(Err(())), ordered.next().await);
+        assert_eq!(Some(Err(())), ordered.next().await);
+        assert_eq!(Some(Ok(3)), ordered.next().await);
+        tokio::time::timeout(Duration::from_millis(10), gate.take()).await.unwrap();
+        assert_eq!((1, 0), { let state = gate.state.lock().unwrap(); (state.taken, state.bytes) });
+    }
+
+    #[tokio::test]
+    async fn gate_bytes() {
+        let gate = ReorderGate::new(ReorderLimit::Bytes(100));
+        let order = Arc::new(Mutex::new(VecDeque::from([1, 2, 3])));
+        let mut ordered = OrderedStream::new(futures::stream::iter([Ok::<_, ()>(2), Ok(3)]).chain(futures::stream::pending()), order, |item: &u32| *item)
+            .with_gate(gate.clone(), |_| 60);
+
+        gate.take().await;
+        assert!(tokio::time::timeout(Duration::from_millis(10), ordered.next()).await.is_err());
+        assert!(tokio::time::timeout(Duration::from_millis(10), gate.take()).await.is_err());
+    }
+}
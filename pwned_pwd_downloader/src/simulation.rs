@@ -0,0 +1,205 @@
+//! Synthetic chunk source for rehearsing a full sync without the network
+//!
+//! [SimulatedSource] yields well-formed chunks (every hash shares the chunk prefix,
+//! hashes are sorted) at a configurable rate, so a store can be loaded
+//! with a realistic amount of data on the real hardware
+
+use std::{convert::Infallible, time::Duration};
+
+use futures::{stream::BoxStream, StreamExt};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd, SHA1_LEN};
+
+use crate::ChunkSource;
+
+/// How many passwords a simulated chunk contains
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SizeDistribution {
+    /// Every chunk contains exactly `n` passwords
+    Fixed(usize),
+
+    /// Every chunk contains from `min` to `max` (inclusive) passwords
+    Uniform { min: usize, max: usize },
+}
+
+impl Default for SizeDistribution {
+    /// Close to the real haveibeenpwned data set
+    fn default() -> Self {
+        Self::Uniform {
+            min: 800,
+            max: 1200,
+        }
+    }
+}
+
+/// A [ChunkSource] which generates synthetic chunks of `N`-byte hashes
+#[derive(Debug, Clone, Default)]
+pub struct SimulatedSource<const N: usize = SHA1_LEN> {
+    size: SizeDistribution,
+    chunks_per_second: Option<u32>,
+    max_count: Option<u32>,
+    seed: u64,
+}
+
+impl SimulatedSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<const N: usize> SimulatedSource<N> {
+    /// Set chunk size distribution
+    pub fn with_size(mut self, size: SizeDistribution) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Limit the rate of the source. Without a limit chunks are produced as fast as they are consumed
+    pub fn with_rate(mut self, chunks_per_second: u32) -> Self {
+        self.chunks_per_second = Some(chunks_per_second);
+        self
+    }
+
+    /// Max generated `count` of a password. Default is 100000
+    pub fn with_max_count(mut self, max_count: u32) -> Self {
+        self.max_count = Some(max_count);
+        self
+    }
+
+    /// Seed of the generator. The same seed produces the same chunks
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Generate a chunk for the prefix
+    pub fn chunk(&self, prefix: Prefix) -> Chunk<N> {
+        let mut rng = SplitMix64::new(self.seed ^ u64::from(u32::from(prefix)));
+
+        let len = match self.size {
+            SizeDistribution::Fixed(n) => n,
+            SizeDistribution::Uniform { min, max } => {
+                let (min, max) = if min <= max { (min, max) } else { (max, min) };
+                min + (rng.next() % (max - min + 1) as u64) as usize
+            }
+        };
+
+        let max_count = u64::from(self.max_count.unwrap_or(100_000).max(1));
+
+        let mut passwords = Vec::with_capacity(len);
+        for _ in 0..len {
+            let mut hash = [0u8; N];
+            for part in hash.chunks_mut(8) {
+                let bytes = rng.next().to_be_bytes();
+                part.copy_from_slice(&bytes[..part.len()]);
+            }
+
+            let low_nibble = hash[2] & 0x0F;
+            prefix.write_prefix(&mut hash);
+            hash[2] |= low_nibble;
+
+            passwords.push(PwnedPwd {
+                hash,
+                count: (1 + rng.next() % max_count) as u32,
+            });
+        }
+
+        passwords.sort_unstable_by_key(|p| p.hash);
+        passwords.dedup_by(|a, b| a.hash == b.hash);
+
+        Chunk { prefix, passwords }
+    }
+}
+
+impl<const N: usize> ChunkSource<N> for SimulatedSource<N> {
+    type Error = Infallible;
+
+    fn chunks<Prefixes: Iterator<Item = Prefix> + Send + 'static>(
+        &self,
+        prefixes: Prefixes,
+    ) -> BoxStream<'static, Result<Chunk<N>, Self::Error>> {
+        let source = self.clone();
+        let stream = futures::stream::iter(prefixes).map(move |prefix| Ok(source.chunk(prefix)));
+
+        match self.chunks_per_second {
+            Some(rate) => {
+                let period = Duration::from_secs(1) / rate.max(1);
+
+                // The interval is created on the first poll, the stream may be created
+                // outside of a runtime
+                futures::stream::unfold((None, stream), move |(interval, mut stream)| async move {
+                    let mut interval = interval.unwrap_or_else(|| tokio::time::interval(period));
+                    interval.tick().await;
+                    let item = stream.next().await?;
+                    Some((item, (Some(interval), stream)))
+                })
+                .boxed()
+            }
+            None => stream.boxed(),
+        }
+    }
+}
+
+/// Small and fast deterministic generator, good enough for synthetic data
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_is_well_formed() {
+        let source = SimulatedSource::new().with_size(SizeDistribution::Uniform { min: 10, max: 20 });
+        let prefix = Prefix::create(0x21BD4).unwrap();
+        let chunk = source.chunk(prefix);
+
+        assert!(!chunk.passwords.is_empty());
+        assert!(chunk.passwords.len() <= 20);
+        assert!(chunk.passwords.windows(2).all(|w| w[0].hash < w[1].hash));
+        assert!(chunk.passwords.iter().all(|p| p.hash[0] == 0x21 && p.hash[1] == 0xBD && p.hash[2] >> 4 == 0x4));
+        assert!(chunk.passwords.iter().all(|p| p.count >= 1));
+    }
+
+    #[test]
+    fn chunk_is_deterministic() {
+        let source = SimulatedSource::new().with_seed(42);
+        let prefix = Prefix::create(0x00FFF).unwrap();
+
+        assert_eq!(source.chunk(prefix).passwords, source.chunk(prefix).passwords);
+    }
+
+    #[test]
+    fn chunks_outside_of_runtime() {
+        let source = SimulatedSource::new().with_size(SizeDistribution::Fixed(5)).with_rate(1000);
+        let chunks = source.chunks(Prefix::create(0xFFFF0).unwrap().into_iter());
+
+        let chunks = tokio::runtime::Runtime::new().unwrap().block_on(chunks.collect::<Vec<_>>());
+        assert_eq!(16, chunks.len());
+    }
+
+    #[tokio::test]
+    async fn chunks() {
+        let source = SimulatedSource::new().with_size(SizeDistribution::Fixed(5)).with_rate(1000);
+        let prefixes = Prefix::create(0xFFFF0).unwrap().into_iter();
+
+        let chunks = source.chunks(prefixes).map(|c| c.unwrap()).collect::<Vec<_>>().await;
+
+        assert_eq!(16, chunks.len());
+        assert!(chunks.iter().all(|c| c.passwords.len() == 5));
+        assert_eq!(Prefix::max(), chunks[15].prefix);
+    }
+}
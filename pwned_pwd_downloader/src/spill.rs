@@ -0,0 +1,257 @@
+//! Spilling of held back chunks to disk
+//!
+//! When the completion order of a download is very skewed, an [crate::ordered::OrderedStream]
+//! holds back a lot. [SpillBuffer] keeps items in memory up to a threshold and writes
+//! the following ones to a temporary file, reading them back when their turn comes.
+//! The file is truncated whenever nothing is spilled and removed with the buffer.
+//!
+//! Files are read and written synchronously on the task which polls the stream
+
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+
+use crate::{ordered::ReorderBuffer, telemetry};
+
+/// An item which can be written to a spill file
+pub trait Spill: Sized {
+    /// Approximate size of the item in memory
+    fn weight(&self) -> usize;
+
+    fn spill(&self, out: &mut Vec<u8>);
+
+    fn unspill(bytes: &[u8]) -> io::Result<Self>;
+}
+
+/// The prefix, then hashes with little-endian counts
+impl<const N: usize> Spill for Chunk<N> {
+    fn weight(&self) -> usize {
+        self.passwords.len() * std::mem::size_of::<PwnedPwd<N>>()
+    }
+
+    fn spill(&self, out: &mut Vec<u8>) {
+        out.reserve(4 + self.passwords.len() * (N + 4));
+        out.extend_from_slice(&u32::from(self.prefix).to_le_bytes());
+        for pwd in &self.passwords {
+            out.extend_from_slice(&pwd.hash);
+            out.extend_from_slice(&pwd.count.to_le_bytes());
+        }
+    }
+
+    fn unspill(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid spilled chunk");
+        let (prefix, passwords) = bytes.split_first_chunk::<4>().ok_or_else(invalid)?;
+        if passwords.len() % (N + 4) != 0 {
+            return Err(invalid());
+        }
+
+        Ok(Chunk {
+            prefix: Prefix::create(u32::from_le_bytes(*prefix)).ok_or_else(invalid)?,
+            passwords: passwords
+                .chunks_exact(N + 4)
+                .map(|record| {
+                    let (hash, count) = record.split_at(N);
+                    PwnedPwd {
+                        hash: hash.try_into().expect("Hash length"),
+                        count: u32::from_le_bytes(count.try_into().expect("Count length")),
+                    }
+                })
+                .collect(),
+        })
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("Held back item can't be spilled or read back")]
+pub struct SpillError<K> {
+    pub key: K,
+
+    #[source]
+    pub source: io::Error,
+}
+
+/// Distinguishes spill files of a process
+static FILES: AtomicU64 = AtomicU64::new(0);
+
+/// A [ReorderBuffer] which spills items over the threshold (in bytes of [Spill::weight])
+/// to a file in the directory. The stream error must be convertible from [SpillError]
+#[derive(Debug)]
+pub struct SpillBuffer<K, T> {
+    memory: BTreeMap<K, T>,
+    memory_bytes: usize,
+    spilled: BTreeMap<K, (u64, usize)>,
+    threshold: usize,
+    dir: PathBuf,
+    file: Option<(PathBuf, File)>,
+    end: u64,
+}
+
+impl<K, T> SpillBuffer<K, T> {
+    pub fn new(dir: impl Into<PathBuf>, threshold: usize) -> Self {
+        Self {
+            memory: BTreeMap::new(),
+            memory_bytes: 0,
+            spilled: BTreeMap::new(),
+            threshold,
+            dir: dir.into(),
+            file: None,
+            end: 0,
+        }
+    }
+
+    /// Items on disk
+    pub fn spilled(&self) -> usize {
+        self.spilled.len()
+    }
+
+    /// The spill file, created on the first spill
+    fn file(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            let path = self.dir.join(format!(
+                "pwned_pwd_reorder_{}_{}.tmp",
+                std::process::id(),
+                FILES.fetch_add(1, Ordering::Relaxed)
+            ));
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)?;
+            self.file = Some((path, file));
+        }
+        Ok(&mut self.file.as_mut().expect("Spill file").1)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<u64> {
+        let offset = self.end;
+        let file = self.file()?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(bytes)?;
+        self.end += bytes.len() as u64;
+        Ok(offset)
+    }
+
+    fn read(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let file = self.file()?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = vec![0; len];
+        file.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+impl<K, T, E> ReorderBuffer<K, T, E> for SpillBuffer<K, T>
+where
+    K: Ord + Clone,
+    T: Spill,
+    E: From<SpillError<K>>,
+{
+    fn hold(&mut self, key: K, item: T) -> Result<(), E> {
+        let weight = item.weight();
+        if self.memory_bytes + weight <= self.threshold {
+            self.memory_bytes += weight;
+            self.memory.insert(key, item);
+            return Ok(());
+        }
+
+        let mut bytes = Vec::new();
+        item.spill(&mut bytes);
+        let offset = self.write(&bytes).map_err(|source| SpillError {
+            key: key.clone(),
+            source,
+        })?;
+        self.spilled.insert(key, (offset, bytes.len()));
+        telemetry::spilled(self.spilled.len());
+        Ok(())
+    }
+
+    fn release(&mut self, key: &K) -> Option<Result<T, E>> {
+        if let Some(item) = self.memory.remove(key) {
+            self.memory_bytes -= item.weight();
+            return Some(Ok(item));
+        }
+
+        let (offset, len) = self.spilled.remove(key)?;
+        let item = self.read(offset, len).and_then(|bytes| T::unspill(&bytes));
+        if self.spilled.is_empty() {
+            self.end = 0;
+            if let Some((_, file)) = &self.file {
+                if let Err(e) = file.set_len(0) {
+                    tracing::warn!("Spill file can't be truncated: {}", e);
+                }
+            }
+        }
+        telemetry::spilled(self.spilled.len());
+
+        Some(item.map_err(|source| {
+            SpillError {
+                key: key.clone(),
+                source,
+            }
+            .into()
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.memory.len() + self.spilled.len()
+    }
+}
+
+impl<K, T> Drop for SpillBuffer<K, T> {
+    fn drop(&mut self) {
+        if let Some((path, _)) = &self.file {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use pwned_pwd_core::NTLM_LEN;
+
+    use super::*;
+
+    fn chunk(prefix: u32, records: u32) -> Chunk {
+        Chunk {
+            prefix: Prefix::create(prefix).unwrap(),
+            passwords: (0..records).map(|i| PwnedPwd { hash: [i as u8; 20], count: i }).collect(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let chunk = chunk(0xABCDE, 3);
+        let mut bytes = Vec::new();
+        chunk.spill(&mut bytes);
+        assert_eq!(4 + 3 * 24, bytes.len());
+        assert_eq!(chunk, Chunk::unspill(&bytes).unwrap());
+        assert!(Chunk::<NTLM_LEN>::unspill(&bytes).is_err());
+    }
+
+    #[test]
+    fn spills_over_threshold() {
+        let mut buffer = SpillBuffer::new(temp_dir(), chunk(0, 2).weight());
+        for prefix in [3, 1, 2] {
+            ReorderBuffer::<_, _, SpillError<Prefix>>::hold(&mut buffer, Prefix::create(prefix).unwrap(), chunk(prefix, 2)).unwrap();
+        }
+        assert_eq!((3, 2), (ReorderBuffer::<_, _, SpillError<Prefix>>::len(&buffer), buffer.spilled()));
+        let path = buffer.file.as_ref().unwrap().0.clone();
+
+        for prefix in [1, 2, 3] {
+            let released: Result<Chunk, SpillError<Prefix>> = buffer.release(&Prefix::create(prefix).unwrap()).unwrap();
+            assert_eq!(chunk(prefix, 2), released.unwrap());
+        }
+        assert_eq!(0, std::fs::metadata(&path).unwrap().len());
+        drop(buffer);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,99 @@
+//! Spans and metrics of downloads
+//!
+//! Spans (`tracing`):
+//! - `download_worker{worker}` — a worker of [crate::Downloader::download]
+//! - `download_prefix{prefix}` — a request of a single range
+//! - `download_watchdog` — the monitor of stalled workers
+//!
+//! Metrics (the `metrics` feature, through the [metrics](https://docs.rs/metrics) facade)
+//! are named by the constants of this module
+
+use std::time::Duration;
+
+use crate::DownloadErrorKind;
+
+/// Downloaded ranges
+pub const DOWNLOADED_CHUNKS: &str = "pwned_pwd_downloaded_chunks_total";
+
+/// Records of downloaded ranges
+pub const DOWNLOADED_RECORDS: &str = "pwned_pwd_downloaded_records_total";
+
+/// Failed ranges, labeled by `kind`: `request`, `parse`, `send`, `stalled` or `spill`
+pub const DOWNLOAD_ERRORS: &str = "pwned_pwd_download_errors_total";
+
+/// Duration of a range request
+pub const DOWNLOAD_DURATION: &str = "pwned_pwd_download_duration_seconds";
+
+/// Chunks held back by an ordered download until the preceding ones arrive
+pub const REORDER_BUFFERED: &str = "pwned_pwd_reorder_buffered_chunks";
+
+/// Held back chunks which are spilled to disk, see [crate::spill]
+pub const REORDER_SPILLED: &str = "pwned_pwd_reorder_spilled_chunks";
+
+/// Describes the metrics to the installed recorder
+#[cfg(feature = "metrics")]
+pub fn describe() {
+    use metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
+
+    describe_counter!(DOWNLOADED_CHUNKS, Unit::Count, "Downloaded ranges");
+    describe_counter!(
+        DOWNLOADED_RECORDS,
+        Unit::Count,
+        "Records of downloaded ranges"
+    );
+    describe_counter!(DOWNLOAD_ERRORS, Unit::Count, "Failed ranges by kind");
+    describe_histogram!(
+        DOWNLOAD_DURATION,
+        Unit::Seconds,
+        "Duration of a range request"
+    );
+    describe_gauge!(
+        REORDER_BUFFERED,
+        Unit::Count,
+        "Chunks held back by an ordered download"
+    );
+    describe_gauge!(
+        REORDER_SPILLED,
+        Unit::Count,
+        "Held back chunks spilled to disk"
+    );
+}
+
+pub(crate) fn downloaded(records: usize, elapsed: Duration) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!(DOWNLOADED_CHUNKS).increment(1);
+        metrics::counter!(DOWNLOADED_RECORDS).increment(records as u64);
+        metrics::histogram!(DOWNLOAD_DURATION).record(elapsed.as_secs_f64());
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = (records, elapsed);
+}
+
+pub(crate) fn failed(kind: &DownloadErrorKind) {
+    let kind = match kind {
+        DownloadErrorKind::Reqwest(_) => "request",
+        DownloadErrorKind::Parse(_) => "parse",
+        DownloadErrorKind::SendError(_) => "send",
+        DownloadErrorKind::Stalled(_) => "stalled",
+        DownloadErrorKind::Spill(_) => "spill",
+    };
+    tracing::warn!(kind, "Range download failed");
+
+    #[cfg(feature = "metrics")]
+    metrics::counter!(DOWNLOAD_ERRORS, "kind" => kind).increment(1);
+}
+
+pub(crate) fn buffered(chunks: usize) {
+    #[cfg(feature = "metrics")]
+    metrics::gauge!(REORDER_BUFFERED).set(chunks as f64);
+    #[cfg(not(feature = "metrics"))]
+    let _ = chunks;
+}
+
+pub(crate) fn spilled(chunks: usize) {
+    #[cfg(feature = "metrics")]
+    metrics::gauge!(REORDER_SPILLED).set(chunks as f64);
+    #[cfg(not(feature = "metrics"))]
+    let _ = chunks;
+}
@@ -0,0 +1,120 @@
+//! Detection of stalled downloads
+//!
+//! A hung connection neither fails nor progresses, so an unattended sync
+//! may wait forever. The [Watchdog] limits the time a prefix download may go
+//! without receiving anything and reports workers and streams which produce nothing
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Watchdog settings of a [crate::Downloader]
+#[derive(Debug, Clone)]
+pub struct Watchdog {
+    stall_timeout: Duration,
+    retries: u32,
+}
+
+impl Watchdog {
+    /// A prefix download which receives nothing (neither the response nor a part of its body)
+    /// for `stall_timeout` is cancelled. A slow download of a large range isn't, while it progresses
+    pub fn new(stall_timeout: Duration) -> Self {
+        Self {
+            stall_timeout,
+            retries: 0,
+        }
+    }
+
+    /// How many times a cancelled prefix download is retried before the download fails
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn stall_timeout(&self) -> Duration {
+        self.stall_timeout
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+}
+
+/// Time of the last progress of every worker
+pub(crate) struct Activity {
+    started: Instant,
+    workers: Vec<AtomicU64>,
+    chunks: AtomicU64,
+}
+
+impl Activity {
+    pub(crate) fn new(workers: u32) -> Arc<Self> {
+        Arc::new(Self {
+            started: Instant::now(),
+            workers: (0..workers).map(|_| AtomicU64::new(0)).collect(),
+            chunks: AtomicU64::new(0),
+        })
+    }
+
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    /// Worker `i` produced a chunk
+    pub(crate) fn progress(&self, i: u32) {
+        self.workers[i as usize].store(self.now(), SeqCst);
+        self.chunks.fetch_add(1, SeqCst);
+    }
+
+    /// Worker `i` has no more prefixes to download
+    pub(crate) fn finished(&self, i: u32) {
+        self.workers[i as usize].store(u64::MAX, SeqCst);
+    }
+
+    /// Logs workers without progress for `stall_timeout` and the overall chunk rate
+    /// until `is_done` returns true
+    pub(crate) async fn monitor(
+        self: Arc<Self>,
+        stall_timeout: Duration,
+        is_done: impl Fn() -> bool,
+    ) {
+        let mut interval = tokio::time::interval(stall_timeout);
+        interval.tick().await;
+
+        let mut last_chunks = 0;
+        loop {
+            interval.tick().await;
+            if is_done() {
+                break;
+            }
+
+            let chunks = self.chunks.load(SeqCst);
+            let rate = (chunks - last_chunks) as f64 / stall_timeout.as_secs_f64();
+            last_chunks = chunks;
+
+            if rate == 0.0 {
+                tracing::warn!("No chunks were downloaded for {:?}", stall_timeout);
+            } else {
+                tracing::info!("Download rate is {:.2} chunks/s", rate);
+            }
+
+            let now = self.now();
+            let stall_timeout = stall_timeout.as_millis() as u64;
+            for (i, last) in self.workers.iter().enumerate() {
+                let last = last.load(SeqCst);
+                if last == u64::MAX {
+                    continue;
+                }
+
+                let idle = now.saturating_sub(last);
+                if idle >= stall_timeout {
+                    tracing::warn!("Worker {} has no progress for {}ms", i, idle);
+                }
+            }
+        }
+    }
+}
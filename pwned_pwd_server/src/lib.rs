@@ -0,0 +1,284 @@
+//! An HTTP server of the haveibeenpwned range API backed by any store
+//!
+//! `GET /range/{prefix}` answers with `SUFFIX:COUNT` lines like
+//! `https://api.pwnedpasswords.com/range/` does, so an organization can run an internal
+//! mirror and point existing clients (in any language) at it. `?mode=ntlm` is served
+//! from an NTLM store, if there is one.
+//!
+//! `POST /exists` takes a JSON array of hex hashes and answers with a JSON array of booleans
+//! in the same order, so an application checks many passwords with one request
+
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use pwned_pwd_core::{Chunk, HashKind, Prefix, PwnedPwd, NTLM_LEN};
+use pwned_pwd_store::dyn_store::{DynError, DynReadStore};
+use serde::Deserialize;
+
+/// Length of a prefix in hex characters, cut from the hashes of a range
+const PREFIX_HEX_LEN: usize = 5;
+
+/// Routes of the range API
+#[derive(Clone)]
+pub struct RangeServer {
+    sha1: Arc<dyn DynReadStore>,
+    ntlm: Option<Arc<dyn DynReadStore<NTLM_LEN>>>,
+    max_age: Option<Duration>,
+    max_batch: usize,
+}
+
+impl RangeServer {
+    /// Hashes of a `POST /exists` request, unless [Self::with_max_batch] is set
+    pub const DEFAULT_MAX_BATCH: usize = 1000;
+
+    /// Serves SHA-1 ranges of the store
+    pub fn new(store: impl DynReadStore + 'static) -> Self {
+        Self {
+            sha1: Arc::new(store),
+            ntlm: None,
+            max_age: None,
+            max_batch: Self::DEFAULT_MAX_BATCH,
+        }
+    }
+
+    /// Serves `?mode=ntlm` ranges of the store, they are rejected with 400 without it
+    pub fn with_ntlm(mut self, store: impl DynReadStore<NTLM_LEN> + 'static) -> Self {
+        self.ntlm = Some(Arc::new(store));
+        self
+    }
+
+    /// `Cache-Control: public, max-age=...` of ranges, so proxies and CDNs can cache them
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Larger `POST /exists` requests are rejected with 413
+    pub fn with_max_batch(mut self, max_batch: usize) -> Self {
+        self.max_batch = max_batch;
+        self
+    }
+
+    /// A router with `GET /range/{prefix}` and `POST /exists`, which can be merged or nested
+    /// into an application
+    pub fn router(self) -> Router {
+        Router::new()
+            .route("/range/{prefix}", get(range))
+            .route("/exists", post(exists))
+            .with_state(self)
+    }
+
+    fn respond<const N: usize>(&self, res: Result<Chunk<N>, DynError>) -> Response {
+        let chunk = match res {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                tracing::error!("Range can't be read: {}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+
+        let mut response =
+            ([(header::CONTENT_TYPE, "text/plain")], range_body(&chunk)).into_response();
+        if let Some(max_age) = self.max_age {
+            let value = format!("public, max-age={}", max_age.as_secs());
+            response.headers_mut().insert(
+                header::CACHE_CONTROL,
+                HeaderValue::from_str(&value).expect("Invalid header value"),
+            );
+        }
+        response
+    }
+}
+
+#[derive(Deserialize)]
+struct RangeQuery {
+    mode: Option<String>,
+}
+
+impl RangeQuery {
+    /// The hash kind of `?mode=`, SHA-1 if it isn't set and None if it is unknown
+    fn kind(&self) -> Option<HashKind> {
+        match self.mode.as_deref() {
+            None => Some(HashKind::Sha1),
+            Some(mode) if mode.eq_ignore_ascii_case(HashKind::Sha1.mode()) => Some(HashKind::Sha1),
+            Some(mode) if mode.eq_ignore_ascii_case(HashKind::Ntlm.mode()) => Some(HashKind::Ntlm),
+            Some(_) => None,
+        }
+    }
+}
+
+async fn range(
+    State(server): State<RangeServer>,
+    Path(prefix): Path<String>,
+    Query(query): Query<RangeQuery>,
+) -> Response {
+    let Ok(prefix) = prefix.parse::<Prefix>() else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "The hash prefix was not in a valid format",
+        )
+            .into_response();
+    };
+
+    match (query.kind(), &server.ntlm) {
+        (None, _) => (StatusCode::BAD_REQUEST, "Unknown mode").into_response(),
+        (Some(HashKind::Sha1), _) => server.respond(server.sha1.range(prefix).await),
+        (Some(HashKind::Ntlm), Some(ntlm)) => server.respond(ntlm.range(prefix).await),
+        (Some(HashKind::Ntlm), None) => ntlm_not_served(),
+    }
+}
+
+async fn exists(
+    State(server): State<RangeServer>,
+    Query(query): Query<RangeQuery>,
+    Json(hashes): Json<Vec<String>>,
+) -> Response {
+    if hashes.len() > server.max_batch {
+        return (StatusCode::PAYLOAD_TOO_LARGE, "Too many hashes").into_response();
+    }
+
+    match (query.kind(), &server.ntlm) {
+        (None, _) => (StatusCode::BAD_REQUEST, "Unknown mode").into_response(),
+        (Some(HashKind::Sha1), _) => exists_many(&*server.sha1, &hashes).await,
+        (Some(HashKind::Ntlm), Some(ntlm)) => exists_many(&**ntlm, &hashes).await,
+        (Some(HashKind::Ntlm), None) => ntlm_not_served(),
+    }
+}
+
+async fn exists_many<const N: usize>(store: &dyn DynReadStore<N>, hashes: &[String]) -> Response {
+    let Ok(vals) = hashes
+        .iter()
+        .map(|hash| PwnedPwd::<N>::try_from_hex(hash, 0).map(|pwd| pwd.hash))
+        .collect::<Result<Vec<_>, _>>()
+    else {
+        return (StatusCode::BAD_REQUEST, "A hash was not in a valid format").into_response();
+    };
+
+    match store.exists_many(&vals).await {
+        Ok(found) => Json(found).into_response(),
+        Err(e) => {
+            tracing::error!("Hashes can't be looked up: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn ntlm_not_served() -> Response {
+    (StatusCode::BAD_REQUEST, "NTLM hashes aren't served").into_response()
+}
+
+/// `SUFFIX:COUNT` lines separated by CRLF. Stores without counts have 0 counts, which
+/// mean padding to the clients, so every hash is reported as seen at least once
+fn range_body<const N: usize>(chunk: &Chunk<N>) -> String {
+    chunk
+        .passwords
+        .iter()
+        .map(|pwd| {
+            let hash = format!("{:X}", pwd);
+            format!("{}:{}", &hash[PREFIX_HEX_LEN..], pwd.count.max(1))
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use hex_literal::hex;
+    use pwned_pwd_core::PwnedPwd;
+    use pwned_pwd_downloader::Downloader;
+    use pwned_pwd_store::WriteStore;
+    use pwned_pwd_store_local::{format::RecordFormat, LocalStore};
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+
+    use super::*;
+
+    const FIRST: PwnedPwd = PwnedPwd { hash: hex!("21BD40003D5DDAF9FAE5D1E4D06CB1B96DDA3F4F"), count: 0 };
+    const SECOND: PwnedPwd = PwnedPwd { hash: hex!("21BD4003FDE4E6EAC0D1F50DFD6EAFE9A2B0EC8E"), count: 10 };
+
+    async fn serve(server: RangeServer) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(listener, server.router()).await.unwrap() });
+        addr
+    }
+
+    /// The status line and the headers of a response
+    async fn head(addr: &str, path: &str) -> String {
+        let mut connection = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n");
+        connection.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        connection.read_to_string(&mut response).await.unwrap();
+        response.split("\r\n\r\n").next().unwrap().to_owned()
+    }
+
+    /// The status line and the body of a JSON request
+    async fn post(addr: &str, path: &str, body: &str) -> (String, String) {
+        let mut connection = TcpStream::connect(addr).await.unwrap();
+        let request = format!("POST {path} HTTP/1.1\r\nHost: {addr}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len());
+        connection.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        connection.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_owned(), body.to_owned())
+    }
+
+    #[test]
+    fn body() {
+        let chunk = Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![FIRST, SECOND] };
+        assert_eq!("0003D5DDAF9FAE5D1E4D06CB1B96DDA3F4F:1\r\n003FDE4E6EAC0D1F50DFD6EAFE9A2B0EC8E:10", range_body(&chunk));
+    }
+
+    #[tokio::test]
+    async fn serves_ranges() {
+        let path = temp_dir().join("pwned_pwd_server");
+        let _ = std::fs::remove_file(&path);
+        let store = LocalStore::builder(&path).with_format(RecordFormat::HashesWithCounts).build().unwrap();
+        let prefix = Prefix::create(0x21BD4).unwrap();
+        store.save(futures::stream::iter([Chunk { prefix, passwords: vec![FIRST, SECOND] }])).await.unwrap();
+
+        let addr = serve(RangeServer::new(store).with_max_age(Duration::from_secs(60))).await;
+        let downloader = Downloader::new(format!("http://{addr}/range/").parse().unwrap(), 1);
+        let chunk = downloader.download_prefix(prefix).await.unwrap();
+        assert_eq!(vec![PwnedPwd { count: 1, ..FIRST }, SECOND], chunk.passwords);
+        assert!(downloader.download_prefix(Prefix::max()).await.unwrap().passwords.is_empty());
+
+        let ok = head(&addr, "/range/21bd4").await;
+        assert!(ok.starts_with("HTTP/1.1 200"), "{ok}");
+        assert!(ok.contains("cache-control: public, max-age=60"), "{ok}");
+
+        assert!(head(&addr, "/range/21BD").await.starts_with("HTTP/1.1 400"));
+        assert!(head(&addr, "/range/21BD4?mode=ntlm").await.starts_with("HTTP/1.1 400"));
+        assert!(head(&addr, "/range/21BD4?mode=md5").await.starts_with("HTTP/1.1 400"));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn answers_batches() {
+        let path = temp_dir().join("pwned_pwd_server_exists");
+        let _ = std::fs::remove_file(&path);
+        let store = LocalStore::builder(&path).with_format(RecordFormat::Hashes).build().unwrap();
+        let prefix = Prefix::create(0x21BD4).unwrap();
+        store.save(futures::stream::iter([Chunk { prefix, passwords: vec![FIRST, SECOND] }])).await.unwrap();
+
+        let addr = serve(RangeServer::new(store).with_max_batch(3)).await;
+        let body = r#"["21BD4003FDE4E6EAC0D1F50DFD6EAFE9A2B0EC8E", "0000000000000000000000000000000000000000", "21bd40003d5ddaf9fae5d1e4d06cb1b96dda3f4f"]"#;
+        assert_eq!(("HTTP/1.1 200 OK".to_owned(), "[true,false,true]".to_owned()), post(&addr, "/exists", body).await);
+        assert_eq!("[]", post(&addr, "/exists", "[]").await.1);
+
+        assert!(post(&addr, "/exists", r#"["a", "b", "c", "d"]"#).await.0.starts_with("HTTP/1.1 413"));
+        assert!(post(&addr, "/exists", r#"["21BD4"]"#).await.0.starts_with("HTTP/1.1 400"));
+        assert!(post(&addr, "/exists?mode=ntlm", "[]").await.0.starts_with("HTTP/1.1 400"));
+        assert!(post(&addr, "/exists", "{}").await.0.starts_with("HTTP/1.1 422"));
+        let _ = std::fs::remove_file(path);
+    }
+}
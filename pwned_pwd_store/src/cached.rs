@@ -0,0 +1,367 @@
+//! Read-through cache of lookups
+//!
+//! Users pick the same passwords, so a store which answers over the network (especially
+//! the online range API) is asked about the same hashes again and again. [CachedStore]
+//! keeps recent answers in memory, with separate lifetimes for found and missing hashes
+
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::Stream;
+use lru::LruCache;
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd, SHA1_LEN};
+
+use crate::{OrderRequirement, ReadStore, StoreMetadata, WriteStore};
+
+/// Counters of a [CachedStore]
+#[derive(Debug, Default)]
+pub struct CacheMetrics {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    expired: AtomicU64,
+}
+
+impl CacheMetrics {
+    /// Hashes answered from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Relaxed)
+    }
+
+    /// Hashes passed to the store
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Relaxed)
+    }
+
+    /// Misses of cached hashes whose answers have expired
+    pub fn expired(&self) -> u64 {
+        self.expired.load(Relaxed)
+    }
+}
+
+/// A cached answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cached {
+    NotPwned,
+
+    /// The count is unknown, if the hash was found by [ReadStore::exists_many]
+    Pwned(Option<u32>),
+}
+
+/// A store which answers lookups from the cache and passes misses to the inner store.
+/// The least recently used hashes are evicted first.
+///
+/// Writes through the wrapper clear the cache, writes to the inner store directly
+/// (e.g. by another process) are seen only when the answers expire
+pub struct CachedStore<S, const N: usize = SHA1_LEN> {
+    store: S,
+    entries: Mutex<LruCache<[u8; N], (Instant, Cached)>>,
+    positive_ttl: Duration,
+    negative_ttl: Duration,
+    metrics: CacheMetrics,
+}
+
+impl<S, const N: usize> CachedStore<S, N> {
+    /// Caches at most `max_entries` hashes, found ones for an hour and missing ones for 10 minutes
+    pub fn new(store: S, max_entries: usize) -> Self {
+        Self {
+            store,
+            entries: Mutex::new(LruCache::new(
+                NonZeroUsize::new(max_entries).unwrap_or(NonZeroUsize::MIN),
+            )),
+            positive_ttl: Duration::from_secs(60 * 60),
+            negative_ttl: Duration::from_secs(10 * 60),
+            metrics: Default::default(),
+        }
+    }
+
+    /// How long found hashes are cached, zero disables it
+    pub fn with_positive_ttl(mut self, ttl: Duration) -> Self {
+        self.positive_ttl = ttl;
+        self
+    }
+
+    /// How long missing hashes are cached, zero disables it.
+    /// A new release of the data set may add them, so it is usually shorter
+    pub fn with_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn metrics(&self) -> &CacheMetrics {
+        &self.metrics
+    }
+
+    /// Count of cached hashes, including expired ones which aren't evicted yet
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget the answer about the hash
+    pub fn invalidate(&self, val: &[u8; N]) {
+        self.entries.lock().unwrap().pop(val);
+    }
+
+    /// Forget all the answers
+    pub fn invalidate_all(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    /// A fresh answer without asking the store, if `counted`, a found hash must have
+    /// a known count. Counts as a hit or a miss
+    pub fn cached(&self, val: &[u8; N], counted: bool) -> Option<Cached> {
+        let mut entries = self.entries.lock().unwrap();
+        let cached = match entries.get(val) {
+            Some((expires, cached)) if *expires > Instant::now() => {
+                Some(*cached).filter(|cached| !counted || *cached != Cached::Pwned(None))
+            }
+            Some(_) => {
+                entries.pop(val);
+                self.metrics.expired.fetch_add(1, Relaxed);
+                None
+            }
+            None => None,
+        };
+
+        match cached {
+            Some(_) => self.metrics.hits.fetch_add(1, Relaxed),
+            None => self.metrics.misses.fetch_add(1, Relaxed),
+        };
+        cached
+    }
+
+    /// Caches an answer got bypassing the wrapper, e.g. from one of several stores
+    pub fn remember(&self, val: [u8; N], cached: Cached) {
+        let ttl = match cached {
+            Cached::NotPwned => self.negative_ttl,
+            Cached::Pwned(_) => self.positive_ttl,
+        };
+        if !ttl.is_zero() {
+            self.entries
+                .lock()
+                .unwrap()
+                .put(val, (Instant::now() + ttl, cached));
+        }
+    }
+}
+
+impl<S, const N: usize> ReadStore<N> for CachedStore<S, N>
+where
+    S: ReadStore<N> + Sync,
+    S::Error: Send,
+{
+    type Error = S::Error;
+
+    async fn exists(&self, val: [u8; N]) -> Result<bool, Self::Error> {
+        if let Some(cached) = self.cached(&val, false) {
+            return Ok(cached != Cached::NotPwned);
+        }
+
+        let found = self.store.exists(val).await?;
+        self.remember(
+            val,
+            if found {
+                Cached::Pwned(None)
+            } else {
+                Cached::NotPwned
+            },
+        );
+        Ok(found)
+    }
+
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd<N>, Self::Error>> + Send {
+        self.store.iter_all()
+    }
+
+    /// Passes only the missed hashes to the inner store, in a single batch
+    async fn exists_many(&self, vals: &[[u8; N]]) -> Result<Vec<bool>, Self::Error> {
+        let mut res = Vec::with_capacity(vals.len());
+        let mut missed = Vec::new();
+        for (i, val) in vals.iter().enumerate() {
+            match self.cached(val, false) {
+                Some(cached) => res.push(cached != Cached::NotPwned),
+                None => {
+                    res.push(false);
+                    missed.push(i);
+                }
+            }
+        }
+
+        if !missed.is_empty() {
+            let hashes = missed.iter().map(|i| vals[*i]).collect::<Vec<_>>();
+            let found = self.store.exists_many(&hashes).await?;
+            for (i, found) in missed.into_iter().zip(found) {
+                res[i] = found;
+                self.remember(
+                    vals[i],
+                    if found {
+                        Cached::Pwned(None)
+                    } else {
+                        Cached::NotPwned
+                    },
+                );
+            }
+        }
+        Ok(res)
+    }
+
+    /// A hash found by [ReadStore::exists_many] is a miss, as its count is unknown
+    async fn exists_count(&self, val: [u8; N]) -> Result<Option<u32>, Self::Error> {
+        match self.cached(&val, true) {
+            Some(Cached::NotPwned) => return Ok(None),
+            Some(Cached::Pwned(count)) => return Ok(count),
+            None => {}
+        }
+
+        let count = self.store.exists_count(val).await?;
+        self.remember(
+            val,
+            count.map_or(Cached::NotPwned, |count| Cached::Pwned(Some(count))),
+        );
+        Ok(count)
+    }
+
+    /// Filters the cached count, so answers are shared by all the thresholds
+    async fn exists_with_min_count(
+        &self,
+        val: [u8; N],
+        min_count: u32,
+    ) -> Result<Option<u32>, Self::Error> {
+        Ok(self
+            .exists_count(val)
+            .await?
+            .filter(|count| *count >= min_count))
+    }
+
+    /// Ranges aren't cached
+    async fn range(&self, prefix: Prefix) -> Result<Chunk<N>, Self::Error> {
+        self.store.range(prefix).await
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        self.store.metadata().await
+    }
+
+    async fn max_prefix(&self) -> Result<Option<Prefix>, Self::Error> {
+        self.store.max_prefix().await
+    }
+
+    async fn healthy(&self) -> Result<bool, Self::Error> {
+        self.store.healthy().await
+    }
+}
+
+/// Writes clear the cache after they are done, even if they fail
+impl<S, const N: usize> WriteStore<N> for CachedStore<S, N>
+where
+    S: WriteStore<N> + Sync,
+    S::Error: Send,
+{
+    fn order_requirement() -> OrderRequirement {
+        S::order_requirement()
+    }
+
+    async fn save<St: Stream<Item = Chunk<N>> + Unpin + Send>(
+        &self,
+        s: St,
+    ) -> Result<(), Self::Error> {
+        let res = self.store.save(s).await;
+        self.invalidate_all();
+        res
+    }
+
+    async fn merge<St: Stream<Item = Chunk<N>> + Unpin + Send>(
+        &self,
+        s: St,
+    ) -> Result<(), Self::Error> {
+        let res = self.store.merge(s).await;
+        self.invalidate_all();
+        res
+    }
+
+    async fn remove(&self, val: [u8; N]) -> Result<bool, Self::Error> {
+        let res = self.store.remove(val).await;
+        self.invalidate(&val);
+        res
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        let res = self.store.clear().await;
+        self.invalidate_all();
+        res
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+    use crate::test_store::TestStore;
+
+    const PWNED: [u8; 20] = hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087");
+    const NOT_PWNED: [u8; 20] = hex!("21BD5000F2D6B0E3CE3E9D1A0E9C3EB1E2A2A4AC");
+
+    fn chunk(passwords: Vec<PwnedPwd>) -> futures::stream::Iter<std::vec::IntoIter<Chunk>> {
+        futures::stream::iter(vec![Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords }])
+    }
+
+    #[tokio::test]
+    async fn read_through() {
+        let store = CachedStore::new(TestStore::records(), 10);
+        store.save(chunk(vec![PwnedPwd { hash: PWNED, count: 3 }])).await.unwrap();
+
+        assert_eq!(Some(3), store.exists_count(PWNED).await.unwrap());
+        assert!(!store.exists(NOT_PWNED).await.unwrap());
+        assert_eq!(2, store.metrics().misses());
+
+        // Answers come from the cache while the inner store changes
+        store.store().save(chunk(vec![PwnedPwd { hash: NOT_PWNED, count: 1 }])).await.unwrap();
+        assert_eq!(Some(3), store.exists_with_min_count(PWNED, 2).await.unwrap());
+        assert_eq!(vec![true, false], store.exists_many(&[PWNED, NOT_PWNED]).await.unwrap());
+        assert_eq!(3, store.metrics().hits());
+        assert_eq!(2, store.len());
+
+        store.invalidate(&NOT_PWNED);
+        assert!(store.exists(NOT_PWNED).await.unwrap());
+        assert_eq!(Some(1), store.exists_count(NOT_PWNED).await.unwrap());
+        assert_eq!(4, store.metrics().misses());
+
+        store.clear().await.unwrap();
+        assert!(store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn ttl() {
+        let store = CachedStore::new(TestStore::records(), 10).with_negative_ttl(Duration::ZERO);
+        store.save(chunk(vec![PwnedPwd { hash: PWNED, count: 3 }])).await.unwrap();
+
+        assert!(!store.exists(NOT_PWNED).await.unwrap());
+        assert!(store.is_empty());
+
+        let store = store.with_positive_ttl(Duration::from_millis(1));
+        assert!(store.exists(PWNED).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert!(store.exists(PWNED).await.unwrap());
+        assert_eq!(1, store.metrics().expired());
+        assert_eq!(3, store.metrics().misses());
+    }
+}
@@ -0,0 +1,100 @@
+//! Cooperative cancellation of long operations
+//!
+//! A save of the whole data set takes hours. Every [crate::WriteStore] future is
+//! drop-safe: when it is dropped, the backend keeps either the previous data or
+//! a consistent part of the new one, as documented by the backend.
+//! [CancelToken] lets an operator drop such a future from another task
+
+use std::{future::Future, sync::Arc};
+
+use tokio::sync::watch;
+
+/// The operation was cancelled by a [CancelToken]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("The operation was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// A token shared between an operation and whoever may cancel it
+#[derive(Debug, Clone)]
+pub struct CancelToken {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self {
+            sender: Arc::new(watch::Sender::new(false)),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Completes when the token is cancelled
+    pub async fn cancelled(&self) {
+        let mut receiver = self.sender.subscribe();
+        // The sender lives in self, so the channel can't be closed
+        let _ = receiver.wait_for(|cancelled| *cancelled).await;
+    }
+
+    /// Runs the future until it completes or the token is cancelled.
+    /// On cancel the future is dropped and [Cancelled] is returned
+    pub async fn run<F: Future>(&self, f: F) -> Result<F::Output, Cancelled> {
+        tokio::select! {
+            biased;
+            _ = self.cancelled() => Err(Cancelled),
+            res = f => Ok(res),
+        }
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::time::Duration;
+
+    use hex_literal::hex;
+
+    use super::*;
+    use crate::{test_store::TestStore, ReadStore};
+
+    #[tokio::test]
+    async fn cancel() {
+        let token = CancelToken::new();
+        let store = TestStore::Hangs;
+
+        let canceller = {
+            let token = token.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                token.cancel();
+            })
+        };
+
+        let res = token.run(store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"))).await;
+        assert_eq!(Err(Cancelled), res.map(|_| ()));
+        assert!(token.is_cancelled());
+        canceller.await.unwrap();
+
+        assert_eq!(Err(Cancelled), token.run(async { 42 }).await);
+        assert_eq!(Ok(42), CancelToken::new().run(async { 42 }).await);
+    }
+}
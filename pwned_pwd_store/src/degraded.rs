@@ -0,0 +1,199 @@
+//! Graceful degradation for authentication flows
+//!
+//! A login must not fail because the breach-check backend is unavailable,
+//! so [DegradingStore] converts store errors and timeouts into a configured verdict
+
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+    time::Duration,
+};
+
+use crate::ReadStore;
+
+/// What should a lookup answer when the store is unavailable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DegradedPolicy {
+    /// The password is treated as not pwned, and a warning is logged
+    #[default]
+    AllowWithWarning,
+
+    /// The password is treated as pwned
+    Deny,
+
+    /// The password is silently treated as not pwned
+    TreatAsNotPwned,
+}
+
+/// Why a lookup was degraded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Degradation {
+    /// The store returned an error
+    Error,
+
+    /// The store didn't answer in time
+    Timeout,
+}
+
+/// Result of a lookup through a [DegradingStore]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lookup {
+    /// Is the password pwned (or treated as pwned by the policy)
+    pub pwned: bool,
+
+    /// Is set if the store was unavailable and `pwned` comes from a [DegradedPolicy]
+    pub degraded: Option<Degradation>,
+}
+
+impl Lookup {
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.is_some()
+    }
+}
+
+/// Counters of a [DegradingStore]
+#[derive(Debug, Default)]
+pub struct DegradationMetrics {
+    lookups: AtomicU64,
+    errors: AtomicU64,
+    timeouts: AtomicU64,
+}
+
+impl DegradationMetrics {
+    /// Total lookups
+    pub fn lookups(&self) -> u64 {
+        self.lookups.load(Relaxed)
+    }
+
+    /// Lookups degraded because of a store error
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Relaxed)
+    }
+
+    /// Lookups degraded because of a timeout
+    pub fn timeouts(&self) -> u64 {
+        self.timeouts.load(Relaxed)
+    }
+
+    /// All degraded lookups
+    pub fn degraded(&self) -> u64 {
+        self.errors() + self.timeouts()
+    }
+}
+
+/// A store wrapper which never fails a lookup
+pub struct DegradingStore<S> {
+    store: S,
+    policy: DegradedPolicy,
+    timeout: Option<Duration>,
+    metrics: DegradationMetrics,
+}
+
+impl<S> DegradingStore<S> {
+    pub fn new(store: S, policy: DegradedPolicy) -> Self {
+        Self {
+            store,
+            policy,
+            timeout: None,
+            metrics: Default::default(),
+        }
+    }
+
+    /// Degrade lookups which take longer than `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn metrics(&self) -> &DegradationMetrics {
+        &self.metrics
+    }
+
+    /// Checks the hash, falling back to the policy if the store is unavailable
+    pub async fn check<const N: usize>(&self, val: [u8; N]) -> Lookup
+    where
+        S: ReadStore<N>,
+        S::Error: Display,
+    {
+        self.metrics.lookups.fetch_add(1, Relaxed);
+
+        let res = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.store.exists(val))
+                .await
+                .ok(),
+            None => Some(self.store.exists(val).await),
+        };
+
+        let degradation = match res {
+            Some(Ok(pwned)) => {
+                return Lookup {
+                    pwned,
+                    degraded: None,
+                }
+            }
+            Some(Err(e)) => {
+                self.metrics.errors.fetch_add(1, Relaxed);
+                tracing::debug!("Store error: {}", e);
+                Degradation::Error
+            }
+            None => {
+                self.metrics.timeouts.fetch_add(1, Relaxed);
+                Degradation::Timeout
+            }
+        };
+
+        let pwned = match self.policy {
+            DegradedPolicy::AllowWithWarning => {
+                tracing::warn!("Pwned password check is degraded ({:?})", degradation);
+                false
+            }
+            DegradedPolicy::Deny => true,
+            DegradedPolicy::TreatAsNotPwned => false,
+        };
+
+        Lookup {
+            pwned,
+            degraded: Some(degradation),
+        }
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+    use crate::test_store::TestStore;
+
+    const HASH: [u8; 20] = hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087");
+
+    #[tokio::test]
+    async fn not_degraded() {
+        let store = DegradingStore::new(TestStore::Found, DegradedPolicy::Deny);
+        assert_eq!(Lookup { pwned: true, degraded: None }, store.check(HASH).await);
+        assert_eq!(1, store.metrics().lookups());
+        assert_eq!(0, store.metrics().degraded());
+    }
+
+    #[tokio::test]
+    async fn degraded_by_error() {
+        let store = DegradingStore::new(TestStore::Fails, DegradedPolicy::Deny);
+        assert_eq!(Lookup { pwned: true, degraded: Some(Degradation::Error) }, store.check(HASH).await);
+
+        let store = DegradingStore::new(TestStore::Fails, DegradedPolicy::TreatAsNotPwned);
+        assert_eq!(Lookup { pwned: false, degraded: Some(Degradation::Error) }, store.check(HASH).await);
+        assert_eq!(1, store.metrics().errors());
+    }
+
+    #[tokio::test]
+    async fn degraded_by_timeout() {
+        let store = DegradingStore::new(TestStore::Hangs, DegradedPolicy::AllowWithWarning).with_timeout(Duration::from_millis(10));
+        assert_eq!(Lookup { pwned: false, degraded: Some(Degradation::Timeout) }, store.check(HASH).await);
+        assert_eq!(1, store.metrics().timeouts());
+    }
+}
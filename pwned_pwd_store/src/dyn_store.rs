@@ -0,0 +1,197 @@
+//! Object-safe stores
+//!
+//! Store methods are generic and return `impl Future`, so the traits can't be
+//! trait objects. [DynReadStore] and [DynStore] box futures and streams and erase errors,
+//! so a backend selected at runtime can be kept as `Box<dyn DynStore>`
+
+use futures::{
+    future::BoxFuture,
+    stream::{BoxStream, StreamExt},
+    FutureExt,
+};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd, SHA1_LEN};
+
+use crate::{OrderRequirement, ReadStore, Store, StoreMetadata, WriteStore};
+
+/// An error of any store
+pub type DynError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Object-safe counterpart of [ReadStore], implemented for every store
+/// whose errors convert into [DynError]
+pub trait DynReadStore<const N: usize = SHA1_LEN>: Send + Sync {
+    fn exists(&self, val: [u8; N]) -> BoxFuture<'_, Result<bool, DynError>>;
+
+    fn iter_all(&self) -> BoxStream<'_, Result<PwnedPwd<N>, DynError>>;
+
+    fn exists_many<'a>(&'a self, vals: &'a [[u8; N]])
+        -> BoxFuture<'a, Result<Vec<bool>, DynError>>;
+
+    fn exists_count(&self, val: [u8; N]) -> BoxFuture<'_, Result<Option<u32>, DynError>>;
+
+    fn exists_count_many<'a>(
+        &'a self,
+        vals: &'a [[u8; N]],
+    ) -> BoxFuture<'a, Result<Vec<Option<u32>>, DynError>>;
+
+    fn exists_with_min_count(
+        &self,
+        val: [u8; N],
+        min_count: u32,
+    ) -> BoxFuture<'_, Result<Option<u32>, DynError>>;
+
+    fn range(&self, prefix: Prefix) -> BoxFuture<'_, Result<Chunk<N>, DynError>>;
+
+    fn metadata(&self) -> BoxFuture<'_, Result<StoreMetadata, DynError>>;
+
+    fn max_prefix(&self) -> BoxFuture<'_, Result<Option<Prefix>, DynError>>;
+
+    fn healthy(&self) -> BoxFuture<'_, Result<bool, DynError>>;
+}
+
+/// Object-safe counterpart of [Store]
+pub trait DynStore<const N: usize = SHA1_LEN>: DynReadStore<N> {
+    fn order_requirement(&self) -> OrderRequirement;
+
+    fn save<'a>(&'a self, s: BoxStream<'a, Chunk<N>>) -> BoxFuture<'a, Result<(), DynError>>;
+
+    fn merge<'a>(&'a self, s: BoxStream<'a, Chunk<N>>) -> BoxFuture<'a, Result<(), DynError>>;
+
+    fn remove(&self, val: [u8; N]) -> BoxFuture<'_, Result<bool, DynError>>;
+
+    fn clear(&self) -> BoxFuture<'_, Result<(), DynError>>;
+}
+
+impl<T, const N: usize> DynReadStore<N> for T
+where
+    T: ReadStore<N> + Send + Sync,
+    T::Error: Into<DynError>,
+{
+    fn exists(&self, val: [u8; N]) -> BoxFuture<'_, Result<bool, DynError>> {
+        ReadStore::exists(self, val)
+            .map(|r| r.map_err(Into::into))
+            .boxed()
+    }
+
+    fn iter_all(&self) -> BoxStream<'_, Result<PwnedPwd<N>, DynError>> {
+        ReadStore::iter_all(self)
+            .map(|r| r.map_err(Into::into))
+            .boxed()
+    }
+
+    fn exists_many<'a>(
+        &'a self,
+        vals: &'a [[u8; N]],
+    ) -> BoxFuture<'a, Result<Vec<bool>, DynError>> {
+        ReadStore::exists_many(self, vals)
+            .map(|r| r.map_err(Into::into))
+            .boxed()
+    }
+
+    fn exists_count(&self, val: [u8; N]) -> BoxFuture<'_, Result<Option<u32>, DynError>> {
+        ReadStore::exists_count(self, val)
+            .map(|r| r.map_err(Into::into))
+            .boxed()
+    }
+
+    fn exists_count_many<'a>(
+        &'a self,
+        vals: &'a [[u8; N]],
+    ) -> BoxFuture<'a, Result<Vec<Option<u32>>, DynError>> {
+        ReadStore::exists_count_many(self, vals)
+            .map(|r| r.map_err(Into::into))
+            .boxed()
+    }
+
+    fn exists_with_min_count(
+        &self,
+        val: [u8; N],
+        min_count: u32,
+    ) -> BoxFuture<'_, Result<Option<u32>, DynError>> {
+        ReadStore::exists_with_min_count(self, val, min_count)
+            .map(|r| r.map_err(Into::into))
+            .boxed()
+    }
+
+    fn range(&self, prefix: Prefix) -> BoxFuture<'_, Result<Chunk<N>, DynError>> {
+        ReadStore::range(self, prefix)
+            .map(|r| r.map_err(Into::into))
+            .boxed()
+    }
+
+    fn metadata(&self) -> BoxFuture<'_, Result<StoreMetadata, DynError>> {
+        ReadStore::metadata(self)
+            .map(|r| r.map_err(Into::into))
+            .boxed()
+    }
+
+    fn max_prefix(&self) -> BoxFuture<'_, Result<Option<Prefix>, DynError>> {
+        ReadStore::max_prefix(self)
+            .map(|r| r.map_err(Into::into))
+            .boxed()
+    }
+
+    fn healthy(&self) -> BoxFuture<'_, Result<bool, DynError>> {
+        ReadStore::healthy(self)
+            .map(|r| r.map_err(Into::into))
+            .boxed()
+    }
+}
+
+impl<T, const N: usize> DynStore<N> for T
+where
+    T: Store<N> + Send + Sync,
+    T::Error: Into<DynError>,
+{
+    fn order_requirement(&self) -> OrderRequirement {
+        T::order_requirement()
+    }
+
+    fn save<'a>(&'a self, s: BoxStream<'a, Chunk<N>>) -> BoxFuture<'a, Result<(), DynError>> {
+        WriteStore::save(self, s)
+            .map(|r| r.map_err(Into::into))
+            .boxed()
+    }
+
+    fn merge<'a>(&'a self, s: BoxStream<'a, Chunk<N>>) -> BoxFuture<'a, Result<(), DynError>> {
+        WriteStore::merge(self, s)
+            .map(|r| r.map_err(Into::into))
+            .boxed()
+    }
+
+    fn remove(&self, val: [u8; N]) -> BoxFuture<'_, Result<bool, DynError>> {
+        WriteStore::remove(self, val)
+            .map(|r| r.map_err(Into::into))
+            .boxed()
+    }
+
+    fn clear(&self) -> BoxFuture<'_, Result<(), DynError>> {
+        WriteStore::clear(self)
+            .map(|r| r.map_err(Into::into))
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+    use crate::test_store::TestStore;
+
+    const HASH: [u8; 20] = hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087");
+
+    #[tokio::test]
+    async fn dyn_store() {
+        let stores: Vec<Box<dyn DynStore>> = vec![Box::new(TestStore::Found), Box::new(TestStore::Fails)];
+
+        assert!(stores[0].exists(HASH).await.unwrap());
+        assert_eq!(vec![true, true], stores[0].exists_many(&[HASH, HASH]).await.unwrap());
+        assert!(matches!(stores[0].order_requirement(), OrderRequirement::Unordered));
+        stores[0].save(futures::stream::empty().boxed()).await.unwrap();
+
+        assert_eq!("unavailable", stores[1].exists(HASH).await.unwrap_err().to_string());
+        assert!(stores[1].iter_all().next().await.is_none());
+        assert!(stores[1].healthy().await.unwrap());
+    }
+}
@@ -1,19 +1,227 @@
-use futures::{future::BoxFuture, Stream};
-use pwned_pwd_core::Chunk;
+use std::{future::Future, time::SystemTime};
 
-pub trait Store {
+use futures::{future, Stream, TryStreamExt};
+use progress::SaveObserver;
+use pwned_pwd_core::{Chunk, ChunkError, HashKind, Prefix, PwnedPwd, SHA1_LEN};
+
+pub mod cached;
+pub mod cancel;
+pub mod degraded;
+pub mod dyn_store;
+#[cfg(feature = "metrics")]
+pub mod metered;
+pub mod min_count;
+pub mod progress;
+pub mod replicated;
+#[cfg(test)]
+mod test_store;
+pub mod tiered;
+pub mod traced;
+
+/// Lookups in a store of `N`-byte hashes: [SHA1_LEN] (default) or [pwned_pwd_core::NTLM_LEN].
+/// A backend may implement both to host both data sets.
+/// Read-only backends (e.g. a client of an online API) implement only this trait
+pub trait ReadStore<const N: usize = SHA1_LEN> {
     type Error;
 
+    fn exists(&self, val: [u8; N]) -> impl Future<Output = Result<bool, Self::Error>> + Send;
+
+    /// Streams everything in the store, e.g. to migrate data into another backend.
+    /// Ordered stores stream passwords in order
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd<N>, Self::Error>> + Send;
+
+    /// Checks many hashes at once, the result is in the order of `vals`.
+    /// The default implementation checks them one by one, a backend may do it in a batch
+    fn exists_many(
+        &self,
+        vals: &[[u8; N]],
+    ) -> impl Future<Output = Result<Vec<bool>, Self::Error>> + Send {
+        let lookups = vals.iter().map(|val| self.exists(*val)).collect::<Vec<_>>();
+
+        async move {
+            let mut res = Vec::with_capacity(lookups.len());
+            for lookup in lookups {
+                res.push(lookup.await?);
+            }
+            Ok(res)
+        }
+    }
+
+    /// Returns how many times the hash appears in the data set or None, if it doesn't exist in the store
+    fn exists_count(
+        &self,
+        val: [u8; N],
+    ) -> impl Future<Output = Result<Option<u32>, Self::Error>> + Send;
+
+    /// [ReadStore::exists_count] of many hashes at once, the result is in the order of `vals`.
+    /// The default implementation looks them up one by one, a backend may do it in a batch
+    fn exists_count_many(
+        &self,
+        vals: &[[u8; N]],
+    ) -> impl Future<Output = Result<Vec<Option<u32>>, Self::Error>> + Send {
+        let lookups = vals
+            .iter()
+            .map(|val| self.exists_count(*val))
+            .collect::<Vec<_>>();
+
+        async move {
+            let mut res = Vec::with_capacity(lookups.len());
+            for lookup in lookups {
+                res.push(lookup.await?);
+            }
+            Ok(res)
+        }
+    }
+
+    /// Returns the count of the hash, if it exists in the store and appears
+    /// at least `min_count` times in the data set, otherwise None
+    /// A backend may filter by the count without reading it (e.g. `WHERE count >= ?`)
+    fn exists_with_min_count(
+        &self,
+        val: [u8; N],
+        min_count: u32,
+    ) -> impl Future<Output = Result<Option<u32>, Self::Error>> + Send {
+        let count = self.exists_count(val);
+        async move { Ok(count.await?.filter(|count| *count >= min_count)) }
+    }
+
+    /// Records of the prefix ordered by hash, like a range of the online API.
+    /// The default implementation filters [ReadStore::iter_all], so it reads the whole store,
+    /// a backend which can find the records of a prefix overrides it
+    fn range(&self, prefix: Prefix) -> impl Future<Output = Result<Chunk<N>, Self::Error>> + Send {
+        let records = self
+            .iter_all()
+            .try_filter(move |pwd| future::ready(Prefix::from_hash(&pwd.hash) == prefix));
+
+        async move {
+            let mut passwords = records.try_collect::<Vec<_>>().await?;
+            passwords.sort_unstable_by_key(|pwd| pwd.hash);
+            Ok(Chunk { prefix, passwords })
+        }
+    }
+
+    /// What data the store contains. By default nothing is known except the hash kind
+    fn metadata(&self) -> impl Future<Output = Result<StoreMetadata, Self::Error>> + Send {
+        async {
+            Ok(StoreMetadata {
+                kind: HashKind::of_len(N).unwrap_or_default(),
+                ..Default::default()
+            })
+        }
+    }
+
+    /// The highest prefix which is fully persisted in the store: all the following
+    /// prefixes are either missing or may be partial. A sync may be resumed after it,
+    /// and a value below [Prefix::max] after a completed save means the data set is truncated.
+    /// None, if the store is empty or doesn't track it (the default)
+    fn max_prefix(&self) -> impl Future<Output = Result<Option<Prefix>, Self::Error>> + Send {
+        async { Ok(None) }
+    }
+
+    /// Readiness check: Err, if the backend is unreachable, false, if the data set
+    /// is missing, empty or damaged. By default the store is healthy
+    /// when its metadata can be read and doesn't report zero records
+    fn healthy(&self) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        let metadata = self.metadata();
+        async move { Ok(metadata.await?.records != Some(0)) }
+    }
+}
+
+/// Ingestion of data into a store
+pub trait WriteStore<const N: usize = SHA1_LEN>: ReadStore<N> {
     fn order_requirement() -> OrderRequirement;
 
-    fn save<'a, S: 'a + Stream<Item = Chunk> + std::marker::Unpin + std::marker::Send>(
-        &'a self,
+    /// Replaces the data with the stream.
+    /// Dropping the future must leave the store in a state documented by the backend,
+    /// see [cancel::CancelToken]
+    fn save<S: Stream<Item = Chunk<N>> + Unpin + Send>(
+        &self,
         s: S,
-    ) -> BoxFuture<'a, Result<(), Self::Error>>;
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// [WriteStore::save] which reports the progress to the observer after every chunk.
+    /// By default chunks are reported when they are taken from the stream
+    fn save_observed<S: Stream<Item = Chunk<N>> + Unpin + Send, O: SaveObserver>(
+        &self,
+        s: S,
+        observer: &O,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        self.save(progress::observe(s, |progress| {
+            observer.on_progress(progress)
+        }))
+    }
+
+    /// Upserts the stream into the existing data instead of replacing it like [WriteStore::save]:
+    /// new hashes are added, counts of existing ones are updated
+    fn merge<S: Stream<Item = Chunk<N>> + Unpin + Send>(
+        &self,
+        s: S,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Removes the hash. Returns false, if there was no such hash
+    fn remove(&self, val: [u8; N]) -> impl Future<Output = Result<bool, Self::Error>> + Send;
 
-    fn exists<'a>(&'a self, val: [u8; 20]) -> BoxFuture<'a, Result<bool, Self::Error>>;
+    /// Removes all the data
+    fn clear(&self) -> impl Future<Output = Result<(), Self::Error>> + Send;
 }
 
+/// A store which can be both read and written
+pub trait Store<const N: usize = SHA1_LEN>: ReadStore<N> + WriteStore<N> {}
+
+impl<T: ReadStore<N> + WriteStore<N>, const N: usize> Store<N> for T {}
+
+/// Description of the data in a store
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct StoreMetadata {
+    /// Count of hashes or None, if the store can't count them cheaply
+    pub records: Option<u64>,
+
+    /// When the data was saved last time
+    pub updated_at: Option<SystemTime>,
+
+    /// Version of the data, increased by every save
+    pub generation: Option<u64>,
+
+    pub kind: HashKind,
+}
+
+impl StoreMetadata {
+    /// Time since the last save or None, if it is unknown
+    pub fn age(&self) -> Option<std::time::Duration> {
+        self.updated_at?.elapsed().ok()
+    }
+}
+
+/// A saved or merged chunk isn't ordered or has hashes of another prefix.
+/// Backends validate chunks with [InvalidChunk::check] before they write them
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidChunk {
+    pub prefix: Prefix,
+    pub error: ChunkError,
+}
+
+impl InvalidChunk {
+    /// See [Chunk::validate]
+    pub fn check<const N: usize>(chunk: &Chunk<N>) -> Result<(), Self> {
+        chunk.validate().map_err(|error| Self {
+            prefix: chunk.prefix,
+            error,
+        })
+    }
+}
+
+impl std::fmt::Display for InvalidChunk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid chunk of the prefix '{}': {}",
+            self.prefix, self.error
+        )
+    }
+}
+
+impl std::error::Error for InvalidChunk {}
+
 /// Store may or may not be order-agnostic to saving data
 /// If it is, a Stream argument must be ordered (for example for local store)
 /// If it's not, a Stream argument can be unordered
@@ -24,3 +232,31 @@ pub enum OrderRequirement {
     /// Stream can be unordered
     Unordered,
 }
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+    use crate::test_store::TestStore;
+
+    #[tokio::test]
+    async fn default_range() {
+        let store = TestStore::records();
+        let pwd = |hash| PwnedPwd { hash, count: 1 };
+        store.save(futures::stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![pwd(hex!("21BD4FFF08998514E6E8F28DBB4CA9F74EA5CAFA"))] },
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![pwd(hex!("21BD5000F2D6B0E3CE3E9D1A0E9C3EB1E2A2A4AC"))] },
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![pwd(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"))] },
+        ])).await.unwrap();
+
+        let chunk = store.range(Prefix::create(0x21BD4).unwrap()).await.unwrap();
+        assert_eq!(Ok(()), chunk.validate());
+        assert_eq!(vec![
+            pwd(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")),
+            pwd(hex!("21BD4FFF08998514E6E8F28DBB4CA9F74EA5CAFA")),
+        ], chunk.passwords);
+        assert!(store.range(Prefix::create(0x21BD6).unwrap()).await.unwrap().passwords.is_empty());
+    }
+}
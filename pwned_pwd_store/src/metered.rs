@@ -0,0 +1,306 @@
+//! Metrics of any store
+//!
+//! [MeteredStore] records lookup latencies, pwned and not pwned hashes, errors and
+//! saved records through the [metrics] facade, so an app exports them with the recorder
+//! it already installed (e.g. Prometheus) and backends don't instrument themselves
+
+use std::{future::Future, time::Instant};
+
+use futures::{Stream, StreamExt};
+use metrics::{counter, describe_counter, describe_histogram, histogram, Unit};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+
+use crate::{progress::SaveObserver, OrderRequirement, ReadStore, StoreMetadata, WriteStore};
+
+/// Latency of a lookup, labeled by `store` and `op`
+pub const LOOKUP_DURATION: &str = "pwned_pwd_lookup_duration_seconds";
+
+/// Checked hashes, labeled by `store` and `result`: `pwned` or `not_pwned`
+pub const LOOKUPS: &str = "pwned_pwd_lookups_total";
+
+/// Failed operations, labeled by `store` and `op`
+pub const ERRORS: &str = "pwned_pwd_errors_total";
+
+/// Duration of a write, labeled by `store` and `op`
+pub const WRITE_DURATION: &str = "pwned_pwd_write_duration_seconds";
+
+/// Records taken from the streams of saves and merges, labeled by `store` and `op`
+pub const WRITTEN_RECORDS: &str = "pwned_pwd_written_records_total";
+
+/// Describes the metrics to the installed recorder, e.g. for `# HELP` lines of Prometheus
+pub fn describe() {
+    describe_histogram!(LOOKUP_DURATION, Unit::Seconds, "Latency of a lookup");
+    describe_counter!(LOOKUPS, Unit::Count, "Checked hashes by result");
+    describe_counter!(ERRORS, Unit::Count, "Failed store operations");
+    describe_histogram!(WRITE_DURATION, Unit::Seconds, "Duration of a write");
+    describe_counter!(
+        WRITTEN_RECORDS,
+        Unit::Count,
+        "Records written by saves and merges"
+    );
+}
+
+/// A store which records metrics of the inner store, labeled with its `name`.
+/// Streaming (`iter_all`), ranges, metadata and health checks aren't metered
+#[derive(Debug, Clone)]
+pub struct MeteredStore<S> {
+    store: S,
+    name: String,
+}
+
+impl<S> MeteredStore<S> {
+    /// The name is the `store` label, e.g. the backend of the store
+    pub fn new(store: S, name: impl Into<String>) -> Self {
+        Self {
+            store,
+            name: name.into(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    /// Records the latency and the result of a lookup, `pwned` counts pwned hashes of the result
+    async fn lookup<T, E>(
+        &self,
+        op: &'static str,
+        hashes: usize,
+        lookup: impl Future<Output = Result<T, E>>,
+        pwned: impl FnOnce(&T) -> usize,
+    ) -> Result<T, E> {
+        let started = Instant::now();
+        let res = lookup.await;
+        histogram!(LOOKUP_DURATION, "store" => self.name.clone(), "op" => op)
+            .record(started.elapsed().as_secs_f64());
+
+        match &res {
+            Ok(found) => {
+                let pwned = pwned(found);
+                counter!(LOOKUPS, "store" => self.name.clone(), "result" => "pwned")
+                    .increment(pwned as u64);
+                counter!(LOOKUPS, "store" => self.name.clone(), "result" => "not_pwned")
+                    .increment((hashes - pwned) as u64);
+            }
+            Err(_) => self.error(op),
+        }
+        res
+    }
+
+    /// Records the duration of a write and its failure
+    async fn write<T, E>(
+        &self,
+        op: &'static str,
+        write: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let started = Instant::now();
+        let res = write.await;
+        histogram!(WRITE_DURATION, "store" => self.name.clone(), "op" => op)
+            .record(started.elapsed().as_secs_f64());
+
+        if res.is_err() {
+            self.error(op);
+        }
+        res
+    }
+
+    fn error(&self, op: &'static str) {
+        counter!(ERRORS, "store" => self.name.clone(), "op" => op).increment(1);
+    }
+
+    /// Counts the records of the stream as they are taken
+    fn count<const N: usize, St: Stream<Item = Chunk<N>> + Unpin + Send>(
+        &self,
+        op: &'static str,
+        s: St,
+    ) -> impl Stream<Item = Chunk<N>> + Unpin + Send {
+        let records = counter!(WRITTEN_RECORDS, "store" => self.name.clone(), "op" => op);
+        s.inspect(move |chunk| records.increment(chunk.passwords.len() as u64))
+    }
+}
+
+impl<S, const N: usize> ReadStore<N> for MeteredStore<S>
+where
+    S: ReadStore<N> + Sync,
+    S::Error: Send,
+{
+    type Error = S::Error;
+
+    async fn exists(&self, val: [u8; N]) -> Result<bool, Self::Error> {
+        self.lookup("exists", 1, self.store.exists(val), |found| {
+            usize::from(*found)
+        })
+        .await
+    }
+
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd<N>, Self::Error>> + Send {
+        self.store.iter_all()
+    }
+
+    async fn exists_many(&self, vals: &[[u8; N]]) -> Result<Vec<bool>, Self::Error> {
+        self.lookup(
+            "exists_many",
+            vals.len(),
+            self.store.exists_many(vals),
+            |found| found.iter().filter(|found| **found).count(),
+        )
+        .await
+    }
+
+    async fn exists_count(&self, val: [u8; N]) -> Result<Option<u32>, Self::Error> {
+        self.lookup("exists_count", 1, self.store.exists_count(val), |count| {
+            usize::from(count.is_some())
+        })
+        .await
+    }
+
+    async fn exists_count_many(&self, vals: &[[u8; N]]) -> Result<Vec<Option<u32>>, Self::Error> {
+        self.lookup(
+            "exists_count_many",
+            vals.len(),
+            self.store.exists_count_many(vals),
+            |counts| counts.iter().filter(|count| count.is_some()).count(),
+        )
+        .await
+    }
+
+    async fn exists_with_min_count(
+        &self,
+        val: [u8; N],
+        min_count: u32,
+    ) -> Result<Option<u32>, Self::Error> {
+        self.lookup(
+            "exists_with_min_count",
+            1,
+            self.store.exists_with_min_count(val, min_count),
+            |count| usize::from(count.is_some()),
+        )
+        .await
+    }
+
+    async fn range(&self, prefix: Prefix) -> Result<Chunk<N>, Self::Error> {
+        self.store.range(prefix).await
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        self.store.metadata().await
+    }
+
+    async fn max_prefix(&self) -> Result<Option<Prefix>, Self::Error> {
+        self.store.max_prefix().await
+    }
+
+    async fn healthy(&self) -> Result<bool, Self::Error> {
+        self.store.healthy().await
+    }
+}
+
+impl<S, const N: usize> WriteStore<N> for MeteredStore<S>
+where
+    S: WriteStore<N> + Sync,
+    S::Error: Send,
+{
+    fn order_requirement() -> OrderRequirement {
+        S::order_requirement()
+    }
+
+    async fn save<St: Stream<Item = Chunk<N>> + Unpin + Send>(
+        &self,
+        s: St,
+    ) -> Result<(), Self::Error> {
+        self.write("save", self.store.save(self.count("save", s)))
+            .await
+    }
+
+    async fn save_observed<St: Stream<Item = Chunk<N>> + Unpin + Send, O: SaveObserver>(
+        &self,
+        s: St,
+        observer: &O,
+    ) -> Result<(), Self::Error> {
+        self.write(
+            "save",
+            self.store.save_observed(self.count("save", s), observer),
+        )
+        .await
+    }
+
+    async fn merge<St: Stream<Item = Chunk<N>> + Unpin + Send>(
+        &self,
+        s: St,
+    ) -> Result<(), Self::Error> {
+        self.write("merge", self.store.merge(self.count("merge", s)))
+            .await
+    }
+
+    async fn remove(&self, val: [u8; N]) -> Result<bool, Self::Error> {
+        self.write("remove", self.store.remove(val)).await
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        self.write("clear", self.store.clear()).await
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use futures::executor::block_on;
+    use hex_literal::hex;
+    use metrics_util::{debugging::{DebugValue, DebuggingRecorder}, CompositeKey};
+
+    use super::*;
+    use crate::test_store::TestStore;
+
+    const PWNED: [u8; 20] = hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087");
+    const NOT_PWNED: [u8; 20] = hex!("21BD5000F2D6B0E3CE3E9D1A0E9C3EB1E2A2A4AC");
+
+    type Snapshot = Vec<(CompositeKey, Option<Unit>, Option<metrics::SharedString>, DebugValue)>;
+
+    /// Values of the metric by the label values, e.g. `["local", "pwned"]`
+    fn values<'a>(snapshot: &'a Snapshot, name: &str) -> Vec<(Vec<String>, &'a DebugValue)> {
+        let mut values = snapshot.iter()
+            .filter(|(key, ..)| key.key().name() == name)
+            .map(|(key, _, _, value)| (key.key().labels().map(|label| label.value().to_string()).collect::<Vec<_>>(), value))
+            .collect::<Vec<_>>();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        values
+    }
+
+    #[test]
+    fn metrics() {
+        let recorder = DebuggingRecorder::new();
+        metrics::with_local_recorder(&recorder, || block_on(async {
+            let store = MeteredStore::new(TestStore::records(), "local");
+            let passwords = vec![PwnedPwd { hash: PWNED, count: 3 }];
+            store.save(futures::stream::iter(vec![Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords }])).await.unwrap();
+
+            assert!(store.exists(PWNED).await.unwrap());
+            assert_eq!(vec![true, false], store.exists_many(&[PWNED, NOT_PWNED]).await.unwrap());
+
+            let failing = MeteredStore::new(TestStore::Fails, "remote");
+            assert!(failing.exists(PWNED).await.is_err());
+        }));
+
+        // Counters are reset by every snapshot
+        let snapshot = recorder.snapshotter().snapshot().into_vec();
+        let label = |labels: &[&str]| labels.iter().map(|label| label.to_string()).collect::<Vec<_>>();
+        assert_eq!(vec![
+            (label(&["local", "not_pwned"]), &DebugValue::Counter(1)),
+            (label(&["local", "pwned"]), &DebugValue::Counter(2)),
+        ], values(&snapshot, LOOKUPS));
+        assert_eq!(vec![(label(&["local", "save"]), &DebugValue::Counter(1))], values(&snapshot, WRITTEN_RECORDS));
+        assert_eq!(vec![(label(&["remote", "exists"]), &DebugValue::Counter(1))], values(&snapshot, ERRORS));
+
+        let lookups = values(&snapshot, LOOKUP_DURATION);
+        assert_eq!(3, lookups.len());
+        assert!(matches!(lookups[0].1, DebugValue::Histogram(latencies) if latencies.len() == 1));
+    }
+}
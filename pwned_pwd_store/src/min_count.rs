@@ -0,0 +1,167 @@
+//! Persisting only commonly breached passwords
+//!
+//! Most hashes in the data set were seen only a few times. A deployment which
+//! rejects only common passwords can wrap its store into [MinCountStore]
+//! to save just the hashes seen at least `min_count` times, which shrinks the data a lot
+
+use std::future::Future;
+
+use futures::{Stream, StreamExt};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+
+use crate::{progress::SaveObserver, OrderRequirement, ReadStore, StoreMetadata, WriteStore};
+
+/// A store which drops hashes with a count below `min_count` on save and merge.
+/// Lookups are passed to the inner store as is
+#[derive(Debug, Clone)]
+pub struct MinCountStore<S> {
+    store: S,
+    min_count: u32,
+}
+
+impl<S> MinCountStore<S> {
+    pub fn new(store: S, min_count: u32) -> Self {
+        Self { store, min_count }
+    }
+
+    pub fn min_count(&self) -> u32 {
+        self.min_count
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    fn filter<const N: usize, St: Stream<Item = Chunk<N>> + Unpin + Send>(
+        &self,
+        s: St,
+    ) -> impl Stream<Item = Chunk<N>> + Unpin + Send {
+        let min_count = self.min_count;
+        s.map(move |mut chunk| {
+            chunk.passwords.retain(|pwd| pwd.count >= min_count);
+            chunk
+        })
+    }
+}
+
+impl<S: ReadStore<N>, const N: usize> ReadStore<N> for MinCountStore<S> {
+    type Error = S::Error;
+
+    fn exists(&self, val: [u8; N]) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        self.store.exists(val)
+    }
+
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd<N>, Self::Error>> + Send {
+        self.store.iter_all()
+    }
+
+    fn exists_many(
+        &self,
+        vals: &[[u8; N]],
+    ) -> impl Future<Output = Result<Vec<bool>, Self::Error>> + Send {
+        self.store.exists_many(vals)
+    }
+
+    fn exists_count(
+        &self,
+        val: [u8; N],
+    ) -> impl Future<Output = Result<Option<u32>, Self::Error>> + Send {
+        self.store.exists_count(val)
+    }
+
+    fn exists_count_many(
+        &self,
+        vals: &[[u8; N]],
+    ) -> impl Future<Output = Result<Vec<Option<u32>>, Self::Error>> + Send {
+        self.store.exists_count_many(vals)
+    }
+
+    fn exists_with_min_count(
+        &self,
+        val: [u8; N],
+        min_count: u32,
+    ) -> impl Future<Output = Result<Option<u32>, Self::Error>> + Send {
+        self.store.exists_with_min_count(val, min_count)
+    }
+
+    fn range(&self, prefix: Prefix) -> impl Future<Output = Result<Chunk<N>, Self::Error>> + Send {
+        self.store.range(prefix)
+    }
+
+    fn metadata(&self) -> impl Future<Output = Result<StoreMetadata, Self::Error>> + Send {
+        self.store.metadata()
+    }
+
+    fn max_prefix(&self) -> impl Future<Output = Result<Option<Prefix>, Self::Error>> + Send {
+        self.store.max_prefix()
+    }
+
+    fn healthy(&self) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        self.store.healthy()
+    }
+}
+
+impl<S: WriteStore<N>, const N: usize> WriteStore<N> for MinCountStore<S> {
+    fn order_requirement() -> OrderRequirement {
+        S::order_requirement()
+    }
+
+    fn save<St: Stream<Item = Chunk<N>> + Unpin + Send>(
+        &self,
+        s: St,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        self.store.save(self.filter(s))
+    }
+
+    fn save_observed<St: Stream<Item = Chunk<N>> + Unpin + Send, O: SaveObserver>(
+        &self,
+        s: St,
+        observer: &O,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        self.store.save_observed(self.filter(s), observer)
+    }
+
+    fn merge<St: Stream<Item = Chunk<N>> + Unpin + Send>(
+        &self,
+        s: St,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        self.store.merge(self.filter(s))
+    }
+
+    fn remove(&self, val: [u8; N]) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        self.store.remove(val)
+    }
+
+    fn clear(&self) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        self.store.clear()
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+    use crate::test_store::TestStore;
+
+    #[tokio::test]
+    async fn save() {
+        let store = MinCountStore::new(TestStore::records(), 5);
+        let pwd = |hash, count| PwnedPwd { hash, count };
+
+        store.save(futures::stream::iter(vec![Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![
+            pwd(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), 4),
+            pwd(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"), 5),
+            pwd(hex!("21BD40110328459B74EC3CC4ADCE47093DA97FD0"), 100),
+        ]}])).await.unwrap();
+
+        assert!(!store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert!(store.exists(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED")).await.unwrap());
+        assert!(store.exists(hex!("21BD40110328459B74EC3CC4ADCE47093DA97FD0")).await.unwrap());
+    }
+}
@@ -0,0 +1,98 @@
+//! Progress of long saves
+//!
+//! A save of the whole data set is a multi-hour run. [crate::WriteStore::save_observed]
+//! reports a [SaveProgress] to a [SaveObserver] after every chunk,
+//! so it can be shown in a UI or logged
+
+use futures::{Stream, StreamExt};
+use pwned_pwd_core::{Chunk, Prefix};
+
+/// What has been saved so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SaveProgress {
+    pub chunks: u64,
+    pub records: u64,
+
+    /// Bytes flushed into the backend or None, if the backend doesn't report them
+    pub bytes: Option<u64>,
+
+    /// The last prefix which was handed over to the backend
+    pub last_prefix: Option<Prefix>,
+}
+
+impl SaveProgress {
+    /// Accounts a chunk of `records` hashes
+    pub fn chunk(&mut self, prefix: Prefix, records: usize) {
+        self.chunks += 1;
+        self.records += records as u64;
+        self.last_prefix = Some(prefix);
+    }
+}
+
+/// Receives the progress of a save after every chunk.
+/// It is called on the saving task, so it must be cheap
+pub trait SaveObserver: Send + Sync {
+    fn on_progress(&self, progress: &SaveProgress);
+}
+
+/// No observation
+impl SaveObserver for () {
+    fn on_progress(&self, _: &SaveProgress) {}
+}
+
+impl<F: Fn(&SaveProgress) + Send + Sync> SaveObserver for F {
+    fn on_progress(&self, progress: &SaveProgress) {
+        self(progress)
+    }
+}
+
+/// Calls `on_progress` for every chunk taken by a backend from the stream.
+/// Bytes are not reported, a backend which knows them reports the progress itself
+pub fn observe<const N: usize, S, F>(
+    s: S,
+    mut on_progress: F,
+) -> impl Stream<Item = Chunk<N>> + Unpin + Send
+where
+    S: Stream<Item = Chunk<N>> + Unpin + Send,
+    F: FnMut(&SaveProgress) + Send,
+{
+    let mut progress = SaveProgress::default();
+    s.inspect(move |chunk| {
+        progress.chunk(chunk.prefix, chunk.passwords.len());
+        on_progress(&progress);
+    })
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::sync::Mutex;
+
+    use hex_literal::hex;
+    use pwned_pwd_core::PwnedPwd;
+
+    use super::*;
+    use crate::{test_store::TestStore, WriteStore};
+
+    #[tokio::test]
+    async fn save_observed() {
+        let store = TestStore::records();
+        let pwd = |hash| PwnedPwd { hash, count: 1 };
+        let reports = Mutex::new(Vec::new());
+
+        store.save_observed(futures::stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![
+                pwd(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")),
+                pwd(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED")),
+            ]},
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![
+                pwd(hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")),
+            ]},
+        ]), &|progress: &SaveProgress| reports.lock().unwrap().push(*progress)).await.unwrap();
+
+        assert_eq!(vec![
+            SaveProgress { chunks: 1, records: 2, bytes: None, last_prefix: Prefix::create(0x21BD4) },
+            SaveProgress { chunks: 2, records: 3, bytes: None, last_prefix: Prefix::create(0x21BD5) },
+        ], reports.into_inner().unwrap());
+    }
+}
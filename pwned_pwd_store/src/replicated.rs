@@ -0,0 +1,333 @@
+//! Redundancy across several stores
+//!
+//! A deployment may keep the data set in stores of different kinds, e.g. a local file
+//! and a Redis cluster, so lookups survive an outage of either. [ReplicatedStore] writes
+//! every save to all of them and reads from the first replica which answers
+
+use std::sync::atomic::{
+    AtomicBool, AtomicUsize,
+    Ordering::{Relaxed, SeqCst},
+};
+
+use futures::{
+    channel::mpsc,
+    future::{self, BoxFuture},
+    stream::{BoxStream, StreamExt},
+    SinkExt, Stream,
+};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd, SHA1_LEN};
+
+use crate::{
+    dyn_store::{self, DynError},
+    OrderRequirement, ReadStore, StoreMetadata, WriteStore,
+};
+
+/// Which replica is asked first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadPreference {
+    /// Replicas are asked in the order they were given, the first one is the primary
+    #[default]
+    InOrder,
+
+    /// Every lookup starts from the next replica, so the load is spread over all of them
+    RoundRobin,
+}
+
+struct Replica<const N: usize> {
+    store: Box<dyn dyn_store::DynStore<N>>,
+
+    /// Cleared by a failed read and set by a successful one or a health check
+    healthy: AtomicBool,
+}
+
+/// A store which writes to all the replicas and reads from the first healthy one.
+///
+/// A read which fails on a replica is retried on the next one, and the failed replica
+/// is asked last until it answers again or passes [ReadStore::healthy]
+pub struct ReplicatedStore<const N: usize = SHA1_LEN> {
+    replicas: Vec<Replica<N>>,
+    preference: ReadPreference,
+    next: AtomicUsize,
+    buffer: usize,
+}
+
+impl<const N: usize> ReplicatedStore<N> {
+    /// # Panics
+    ///
+    /// If there are no stores
+    pub fn new(stores: Vec<Box<dyn dyn_store::DynStore<N>>>) -> Self {
+        assert!(!stores.is_empty(), "No replicas");
+        Self {
+            replicas: stores
+                .into_iter()
+                .map(|store| Replica {
+                    store,
+                    healthy: AtomicBool::new(true),
+                })
+                .collect(),
+            preference: ReadPreference::default(),
+            next: AtomicUsize::new(0),
+            buffer: 16,
+        }
+    }
+
+    pub fn with_read_preference(mut self, preference: ReadPreference) -> Self {
+        self.preference = preference;
+        self
+    }
+
+    /// How many chunks a replica may lag behind the fastest one during a save
+    pub fn with_buffer(mut self, buffer: usize) -> Self {
+        self.buffer = buffer;
+        self
+    }
+
+    pub fn replicas(&self) -> impl Iterator<Item = &dyn dyn_store::DynStore<N>> {
+        self.replicas.iter().map(|replica| replica.store.as_ref())
+    }
+
+    /// Indices of the replicas to ask: healthy ones by the preference, then the others
+    fn read_order(&self) -> Vec<usize> {
+        let len = self.replicas.len();
+        let start = match self.preference {
+            ReadPreference::InOrder => 0,
+            ReadPreference::RoundRobin => self.next.fetch_add(1, Relaxed) % len,
+        };
+
+        let (mut order, unhealthy): (Vec<_>, Vec<_>) = (0..len)
+            .map(|i| (start + i) % len)
+            .partition(|i| self.replicas[*i].healthy.load(SeqCst));
+        order.extend(unhealthy);
+        order
+    }
+
+    /// Reads from the replicas in the [ReplicatedStore::read_order] until one answers
+    async fn read<'a, T>(
+        &'a self,
+        read: impl Fn(&'a dyn dyn_store::DynStore<N>) -> BoxFuture<'a, Result<T, DynError>>,
+    ) -> Result<T, DynError> {
+        let mut error = None;
+        for i in self.read_order() {
+            let replica = &self.replicas[i];
+            match read(replica.store.as_ref()).await {
+                Ok(res) => {
+                    replica.healthy.store(true, SeqCst);
+                    return Ok(res);
+                }
+                Err(e) => {
+                    tracing::warn!("Replica {} failed to read: {}", i, e);
+                    replica.healthy.store(false, SeqCst);
+                    error = Some(e);
+                }
+            }
+        }
+        Err(error.expect("No replicas"))
+    }
+
+    /// Feeds the stream to the write of every replica. A failed replica stops receiving
+    /// chunks, the others complete, and the error of the first failed one is returned
+    async fn replicate<'a>(
+        &'a self,
+        mut s: impl Stream<Item = Chunk<N>> + Unpin + Send,
+        write: impl Fn(
+            &'a dyn dyn_store::DynStore<N>,
+            BoxStream<'a, Chunk<N>>,
+        ) -> BoxFuture<'a, Result<(), DynError>>,
+    ) -> Result<(), DynError> {
+        let (mut senders, writes): (Vec<_>, Vec<_>) = self
+            .replicas
+            .iter()
+            .map(|replica| {
+                let (tx, rx) = mpsc::channel(self.buffer);
+                (tx, write(replica.store.as_ref(), rx.boxed()))
+            })
+            .unzip();
+
+        let feed = async move {
+            while let Some(chunk) = s.next().await {
+                let mut open = Vec::with_capacity(senders.len());
+                for mut tx in senders {
+                    if tx.send(chunk.clone()).await.is_ok() {
+                        open.push(tx);
+                    }
+                }
+                if open.is_empty() {
+                    break;
+                }
+                senders = open;
+            }
+        };
+
+        let (_, results) = future::join(feed, future::join_all(writes)).await;
+        first_error("write", results)
+    }
+
+    /// Runs the operation on every replica concurrently
+    async fn on_all<'a, T>(
+        &'a self,
+        op: impl Fn(&'a dyn dyn_store::DynStore<N>) -> BoxFuture<'a, Result<T, DynError>>,
+    ) -> Vec<Result<T, DynError>> {
+        future::join_all(self.replicas().map(op)).await
+    }
+}
+
+/// Logs every error and returns the first one
+fn first_error<T>(op: &str, results: Vec<Result<T, DynError>>) -> Result<(), DynError> {
+    let mut error = None;
+    for (i, res) in results.into_iter().enumerate() {
+        if let Err(e) = res {
+            tracing::warn!("Replica {} failed to {}: {}", i, op, e);
+            error.get_or_insert(e);
+        }
+    }
+    error.map_or(Ok(()), Err)
+}
+
+impl<const N: usize> ReadStore<N> for ReplicatedStore<N> {
+    type Error = DynError;
+
+    async fn exists(&self, val: [u8; N]) -> Result<bool, Self::Error> {
+        self.read(|store| store.exists(val)).await
+    }
+
+    /// Streams the first healthy replica, a failure isn't retried on another one
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd<N>, Self::Error>> + Send {
+        self.replicas[self.read_order()[0]].store.iter_all()
+    }
+
+    async fn exists_many(&self, vals: &[[u8; N]]) -> Result<Vec<bool>, Self::Error> {
+        self.read(|store| store.exists_many(vals)).await
+    }
+
+    async fn exists_count(&self, val: [u8; N]) -> Result<Option<u32>, Self::Error> {
+        self.read(|store| store.exists_count(val)).await
+    }
+
+    async fn exists_count_many(&self, vals: &[[u8; N]]) -> Result<Vec<Option<u32>>, Self::Error> {
+        self.read(|store| store.exists_count_many(vals)).await
+    }
+
+    async fn exists_with_min_count(
+        &self,
+        val: [u8; N],
+        min_count: u32,
+    ) -> Result<Option<u32>, Self::Error> {
+        self.read(|store| store.exists_with_min_count(val, min_count))
+            .await
+    }
+
+    async fn range(&self, prefix: Prefix) -> Result<Chunk<N>, Self::Error> {
+        self.read(|store| store.range(prefix)).await
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        self.read(|store| store.metadata()).await
+    }
+
+    async fn max_prefix(&self) -> Result<Option<Prefix>, Self::Error> {
+        self.read(|store| store.max_prefix()).await
+    }
+
+    /// Checks every replica and refreshes their health. The store is healthy
+    /// if any replica is, an error is returned only if all of them failed
+    async fn healthy(&self) -> Result<bool, Self::Error> {
+        let results = self.on_all(|store| store.healthy()).await;
+
+        let mut errors = Vec::new();
+        let mut healthy = false;
+        for (replica, res) in self.replicas.iter().zip(results) {
+            let ok = res.unwrap_or_else(|e| {
+                errors.push(e);
+                false
+            });
+            replica.healthy.store(ok, SeqCst);
+            healthy |= ok;
+        }
+
+        match errors.len() == self.replicas.len() {
+            true => Err(errors.swap_remove(0)),
+            false => Ok(healthy),
+        }
+    }
+}
+
+impl<const N: usize> WriteStore<N> for ReplicatedStore<N> {
+    /// Replicas are known only at runtime, and an ordered stream suits all of them
+    fn order_requirement() -> OrderRequirement {
+        OrderRequirement::Ordered
+    }
+
+    /// Saves the stream to all the replicas at once, see [ReplicatedStore::with_buffer]
+    async fn save<S: Stream<Item = Chunk<N>> + Unpin + Send>(
+        &self,
+        s: S,
+    ) -> Result<(), Self::Error> {
+        self.replicate(s, |store, s| store.save(s)).await
+    }
+
+    async fn merge<S: Stream<Item = Chunk<N>> + Unpin + Send>(
+        &self,
+        s: S,
+    ) -> Result<(), Self::Error> {
+        self.replicate(s, |store, s| store.merge(s)).await
+    }
+
+    /// Removes the hash from all the replicas, true if any of them had it
+    async fn remove(&self, val: [u8; N]) -> Result<bool, Self::Error> {
+        let results = self.on_all(|store| store.remove(val)).await;
+        let removed = results.iter().any(|res| matches!(res, Ok(true)));
+        first_error("remove", results)?;
+        Ok(removed)
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        first_error("clear", self.on_all(|store| store.clear()).await)
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+    use crate::test_store::TestStore;
+
+    const PWNED: [u8; 20] = hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087");
+
+    fn chunks() -> futures::stream::Iter<std::vec::IntoIter<Chunk>> {
+        let passwords = vec![PwnedPwd { hash: PWNED, count: 3 }];
+        futures::stream::iter(vec![Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords }])
+    }
+
+    #[tokio::test]
+    async fn failover() {
+        let store = ReplicatedStore::new(vec![Box::new(TestStore::Fails), Box::new(TestStore::records()), Box::new(TestStore::records())]);
+        store.save(chunks()).await.unwrap();
+
+        for replica in store.replicas().skip(1) {
+            assert_eq!(Some(3), replica.exists_count(PWNED).await.unwrap());
+        }
+        assert!(store.exists(PWNED).await.unwrap());
+        assert_eq!(Some(3), store.exists_count(PWNED).await.unwrap());
+        assert_eq!(vec![1, 2, 0], store.read_order());
+
+        assert!(store.healthy().await.unwrap());
+        assert_eq!(vec![0, 1, 2], store.read_order());
+    }
+
+    #[test]
+    fn round_robin() {
+        let store = ReplicatedStore::new(vec![Box::new(TestStore::Found), Box::new(TestStore::Found)])
+            .with_read_preference(ReadPreference::RoundRobin);
+
+        assert_eq!(vec![0, 1], store.read_order());
+        assert_eq!(vec![1, 0], store.read_order());
+    }
+
+    #[tokio::test]
+    async fn all_fail() {
+        let store = ReplicatedStore::new(vec![Box::new(TestStore::Fails), Box::new(TestStore::Fails)]);
+        assert_eq!("unavailable", store.exists(PWNED).await.unwrap_err().to_string());
+    }
+}
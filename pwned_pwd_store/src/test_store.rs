@@ -0,0 +1,84 @@
+//! A store for unit tests of wrappers
+
+use std::sync::Mutex;
+
+use futures::{Stream, StreamExt};
+use pwned_pwd_core::{Chunk, PwnedPwd};
+
+use crate::{OrderRequirement, ReadStore, WriteStore};
+
+/// Every lookup of the store finds the hash, fails or never completes.
+/// `Records` keeps saved hashes and finds only them
+pub(crate) enum TestStore {
+    Found,
+    Fails,
+    Hangs,
+    Records(Mutex<Vec<PwnedPwd>>),
+}
+
+impl TestStore {
+    pub(crate) fn records() -> Self {
+        TestStore::Records(Mutex::new(Vec::new()))
+    }
+}
+
+impl ReadStore for TestStore {
+    type Error = &'static str;
+
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        match self {
+            TestStore::Found => Ok(true),
+            TestStore::Fails => Err("unavailable"),
+            TestStore::Hangs => futures::future::pending().await,
+            TestStore::Records(records) => {
+                Ok(records.lock().unwrap().iter().any(|pwd| pwd.hash == val))
+            }
+        }
+    }
+
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        let records = match self {
+            TestStore::Records(records) => records.lock().unwrap().clone(),
+            _ => Vec::new(),
+        };
+        futures::stream::iter(records.into_iter().map(Ok))
+    }
+
+    async fn exists_count(&self, val: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        match self {
+            TestStore::Records(records) => Ok(records
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|pwd| pwd.hash == val)
+                .map(|pwd| pwd.count)),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl WriteStore for TestStore {
+    fn order_requirement() -> OrderRequirement {
+        OrderRequirement::Unordered
+    }
+
+    async fn save<S: Stream<Item = Chunk> + Unpin + Send>(&self, s: S) -> Result<(), Self::Error> {
+        let chunks = s.collect::<Vec<_>>().await;
+        if let TestStore::Records(records) = self {
+            *records.lock().unwrap() = chunks.into_iter().flatten().collect();
+        }
+        Ok(())
+    }
+
+    async fn merge<S: Stream<Item = Chunk> + Unpin + Send>(&self, _: S) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn remove(&self, _: [u8; 20]) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+//! A probabilistic filter in front of an authoritative store
+//!
+//! Most checked passwords aren't pwned, and a filter (e.g. a Golomb-coded set or a binary
+//! fuse filter of the local store) rejects them from memory. [TieredStore] asks the
+//! authoritative store only about the hashes the filter may contain, so the answers
+//! stay exact while most lookups never reach the backend
+
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+};
+
+use futures::Stream;
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+
+use crate::{ReadStore, StoreMetadata};
+
+/// Counters of a [TieredStore]
+#[derive(Debug, Default)]
+pub struct TieredMetrics {
+    lookups: AtomicU64,
+    filtered: AtomicU64,
+    false_positives: AtomicU64,
+    filter_errors: AtomicU64,
+}
+
+impl TieredMetrics {
+    /// Total checked hashes
+    pub fn lookups(&self) -> u64 {
+        self.lookups.load(Relaxed)
+    }
+
+    /// Hashes rejected by the filter without a lookup in the authoritative store
+    pub fn filtered(&self) -> u64 {
+        self.filtered.load(Relaxed)
+    }
+
+    /// Hashes the filter may contain, but the authoritative store doesn't
+    pub fn false_positives(&self) -> u64 {
+        self.false_positives.load(Relaxed)
+    }
+
+    /// Hashes checked in the authoritative store because the filter failed
+    pub fn filter_errors(&self) -> u64 {
+        self.filter_errors.load(Relaxed)
+    }
+}
+
+/// A store which checks hashes in the filter `F` and then, if the filter may contain them,
+/// in the authoritative store `A`. Everything except lookups is served by `A`.
+///
+/// The filter must have no false negatives: it must be built from the data set of `A`
+/// (or a superset), so it is rebuilt after every save of `A`. A failed filter is skipped
+pub struct TieredStore<F, A> {
+    filter: F,
+    authoritative: A,
+    metrics: TieredMetrics,
+}
+
+impl<F, A> TieredStore<F, A> {
+    pub fn new(filter: F, authoritative: A) -> Self {
+        Self {
+            filter,
+            authoritative,
+            metrics: Default::default(),
+        }
+    }
+
+    pub fn filter(&self) -> &F {
+        &self.filter
+    }
+
+    pub fn authoritative(&self) -> &A {
+        &self.authoritative
+    }
+
+    pub fn metrics(&self) -> &TieredMetrics {
+        &self.metrics
+    }
+
+    pub fn into_inner(self) -> (F, A) {
+        (self.filter, self.authoritative)
+    }
+
+    /// May the filter contain the hash, None if the filter failed
+    async fn maybe<const N: usize>(&self, val: [u8; N]) -> Option<bool>
+    where
+        F: ReadStore<N>,
+        F::Error: Display,
+    {
+        self.metrics.lookups.fetch_add(1, Relaxed);
+        match self.filter.exists(val).await {
+            Ok(true) => Some(true),
+            Ok(false) => {
+                self.metrics.filtered.fetch_add(1, Relaxed);
+                Some(false)
+            }
+            Err(e) => {
+                self.metrics.filter_errors.fetch_add(1, Relaxed);
+                tracing::debug!("Filter error: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Counts a hash the filter may contain, if the authoritative store hasn't found it
+    fn checked(&self, found: bool) {
+        if !found {
+            self.metrics.false_positives.fetch_add(1, Relaxed);
+        }
+    }
+}
+
+impl<F, A, const N: usize> ReadStore<N> for TieredStore<F, A>
+where
+    F: ReadStore<N> + Sync,
+    F::Error: Display + Send,
+    A: ReadStore<N> + Sync,
+    A::Error: Send,
+{
+    type Error = A::Error;
+
+    async fn exists(&self, val: [u8; N]) -> Result<bool, Self::Error> {
+        if self.maybe(val).await == Some(false) {
+            return Ok(false);
+        }
+
+        let found = self.authoritative.exists(val).await?;
+        self.checked(found);
+        Ok(found)
+    }
+
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd<N>, Self::Error>> + Send {
+        self.authoritative.iter_all()
+    }
+
+    /// The filter checks all the hashes in a batch, then the authoritative store
+    /// checks the ones the filter may contain in another batch
+    async fn exists_many(&self, vals: &[[u8; N]]) -> Result<Vec<bool>, Self::Error> {
+        self.metrics.lookups.fetch_add(vals.len() as u64, Relaxed);
+
+        let maybe = match self.filter.exists_many(vals).await {
+            Ok(maybe) if maybe.len() == vals.len() => maybe,
+            res => {
+                if let Err(e) = res {
+                    tracing::debug!("Filter error: {}", e);
+                }
+                self.metrics
+                    .filter_errors
+                    .fetch_add(vals.len() as u64, Relaxed);
+                vec![true; vals.len()]
+            }
+        };
+
+        let passed = vals
+            .iter()
+            .zip(&maybe)
+            .filter(|(_, maybe)| **maybe)
+            .map(|(val, _)| *val)
+            .collect::<Vec<_>>();
+        self.metrics
+            .filtered
+            .fetch_add((vals.len() - passed.len()) as u64, Relaxed);
+
+        let mut found = self.authoritative.exists_many(&passed).await?.into_iter();
+        let res = maybe
+            .into_iter()
+            .map(|maybe| maybe && found.next().unwrap_or(false))
+            .collect::<Vec<_>>();
+
+        let false_positives = passed.len() - res.iter().filter(|found| **found).count();
+        self.metrics
+            .false_positives
+            .fetch_add(false_positives as u64, Relaxed);
+        Ok(res)
+    }
+
+    async fn exists_count(&self, val: [u8; N]) -> Result<Option<u32>, Self::Error> {
+        if self.maybe(val).await == Some(false) {
+            return Ok(None);
+        }
+
+        let count = self.authoritative.exists_count(val).await?;
+        self.checked(count.is_some());
+        Ok(count)
+    }
+
+    async fn exists_with_min_count(
+        &self,
+        val: [u8; N],
+        min_count: u32,
+    ) -> Result<Option<u32>, Self::Error> {
+        if self.maybe(val).await == Some(false) {
+            return Ok(None);
+        }
+
+        self.authoritative
+            .exists_with_min_count(val, min_count)
+            .await
+    }
+
+    /// The filter can't reject a range, it is read from the authoritative store
+    async fn range(&self, prefix: Prefix) -> Result<Chunk<N>, Self::Error> {
+        self.authoritative.range(prefix).await
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        self.authoritative.metadata().await
+    }
+
+    async fn max_prefix(&self) -> Result<Option<Prefix>, Self::Error> {
+        self.authoritative.max_prefix().await
+    }
+
+    /// The authoritative store is healthy, an unhealthy filter only slows lookups down
+    async fn healthy(&self) -> Result<bool, Self::Error> {
+        if !matches!(self.filter.healthy().await, Ok(true)) {
+            tracing::warn!("The filter of the tiered store isn't healthy");
+        }
+        self.authoritative.healthy().await
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+    use crate::{test_store::TestStore, WriteStore};
+
+    const PWNED: [u8; 20] = hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087");
+    const FALSE_POSITIVE: [u8; 20] = hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6");
+    const NOT_PWNED: [u8; 20] = hex!("21BD5000F2D6B0E3CE3E9D1A0E9C3EB1E2A2A4AC");
+
+    async fn records(hashes: &[[u8; 20]]) -> TestStore {
+        let store = TestStore::records();
+        let passwords = hashes.iter().map(|hash| PwnedPwd { hash: *hash, count: 1 }).collect();
+        store.save(futures::stream::iter(vec![Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords }])).await.unwrap();
+        store
+    }
+
+    #[tokio::test]
+    async fn exists() {
+        let store = TieredStore::new(records(&[PWNED, FALSE_POSITIVE]).await, records(&[PWNED]).await);
+
+        assert!(store.exists(PWNED).await.unwrap());
+        assert!(!store.exists(FALSE_POSITIVE).await.unwrap());
+        assert!(!store.exists(NOT_PWNED).await.unwrap());
+        assert_eq!(vec![false, true, false], store.exists_many(&[NOT_PWNED, PWNED, FALSE_POSITIVE]).await.unwrap());
+
+        assert_eq!(6, store.metrics().lookups());
+        assert_eq!(2, store.metrics().filtered());
+        assert_eq!(2, store.metrics().false_positives());
+    }
+
+    #[tokio::test]
+    async fn filter_fails() {
+        let store = TieredStore::new(TestStore::Fails, records(&[PWNED]).await);
+
+        assert!(store.exists(PWNED).await.unwrap());
+        assert_eq!(vec![true, false], store.exists_many(&[PWNED, NOT_PWNED]).await.unwrap());
+        assert_eq!(3, store.metrics().filter_errors());
+        assert_eq!(0, store.metrics().filtered());
+    }
+}
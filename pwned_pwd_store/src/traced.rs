@@ -0,0 +1,200 @@
+//! Spans of any store
+//!
+//! [TracedStore] runs every operation of the inner store in a `store{store, op}` span
+//! and logs failures as `warn` events, so lookups and writes of any backend show up
+//! in the traces of an app next to the spans of the downloader
+
+use std::{fmt::Display, future::Future};
+
+use futures::Stream;
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+use tracing::Instrument;
+
+use crate::{progress::SaveObserver, OrderRequirement, ReadStore, StoreMetadata, WriteStore};
+
+/// A store which traces the operations of the inner store, labeled with its `name`.
+/// Streaming (`iter_all`) isn't traced
+#[derive(Debug, Clone)]
+pub struct TracedStore<S> {
+    store: S,
+    name: String,
+}
+
+impl<S> TracedStore<S> {
+    /// The name is the `store` field of spans, e.g. the backend of the store
+    pub fn new(store: S, name: impl Into<String>) -> Self {
+        Self {
+            store,
+            name: name.into(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    async fn trace<T, E: Display>(
+        &self,
+        op: &'static str,
+        call: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let span = tracing::debug_span!("store", store = %self.name, op);
+        let res = call.instrument(span.clone()).await;
+        if let Err(e) = &res {
+            span.in_scope(|| tracing::warn!(error = %e, "Store operation failed"));
+        }
+        res
+    }
+}
+
+impl<S, const N: usize> ReadStore<N> for TracedStore<S>
+where
+    S: ReadStore<N> + Sync,
+    S::Error: Display + Send,
+{
+    type Error = S::Error;
+
+    async fn exists(&self, val: [u8; N]) -> Result<bool, Self::Error> {
+        self.trace("exists", self.store.exists(val)).await
+    }
+
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd<N>, Self::Error>> + Send {
+        self.store.iter_all()
+    }
+
+    async fn exists_many(&self, vals: &[[u8; N]]) -> Result<Vec<bool>, Self::Error> {
+        self.trace("exists_many", self.store.exists_many(vals))
+            .await
+    }
+
+    async fn exists_count(&self, val: [u8; N]) -> Result<Option<u32>, Self::Error> {
+        self.trace("exists_count", self.store.exists_count(val))
+            .await
+    }
+
+    async fn exists_count_many(&self, vals: &[[u8; N]]) -> Result<Vec<Option<u32>>, Self::Error> {
+        self.trace("exists_count_many", self.store.exists_count_many(vals))
+            .await
+    }
+
+    async fn exists_with_min_count(
+        &self,
+        val: [u8; N],
+        min_count: u32,
+    ) -> Result<Option<u32>, Self::Error> {
+        self.trace(
+            "exists_with_min_count",
+            self.store.exists_with_min_count(val, min_count),
+        )
+        .await
+    }
+
+    async fn range(&self, prefix: Prefix) -> Result<Chunk<N>, Self::Error> {
+        self.trace("range", self.store.range(prefix)).await
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        self.trace("metadata", self.store.metadata()).await
+    }
+
+    async fn max_prefix(&self) -> Result<Option<Prefix>, Self::Error> {
+        self.trace("max_prefix", self.store.max_prefix()).await
+    }
+
+    async fn healthy(&self) -> Result<bool, Self::Error> {
+        self.trace("healthy", self.store.healthy()).await
+    }
+}
+
+impl<S, const N: usize> WriteStore<N> for TracedStore<S>
+where
+    S: WriteStore<N> + Sync,
+    S::Error: Display + Send,
+{
+    fn order_requirement() -> OrderRequirement {
+        S::order_requirement()
+    }
+
+    async fn save<St: Stream<Item = Chunk<N>> + Unpin + Send>(
+        &self,
+        s: St,
+    ) -> Result<(), Self::Error> {
+        self.trace("save", self.store.save(s)).await
+    }
+
+    async fn save_observed<St: Stream<Item = Chunk<N>> + Unpin + Send, O: SaveObserver>(
+        &self,
+        s: St,
+        observer: &O,
+    ) -> Result<(), Self::Error> {
+        self.trace("save", self.store.save_observed(s, observer))
+            .await
+    }
+
+    async fn merge<St: Stream<Item = Chunk<N>> + Unpin + Send>(
+        &self,
+        s: St,
+    ) -> Result<(), Self::Error> {
+        self.trace("merge", self.store.merge(s)).await
+    }
+
+    async fn remove(&self, val: [u8; N]) -> Result<bool, Self::Error> {
+        self.trace("remove", self.store.remove(val)).await
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        self.trace("clear", self.store.clear()).await
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::{io::Write, sync::{Arc, Mutex}};
+
+    use futures::executor::block_on;
+    use hex_literal::hex;
+
+    use super::*;
+    use crate::test_store::TestStore;
+
+    const PWNED: [u8; 20] = hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087");
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn spans() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt().with_writer(move || writer.clone()).with_ansi(false).with_max_level(tracing::Level::DEBUG).finish();
+
+        tracing::subscriber::with_default(subscriber, || block_on(async {
+            assert!(TracedStore::new(TestStore::Found, "local").exists(PWNED).await.unwrap());
+            assert!(TracedStore::new(TestStore::Fails, "remote").exists(PWNED).await.is_err());
+        }));
+
+        let logs = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(1, logs.lines().count());
+        assert!(logs.contains("store{store=remote op=\"exists\"}"), "{}", logs);
+        assert!(logs.contains("error=unavailable"), "{}", logs);
+    }
+}
@@ -0,0 +1,577 @@
+//! ClickHouse backend
+//!
+//! Built for audits rather than login checks: a save bulk inserts the whole data set with
+//! a single streamed `INSERT`, and [ClickHouseStore::audit] joins it with a table of customer
+//! credential hashes inside ClickHouse, so the hashes never leave the cluster.
+//! [ReadStore::exists_many] sends the hashes in batched `IN` queries.
+//!
+//! The store talks to the HTTP interface in the `RowBinary` format. Records are kept in
+//! a `ReplacingMergeTree` versioned by the generation of the write, so a merge replaces
+//! the counts of existing hashes, and lookups read the table with `FINAL`.
+//! ClickHouse has no transactions, so there must be a single writer at a time
+
+use std::{
+    collections::HashSet,
+    io,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use futures::{channel::mpsc, future, stream, SinkExt, Stream, StreamExt, TryStreamExt};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+use pwned_pwd_store::{InvalidChunk, OrderRequirement, ReadStore, StoreMetadata, WriteStore};
+use reqwest::{Body, Client, Response};
+use url::Url;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClickHouseStoreError {
+    #[error("Http error: {0}")]
+    Http(#[from] reqwest::Error),
+
+    /// ClickHouse rejected the query
+    #[error("ClickHouse error ({status}): {message}")]
+    ClickHouse {
+        status: reqwest::StatusCode,
+        message: String,
+    },
+
+    /// A save stops at an invalid chunk and keeps the data, a merge keeps the batches inserted before
+    #[error(transparent)]
+    InvalidChunk(#[from] InvalidChunk),
+
+    /// A reply ends in the middle of a row
+    #[error("Invalid record in the reply")]
+    InvalidRecord,
+}
+
+const SCHEMA: [&str; 2] = [
+    "CREATE TABLE IF NOT EXISTS pwned_pwd (hash FixedString(20), count UInt32, version UInt64)
+    ENGINE = ReplacingMergeTree(version) ORDER BY hash",
+    "CREATE TABLE IF NOT EXISTS pwned_pwd_meta (
+        id UInt8, records UInt64, updated_at UInt64, generation UInt64, version UInt64
+    ) ENGINE = ReplacingMergeTree(version) ORDER BY id",
+];
+
+/// The row of the meta table, an empty table is the zero row
+const META: &str = "SELECT max(records) AS records, max(updated_at) AS updated_at,
+    max(generation) AS generation FROM pwned_pwd_meta FINAL";
+
+/// `hash`, `count` and `version`
+const ROW_LEN: usize = 32;
+
+/// A store of SHA-1 hashes in the `pwned_pwd` table of a ClickHouse database
+pub struct ClickHouseStore {
+    client: Client,
+    url: Url,
+    database: Option<String>,
+    credentials: Option<(String, String)>,
+    batch_size: usize,
+    concurrency: usize,
+}
+
+impl ClickHouseStore {
+    /// A store of the HTTP interface at the url, e.g. `http://localhost:8123`
+    pub fn new(url: Url) -> Self {
+        Self {
+            client: Client::new(),
+            url,
+            database: None,
+            credentials: None,
+            batch_size: 1000,
+            concurrency: 4,
+        }
+    }
+
+    /// Send the requests with the client, e.g. with timeouts or TLS settings
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// The database of the tables, the default database of the user otherwise
+    pub fn with_database(mut self, database: impl Into<String>) -> Self {
+        self.database = Some(database.into());
+        self
+    }
+
+    pub fn with_credentials(
+        mut self,
+        user: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.credentials = Some((user.into(), password.into()));
+        self
+    }
+
+    /// How many hashes are sent in a query by [ReadStore::exists_many],
+    /// and how many records are inserted at once by a merge
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// How many queries of [ReadStore::exists_many] are in flight
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Creates the tables, if they don't exist
+    pub async fn migrate(&self) -> Result<(), ClickHouseStoreError> {
+        for query in SCHEMA {
+            self.execute(query).await?;
+        }
+        Ok(())
+    }
+
+    /// The records of the hashes in the `column` of the customer `table`, e.g. a `FixedString(20)`
+    /// column of SHA-1 hashes of passwords. The tables are joined by ClickHouse with an `IN`
+    /// subquery, found records are streamed in no particular order
+    pub fn audit(
+        &self,
+        table: &str,
+        column: &str,
+    ) -> impl Stream<Item = Result<PwnedPwd, ClickHouseStoreError>> + Send + '_ {
+        let query = format!(
+            "SELECT hash, count FROM pwned_pwd FINAL WHERE hash IN (SELECT {} FROM {}) FORMAT RowBinary",
+            identifier(column),
+            identifier(table)
+        );
+        self.records(query)
+    }
+
+    /// Sends the query, or the data of the `INSERT` query, in the body
+    async fn request(
+        &self,
+        query: &str,
+        data: Option<Body>,
+    ) -> Result<Response, ClickHouseStoreError> {
+        let mut url = self.url.clone();
+        if let Some(database) = &self.database {
+            url.query_pairs_mut().append_pair("database", database);
+        }
+
+        let mut request = match data {
+            Some(data) => {
+                url.query_pairs_mut().append_pair("query", query);
+                self.client.post(url).body(data)
+            }
+            None => self.client.post(url).body(query.to_string()),
+        };
+        if let Some((user, password)) = &self.credentials {
+            request = request
+                .header("X-ClickHouse-User", user)
+                .header("X-ClickHouse-Key", password);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(ClickHouseStoreError::ClickHouse {
+                status,
+                message: response.text().await?.trim_end().to_string(),
+            });
+        }
+        Ok(response)
+    }
+
+    async fn execute(&self, query: &str) -> Result<(), ClickHouseStoreError> {
+        self.request(query, None).await?.bytes().await?;
+        Ok(())
+    }
+
+    /// Inserts the rows into the table, a stream error aborts the insert
+    async fn insert(
+        &self,
+        table: &str,
+        rows: impl Stream<Item = io::Result<Vec<u8>>> + Send + 'static,
+    ) -> Result<(), ClickHouseStoreError> {
+        let query = format!("INSERT INTO {table} (hash, count, version) FORMAT RowBinary");
+        self.request(&query, Some(Body::wrap_stream(rows)))
+            .await?
+            .bytes()
+            .await?;
+        Ok(())
+    }
+
+    /// Streams the `hash, count` rows of the query
+    fn records(
+        &self,
+        query: String,
+    ) -> impl Stream<Item = Result<PwnedPwd, ClickHouseStoreError>> + Send + '_ {
+        stream::once(async move { self.request(&query, None).await })
+            .map_ok(rows::<24>)
+            .try_flatten()
+            .map_ok(|row| PwnedPwd {
+                hash: row[..20].try_into().unwrap(),
+                count: u32::from_le_bytes(row[20..].try_into().unwrap()),
+            })
+    }
+
+    /// The hashes of the batch in the table
+    async fn found(&self, vals: Vec<[u8; 20]>) -> Result<Vec<[u8; 20]>, ClickHouseStoreError> {
+        let query = format!(
+            "SELECT hash FROM pwned_pwd FINAL WHERE hash IN ({}) FORMAT RowBinary",
+            hashes(&vals)
+        );
+        rows(self.request(&query, None).await?).try_collect().await
+    }
+
+    /// The count of the hash, if it is at least `min_count`
+    async fn count(
+        &self,
+        val: [u8; 20],
+        min_count: u32,
+    ) -> Result<Option<u32>, ClickHouseStoreError> {
+        let query = format!(
+            "SELECT count FROM pwned_pwd FINAL WHERE hash = {} AND count >= {min_count} FORMAT RowBinary",
+            hash(&val)
+        );
+        let counts = rows::<4>(self.request(&query, None).await?)
+            .try_collect::<Vec<_>>()
+            .await?;
+        Ok(counts.first().map(|count| u32::from_le_bytes(*count)))
+    }
+
+    /// Inserts a new row of the meta table, the arguments are expressions of its current columns
+    async fn update_meta(
+        &self,
+        records: &str,
+        updated_at: &str,
+        generation: &str,
+    ) -> Result<(), ClickHouseStoreError> {
+        self.execute(&format!(
+            "INSERT INTO pwned_pwd_meta (id, records, updated_at, generation, version)
+            SELECT 0, {records}, {updated_at}, {generation}, toUnixTimestamp64Nano(now64(9)) FROM ({META})"
+        ))
+        .await
+    }
+}
+
+/// Quotes a table or a column name
+fn identifier(name: &str) -> String {
+    format!("`{}`", name.replace('\\', "\\\\").replace('`', "\\`"))
+}
+
+fn hash(val: &[u8]) -> String {
+    format!("unhex('{}')", hex::encode_upper(val))
+}
+
+fn hashes(vals: &[[u8; 20]]) -> String {
+    vals.iter()
+        .map(|val| hash(val))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Encodes the passwords as rows of the `pwned_pwd` table
+fn encode(passwords: &[PwnedPwd], version: u64) -> Vec<u8> {
+    let mut rows = Vec::with_capacity(passwords.len() * ROW_LEN);
+    for pwd in passwords {
+        rows.extend_from_slice(&pwd.hash);
+        rows.extend_from_slice(&pwd.count.to_le_bytes());
+        rows.extend_from_slice(&version.to_le_bytes());
+    }
+    rows
+}
+
+/// Cuts the `RowBinary` body into rows of fixed length
+fn rows<const LEN: usize>(
+    response: Response,
+) -> impl Stream<Item = Result<[u8; LEN], ClickHouseStoreError>> + Send {
+    stream::try_unfold(
+        (response.bytes_stream(), Vec::new()),
+        |(mut body, mut buf)| async move {
+            loop {
+                match body.next().await.transpose()? {
+                    Some(bytes) => buf.extend_from_slice(&bytes),
+                    None if buf.is_empty() => return Ok(None),
+                    None => return Err(ClickHouseStoreError::InvalidRecord),
+                }
+
+                let (rows, _) = buf.as_chunks::<LEN>();
+                if !rows.is_empty() {
+                    let rows = rows.to_vec();
+                    buf.drain(..rows.len() * LEN);
+                    return Ok(Some((stream::iter(rows.into_iter().map(Ok)), (body, buf))));
+                }
+            }
+        },
+    )
+    .try_flatten()
+}
+
+impl ReadStore for ClickHouseStore {
+    type Error = ClickHouseStoreError;
+
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        Ok(self.exists_count(val).await?.is_some())
+    }
+
+    /// Streams the table in order with a single query
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        self.records(
+            "SELECT hash, count FROM pwned_pwd FINAL ORDER BY hash FORMAT RowBinary".to_string(),
+        )
+    }
+
+    /// Sends the hashes in `IN` queries of the batch size, up to the concurrency of them at once
+    async fn exists_many(&self, vals: &[[u8; 20]]) -> Result<Vec<bool>, Self::Error> {
+        let batches = vals.chunks(self.batch_size).map(<[_]>::to_vec);
+        let found = stream::iter(batches.collect::<Vec<_>>())
+            .map(|batch| self.found(batch))
+            .buffer_unordered(self.concurrency)
+            .try_fold(HashSet::new(), |mut found, batch| {
+                found.extend(batch);
+                future::ok(found)
+            })
+            .await?;
+
+        Ok(vals.iter().map(|val| found.contains(val)).collect())
+    }
+
+    async fn exists_count(&self, val: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        self.count(val, 0).await
+    }
+
+    /// Filters by the count in the query
+    async fn exists_with_min_count(
+        &self,
+        val: [u8; 20],
+        min_count: u32,
+    ) -> Result<Option<u32>, Self::Error> {
+        self.count(val, min_count).await
+    }
+
+    /// Selects the hashes from the first bytes of the prefix up to the ones of the next prefix,
+    /// the primary key of the table
+    async fn range(&self, prefix: Prefix) -> Result<Chunk, Self::Error> {
+        let end = prefix
+            .next()
+            .map(|next| format!(" AND hash < {}", hash(&next.to_bytes())))
+            .unwrap_or_default();
+        let query = format!(
+            "SELECT hash, count FROM pwned_pwd FINAL WHERE hash >= {}{end} ORDER BY hash FORMAT RowBinary",
+            hash(&prefix.to_bytes())
+        );
+
+        let passwords = self.records(query).try_collect().await?;
+        Ok(Chunk { prefix, passwords })
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        let query =
+            format!("SELECT records, updated_at, generation FROM ({META}) FORMAT RowBinary");
+        let meta = rows::<24>(self.request(&query, None).await?)
+            .try_collect::<Vec<_>>()
+            .await?;
+        let meta = meta.first().ok_or(ClickHouseStoreError::InvalidRecord)?;
+        let u64_at = |i: usize| u64::from_le_bytes(meta[i..i + 8].try_into().unwrap());
+
+        Ok(StoreMetadata {
+            records: Some(u64_at(0)),
+            updated_at: Some(u64_at(8))
+                .filter(|updated_at| *updated_at > 0)
+                .map(|updated_at| UNIX_EPOCH + Duration::from_secs(updated_at)),
+            generation: Some(u64_at(16)),
+            ..Default::default()
+        })
+    }
+}
+
+impl WriteStore for ClickHouseStore {
+    fn order_requirement() -> OrderRequirement {
+        OrderRequirement::Unordered
+    }
+
+    /// Streams the records into a new table with a single `INSERT` and exchanges it with the
+    /// current one. A hash must not be repeated in the stream.
+    /// If the future is dropped, the data isn't changed and the next save drops the new table
+    async fn save<S: Stream<Item = Chunk> + Unpin + Send>(
+        &self,
+        mut s: S,
+    ) -> Result<(), Self::Error> {
+        self.execute("DROP TABLE IF EXISTS pwned_pwd_saved").await?;
+        self.execute("CREATE TABLE pwned_pwd_saved AS pwned_pwd")
+            .await?;
+
+        let version = self.metadata().await?.generation.unwrap_or(0) + 1;
+        let (mut tx, rx) = mpsc::channel(16);
+        let produce = async move {
+            let mut records = 0;
+            while let Some(chunk) = s.next().await {
+                if let Err(e) = InvalidChunk::check(&chunk) {
+                    // Fails the body, so ClickHouse aborts the insert
+                    let _ = tx.send(Err(io::Error::other(e.to_string()))).await;
+                    return Err(ClickHouseStoreError::from(e));
+                }
+
+                records += chunk.passwords.len() as u64;
+                if tx
+                    .send(Ok(encode(&chunk.passwords, version)))
+                    .await
+                    .is_err()
+                {
+                    // The insert failed, its error is returned
+                    break;
+                }
+            }
+            Ok(records)
+        };
+
+        let (records, inserted) = future::join(produce, self.insert("pwned_pwd_saved", rx)).await;
+        let records = records?;
+        inserted?;
+
+        self.execute("EXCHANGE TABLES pwned_pwd AND pwned_pwd_saved")
+            .await?;
+        self.execute("DROP TABLE pwned_pwd_saved").await?;
+        self.update_meta(
+            &records.to_string(),
+            "toUnixTimestamp(now())",
+            "generation + 1",
+        )
+        .await
+    }
+
+    /// Inserts the stream in batches, the record count is updated after all of them.
+    /// A hash must not be repeated in a batch
+    async fn merge<S: Stream<Item = Chunk> + Unpin + Send>(
+        &self,
+        mut s: S,
+    ) -> Result<(), Self::Error> {
+        let version = self.metadata().await?.generation.unwrap_or(0) + 1;
+        let mut batch = Vec::with_capacity(self.batch_size);
+        let mut added = 0;
+
+        loop {
+            let chunk = s.next().await;
+            if let Some(chunk) = &chunk {
+                InvalidChunk::check(chunk)?;
+            }
+
+            let last = chunk.is_none();
+            batch.extend(chunk.into_iter().flat_map(|chunk| chunk.passwords));
+
+            if batch.len() >= self.batch_size || (last && !batch.is_empty()) {
+                let hashes = batch.iter().map(|pwd| pwd.hash).collect();
+                added += (batch.len() - self.found(hashes).await?.len()) as u64;
+
+                let rows = encode(&batch, version);
+                self.insert("pwned_pwd", stream::once(future::ok(rows)))
+                    .await?;
+                batch.clear();
+            }
+
+            if last {
+                break;
+            }
+        }
+
+        self.update_meta(
+            &format!("records + {added}"),
+            "toUnixTimestamp(now())",
+            "generation + 1",
+        )
+        .await
+    }
+
+    /// Deletes the record with a lightweight `DELETE` of ClickHouse 23.3 or later
+    async fn remove(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        if self.exists_count(val).await?.is_none() {
+            return Ok(false);
+        }
+
+        self.execute(&format!(
+            "DELETE FROM pwned_pwd WHERE hash = {}",
+            hash(&val)
+        ))
+        .await?;
+        self.update_meta("records - 1", "updated_at", "generation")
+            .await?;
+        Ok(true)
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        self.execute("TRUNCATE TABLE pwned_pwd").await?;
+        self.update_meta("0", "toUnixTimestamp(now())", "generation + 1")
+            .await
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+
+    #[test]
+    fn sql() {
+        assert_eq!("`customer_hashes`", identifier("customer_hashes"));
+        assert_eq!(r"`a\`b\\`", identifier(r"a`b\"));
+        assert_eq!(
+            "unhex('21BD4004DDDC80AE4683948C5A1C5903584D8087'),unhex('21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6')",
+            hashes(&[hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6")]),
+        );
+        assert_eq!(ROW_LEN, encode(&[PwnedPwd { hash: [1; 20], count: 2 }], 3).len());
+    }
+
+    /// The tests need a server, the url of its HTTP interface is set by `PWNED_PWD_CLICKHOUSE`.
+    /// They are ignored by default, `cargo test -- --ignored` runs them
+    async fn store() -> ClickHouseStore {
+        let url = std::env::var("PWNED_PWD_CLICKHOUSE").expect("PWNED_PWD_CLICKHOUSE").parse().unwrap();
+        let store = ClickHouseStore::new(url).with_batch_size(2);
+        store.migrate().await.unwrap();
+        store
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PWNED_PWD_CLICKHOUSE"]
+    async fn save_merge() {
+        let store = store().await;
+
+        let first = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let second = PwnedPwd { hash: hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), count: 5 };
+        let other = PwnedPwd { hash: hex!("21BD5000F2D6B0E3CE3E9D1A0E9C3EB1E2A2A4AC"), count: 2 };
+
+        store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![other.clone()] },
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![first.clone()] },
+        ])).await.unwrap();
+        let generation = store.metadata().await.unwrap().generation.unwrap();
+
+        assert!(store.exists(first.hash).await.unwrap());
+        assert_eq!(Some(2), store.exists_count(other.hash).await.unwrap());
+        assert_eq!(None, store.exists_with_min_count(first.hash, 2).await.unwrap());
+        assert_eq!(vec![true, false, true], store.exists_many(&[other.hash, second.hash, first.hash]).await.unwrap());
+        assert_eq!(vec![first.clone(), other.clone()], store.iter_all().try_collect::<Vec<_>>().await.unwrap());
+        assert_eq!(vec![first.clone()], store.range(Prefix::create(0x21BD4).unwrap()).await.unwrap().passwords);
+        assert!(store.range(Prefix::max()).await.unwrap().passwords.is_empty());
+        assert_eq!(Some(2), store.metadata().await.unwrap().records);
+
+        store.execute("CREATE OR REPLACE TABLE customer_hashes (password_hash FixedString(20)) ENGINE = Memory").await.unwrap();
+        store.execute("INSERT INTO customer_hashes VALUES (unhex('21BD5000F2D6B0E3CE3E9D1A0E9C3EB1E2A2A4AC')), (unhex('21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6'))").await.unwrap();
+        assert_eq!(vec![other.clone()], store.audit("customer_hashes", "password_hash").try_collect::<Vec<_>>().await.unwrap());
+
+        store.merge(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![PwnedPwd { count: 7, ..first.clone() }, second.clone()] },
+        ])).await.unwrap();
+        assert_eq!(Some(7), store.exists_count(first.hash).await.unwrap());
+        assert_eq!(Some(3), store.metadata().await.unwrap().records);
+        assert_eq!(Some(generation + 1), store.metadata().await.unwrap().generation);
+
+        assert!(store.remove(other.hash).await.unwrap());
+        assert!(!store.remove(other.hash).await.unwrap());
+        assert_eq!(Some(2), store.metadata().await.unwrap().records);
+
+        let err = store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![first.clone()] },
+        ])).await.err().unwrap();
+        assert!(matches!(err, ClickHouseStoreError::InvalidChunk(_)));
+        assert!(store.exists(second.hash).await.unwrap());
+
+        store.clear().await.unwrap();
+        assert!(!store.exists(second.hash).await.unwrap());
+        assert_eq!(Some(0), store.metadata().await.unwrap().records);
+    }
+}
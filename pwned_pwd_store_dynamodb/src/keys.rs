@@ -0,0 +1,116 @@
+//! Layout of a data set in a DynamoDB table
+//!
+//! A password is an item with the partition key `prefix` (the hex string of its [Prefix]) and the
+//! sort key `suffix` (the bytes of its [Suffix]), so the hashes of a prefix share a partition.
+//! [pwned_pwd_store::StoreMetadata] is the item of the partition `meta`, which isn't a prefix
+
+use std::{collections::HashMap, str::FromStr};
+
+use aws_sdk_dynamodb::{primitives::Blob, types::AttributeValue};
+use pwned_pwd_core::{Prefix, PwnedPwd, Suffix};
+
+pub(crate) type Item = HashMap<String, AttributeValue>;
+
+/// Attribute names, they are aliased in expressions, as some of them are reserved words
+pub(crate) const PREFIX: &str = "prefix";
+pub(crate) const SUFFIX: &str = "suffix";
+pub(crate) const COUNT: &str = "count";
+/// The generation of the save or the merge which has written the item
+pub(crate) const GENERATION: &str = "generation";
+pub(crate) const RECORDS: &str = "records";
+pub(crate) const UPDATED_AT: &str = "updated_at";
+
+/// The partition key of the metadata
+pub(crate) const META: &str = "meta";
+
+/// Aliases `#name` of the attributes used by the expressions, DynamoDB rejects unused ones
+pub(crate) fn names(expressions: &[&str]) -> HashMap<String, String> {
+    [PREFIX, SUFFIX, COUNT, GENERATION, RECORDS, UPDATED_AT]
+        .into_iter()
+        .map(|name| (format!("#{name}"), name.to_string()))
+        .filter(|(alias, _)| expressions.iter().any(|e| e.contains(alias.as_str())))
+        .collect()
+}
+
+/// The key of the hash
+pub(crate) fn key(hash: &[u8; 20]) -> Item {
+    HashMap::from([
+        (
+            PREFIX.to_string(),
+            AttributeValue::S(Prefix::from_sha1(hash).as_prefix_str().as_ref().to_string()),
+        ),
+        (
+            SUFFIX.to_string(),
+            AttributeValue::B(Blob::new(Suffix::from_sha1(hash).as_bytes().to_vec())),
+        ),
+    ])
+}
+
+/// The key of the metadata, a key attribute can't be empty
+pub(crate) fn meta_key() -> Item {
+    HashMap::from([
+        (PREFIX.to_string(), string(META)),
+        (SUFFIX.to_string(), AttributeValue::B(Blob::new(vec![0]))),
+    ])
+}
+
+pub(crate) fn item(pwd: &PwnedPwd, generation: u64) -> Item {
+    let mut item = key(&pwd.hash);
+    item.insert(COUNT.to_string(), number(pwd.count));
+    item.insert(GENERATION.to_string(), number(generation));
+    item
+}
+
+pub(crate) fn number(n: impl ToString) -> AttributeValue {
+    AttributeValue::N(n.to_string())
+}
+
+pub(crate) fn string(s: &str) -> AttributeValue {
+    AttributeValue::S(s.to_string())
+}
+
+/// The hash of a key or an item, None if it isn't a password
+pub(crate) fn hash(item: &Item) -> Option<[u8; 20]> {
+    let prefix = Prefix::from_str(item.get(PREFIX)?.as_s().ok()?).ok()?;
+    let suffix = <[u8; 18]>::try_from(item.get(SUFFIX)?.as_b().ok()?.as_ref()).ok()?;
+    Some(prefix.with_suffix(&Suffix::from_bytes(suffix)?))
+}
+
+/// A number attribute, None if there is no such attribute or it isn't a number
+pub(crate) fn get<T: FromStr>(item: &Item, name: &str) -> Option<T> {
+    item.get(name)?.as_n().ok()?.parse().ok()
+}
+
+pub(crate) fn pwned(item: &Item) -> Option<PwnedPwd> {
+    Some(PwnedPwd {
+        hash: hash(item)?,
+        count: get(item, COUNT)?,
+    })
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+
+    #[test]
+    fn layout() {
+        let pwd = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 5 };
+
+        let item = item(&pwd, 3);
+        assert_eq!(&AttributeValue::S("21BD4".to_string()), item.get(PREFIX).unwrap());
+        assert_eq!(&AttributeValue::B(Blob::new(hex!("0004DDDC80AE4683948C5A1C5903584D8087").to_vec())), item.get(SUFFIX).unwrap());
+        assert_eq!(Some(3u64), get(&item, GENERATION));
+        assert_eq!(Some(pwd.clone()), pwned(&item));
+        assert_eq!(Some(pwd.hash), hash(&key(&pwd.hash)));
+
+        assert_eq!(None, hash(&meta_key()));
+        assert_eq!(None, pwned(&key(&pwd.hash)));
+
+        let names = names(&["#prefix <> :meta", "#count"]);
+        assert_eq!(2, names.len());
+        assert_eq!("count", names["#count"]);
+    }
+}
@@ -0,0 +1,655 @@
+//! DynamoDB backend
+//!
+//! For serverless deployments without a persistent disk: a password is an item of a table, keyed by
+//! its prefix and suffix (see [keys]). Saves and merges write with `BatchWriteItem`,
+//! [ReadStore::exists_many] reads with `BatchGetItem`
+
+mod keys;
+
+use std::{
+    collections::{HashMap, HashSet},
+    pin::pin,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use aws_sdk_dynamodb::{
+    client::Waiters,
+    error::{BuildError, SdkError},
+    types::{
+        AttributeDefinition, BillingMode, DeleteRequest, KeySchemaElement, KeyType,
+        KeysAndAttributes, PutRequest, ReturnValue, ScalarAttributeType, WriteRequest,
+    },
+    waiters::table_exists::WaitUntilTableExistsError,
+    Client,
+};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use keys::{Item, COUNT, GENERATION, META, PREFIX, RECORDS, SUFFIX, UPDATED_AT};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+use pwned_pwd_store::{InvalidChunk, OrderRequirement, ReadStore, StoreMetadata, WriteStore};
+
+/// Limits of DynamoDB on a single request
+const BATCH_GET: usize = 100;
+const BATCH_WRITE: usize = 25;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DynamoDbStoreError {
+    #[error("DynamoDB error: {0}")]
+    DynamoDb(Box<aws_sdk_dynamodb::Error>),
+
+    #[error("Invalid request: {0}")]
+    Build(#[from] BuildError),
+
+    /// A save stops at an invalid chunk, the data set may have a part of the new passwords
+    #[error(transparent)]
+    InvalidChunk(#[from] InvalidChunk),
+
+    /// An item isn't a password, the table is used by something else
+    #[error("Invalid item in the table")]
+    InvalidItem,
+}
+
+impl<E, R> From<SdkError<E, R>> for DynamoDbStoreError
+where
+    aws_sdk_dynamodb::Error: From<SdkError<E, R>>,
+{
+    fn from(e: SdkError<E, R>) -> Self {
+        Self::DynamoDb(Box::new(e.into()))
+    }
+}
+
+impl From<WaitUntilTableExistsError> for DynamoDbStoreError {
+    fn from(e: WaitUntilTableExistsError) -> Self {
+        Self::DynamoDb(Box::new(e.into()))
+    }
+}
+
+/// A store of SHA-1 hashes in a DynamoDB table.
+///
+/// A save isn't atomic: it overwrites the passwords and then deletes the ones of the previous
+/// data set, so lookups see both data sets while it runs
+pub struct DynamoDbStore {
+    client: Client,
+    table: String,
+    concurrency: usize,
+}
+
+impl DynamoDbStore {
+    pub fn new(client: Client, table: impl Into<String>) -> Self {
+        Self {
+            client,
+            table: table.into(),
+            concurrency: 16,
+        }
+    }
+
+    /// How many requests are sent at once by saves, merges and [ReadStore::exists_many]
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    /// Creates the table with on-demand capacity, if it doesn't exist, and waits until it's active
+    pub async fn create_table(&self) -> Result<(), DynamoDbStoreError> {
+        let created = self
+            .client
+            .create_table()
+            .table_name(&self.table)
+            .billing_mode(BillingMode::PayPerRequest)
+            .attribute_definitions(
+                AttributeDefinition::builder()
+                    .attribute_name(PREFIX)
+                    .attribute_type(ScalarAttributeType::S)
+                    .build()?,
+            )
+            .attribute_definitions(
+                AttributeDefinition::builder()
+                    .attribute_name(SUFFIX)
+                    .attribute_type(ScalarAttributeType::B)
+                    .build()?,
+            )
+            .key_schema(
+                KeySchemaElement::builder()
+                    .attribute_name(PREFIX)
+                    .key_type(KeyType::Hash)
+                    .build()?,
+            )
+            .key_schema(
+                KeySchemaElement::builder()
+                    .attribute_name(SUFFIX)
+                    .key_type(KeyType::Range)
+                    .build()?,
+            )
+            .send()
+            .await;
+
+        match created {
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_resource_in_use_exception()) => {}
+            created => {
+                created?;
+            }
+        }
+
+        self.client
+            .wait_until_table_exists()
+            .table_name(&self.table)
+            .wait(Duration::from_secs(300))
+            .await?;
+        Ok(())
+    }
+
+    /// Records per [Self::write], so that every request in flight is full
+    fn batch_len(&self) -> usize {
+        BATCH_WRITE * self.concurrency
+    }
+
+    /// Sends the requests with `BatchWriteItem`, unprocessed ones are retried
+    async fn write(&self, requests: Vec<WriteRequest>) -> Result<(), DynamoDbStoreError> {
+        stream::iter(requests.chunks(BATCH_WRITE).map(<[_]>::to_vec))
+            .map(|mut requests| async move {
+                let mut attempt = 0;
+                while !requests.is_empty() {
+                    if attempt > 0 {
+                        tokio::time::sleep(backoff(attempt)).await;
+                    }
+                    attempt += 1;
+
+                    let output = self
+                        .client
+                        .batch_write_item()
+                        .request_items(&self.table, requests)
+                        .send()
+                        .await?;
+                    requests = output
+                        .unprocessed_items()
+                        .and_then(|items| items.get(&self.table))
+                        .cloned()
+                        .unwrap_or_default();
+                }
+                Ok(())
+            })
+            .buffer_unordered(self.concurrency)
+            .try_collect()
+            .await
+    }
+
+    /// The hashes of the keys which are in the table, unprocessed keys are retried
+    async fn get(&self, keys: Vec<Item>) -> Result<Vec<[u8; 20]>, DynamoDbStoreError> {
+        let projection = "#prefix, #suffix";
+        let mut request = Some(
+            KeysAndAttributes::builder()
+                .set_keys(Some(keys))
+                .projection_expression(projection)
+                .set_expression_attribute_names(Some(keys::names(&[projection])))
+                .build()?,
+        );
+
+        let mut found = Vec::new();
+        let mut attempt = 0;
+        while let Some(keys) = request.take() {
+            if attempt > 0 {
+                tokio::time::sleep(backoff(attempt)).await;
+            }
+            attempt += 1;
+
+            let output = self
+                .client
+                .batch_get_item()
+                .request_items(&self.table, keys)
+                .send()
+                .await?;
+
+            for item in output
+                .responses()
+                .and_then(|responses| responses.get(&self.table))
+                .into_iter()
+                .flatten()
+            {
+                found.push(keys::hash(item).ok_or(DynamoDbStoreError::InvalidItem)?);
+            }
+            request = output
+                .unprocessed_keys()
+                .and_then(|keys| keys.get(&self.table))
+                .filter(|keys| !keys.keys().is_empty())
+                .cloned();
+        }
+        Ok(found)
+    }
+
+    /// Streams the passwords matching `filter` with the attributes of `projection`,
+    /// the metadata is skipped
+    fn scan<'a>(
+        &'a self,
+        filter: &'a str,
+        values: Item,
+        projection: &'a str,
+    ) -> impl Stream<Item = Result<Item, DynamoDbStoreError>> + Send + 'a {
+        let filter = match filter {
+            "" => "#prefix <> :meta".to_string(),
+            filter => format!("#prefix <> :meta AND {filter}"),
+        };
+        let mut values = values;
+        values.insert(":meta".to_string(), keys::string(META));
+
+        stream::try_unfold(Some(None), move |start| {
+            let filter = filter.clone();
+            let values = values.clone();
+            async move {
+                let Some(start) = start else {
+                    return Ok::<_, DynamoDbStoreError>(None);
+                };
+
+                let output = self
+                    .client
+                    .scan()
+                    .table_name(&self.table)
+                    .filter_expression(&filter)
+                    .projection_expression(projection)
+                    .set_expression_attribute_names(Some(keys::names(&[&filter, projection])))
+                    .set_expression_attribute_values(Some(values))
+                    .set_exclusive_start_key(start)
+                    .send()
+                    .await?;
+
+                let next = output.last_evaluated_key().cloned().map(Some);
+                let items = output.items().to_vec();
+                Ok(Some((stream::iter(items.into_iter().map(Ok)), next)))
+            }
+        })
+        .try_flatten()
+    }
+
+    /// Deletes the passwords matching `filter`, see [Self::scan]
+    async fn delete_where(&self, filter: &str, values: Item) -> Result<(), DynamoDbStoreError> {
+        let mut items = pin!(self.scan(filter, values, "#prefix, #suffix"));
+        let mut requests = Vec::with_capacity(self.batch_len());
+
+        while let Some(key) = items.try_next().await? {
+            requests.push(
+                WriteRequest::builder()
+                    .delete_request(DeleteRequest::builder().set_key(Some(key)).build()?)
+                    .build(),
+            );
+            if requests.len() >= self.batch_len() {
+                self.write(std::mem::take(&mut requests)).await?;
+            }
+        }
+        self.write(requests).await
+    }
+
+    /// Updates the metadata item with the expression
+    async fn update_meta(&self, expression: &str, values: Item) -> Result<(), DynamoDbStoreError> {
+        self.client
+            .update_item()
+            .table_name(&self.table)
+            .set_key(Some(keys::meta_key()))
+            .update_expression(expression)
+            .set_expression_attribute_names(Some(keys::names(&[expression])))
+            .set_expression_attribute_values(Some(values))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Replaces the metadata item
+    async fn put_meta(&self, records: u64, generation: u64) -> Result<(), DynamoDbStoreError> {
+        let mut item = keys::meta_key();
+        item.insert(RECORDS.to_string(), keys::number(records));
+        item.insert(UPDATED_AT.to_string(), keys::number(now()));
+        item.insert(GENERATION.to_string(), keys::number(generation));
+
+        self.client
+            .put_item()
+            .table_name(&self.table)
+            .set_item(Some(item))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Upserts the password, true if it's new
+    async fn upsert(&self, pwd: PwnedPwd, generation: u64) -> Result<bool, DynamoDbStoreError> {
+        let expression = "SET #count = :count, #generation = :generation";
+        let output = self
+            .client
+            .update_item()
+            .table_name(&self.table)
+            .set_key(Some(keys::key(&pwd.hash)))
+            .update_expression(expression)
+            .set_expression_attribute_names(Some(keys::names(&[expression])))
+            .expression_attribute_values(":count", keys::number(pwd.count))
+            .expression_attribute_values(":generation", keys::number(generation))
+            .return_values(ReturnValue::UpdatedOld)
+            .send()
+            .await?;
+        Ok(output.attributes().is_none_or(HashMap::is_empty))
+    }
+}
+
+/// Delay before the retry of unprocessed items, DynamoDB returns them when it's throttling
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_millis(50 << attempt.min(6))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl ReadStore for DynamoDbStore {
+    type Error = DynamoDbStoreError;
+
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        Ok(self.exists_count(val).await?.is_some())
+    }
+
+    /// Scans the table, the passwords aren't ordered
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        self.scan("", Item::new(), "#prefix, #suffix, #count")
+            .and_then(
+                |item| async move { keys::pwned(&item).ok_or(DynamoDbStoreError::InvalidItem) },
+            )
+    }
+
+    /// Queries the partition of the prefix, DynamoDB returns it ordered by the suffix
+    async fn range(&self, prefix: Prefix) -> Result<Chunk, Self::Error> {
+        let condition = "#prefix = :prefix";
+        let projection = "#prefix, #suffix, #count";
+        let values = Item::from([(
+            ":prefix".to_string(),
+            keys::string(prefix.as_prefix_str().as_ref()),
+        )]);
+
+        let mut passwords = Vec::new();
+        let mut start = None;
+        loop {
+            let output = self
+                .client
+                .query()
+                .table_name(&self.table)
+                .key_condition_expression(condition)
+                .projection_expression(projection)
+                .set_expression_attribute_names(Some(keys::names(&[condition, projection])))
+                .set_expression_attribute_values(Some(values.clone()))
+                .set_exclusive_start_key(start)
+                .send()
+                .await?;
+
+            for item in output.items() {
+                passwords.push(keys::pwned(item).ok_or(DynamoDbStoreError::InvalidItem)?);
+            }
+            start = output.last_evaluated_key().cloned();
+            if start.is_none() {
+                break;
+            }
+        }
+        Ok(Chunk { prefix, passwords })
+    }
+
+    /// Looks the hashes up with `BatchGetItem`, 100 unique hashes per request
+    async fn exists_many(&self, vals: &[[u8; 20]]) -> Result<Vec<bool>, Self::Error> {
+        let unique = vals.iter().collect::<HashSet<_>>();
+        let keys = unique.into_iter().map(keys::key).collect::<Vec<_>>();
+
+        let found = stream::iter(keys.chunks(BATCH_GET).map(<[_]>::to_vec))
+            .map(|keys| self.get(keys))
+            .buffer_unordered(self.concurrency)
+            .try_fold(HashSet::new(), |mut found, hashes| async move {
+                found.extend(hashes);
+                Ok(found)
+            })
+            .await?;
+
+        Ok(vals.iter().map(|val| found.contains(val)).collect())
+    }
+
+    async fn exists_count(&self, val: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        let projection = "#count";
+        let output = self
+            .client
+            .get_item()
+            .table_name(&self.table)
+            .set_key(Some(keys::key(&val)))
+            .projection_expression(projection)
+            .set_expression_attribute_names(Some(keys::names(&[projection])))
+            .send()
+            .await?;
+
+        output
+            .item()
+            .map(|item| keys::get(item, COUNT).ok_or(DynamoDbStoreError::InvalidItem))
+            .transpose()
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        let output = self
+            .client
+            .get_item()
+            .table_name(&self.table)
+            .set_key(Some(keys::meta_key()))
+            .consistent_read(true)
+            .send()
+            .await?;
+        let item = output.item().cloned().unwrap_or_default();
+
+        Ok(StoreMetadata {
+            records: Some(keys::get(&item, RECORDS).unwrap_or_default()),
+            updated_at: keys::get(&item, UPDATED_AT)
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+            generation: keys::get(&item, GENERATION),
+            ..Default::default()
+        })
+    }
+}
+
+impl WriteStore for DynamoDbStore {
+    fn order_requirement() -> OrderRequirement {
+        OrderRequirement::Unordered
+    }
+
+    /// Puts the stream with the next generation, then deletes the passwords of older generations
+    /// and updates the metadata. If the future is dropped, the next save cleans up
+    async fn save<S: Stream<Item = Chunk> + Unpin + Send>(
+        &self,
+        mut s: S,
+    ) -> Result<(), Self::Error> {
+        let generation = self.metadata().await?.generation.unwrap_or_default() + 1;
+        let mut records = 0u64;
+        let mut requests = Vec::with_capacity(self.batch_len());
+
+        loop {
+            let chunk = s.next().await;
+            if let Some(chunk) = &chunk {
+                InvalidChunk::check(chunk)?;
+            }
+
+            let last = chunk.is_none();
+            for pwd in chunk.iter().flat_map(|chunk| &chunk.passwords) {
+                requests.push(
+                    WriteRequest::builder()
+                        .put_request(
+                            PutRequest::builder()
+                                .set_item(Some(keys::item(pwd, generation)))
+                                .build()?,
+                        )
+                        .build(),
+                );
+            }
+
+            if requests.len() >= self.batch_len() || (last && !requests.is_empty()) {
+                records += requests.len() as u64;
+                self.write(std::mem::take(&mut requests)).await?;
+            }
+
+            if last {
+                break;
+            }
+        }
+
+        let mut values = Item::new();
+        values.insert(":generation".to_string(), keys::number(generation));
+        self.delete_where("#generation < :generation", values)
+            .await?;
+
+        self.put_meta(records, generation).await
+    }
+
+    /// Upserts the stream item by item, the record count is updated by every batch
+    async fn merge<S: Stream<Item = Chunk> + Unpin + Send>(
+        &self,
+        mut s: S,
+    ) -> Result<(), Self::Error> {
+        let generation = self.metadata().await?.generation.unwrap_or_default() + 1;
+        let mut batch = Vec::with_capacity(self.batch_len());
+
+        loop {
+            let chunk = s.next().await;
+            if let Some(chunk) = &chunk {
+                InvalidChunk::check(chunk)?;
+            }
+
+            let last = chunk.is_none();
+            batch.extend(chunk.into_iter().flat_map(|chunk| chunk.passwords));
+
+            if batch.len() >= self.batch_len() || (last && !batch.is_empty()) {
+                let added = stream::iter(std::mem::take(&mut batch))
+                    .map(|pwd| self.upsert(pwd, generation))
+                    .buffer_unordered(self.concurrency)
+                    .try_fold(0u64, |added, new| async move { Ok(added + new as u64) })
+                    .await?;
+
+                let mut values = Item::new();
+                values.insert(":added".to_string(), keys::number(added));
+                self.update_meta("ADD #records :added", values).await?;
+            }
+
+            if last {
+                break;
+            }
+        }
+
+        let mut values = Item::new();
+        values.insert(":now".to_string(), keys::number(now()));
+        values.insert(":generation".to_string(), keys::number(generation));
+        self.update_meta("SET #updated_at = :now, #generation = :generation", values)
+            .await
+    }
+
+    async fn remove(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        let output = self
+            .client
+            .delete_item()
+            .table_name(&self.table)
+            .set_key(Some(keys::key(&val)))
+            .return_values(ReturnValue::AllOld)
+            .send()
+            .await?;
+        if output.attributes().is_none_or(HashMap::is_empty) {
+            return Ok(false);
+        }
+
+        let mut values = Item::new();
+        values.insert(":removed".to_string(), keys::number(-1));
+        self.update_meta("ADD #records :removed", values).await?;
+        Ok(true)
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        let generation = self.metadata().await?.generation.unwrap_or_default() + 1;
+        self.delete_where("", Item::new()).await?;
+        self.put_meta(0, generation).await
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use aws_sdk_dynamodb::config::{BehaviorVersion, Credentials, Region};
+    use hex_literal::hex;
+
+    use super::*;
+
+    /// The tests need a table, the endpoint of DynamoDB Local is set by `PWNED_PWD_DYNAMODB`.
+    /// They are ignored by default, `cargo test -- --ignored` runs them
+    async fn store(table: &str) -> DynamoDbStore {
+        let endpoint = std::env::var("PWNED_PWD_DYNAMODB").expect("PWNED_PWD_DYNAMODB");
+        let config = aws_sdk_dynamodb::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .endpoint_url(endpoint)
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+            .build();
+
+        let store = DynamoDbStore::new(Client::from_conf(config), table).with_concurrency(2);
+        store.create_table().await.unwrap();
+        store.clear().await.unwrap();
+        store
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PWNED_PWD_DYNAMODB"]
+    async fn save() {
+        let store = store("pwned_pwd_save").await;
+        assert!(!store.healthy().await.unwrap());
+
+        let first = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let second = PwnedPwd { hash: hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), count: 5 };
+        let other = PwnedPwd { hash: hex!("21BD5000F2D6B0E3CE3E9D1A0E9C3EB1E2A2A4AC"), count: 2 };
+
+        store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![other.clone()] },
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![first.clone(), second.clone()] },
+        ])).await.unwrap();
+
+        assert!(store.exists(first.hash).await.unwrap());
+        assert_eq!(Some(2), store.exists_count(other.hash).await.unwrap());
+        assert_eq!(vec![true, false, true], store.exists_many(&[second.hash, [0; 20], second.hash]).await.unwrap());
+        let mut all = store.iter_all().try_collect::<Vec<_>>().await.unwrap();
+        all.sort_by_key(|pwd| pwd.hash);
+        assert_eq!(vec![first.clone(), second.clone(), other.clone()], all);
+        assert_eq!(vec![first.clone(), second.clone()], store.range(Prefix::create(0x21BD4).unwrap()).await.unwrap().passwords);
+        assert!(store.range(Prefix::max()).await.unwrap().passwords.is_empty());
+        assert_eq!(Some(3), store.metadata().await.unwrap().records);
+
+        store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![second.clone()] },
+        ])).await.unwrap();
+        assert!(!store.exists(first.hash).await.unwrap());
+        assert_eq!(Some(5), store.exists_count(second.hash).await.unwrap());
+        assert_eq!(Some(1), store.metadata().await.unwrap().records);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PWNED_PWD_DYNAMODB"]
+    async fn merge_remove_clear() {
+        let store = store("pwned_pwd_merge").await;
+
+        let first = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let second = PwnedPwd { hash: hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), count: 5 };
+
+        store.merge(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![first.clone()] },
+        ])).await.unwrap();
+        store.merge(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![PwnedPwd { count: 7, ..first.clone() }, second.clone()] },
+        ])).await.unwrap();
+
+        assert_eq!(Some(7), store.exists_count(first.hash).await.unwrap());
+        assert_eq!(Some(2), store.metadata().await.unwrap().records);
+
+        assert!(store.remove(first.hash).await.unwrap());
+        assert!(!store.remove(first.hash).await.unwrap());
+        assert_eq!(Some(1), store.metadata().await.unwrap().records);
+
+        store.clear().await.unwrap();
+        assert!(!store.exists(second.hash).await.unwrap());
+        assert_eq!(Some(0), store.metadata().await.unwrap().records);
+    }
+}
@@ -0,0 +1,224 @@
+//! A store backed by the online range API of Have I Been Pwned
+//!
+//! [HibpStore] keeps no data: a lookup downloads the range of the prefix of the hash
+//! and searches the hash in it, so only the first 5 hex characters of the hash leave the host
+//! (k-anonymity). A small app may check passwords through [ReadStore] without a copy
+//! of the data set and switch to a local backend later without code changes.
+//! Ranges may be kept in a [ChunkCache] to save requests for hashes of the same prefix
+
+use std::{collections::HashMap, sync::Arc};
+
+use futures::{future::try_join_all, Stream, StreamExt};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd, NTLM_LEN, SHA1_LEN};
+use pwned_pwd_downloader::{cache::ChunkCache, ChunkSource, DownloadError, Downloader};
+use pwned_pwd_store::ReadStore;
+
+/// A read-only store which requests the range API on every lookup, see the [crate] docs
+pub struct HibpStore<const N: usize = SHA1_LEN> {
+    downloader: Downloader<N>,
+    cache: Option<ChunkCache<N>>,
+}
+
+impl HibpStore {
+    /// A store of SHA-1 hashes which requests [Downloader::DEFAULT_BASE_URL]
+    pub fn new() -> Self {
+        Self::from_downloader(Downloader::default())
+    }
+}
+
+impl Default for HibpStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HibpStore<NTLM_LEN> {
+    /// A store of NTLM hashes which requests [Downloader::DEFAULT_BASE_URL]
+    pub fn ntlm() -> Self {
+        Self::from_downloader(Downloader::ntlm(
+            Downloader::DEFAULT_BASE_URL
+                .parse()
+                .expect("Invalid default url"),
+            16,
+        ))
+    }
+}
+
+impl<const N: usize> HibpStore<N> {
+    /// A store which requests ranges with the downloader, e.g. of another url or with a watchdog.
+    /// Parallel workers of the downloader are used only by [ReadStore::iter_all]
+    pub fn from_downloader(downloader: Downloader<N>) -> Self {
+        Self {
+            downloader,
+            cache: None,
+        }
+    }
+
+    /// Keep downloaded ranges in the cache
+    pub fn with_cache(mut self, cache: ChunkCache<N>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn downloader(&self) -> &Downloader<N> {
+        &self.downloader
+    }
+
+    pub fn cache(&self) -> Option<&ChunkCache<N>> {
+        self.cache.as_ref()
+    }
+
+    async fn fetch_range(&self, prefix: Prefix) -> Result<Arc<Chunk<N>>, DownloadError> {
+        match &self.cache {
+            Some(cache) => self.downloader.download_prefix_cached(prefix, cache).await,
+            None => Ok(Arc::new(self.downloader.download_prefix(prefix).await?)),
+        }
+    }
+}
+
+fn count<const N: usize>(chunk: &Chunk<N>, val: &[u8; N]) -> Option<u32> {
+    chunk
+        .passwords
+        .iter()
+        .find(|pwd| pwd.hash == *val)
+        .map(|pwd| pwd.count)
+}
+
+impl<const N: usize> ReadStore<N> for HibpStore<N> {
+    type Error = DownloadError;
+
+    async fn exists(&self, val: [u8; N]) -> Result<bool, Self::Error> {
+        Ok(self.exists_count(val).await?.is_some())
+    }
+
+    /// Downloads the whole data set with the parallel workers of the downloader,
+    /// ranges are streamed as they are downloaded, so the passwords aren't ordered
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd<N>, Self::Error>> + Send {
+        self.downloader
+            .chunks(Prefix::default().into_iter())
+            .flat_map(|chunk| {
+                let passwords = match chunk {
+                    Ok(chunk) => chunk.passwords.into_iter().map(Ok).collect(),
+                    Err(e) => vec![Err(e)],
+                };
+                futures::stream::iter(passwords)
+            })
+    }
+
+    /// Requests the range of every distinct prefix once
+    async fn exists_many(&self, vals: &[[u8; N]]) -> Result<Vec<bool>, Self::Error> {
+        let mut prefixes = vals
+            .iter()
+            .map(|val| Prefix::from_hash(val))
+            .collect::<Vec<_>>();
+        prefixes.sort_unstable_by_key(|prefix| u32::from(*prefix));
+        prefixes.dedup();
+
+        let ranges = try_join_all(prefixes.into_iter().map(|prefix| self.fetch_range(prefix)))
+            .await?
+            .into_iter()
+            .map(|chunk| (chunk.prefix, chunk))
+            .collect::<HashMap<_, _>>();
+
+        Ok(vals
+            .iter()
+            .map(|val| {
+                ranges
+                    .get(&Prefix::from_hash(val))
+                    .is_some_and(|chunk| count(chunk, val).is_some())
+            })
+            .collect())
+    }
+
+    async fn exists_count(&self, val: [u8; N]) -> Result<Option<u32>, Self::Error> {
+        let chunk = self.fetch_range(Prefix::from_hash(&val)).await?;
+        Ok(count(&chunk, &val))
+    }
+
+    async fn range(&self, prefix: Prefix) -> Result<Chunk<N>, Self::Error> {
+        Ok(Arc::unwrap_or_clone(self.fetch_range(prefix).await?))
+    }
+
+    /// The API answers with a range which isn't empty
+    async fn healthy(&self) -> Result<bool, Self::Error> {
+        Ok(!self
+            .fetch_range(Prefix::default())
+            .await?
+            .passwords
+            .is_empty())
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::{sync::atomic::{AtomicUsize, Ordering::SeqCst}, time::Duration};
+
+    use hex_literal::hex;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    use super::*;
+
+    /// Serves the range `21BD4`, other ranges are empty. Returns the url and the count of requests
+    async fn serve() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/range/", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut connection, _)) = listener.accept().await {
+                counter.fetch_add(1, SeqCst);
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let n = connection.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..n]);
+                    }
+
+                    let body = match String::from_utf8_lossy(&request).starts_with("GET /range/21BD4 ") {
+                        true => "004DDDC80AE4683948C5A1C5903584D8087:1\r\n00E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6:5\r\n",
+                        false => "",
+                    };
+                    let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len());
+                    connection.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+        (url, requests)
+    }
+
+    #[tokio::test]
+    async fn lookups() {
+        let (url, requests) = serve().await;
+        let store = HibpStore::from_downloader(Downloader::new(url.parse().unwrap(), 1));
+
+        assert!(store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert_eq!(Some(5), store.exists_count(hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6")).await.unwrap());
+        assert_eq!(None, store.exists_count(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED")).await.unwrap());
+        assert!(!store.exists(hex!("21BD5000F2D6B0E3CE3E9D1A0E9C3EB1E2A2A4AC")).await.unwrap());
+        assert_eq!(4, requests.load(SeqCst));
+
+        let found = store.exists_many(&[
+            hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"),
+            hex!("21BD5000F2D6B0E3CE3E9D1A0E9C3EB1E2A2A4AC"),
+            hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"),
+        ]).await.unwrap();
+        assert_eq!(vec![true, false, true], found);
+        assert_eq!(6, requests.load(SeqCst));
+        assert!(!store.healthy().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn cache() {
+        let (url, requests) = serve().await;
+        let store = HibpStore::from_downloader(Downloader::new(url.parse().unwrap(), 1))
+            .with_cache(ChunkCache::new(Duration::from_secs(60), 16));
+
+        assert!(store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert!(store.exists(hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6")).await.unwrap());
+        assert_eq!(1, requests.load(SeqCst));
+        assert_eq!(1, store.cache().unwrap().metrics().hits);
+    }
+}
@@ -0,0 +1,574 @@
+//! LMDB backend
+//!
+//! Lookups are B-tree searches in a memory map, so they read almost like the raw file of
+//! `pwned_pwd_store_local`, while counts and metadata stay queryable. A save appends the ordered
+//! stream to the spare database in a single write transaction, whose commit replaces the data set
+
+use std::{
+    io,
+    ops::Bound,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use heed::{types::Bytes, Database, Env, EnvOpenOptions, PutFlags, RoTxn};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+use pwned_pwd_store::{InvalidChunk, OrderRequirement, ReadStore, StoreMetadata, WriteStore};
+use tokio::sync::mpsc;
+
+type Db = Database<Bytes, Bytes>;
+
+/// Keys of the metadata database
+const CURRENT: &[u8] = b"current";
+const RECORDS: &[u8] = b"records";
+const UPDATED_AT: &[u8] = b"updated_at";
+const GENERATION: &[u8] = b"generation";
+
+#[derive(Debug, thiserror::Error)]
+pub enum LmdbStoreError {
+    #[error("LMDB error: {0}")]
+    Lmdb(#[from] heed::Error),
+
+    #[error("Io error: {0}")]
+    Io(#[from] io::Error),
+
+    /// A blocking task with the environment panicked or was cancelled
+    #[error("Blocking task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+
+    /// A save stops at an invalid chunk and keeps the data
+    #[error(transparent)]
+    InvalidChunk(#[from] InvalidChunk),
+
+    /// A saved chunk doesn't follow the previous one, chunks must be ordered by prefix
+    #[error("The chunk of the prefix '{prefix}' follows the chunk of '{previous}'")]
+    UnorderedChunk { prefix: Prefix, previous: Prefix },
+
+    /// A key isn't a SHA-1 hash or a value isn't a count, the environment is used by something else
+    #[error("Invalid record in the environment")]
+    InvalidRecord,
+}
+
+/// Named databases of the environment
+#[derive(Debug, Clone, Copy)]
+struct Databases {
+    meta: Db,
+
+    /// The data set and the spare one, `current` is the index of the data set
+    data: [Db; 2],
+}
+
+impl Databases {
+    /// Index of the data set, None if there is no data set
+    fn current(&self, txn: &RoTxn) -> Result<Option<usize>, LmdbStoreError> {
+        match self.meta.get(txn, CURRENT)? {
+            None => Ok(None),
+            Some(&[index]) if index < 2 => Ok(Some(index as usize)),
+            Some(_) => Err(LmdbStoreError::InvalidRecord),
+        }
+    }
+
+    fn number(&self, txn: &RoTxn, key: &[u8]) -> Result<Option<u64>, LmdbStoreError> {
+        self.meta
+            .get(txn, key)?
+            .map(|value| {
+                <[u8; 8]>::try_from(value)
+                    .map(u64::from_be_bytes)
+                    .map_err(|_| LmdbStoreError::InvalidRecord)
+            })
+            .transpose()
+    }
+}
+
+/// A store of SHA-1 hashes in an LMDB environment.
+/// LMDB serializes write transactions, so saves, merges and removals don't need a lock
+pub struct LmdbStore {
+    env: Env,
+    dbs: Databases,
+    batch_size: usize,
+}
+
+impl LmdbStore {
+    /// Enough for two copies of the full data set. The map is virtual, the file grows with the data
+    pub const DEFAULT_MAP_SIZE: usize = 128 << 30;
+
+    /// Opens the environment in the directory, it is created if it doesn't exist
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, LmdbStoreError> {
+        Self::open_with_map_size(path, Self::DEFAULT_MAP_SIZE)
+    }
+
+    /// Opens the environment with the maximum size of the data, a save needs room for the old
+    /// and the new data set
+    pub fn open_with_map_size(
+        path: impl AsRef<Path>,
+        map_size: usize,
+    ) -> Result<Self, LmdbStoreError> {
+        std::fs::create_dir_all(&path)?;
+
+        // SAFETY: the environment is opened once per process by heed,
+        // the files must not be changed by something else while they are mapped
+        let env = unsafe {
+            EnvOpenOptions::new()
+                .map_size(map_size)
+                .max_dbs(3)
+                .open(path)?
+        };
+
+        let mut txn = env.write_txn()?;
+        let dbs = Databases {
+            meta: env.create_database(&mut txn, Some("meta"))?,
+            data: [
+                env.create_database(&mut txn, Some("data.0"))?,
+                env.create_database(&mut txn, Some("data.1"))?,
+            ],
+        };
+        txn.commit()?;
+
+        Ok(Self {
+            env,
+            dbs,
+            batch_size: 10_000,
+        })
+    }
+
+    /// How many records are sent to the writer at once by a save, upserted in a transaction
+    /// by a merge, and read at once by [ReadStore::iter_all]
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        self.env.path()
+    }
+
+    /// Runs `f` in a read transaction with the data set on the blocking thread pool,
+    /// None if there is no data set
+    async fn read<T, F>(&self, f: F) -> Result<Option<T>, LmdbStoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&RoTxn, Db) -> Result<T, LmdbStoreError> + Send + 'static,
+    {
+        self.blocking(move |env, dbs| {
+            let txn = env.read_txn()?;
+            dbs.current(&txn)?
+                .map(|current| f(&txn, dbs.data[current]))
+                .transpose()
+        })
+        .await
+    }
+
+    /// Runs `f` with the environment on the blocking thread pool
+    async fn blocking<T, F>(&self, f: F) -> Result<T, LmdbStoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Env, Databases) -> Result<T, LmdbStoreError> + Send + 'static,
+    {
+        let env = self.env.clone();
+        let dbs = self.dbs;
+        tokio::task::spawn_blocking(move || f(&env, dbs)).await?
+    }
+}
+
+fn count(value: &[u8]) -> Result<u32, LmdbStoreError> {
+    <[u8; 4]>::try_from(value)
+        .map(u32::from_be_bytes)
+        .map_err(|_| LmdbStoreError::InvalidRecord)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl ReadStore for LmdbStore {
+    type Error = LmdbStoreError;
+
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        Ok(self.exists_count(val).await?.is_some())
+    }
+
+    /// Iterates the data set in order, a batch of records at a time
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        let batch_size = self.batch_size;
+
+        stream::try_unfold(Some(Vec::new()), move |after| async move {
+            let Some(after) = after else {
+                return Ok::<_, LmdbStoreError>(None);
+            };
+
+            let page = self
+                .read(move |txn, data| {
+                    // LMDB rejects an empty key, the first page has no bound
+                    let start = if after.is_empty() {
+                        Bound::Unbounded
+                    } else {
+                        Bound::Excluded(after.as_slice())
+                    };
+                    let range = (start, Bound::Unbounded);
+                    data.range(txn, &range)?
+                        .take(batch_size)
+                        .map(|item| {
+                            let (key, value) = item?;
+                            Ok(PwnedPwd {
+                                hash: <[u8; 20]>::try_from(key)
+                                    .map_err(|_| LmdbStoreError::InvalidRecord)?,
+                                count: count(value)?,
+                            })
+                        })
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .await?
+                .unwrap_or_default();
+
+            let next = page
+                .last()
+                .filter(|_| page.len() == batch_size)
+                .map(|pwd| pwd.hash.to_vec());
+            Ok(Some((stream::iter(page.into_iter().map(Ok)), next)))
+        })
+        .try_flatten()
+    }
+
+    /// Looks the hashes up in a single read transaction
+    async fn exists_many(&self, vals: &[[u8; 20]]) -> Result<Vec<bool>, Self::Error> {
+        let vals = vals.to_vec();
+        let len = vals.len();
+
+        Ok(self
+            .read(move |txn, data| {
+                vals.iter()
+                    .map(|val| Ok(data.get(txn, val)?.is_some()))
+                    .collect()
+            })
+            .await?
+            .unwrap_or_else(|| vec![false; len]))
+    }
+
+    async fn exists_count(&self, val: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        Ok(self
+            .read(move |txn, data| data.get(txn, &val)?.map(count).transpose())
+            .await?
+            .flatten())
+    }
+
+    /// Reads the keys from the first bytes of the prefix up to the ones of the next prefix
+    async fn range(&self, prefix: Prefix) -> Result<Chunk, Self::Error> {
+        let start = prefix.to_bytes();
+        let end = prefix.next().map(|next| next.to_bytes());
+
+        let passwords = self
+            .read(move |txn, data| {
+                let end = end
+                    .as_ref()
+                    .map_or(Bound::Unbounded, |end| Bound::Excluded(&end[..]));
+                let range = (Bound::Included(&start[..]), end);
+                data.range(txn, &range)?
+                    .map(|item| {
+                        let (key, value) = item?;
+                        Ok(PwnedPwd {
+                            hash: <[u8; 20]>::try_from(key)
+                                .map_err(|_| LmdbStoreError::InvalidRecord)?,
+                            count: count(value)?,
+                        })
+                    })
+                    .collect()
+            })
+            .await?
+            .unwrap_or_default();
+        Ok(Chunk { prefix, passwords })
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        self.blocking(|env, dbs| {
+            let txn = env.read_txn()?;
+            Ok(StoreMetadata {
+                records: Some(dbs.number(&txn, RECORDS)?.unwrap_or_default()),
+                updated_at: dbs
+                    .number(&txn, UPDATED_AT)?
+                    .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+                generation: dbs.number(&txn, GENERATION)?,
+                ..Default::default()
+            })
+        })
+        .await
+    }
+}
+
+impl WriteStore for LmdbStore {
+    fn order_requirement() -> OrderRequirement {
+        OrderRequirement::Ordered
+    }
+
+    /// Appends the stream to the spare database in a write transaction on a blocking thread,
+    /// which is committed with the metadata at the end of the stream.
+    /// If the future is dropped, the transaction is aborted and the data isn't changed
+    async fn save<S: Stream<Item = Chunk> + Unpin + Send>(
+        &self,
+        mut s: S,
+    ) -> Result<(), Self::Error> {
+        // A batch of records or the end of the stream
+        let (sender, mut receiver) = mpsc::channel::<Option<Vec<PwnedPwd>>>(2);
+
+        let (env, dbs) = (self.env.clone(), self.dbs);
+        let writer = tokio::task::spawn_blocking(move || {
+            let mut txn = env.write_txn()?;
+            let previous = dbs.current(&txn)?;
+            let target = previous.map_or(0, |previous| 1 - previous);
+            let data = dbs.data[target];
+            data.clear(&mut txn)?;
+
+            let mut records = 0u64;
+            while let Some(batch) = receiver.blocking_recv() {
+                let Some(batch) = batch else {
+                    let generation = dbs.number(&txn, GENERATION)?.unwrap_or_default() + 1;
+                    dbs.meta.put(&mut txn, CURRENT, &[target as u8])?;
+                    dbs.meta.put(&mut txn, RECORDS, &records.to_be_bytes())?;
+                    dbs.meta.put(&mut txn, UPDATED_AT, &now().to_be_bytes())?;
+                    dbs.meta
+                        .put(&mut txn, GENERATION, &generation.to_be_bytes())?;
+                    if let Some(previous) = previous {
+                        dbs.data[previous].clear(&mut txn)?;
+                    }
+                    txn.commit()?;
+                    break;
+                };
+
+                for pwd in &batch {
+                    data.put_with_flags(
+                        &mut txn,
+                        PutFlags::APPEND,
+                        &pwd.hash,
+                        &pwd.count.to_be_bytes(),
+                    )?;
+                }
+                records += batch.len() as u64;
+            }
+            Ok(())
+        });
+
+        let mut batch = Vec::with_capacity(self.batch_size);
+        let mut previous: Option<Prefix> = None;
+        loop {
+            let chunk = s.next().await;
+            if let Some(chunk) = &chunk {
+                InvalidChunk::check(chunk)?;
+                if let Some(previous) =
+                    previous.filter(|previous| u32::from(*previous) >= u32::from(chunk.prefix))
+                {
+                    return Err(LmdbStoreError::UnorderedChunk {
+                        prefix: chunk.prefix,
+                        previous,
+                    });
+                }
+                previous = Some(chunk.prefix);
+            }
+
+            let last = chunk.is_none();
+            batch.extend(chunk.into_iter().flat_map(|chunk| chunk.passwords));
+
+            // A failed send means the writer has stopped with an error
+            if batch.len() >= self.batch_size || (last && !batch.is_empty()) {
+                let passwords = std::mem::replace(&mut batch, Vec::with_capacity(self.batch_size));
+                if sender.send(Some(passwords)).await.is_err() {
+                    break;
+                }
+            }
+
+            if last {
+                let _ = sender.send(None).await;
+                break;
+            }
+        }
+
+        drop(sender);
+        writer.await?
+    }
+
+    /// Upserts the stream in write transactions, the record count is updated by every batch
+    async fn merge<S: Stream<Item = Chunk> + Unpin + Send>(
+        &self,
+        mut s: S,
+    ) -> Result<(), Self::Error> {
+        let mut batch = Vec::with_capacity(self.batch_size);
+
+        loop {
+            let chunk = s.next().await;
+            if let Some(chunk) = &chunk {
+                InvalidChunk::check(chunk)?;
+            }
+
+            let last = chunk.is_none();
+            batch.extend(chunk.into_iter().flat_map(|chunk| chunk.passwords));
+
+            if batch.len() >= self.batch_size || (last && !batch.is_empty()) {
+                let passwords = std::mem::replace(&mut batch, Vec::with_capacity(self.batch_size));
+                self.blocking(move |env, dbs| {
+                    let mut txn = env.write_txn()?;
+                    let current = match dbs.current(&txn)? {
+                        Some(current) => current,
+                        None => {
+                            dbs.meta.put(&mut txn, CURRENT, &[0])?;
+                            0
+                        }
+                    };
+                    let data = dbs.data[current];
+
+                    let mut added = 0;
+                    for pwd in &passwords {
+                        if data.get(&txn, &pwd.hash)?.is_none() {
+                            added += 1;
+                        }
+                        data.put(&mut txn, &pwd.hash, &pwd.count.to_be_bytes())?;
+                    }
+
+                    let records = dbs.number(&txn, RECORDS)?.unwrap_or_default() + added;
+                    dbs.meta.put(&mut txn, RECORDS, &records.to_be_bytes())?;
+                    Ok(txn.commit()?)
+                })
+                .await?;
+            }
+
+            if last {
+                break;
+            }
+        }
+
+        self.blocking(|env, dbs| {
+            let mut txn = env.write_txn()?;
+            let generation = dbs.number(&txn, GENERATION)?.unwrap_or_default() + 1;
+            dbs.meta.put(&mut txn, UPDATED_AT, &now().to_be_bytes())?;
+            dbs.meta
+                .put(&mut txn, GENERATION, &generation.to_be_bytes())?;
+            Ok(txn.commit()?)
+        })
+        .await
+    }
+
+    async fn remove(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        self.blocking(move |env, dbs| {
+            let mut txn = env.write_txn()?;
+            let Some(current) = dbs.current(&txn)? else {
+                return Ok(false);
+            };
+            if !dbs.data[current].delete(&mut txn, &val)? {
+                return Ok(false);
+            }
+
+            let records = dbs
+                .number(&txn, RECORDS)?
+                .unwrap_or_default()
+                .saturating_sub(1);
+            dbs.meta.put(&mut txn, RECORDS, &records.to_be_bytes())?;
+            txn.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        self.blocking(|env, dbs| {
+            let mut txn = env.write_txn()?;
+            let generation = dbs.number(&txn, GENERATION)?.unwrap_or_default() + 1;
+            if let Some(current) = dbs.current(&txn)? {
+                dbs.data[current].clear(&mut txn)?;
+            }
+
+            dbs.meta.delete(&mut txn, CURRENT)?;
+            dbs.meta.put(&mut txn, RECORDS, &0u64.to_be_bytes())?;
+            dbs.meta.put(&mut txn, UPDATED_AT, &now().to_be_bytes())?;
+            dbs.meta
+                .put(&mut txn, GENERATION, &generation.to_be_bytes())?;
+            Ok(txn.commit()?)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use hex_literal::hex;
+
+    use super::*;
+
+    fn open(name: &str) -> LmdbStore {
+        let path = temp_dir().join("pwned_pwd_lmdb").join(name);
+        let _ = std::fs::remove_dir_all(&path);
+        LmdbStore::open_with_map_size(path, 1 << 20).unwrap().with_batch_size(2)
+    }
+
+    #[tokio::test]
+    async fn save() {
+        let store = open("save");
+        assert!(!store.healthy().await.unwrap());
+
+        let first = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let second = PwnedPwd { hash: hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), count: 5 };
+        let other = PwnedPwd { hash: hex!("21BD5000F2D6B0E3CE3E9D1A0E9C3EB1E2A2A4AC"), count: 2 };
+
+        store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![first.clone(), second.clone()] },
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![other.clone()] },
+        ])).await.unwrap();
+
+        assert!(store.exists(first.hash).await.unwrap());
+        assert_eq!(Some(2), store.exists_count(other.hash).await.unwrap());
+        assert_eq!(vec![true, false], store.exists_many(&[second.hash, [0; 20]]).await.unwrap());
+        assert_eq!(vec![first.clone(), second.clone(), other.clone()], store.iter_all().try_collect::<Vec<_>>().await.unwrap());
+        assert_eq!(vec![first.clone(), second.clone()], store.range(Prefix::create(0x21BD4).unwrap()).await.unwrap().passwords);
+        assert!(store.range(Prefix::max()).await.unwrap().passwords.is_empty());
+        assert_eq!(Some(3), store.metadata().await.unwrap().records);
+        assert_eq!(Some(1), store.metadata().await.unwrap().generation);
+
+        let err = store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![other.clone()] },
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![first.clone()] },
+        ])).await.err().unwrap();
+        assert!(matches!(err, LmdbStoreError::UnorderedChunk { .. }));
+        assert!(store.exists(second.hash).await.unwrap());
+
+        store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![second.clone()] },
+        ])).await.unwrap();
+        assert!(!store.exists(first.hash).await.unwrap());
+        assert_eq!(Some(2), store.metadata().await.unwrap().generation);
+
+        let path = store.path().to_path_buf();
+        drop(store);
+        let store = LmdbStore::open_with_map_size(path, 1 << 20).unwrap();
+        assert_eq!(Some(5), store.exists_count(second.hash).await.unwrap());
+        assert_eq!(Some(1), store.metadata().await.unwrap().records);
+    }
+
+    #[tokio::test]
+    async fn merge_remove_clear() {
+        let store = open("merge");
+
+        let first = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let second = PwnedPwd { hash: hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), count: 5 };
+
+        store.merge(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![first.clone()] },
+        ])).await.unwrap();
+        store.merge(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![PwnedPwd { count: 7, ..first.clone() }, second.clone()] },
+        ])).await.unwrap();
+
+        assert_eq!(Some(7), store.exists_count(first.hash).await.unwrap());
+        assert_eq!(Some(2), store.metadata().await.unwrap().records);
+
+        assert!(store.remove(first.hash).await.unwrap());
+        assert!(!store.remove(first.hash).await.unwrap());
+        assert_eq!(Some(1), store.metadata().await.unwrap().records);
+
+        store.clear().await.unwrap();
+        assert!(!store.exists(second.hash).await.unwrap());
+        assert_eq!(Some(0), store.metadata().await.unwrap().records);
+    }
+}
@@ -0,0 +1,171 @@
+//! Startup checks of a store file and its surroundings
+//!
+//! A [crate::LocalStore] is a single file of ordered records behind a header,
+//! so [Advisory] looks for what can go wrong with it: a missing, foreign or
+//! truncated file and temp files left by interrupted saves
+
+use std::{
+    fs::{metadata, remove_file},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{LocalStore, LocalStoreError};
+
+/// A problem found by [LocalStore::advise]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+    /// There is no store file, a save is required
+    MissingFile { path: PathBuf },
+
+    /// File length is not a multiple of a record length, the file is corrupted
+    UnalignedFile { path: PathBuf, len: u64 },
+
+    /// The header is missing or doesn't describe the file
+    InvalidHeader { path: PathBuf, reason: &'static str },
+
+    /// A temp file of an interrupted save
+    OrphanedTempFile { path: PathBuf, len: u64 },
+}
+
+impl Issue {
+    /// Can [Advisory::auto_upgrade] fix the issue
+    pub fn is_fixable(&self) -> bool {
+        matches!(self, Issue::OrphanedTempFile { .. })
+    }
+
+    /// What an operator should do
+    pub fn suggestion(&self) -> &'static str {
+        match self {
+            Issue::MissingFile { .. } => "Save the data set into the store",
+            Issue::UnalignedFile { .. } => "Save the data set again, the file is corrupted",
+            Issue::InvalidHeader { .. } => {
+                "Save the data set again, the file is foreign or incomplete"
+            }
+            Issue::OrphanedTempFile { .. } => "Remove the temp file to free the space",
+        }
+    }
+}
+
+/// Result of [LocalStore::advise]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Advisory {
+    pub issues: Vec<Issue>,
+}
+
+impl Advisory {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Fixes the issues which don't need a new download and returns the rest
+    pub fn auto_upgrade(self) -> io::Result<Advisory> {
+        let mut rest = Vec::new();
+
+        for issue in self.issues {
+            match issue {
+                Issue::OrphanedTempFile { path, .. } => {
+                    tracing::info!("Removing orphaned temp file '{}'", path.display());
+                    remove_file(&path)?;
+                }
+                issue => rest.push(issue),
+            }
+        }
+
+        Ok(Advisory { issues: rest })
+    }
+}
+
+impl LocalStore {
+    /// Inspects the store file and its temp file
+    pub fn advise(&self) -> io::Result<Advisory> {
+        let mut issues = Vec::new();
+
+        match len(&self.file_path)? {
+            None => issues.push(Issue::MissingFile {
+                path: self.file_path.clone(),
+            }),
+            Some(_) if self.header => match self.header() {
+                Ok(_) => (),
+                Err(LocalStoreError::InvalidHeader { path, reason }) => {
+                    issues.push(Issue::InvalidHeader { path, reason })
+                }
+                Err(LocalStoreError::Io(e)) => return Err(e),
+                Err(e) => return Err(io::Error::other(e)),
+            },
+            Some(len) if self.format.records_in(len).is_none() => {
+                issues.push(Issue::UnalignedFile {
+                    path: self.file_path.clone(),
+                    len,
+                })
+            }
+            Some(_) => (),
+        }
+
+        let path = self.temp_path();
+        if let Some(len) = len(&path)? {
+            issues.push(Issue::OrphanedTempFile { path, len });
+        }
+
+        Ok(Advisory { issues })
+    }
+}
+
+fn len(path: &Path) -> io::Result<Option<u64>> {
+    match metadata(path) {
+        Ok(m) => Ok(Some(m.len())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::{env::temp_dir, fs::{create_dir_all, write}};
+
+    use super::*;
+
+    fn store(dir: &str) -> LocalStore {
+        let dir = temp_dir().join(dir);
+        create_dir_all(&dir).unwrap();
+
+        LocalStore::builder(dir.join("pwned")).without_header().build().unwrap()
+    }
+
+    #[test]
+    fn missing_file() {
+        let store = store("pwned_pwd_advisor_missing");
+        let _ = remove_file(&store.file_path);
+
+        let advisory = store.advise().unwrap();
+        assert_eq!(vec![Issue::MissingFile { path: store.file_path.clone() }], advisory.issues);
+        assert!(!advisory.issues[0].is_fixable());
+    }
+
+    #[test]
+    fn orphaned_temp_file() {
+        let store = store("pwned_pwd_advisor_orphaned");
+        let temp_path = store.file_path.with_file_name("download_tmp");
+        write(&store.file_path, [0u8; 41]).unwrap();
+        write(&temp_path, [0u8; 20]).unwrap();
+
+        let advisory = store.advise().unwrap();
+        assert_eq!(vec![
+            Issue::UnalignedFile { path: store.file_path.clone(), len: 41 },
+            Issue::OrphanedTempFile { path: temp_path.clone(), len: 20 },
+        ], advisory.issues);
+
+        let rest = advisory.auto_upgrade().unwrap();
+        assert_eq!(vec![Issue::UnalignedFile { path: store.file_path.clone(), len: 41 }], rest.issues);
+        assert!(!temp_path.exists());
+
+        write(&store.file_path, [0u8; 40]).unwrap();
+        assert!(store.advise().unwrap().is_ok());
+
+        let store = LocalStore { header: true, ..store };
+        assert_eq!(vec![
+            Issue::InvalidHeader { path: store.file_path.clone(), reason: "The file is shorter than the header" },
+        ], store.advise().unwrap().issues);
+    }
+}
@@ -0,0 +1,421 @@
+//! Construction of a [LocalStore]
+//!
+//! Paths are checked once when the store is built, so a misconfigured
+//! deployment fails at startup instead of after a multi-hour download
+
+use std::{
+    fs::metadata,
+    io,
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::Duration,
+};
+
+use crate::{
+    cache::{CacheSize, LookupCache},
+    format::RecordFormat,
+    golomb::GolombStore,
+    index::PrefixIndex,
+    read_only::ReadOnlyStore,
+    sharded::{ShardedStore, MAX_SHARD_BITS},
+    versioned::VersionedStore,
+    ExistenceBehaviour, LocalStore, LocalStoreError, Preallocation,
+};
+
+/// Builder of a [LocalStore], see [LocalStore::builder]
+#[derive(Debug, Clone)]
+pub struct LocalStoreBuilder {
+    file_path: PathBuf,
+    existence_behaviour: ExistenceBehaviour,
+    buff_capacity: Option<usize>,
+    format: RecordFormat,
+    header: bool,
+    index: bool,
+    sync: bool,
+    preallocation: Preallocation,
+    checkpoint_interval: Option<Duration>,
+    direct_io: bool,
+    reload_interval: Option<Duration>,
+    cache: Option<CacheSize>,
+}
+
+impl LocalStoreBuilder {
+    pub(crate) fn new(file_path: PathBuf) -> Self {
+        Self {
+            file_path,
+            existence_behaviour: Default::default(),
+            buff_capacity: None,
+            format: Default::default(),
+            header: true,
+            index: false,
+            sync: true,
+            preallocation: Default::default(),
+            checkpoint_interval: None,
+            direct_io: false,
+            reload_interval: None,
+            cache: None,
+        }
+    }
+
+    pub fn with_existence_behaviour(mut self, existence_behaviour: ExistenceBehaviour) -> Self {
+        self.existence_behaviour = existence_behaviour;
+        self
+    }
+
+    /// Capacity of the write buffer, 8 KiB by default
+    pub fn with_buff_capacity(mut self, buff_capacity: usize) -> Self {
+        self.buff_capacity = Some(buff_capacity.max(1));
+        self
+    }
+
+    /// Format of the records, [RecordFormat::Hashes] by default
+    pub fn with_format(mut self, format: RecordFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// The file is bare records without a [crate::header::FileHeader],
+    /// like files saved before headers were introduced
+    pub fn without_header(mut self) -> Self {
+        self.header = false;
+        self
+    }
+
+    /// Keep a [PrefixIndex] in memory to search a hash only among the hashes of its prefix.
+    /// A store of [RecordFormat::Suffixes] always keeps it, loading it from the file.
+    /// The index is built by [LocalStoreBuilder::build] and after every change of the file,
+    /// each time it takes a read of the whole file
+    pub fn with_index(mut self) -> Self {
+        self.index = true;
+        self
+    }
+
+    /// Don't sync a saved file to the disk before it replaces the store file.
+    /// A save is faster, but a power loss can leave a truncated store file
+    pub fn without_sync(mut self) -> Self {
+        self.sync = false;
+        self
+    }
+
+    /// Disk space reserved by a save, [Preallocation::CurrentLen] by default.
+    /// Only Linux reserves the space, on other systems it is ignored
+    pub fn with_preallocation(mut self, preallocation: Preallocation) -> Self {
+        self.preallocation = preallocation;
+        self
+    }
+
+    /// A save records how far it got at most once per `interval`, so an interrupted save
+    /// can be continued by [LocalStore::resume]. A store of [RecordFormat::Suffixes]
+    /// keeps the counts of the prefixes in memory until the end, its saves aren't resumable
+    pub fn with_checkpoints(mut self, interval: Duration) -> Self {
+        self.checkpoint_interval = Some(interval);
+        self
+    }
+
+    /// Lookups read the file with direct I/O (`O_DIRECT`) bypassing the page cache,
+    /// so a large data set doesn't evict the cache of other services on a shared host.
+    /// Every lookup reads the disk, which is slower, unless the store has a cache
+    /// (see [LocalStoreBuilder::with_cache]). Only Linux supports it, and not every
+    /// filesystem: a lookup fails, if the file can't be opened for direct I/O
+    pub fn with_direct_io(mut self) -> Self {
+        self.direct_io = true;
+        self
+    }
+
+    /// Lookups check at most once per `interval`, whether the store file was replaced
+    /// by another process, and switch to the new file. Without it a replaced file
+    /// is picked up only by [LocalStore::reload]
+    pub fn with_reload_interval(mut self, interval: Duration) -> Self {
+        self.reload_interval = Some(interval);
+        self
+    }
+
+    /// Keep the results of the recent lookups in memory, so repeated lookups skip the disk.
+    /// The cache is cleared when the file changes. Every shard of a sharded store has its own cache
+    pub fn with_cache(mut self, size: CacheSize) -> Self {
+        self.cache = Some(size);
+        self
+    }
+
+    /// Checks that the store file can be created, the download path
+    /// can be renamed into it and an existing file has a valid header
+    pub fn build(self) -> Result<LocalStore, LocalStoreError> {
+        self.check_paths()?;
+
+        let store = LocalStore {
+            file_path: self.file_path,
+            existence_behaviour: self.existence_behaviour,
+            buff_capacity: self.buff_capacity,
+            format: self.format,
+            header: self.header,
+            index: (self.index || !self.format.is_flat())
+                .then(|| RwLock::new(PrefixIndex::empty())),
+            sync: self.sync,
+            preallocation: self.preallocation,
+            checkpoint_interval: self.checkpoint_interval,
+            direct_io: self.direct_io,
+            cache: self.cache.map(LookupCache::new),
+            reload_interval: self.reload_interval,
+            handle: Default::default(),
+        };
+
+        if store.file_path.exists() {
+            store.header()?;
+        }
+
+        store.refresh()?;
+        Ok(store)
+    }
+
+    fn check_paths(&self) -> Result<(), LocalStoreError> {
+        check_file(&self.file_path)?;
+
+        if let ExistenceBehaviour::DownloadThenReplace {
+            download_path: Some(download_path),
+        } = &self.existence_behaviour
+        {
+            check_file(download_path)?;
+
+            if download_path == &self.file_path {
+                return Err(invalid(
+                    download_path,
+                    "The download path is the store file",
+                ));
+            }
+
+            if !same_device(dir_of(download_path), dir_of(&self.file_path))? {
+                return Err(invalid(
+                    download_path,
+                    "The download path must be on the mountpoint of the store file",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl LocalStoreBuilder {
+    /// Builds a [ShardedStore] of `2^shard_bits` files in the directory at the path
+    /// of the builder, `shard_bits` is clamped to [MAX_SHARD_BITS].
+    /// Every shard is a store with the settings of the builder, a download path
+    /// of a shard is the shard file with the `download` extension
+    pub fn build_sharded(self, shard_bits: u8) -> Result<ShardedStore, LocalStoreError> {
+        let shard_bits = shard_bits.min(MAX_SHARD_BITS);
+        let dir = self.file_path.clone();
+        if !metadata(&dir)?.is_dir() {
+            return Err(invalid(&dir, "The path is not a directory"));
+        }
+
+        let shards = (0..1usize << shard_bits)
+            .map(|shard| {
+                let file_path = dir.join(ShardedStore::shard_name(shard_bits, shard));
+                let existence_behaviour = match &self.existence_behaviour {
+                    ExistenceBehaviour::DownloadThenReplace { .. } => {
+                        ExistenceBehaviour::DownloadThenReplace {
+                            download_path: Some(file_path.with_extension("download")),
+                        }
+                    }
+                    behaviour => behaviour.clone(),
+                };
+
+                self.for_file(file_path, existence_behaviour).build()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ShardedStore::new(shard_bits, shards))
+    }
+
+    /// Builds a store which only looks up the file at the path. The file must exist,
+    /// have a valid header, if the builder expects one, and hold whole records.
+    /// The existence behaviour, the buffer capacity and the save settings aren't used
+    pub fn build_read_only(self) -> Result<ReadOnlyStore, LocalStoreError> {
+        let len = match metadata(&self.file_path) {
+            Ok(metadata) if metadata.is_dir() => {
+                return Err(invalid(&self.file_path, "The path is a directory"))
+            }
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(invalid(&self.file_path, "The file doesn't exist"))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let store = Self {
+            existence_behaviour: ExistenceBehaviour::RemoveOldThenCreateNew,
+            ..self
+        }
+        .build()?;
+
+        let whole = len
+            .checked_sub(store.offset())
+            .and_then(|len| store.format.records_in(len))
+            .is_some();
+        if !whole {
+            return Err(LocalStoreError::InvalidHeader {
+                path: store.file_path,
+                reason: "The file isn't a whole number of records",
+            });
+        }
+
+        Ok(ReadOnlyStore::new(store))
+    }
+
+    /// Builds a [VersionedStore] of the versions of the file at the path of the builder,
+    /// `retain` versions before the current one are kept after a save.
+    /// Every version is a store with the settings of the builder, which is written in place
+    pub fn build_versioned(self, retain: usize) -> Result<VersionedStore, LocalStoreError> {
+        self.check_paths()?;
+
+        let path = self.file_path.clone();
+        VersionedStore::new(self, &path, retain)
+    }
+
+    /// The settings of the builder for another file
+    pub(crate) fn for_file(
+        &self,
+        file_path: PathBuf,
+        existence_behaviour: ExistenceBehaviour,
+    ) -> Self {
+        Self {
+            file_path,
+            existence_behaviour,
+            ..self.clone()
+        }
+    }
+
+    /// Builds a [GolombStore] with the false-positive rate of 1 in `2^false_positive_bits`,
+    /// `false_positive_bits` is clamped to [golomb::MAX_FALSE_POSITIVE_BITS].
+    /// The format, the header, the index and the buffer capacity of the builder aren't used
+    pub fn build_golomb(self, false_positive_bits: u8) -> Result<GolombStore, LocalStoreError> {
+        self.check_paths()?;
+
+        let temp_path = match self.existence_behaviour {
+            ExistenceBehaviour::RemoveOldThenCreateNew => None,
+            ExistenceBehaviour::DownloadThenReplace { download_path } => {
+                Some(download_path.unwrap_or_else(|| self.file_path.with_file_name("download_tmp")))
+            }
+        };
+
+        Ok(GolombStore::new(
+            self.file_path,
+            temp_path,
+            false_positive_bits,
+            self.sync,
+        ))
+    }
+}
+
+fn invalid(path: &Path, reason: &'static str) -> LocalStoreError {
+    LocalStoreError::InvalidPath {
+        path: path.to_path_buf(),
+        reason,
+    }
+}
+
+pub(crate) fn dir_of(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// The file may be absent, but its directory must exist
+fn check_file(path: &Path) -> Result<(), LocalStoreError> {
+    match metadata(path) {
+        Ok(m) if m.is_dir() => return Err(invalid(path, "The path is a directory")),
+        Ok(_) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    match metadata(dir_of(path)) {
+        Ok(m) if m.is_dir() => Ok(()),
+        Ok(_) => Err(invalid(path, "The parent is not a directory")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Err(invalid(path, "The parent directory doesn't exist"))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(unix)]
+fn same_device(left: &Path, right: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(metadata(left)?.dev() == metadata(right)?.dev())
+}
+
+/// A rename between volumes fails at the end of a save, it can't be checked here
+#[cfg(not(unix))]
+fn same_device(_: &Path, _: &Path) -> io::Result<bool> {
+    Ok(true)
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use super::*;
+
+    #[test]
+    fn build() {
+        let dir = temp_dir().join("pwned_pwd_builder");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let store = LocalStore::builder(dir.join("pwned"))
+            .with_existence_behaviour(ExistenceBehaviour::DownloadThenReplace { download_path: Some(dir.join("download")) })
+            .with_buff_capacity(0)
+            .build()
+            .unwrap();
+
+        assert_eq!(dir.join("pwned"), store.file_path());
+        assert_eq!(Some(1), store.buff_capacity);
+        assert!(store.sync);
+        assert!(!LocalStore::builder(dir.join("pwned")).without_sync().build().unwrap().sync);
+        assert_eq!(dir.join("download"), store.temp_path());
+    }
+
+    #[tokio::test]
+    async fn indexed() {
+        use hex_literal::hex;
+        use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+        use pwned_pwd_store::{ReadStore, WriteStore};
+
+        let dir = temp_dir().join("pwned_pwd_builder_indexed");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("pwned"), hex!("
+            21BD4004DDDC80AE4683948C5A1C5903584D8087
+            21BD5004DDDC80AE4683948C5A1C5903584D8087
+        ")).unwrap();
+
+        let store = LocalStore::builder(dir.join("pwned")).without_header().with_index().build().unwrap();
+        assert!(store.exists(hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert!(!store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8088")).await.unwrap());
+
+        store.save(futures::stream::iter(vec![Chunk { prefix: Prefix::create(0x00000).unwrap(), passwords: vec![
+            PwnedPwd { hash: hex!("0000000C53D0B33029D7FE4FB08D3D1C9832D2ED"), count: 1 },
+        ]}])).await.unwrap();
+
+        assert!(store.exists(hex!("0000000C53D0B33029D7FE4FB08D3D1C9832D2ED")).await.unwrap());
+        assert!(!store.exists(hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+    }
+
+    #[test]
+    fn invalid_paths() {
+        let dir = temp_dir().join("pwned_pwd_builder_invalid");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let reason = |builder: LocalStoreBuilder| match builder.build() {
+            Err(LocalStoreError::InvalidPath { reason, .. }) => reason,
+            _ => panic!("The path must be invalid"),
+        };
+
+        assert_eq!("The path is a directory", reason(LocalStore::builder(dir.clone())));
+        assert_eq!("The parent directory doesn't exist", reason(LocalStore::builder(dir.join("missing/pwned"))));
+        assert_eq!("The download path is the store file", reason(
+            LocalStore::builder(dir.join("pwned"))
+                .with_existence_behaviour(ExistenceBehaviour::DownloadThenReplace { download_path: Some(dir.join("pwned")) })
+        ));
+    }
+}
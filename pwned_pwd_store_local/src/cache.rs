@@ -0,0 +1,87 @@
+//! Cache of recent lookups
+//!
+//! Common passwords are checked again and again, for example by retried or bursty signups.
+//! [LookupCache] keeps the results of the recent lookups, found or not, so these
+//! skip the disk. The cache is cleared whenever the store file changes
+
+use std::{mem::size_of, num::NonZeroUsize, sync::Mutex};
+
+use lru::LruCache;
+
+/// Size of a [LookupCache], see [crate::builder::LocalStoreBuilder::with_cache]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheSize {
+    /// Count of cached hashes
+    Entries(usize),
+
+    /// Approximate memory used by the cache
+    Bytes(usize),
+}
+
+impl CacheSize {
+    /// Memory of an entry: the hash, the result and two links of the LRU list
+    const ENTRY_BYTES: usize =
+        size_of::<[u8; 20]>() + size_of::<Option<u32>>() + 2 * size_of::<usize>();
+
+    /// Count of cached hashes, at least one
+    pub fn entries(&self) -> NonZeroUsize {
+        let entries = match *self {
+            Self::Entries(entries) => entries,
+            Self::Bytes(bytes) => bytes / Self::ENTRY_BYTES,
+        };
+        NonZeroUsize::new(entries).unwrap_or(NonZeroUsize::MIN)
+    }
+}
+
+/// The least recently used lookups are evicted first
+pub(crate) struct LookupCache {
+    entries: Mutex<LruCache<[u8; 20], Option<u32>>>,
+}
+
+impl LookupCache {
+    pub(crate) fn new(size: CacheSize) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(size.entries())),
+        }
+    }
+
+    /// The result of a cached lookup: None, if the hash isn't cached,
+    /// Some(None), if the hash wasn't found
+    pub(crate) fn get(&self, val: &[u8; 20]) -> Option<Option<u32>> {
+        self.entries.lock().unwrap().get(val).copied()
+    }
+
+    pub(crate) fn put(&self, val: [u8; 20], found: Option<u32>) {
+        self.entries.lock().unwrap().put(val, found);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts() {
+        assert_eq!(100, CacheSize::Entries(100).entries().get());
+        assert_eq!(1, CacheSize::Bytes(0).entries().get());
+        assert_eq!(1024 / CacheSize::ENTRY_BYTES, CacheSize::Bytes(1024).entries().get());
+
+        let cache = LookupCache::new(CacheSize::Entries(2));
+        cache.put([1; 20], Some(3));
+        cache.put([2; 20], None);
+        assert_eq!(Some(Some(3)), cache.get(&[1; 20]));
+
+        cache.put([3; 20], Some(1));
+        assert_eq!(None, cache.get(&[2; 20]));
+        assert_eq!(Some(Some(3)), cache.get(&[1; 20]));
+        assert_eq!(Some(Some(1)), cache.get(&[3; 20]));
+
+        cache.clear();
+        assert_eq!(None, cache.get(&[1; 20]));
+    }
+}
@@ -0,0 +1,150 @@
+//! Checkpoints of a save
+//!
+//! A save of the whole data set takes hours. With [crate::builder::LocalStoreBuilder::with_checkpoints]
+//! the save periodically records how far it got into a sidecar file next to the written file,
+//! and [crate::LocalStore::resume] truncates an interrupted file to the checkpoint and continues
+//! after its prefix instead of starting over
+
+use std::{
+    fs::{read_to_string, remove_file, rename, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use pwned_pwd_core::{Prefix, PrefixStr};
+
+/// The state of a save after a completely written prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// The last prefix which was completely written, a resumed save continues after it
+    pub last_prefix: Prefix,
+
+    /// Bytes of the records written up to the end of the prefix
+    pub written: u64,
+
+    pub records: u64,
+
+    /// The checksum of the written records
+    pub(crate) checksum: u64,
+}
+
+impl Checkpoint {
+    /// The sidecar of the written file
+    pub(crate) fn path(file: &Path) -> PathBuf {
+        let mut name = file.file_name().unwrap_or_default().to_os_string();
+        name.push(".checkpoint");
+        file.with_file_name(name)
+    }
+
+    /// Reads the checkpoint of the written file, None if there is no checkpoint
+    pub(crate) fn read(file: &Path) -> io::Result<Option<Self>> {
+        let line = match read_to_string(Self::path(file)) {
+            Ok(line) => line,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        Self::parse(&line)
+            .map(Some)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid checkpoint"))
+    }
+
+    /// `PREFIX WRITTEN RECORDS CHECKSUM`, the checksum is in hex
+    fn parse(line: &str) -> Option<Self> {
+        let mut parts = line.split_ascii_whitespace();
+        let checkpoint = Self {
+            last_prefix: parts.next()?.parse().ok()?,
+            written: parts.next()?.parse().ok()?,
+            records: parts.next()?.parse().ok()?,
+            checksum: u64::from_str_radix(parts.next()?, 16).ok()?,
+        };
+        parts.next().is_none().then_some(checkpoint)
+    }
+
+    /// Replaces the checkpoint of the written file atomically
+    pub(crate) fn write(&self, file: &Path, sync: bool) -> io::Result<()> {
+        let path = Self::path(file);
+        let temp = path.with_extension("checkpoint.tmp");
+
+        let mut out = File::create(&temp)?;
+        writeln!(
+            out,
+            "{} {} {} {:016x}",
+            PrefixStr::from(&self.last_prefix).as_ref(),
+            self.written,
+            self.records,
+            self.checksum
+        )?;
+        if sync {
+            out.sync_all()?;
+        }
+        drop(out);
+
+        rename(&temp, &path)
+    }
+
+    /// Removes the checkpoint of the written file, if there is one
+    pub(crate) fn remove(file: &Path) -> io::Result<()> {
+        match remove_file(Self::path(file)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// When a save writes the next checkpoint
+#[derive(Debug)]
+pub(crate) struct Checkpoints {
+    interval: Duration,
+    last: Instant,
+}
+
+impl Checkpoints {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: Instant::now(),
+        }
+    }
+
+    /// Is the next checkpoint due, the time is reset if it is
+    pub(crate) fn due(&mut self) -> bool {
+        if self.last.elapsed() < self.interval {
+            return false;
+        }
+
+        self.last = Instant::now();
+        true
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use super::*;
+
+    #[test]
+    fn write_read() {
+        let dir = temp_dir().join("pwned_pwd_checkpoint");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("pwned.bin");
+
+        assert_eq!(dir.join("pwned.bin.checkpoint"), Checkpoint::path(&file));
+        Checkpoint::remove(&file).unwrap();
+        assert_eq!(None, Checkpoint::read(&file).unwrap());
+
+        let checkpoint = Checkpoint { last_prefix: Prefix::create(0x21BD4).unwrap(), written: 40, records: 2, checksum: 0xABCDEF };
+        checkpoint.write(&file, false).unwrap();
+        assert_eq!("21BD4 40 2 0000000000abcdef\n", std::fs::read_to_string(Checkpoint::path(&file)).unwrap());
+        assert_eq!(Some(checkpoint), Checkpoint::read(&file).unwrap());
+
+        assert_eq!(None, Checkpoint::parse("21BD4 40 2"));
+        assert_eq!(None, Checkpoint::parse("21BD4 40 2 0 0"));
+
+        Checkpoint::remove(&file).unwrap();
+        assert_eq!(None, Checkpoint::read(&file).unwrap());
+    }
+}
@@ -0,0 +1,310 @@
+//! On-disk formats of records
+//!
+//! A file of bare hashes is the smallest one, but it can't answer how many times
+//! a password was breached. [RecordFormat::HashesWithCounts] keeps the count
+//! next to the hash for threshold-based policies at the cost of 20% more space.
+//! [RecordFormat::Suffixes] drops the prefix bits shared by the hashes of a prefix
+//! and keeps a directory of the prefixes instead
+
+use std::{
+    fmt,
+    io::{self, Read},
+    str::FromStr,
+};
+
+use pwned_pwd_core::{Prefix, PwnedPwd, Suffix};
+
+use crate::index::PrefixIndex;
+
+/// How a record of a [crate::LocalStore] file is laid out.
+/// A file must be opened with the format it was saved with, the header rejects another one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecordFormat {
+    /// A 20-byte hash, counts are dropped on save
+    #[default]
+    Hashes,
+
+    /// A 20-byte hash followed by its big-endian u32 count
+    HashesWithCounts,
+
+    /// An 18-byte [Suffix] of a hash, counts are dropped on save. The records are
+    /// followed by a directory of record counts of every prefix (4 MiB), so the file
+    /// is ~10% smaller than [RecordFormat::Hashes] and a lookup always searches only
+    /// among the records of its prefix
+    Suffixes,
+}
+
+impl RecordFormat {
+    pub const HASH_LEN: usize = 20;
+
+    pub const SUFFIX_LEN: usize = 18;
+
+    pub const fn record_len(&self) -> u64 {
+        match self {
+            RecordFormat::Hashes => Self::HASH_LEN as u64,
+            RecordFormat::HashesWithCounts => Self::HASH_LEN as u64 + 4,
+            RecordFormat::Suffixes => Self::SUFFIX_LEN as u64,
+        }
+    }
+
+    /// The format of records of the length, formats have records of different lengths
+    pub const fn of_record_len(len: u64) -> Option<Self> {
+        match len {
+            20 => Some(RecordFormat::Hashes),
+            24 => Some(RecordFormat::HashesWithCounts),
+            18 => Some(RecordFormat::Suffixes),
+            _ => None,
+        }
+    }
+
+    /// Length of the part of a record which is compared by a search
+    pub const fn key_len(&self) -> usize {
+        match self {
+            RecordFormat::Hashes | RecordFormat::HashesWithCounts => Self::HASH_LEN,
+            RecordFormat::Suffixes => Self::SUFFIX_LEN,
+        }
+    }
+
+    /// Stable name of the format, the string form of [Display](fmt::Display) and [FromStr]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            RecordFormat::Hashes => "hashes",
+            RecordFormat::HashesWithCounts => "hashes-with-counts",
+            RecordFormat::Suffixes => "suffixes",
+        }
+    }
+
+    pub const fn has_counts(&self) -> bool {
+        matches!(self, RecordFormat::HashesWithCounts)
+    }
+
+    /// Do the records follow each other without a prefix directory,
+    /// so hashes are ordered across the whole file
+    pub const fn is_flat(&self) -> bool {
+        !matches!(self, RecordFormat::Suffixes)
+    }
+
+    /// Length of the prefix directory after the records
+    pub fn directory_len(&self) -> u64 {
+        if self.is_flat() {
+            0
+        } else {
+            PrefixIndex::directory_len()
+        }
+    }
+
+    /// Records in data of the length or None, if the length doesn't fit the format
+    pub fn records_in(&self, len: u64) -> Option<u64> {
+        let records = len.checked_sub(self.directory_len())?;
+        (records % self.record_len() == 0).then(|| records / self.record_len())
+    }
+
+    pub(crate) fn encode<'a>(&self, pwd: &PwnedPwd, buf: &'a mut [u8; 24]) -> &'a [u8] {
+        match self {
+            RecordFormat::Suffixes => {
+                buf[..Self::SUFFIX_LEN].copy_from_slice(Suffix::from_sha1(&pwd.hash).as_bytes())
+            }
+            _ => {
+                buf[..Self::HASH_LEN].copy_from_slice(&pwd.hash);
+                buf[Self::HASH_LEN..].copy_from_slice(&pwd.count.to_be_bytes());
+            }
+        }
+        &buf[..self.record_len() as usize]
+    }
+
+    /// Reads the next record or None at the end of data.
+    /// Counts are 0, if the format doesn't keep them.
+    /// Suffixes can't be read without their prefixes, see [Records]
+    pub(crate) fn read<T: Read>(&self, data: &mut T) -> io::Result<Option<PwnedPwd>> {
+        if !self.is_flat() {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+
+        let mut buf = [0u8; 24];
+        let buf = &mut buf[..self.record_len() as usize];
+        if !read_record(data, buf)? {
+            return Ok(None);
+        }
+
+        let (hash, count) = buf.split_at(Self::HASH_LEN);
+        Ok(Some(PwnedPwd {
+            hash: hash.try_into().expect("Hash is 20 bytes"),
+            count: count.try_into().map_or(0, u32::from_be_bytes),
+        }))
+    }
+}
+
+impl fmt::Display for RecordFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A string which isn't a [RecordFormat::name]
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Unknown record format '{0}'")]
+pub struct UnknownFormat(pub String);
+
+impl FromStr for RecordFormat {
+    type Err = UnknownFormat;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        [
+            RecordFormat::Hashes,
+            RecordFormat::HashesWithCounts,
+            RecordFormat::Suffixes,
+        ]
+        .into_iter()
+        .find(|format| format.name() == value)
+        .ok_or_else(|| UnknownFormat(value.to_owned()))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for RecordFormat {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for RecordFormat {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Sequential reader of the records of a file in any format
+pub(crate) struct Records<T> {
+    data: T,
+    format: RecordFormat,
+
+    /// The directory of a file of suffixes
+    index: Option<PrefixIndex>,
+    record: u64,
+    prefix: Prefix,
+}
+
+impl<T: Read> Records<T> {
+    /// `index` is required, if the format isn't flat
+    pub(crate) fn new(data: T, format: RecordFormat, index: Option<PrefixIndex>) -> Self {
+        Self {
+            data,
+            format,
+            index,
+            record: 0,
+            prefix: Prefix::create(0).expect("0 is a prefix"),
+        }
+    }
+
+    /// Reads the next record or None after the last one
+    pub(crate) fn read(&mut self) -> io::Result<Option<PwnedPwd>> {
+        let Some(index) = &self.index else {
+            return self.format.read(&mut self.data);
+        };
+
+        if self.record >= index.records() {
+            return Ok(None);
+        }
+
+        while index.range(self.prefix).end <= self.record {
+            self.prefix = self.prefix.next().ok_or(io::ErrorKind::InvalidData)?;
+        }
+
+        let mut buf = [0u8; RecordFormat::SUFFIX_LEN];
+        self.data.read_exact(&mut buf)?;
+        let suffix = Suffix::from_bytes(buf).ok_or(io::ErrorKind::InvalidData)?;
+        self.record += 1;
+
+        Ok(Some(PwnedPwd {
+            hash: self.prefix.with_suffix(&suffix),
+            count: 0,
+        }))
+    }
+}
+
+/// Fills the buffer. Returns false at the end of data
+fn read_record<T: Read>(data: &mut T, buf: &mut [u8]) -> io::Result<bool> {
+    let mut read = 0;
+
+    while read < buf.len() {
+        match data.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::io::Cursor;
+
+    use hex_literal::hex;
+
+    use super::*;
+
+    #[test]
+    fn write_read() {
+        let pwd = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 0x01020304 };
+
+        let mut data = Vec::new();
+        data.extend_from_slice(RecordFormat::HashesWithCounts.encode(&pwd, &mut [0; 24]));
+        data.extend_from_slice(RecordFormat::Hashes.encode(&pwd, &mut [0; 24]));
+
+        assert_eq!(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087 01020304 21BD4004DDDC80AE4683948C5A1C5903584D8087").as_slice(), data.as_slice());
+
+        let mut cursor = Cursor::new(data);
+        assert_eq!(Some(pwd.clone()), RecordFormat::HashesWithCounts.read(&mut cursor).unwrap());
+        assert_eq!(Some(PwnedPwd { count: 0, ..pwd }), RecordFormat::Hashes.read(&mut cursor).unwrap());
+        assert_eq!(None, RecordFormat::Hashes.read(&mut cursor).unwrap());
+    }
+
+    #[test]
+    fn suffixes() {
+        let hashes = [
+            hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"),
+            hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"),
+            hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087"),
+        ];
+
+        let mut data = Vec::new();
+        let mut counts = vec![0u32; PrefixIndex::directory_len() as usize / 4];
+        for hash in hashes {
+            data.extend_from_slice(RecordFormat::Suffixes.encode(&PwnedPwd { hash, count: 7 }, &mut [0; 24]));
+            counts[u32::from(Prefix::from_sha1(&hash)) as usize] += 1;
+        }
+        assert_eq!(hex!("00 04DDDC80AE4683948C5A1C5903584D8087").as_slice(), &data[..18]);
+        assert_eq!(None, RecordFormat::Suffixes.records_in(data.len() as u64));
+
+        let index = PrefixIndex::from_counts(counts);
+        let mut records = Records::new(Cursor::new(data), RecordFormat::Suffixes, Some(index));
+        for hash in hashes {
+            assert_eq!(Some(PwnedPwd { hash, count: 0 }), records.read().unwrap());
+        }
+        assert_eq!(None, records.read().unwrap());
+    }
+
+    #[test]
+    fn names() {
+        for format in [RecordFormat::Hashes, RecordFormat::HashesWithCounts, RecordFormat::Suffixes] {
+            assert_eq!(Ok(format), format.to_string().parse());
+        }
+        assert_eq!("hashes-with-counts", RecordFormat::HashesWithCounts.to_string());
+        assert_eq!(Err(UnknownFormat("Hashes".to_owned())), "Hashes".parse::<RecordFormat>());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        assert_eq!(r#""suffixes""#, serde_json::to_string(&RecordFormat::Suffixes).unwrap());
+        assert_eq!(RecordFormat::HashesWithCounts, serde_json::from_str::<RecordFormat>(r#""hashes-with-counts""#).unwrap());
+        assert!(serde_json::from_str::<RecordFormat>(r#""bloom""#).is_err());
+    }
+}
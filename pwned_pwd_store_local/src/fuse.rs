@@ -0,0 +1,701 @@
+//! A binary fuse filter of hashes
+//!
+//! [FuseStore] keeps a fingerprint of every hash in a binary fuse filter (Graf and Lemire, 2022):
+//! a hash maps to three slots of a table and is found when the xor of their fingerprints
+//! equals its own fingerprint. The table has ~1.13 slots per hash, so with
+//! [Fingerprint::U16] a hash takes ~18 bits and the false-positive rate is 1 in 65536,
+//! with [Fingerprint::U8] it takes ~9 bits and the rate is 1 in 256.
+//! Unlike [crate::golomb] the filter can't be written from a stream: it is built offline
+//! from a completed [LocalStore] file by [FuseStore::build], and [FuseStore::open] maps
+//! the file into memory, so a lookup reads three fingerprints and never decodes anything
+//!
+//! The file is a header, a directory of offsets of the filters of every shard and the filters.
+//! Hashes are sharded by their first byte, so the build holds one shard at a time.
+//! A filter is its layout followed by the fingerprints
+
+use std::{
+    fs::{remove_file, File, OpenOptions},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::Stream;
+use memmap2::Mmap;
+use pwned_pwd_core::PwnedPwd;
+use pwned_pwd_store::{ReadStore, StoreMetadata};
+use rand::Rng;
+
+use crate::{header::HEADER_LEN, replace, sync_dir, LocalStore, LocalStoreError};
+
+const MAGIC: [u8; 8] = *b"PWNEDBFF";
+
+/// Hashes are sharded by their first byte
+const SHARDS: usize = 256;
+
+/// Where the filters start, after the offsets of every shard and the end of the last filter
+const FILTERS_OFFSET: u64 = HEADER_LEN + (SHARDS as u64 + 1) * 8;
+
+/// Seeds tried before the build of a filter gives up, a seed fails with a tiny probability
+const MAX_ATTEMPTS: u32 = 100;
+
+/// Width of the fingerprints, it sets the size of the filter and the false-positive rate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Fingerprint {
+    /// ~9 bits per hash, the false-positive rate is 1 in 256
+    U8,
+
+    /// ~18 bits per hash, the false-positive rate is 1 in 65536
+    #[default]
+    U16,
+}
+
+impl Fingerprint {
+    pub fn bits(self) -> u8 {
+        match self {
+            Self::U8 => 8,
+            Self::U16 => 16,
+        }
+    }
+
+    /// The probability to find a hash which isn't in the filter
+    pub fn false_positive_rate(self) -> f64 {
+        0.5f64.powi(i32::from(self.bits()))
+    }
+
+    fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            8 => Some(Self::U8),
+            16 => Some(Self::U16),
+            _ => None,
+        }
+    }
+
+    fn len(self) -> usize {
+        usize::from(self.bits() / 8)
+    }
+
+    fn of(self, hash: u64) -> u16 {
+        let fingerprint = (hash ^ (hash >> 32)) as u16;
+        match self {
+            Self::U8 => fingerprint & 0xFF,
+            Self::U16 => fingerprint,
+        }
+    }
+
+    fn read(self, fingerprints: &[u8], slot: usize) -> u16 {
+        match self {
+            Self::U8 => u16::from(fingerprints[slot]),
+            Self::U16 => u16::from_le_bytes([fingerprints[slot * 2], fingerprints[slot * 2 + 1]]),
+        }
+    }
+}
+
+/// Description of a filter file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FuseHeader {
+    pub version: u16,
+    pub fingerprint: Fingerprint,
+
+    /// Records of the store the filter is built from
+    pub records: u64,
+
+    /// Seconds since the unix epoch when the file was written
+    pub created_at: u64,
+}
+
+impl FuseHeader {
+    pub const VERSION: u16 = 1;
+
+    pub fn created_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.created_at)
+    }
+
+    fn to_bytes(self) -> [u8; HEADER_LEN as usize] {
+        let mut bytes = [0u8; HEADER_LEN as usize];
+        bytes[0..8].copy_from_slice(&MAGIC);
+        bytes[8..10].copy_from_slice(&self.version.to_be_bytes());
+        bytes[10] = self.fingerprint.bits();
+        bytes[16..24].copy_from_slice(&self.records.to_be_bytes());
+        bytes[24..32].copy_from_slice(&self.created_at.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; HEADER_LEN as usize]) -> Result<Self, &'static str> {
+        let u64_at = |i: usize| u64::from_be_bytes(bytes[i..i + 8].try_into().unwrap());
+
+        if bytes[0..8] != MAGIC {
+            return Err("The file has no header");
+        }
+
+        let version = u16::from_be_bytes([bytes[8], bytes[9]]);
+        if version != Self::VERSION {
+            return Err("Unsupported version of the file");
+        }
+
+        Ok(Self {
+            version,
+            fingerprint: Fingerprint::from_bits(bytes[10]).ok_or("Unsupported fingerprint")?,
+            records: u64_at(16),
+            created_at: u64_at(24),
+        })
+    }
+}
+
+/// The key of a hash in a filter, the bytes of a shard differ only after the first one
+fn key(hash: &[u8; 20]) -> u64 {
+    u64::from_be_bytes(hash[12..20].try_into().unwrap())
+}
+
+/// The finalizer of MurmurHash3 over the seeded key
+fn mix(key: u64, seed: u64) -> u64 {
+    let mut h = key.wrapping_add(seed);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+/// Dimensions of the table of a filter and its seed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Layout {
+    seed: u64,
+    segment_length: u32,
+    segment_count_length: u32,
+
+    /// Slots of the table, 0 if the filter is empty
+    array_length: u32,
+}
+
+impl Layout {
+    const LEN: usize = 20;
+
+    /// The layout of a filter of `size` keys from the reference implementation
+    fn new(size: usize) -> io::Result<Self> {
+        if size == 0 {
+            return Ok(Self::default());
+        }
+        if size > u32::MAX as usize / 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Too many hashes in a shard",
+            ));
+        }
+
+        let size_f = size as f64;
+        let segment_length = 1u32 << ((size_f.ln() / 3.33f64.ln() + 2.25).floor() as u32).min(18);
+        let capacity = match size {
+            1 => 0,
+            _ => (size_f * (0.875 + 0.25 * 1e6f64.ln() / size_f.ln()).max(1.125)).round() as u32,
+        };
+
+        let segment_count = capacity.div_ceil(segment_length).saturating_sub(2) + 2;
+        let segment_count = match segment_count {
+            0..=2 => 1,
+            count => count - 2,
+        };
+
+        Ok(Self {
+            seed: 0,
+            segment_length,
+            segment_count_length: segment_count * segment_length,
+            array_length: (segment_count + 2) * segment_length,
+        })
+    }
+
+    fn segment_count(&self) -> u32 {
+        self.segment_count_length / self.segment_length
+    }
+
+    /// The slots of a mixed key, one in each of three consecutive segments
+    fn slots(&self, hash: u64) -> [usize; 3] {
+        let mask = u64::from(self.segment_length - 1);
+        let length = u64::from(self.segment_length);
+        let h0 = ((u128::from(hash) * u128::from(self.segment_count_length)) >> 64) as u64;
+        let h1 = (h0 + length) ^ ((hash >> 18) & mask);
+        let h2 = (h0 + 2 * length) ^ (hash & mask);
+        [h0 as usize, h1 as usize, h2 as usize]
+    }
+
+    fn to_bytes(self) -> [u8; Self::LEN] {
+        let mut bytes = [0u8; Self::LEN];
+        bytes[0..8].copy_from_slice(&self.seed.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.segment_length.to_be_bytes());
+        bytes[12..16].copy_from_slice(&self.segment_count_length.to_be_bytes());
+        bytes[16..20].copy_from_slice(&self.array_length.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; Self::LEN]) -> Self {
+        let u32_at = |i: usize| u32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+        Self {
+            seed: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            segment_length: u32_at(8),
+            segment_count_length: u32_at(12),
+            array_length: u32_at(16),
+        }
+    }
+
+    /// The table matches the segments, so every slot of a key is in it
+    fn is_valid(&self) -> bool {
+        self.array_length == 0
+            || (self.segment_length.is_power_of_two()
+                && self
+                    .segment_count_length
+                    .is_multiple_of(self.segment_length)
+                && u64::from(self.array_length)
+                    == u64::from(self.segment_count_length) + 2 * u64::from(self.segment_length))
+    }
+}
+
+/// Builds the table of the filter of the keys, duplicates take no slots
+fn build_filter(keys: &[u64], fingerprint: Fingerprint) -> io::Result<(Layout, Vec<u16>)> {
+    let size = keys.len();
+    let mut layout = Layout::new(size)?;
+    if size == 0 {
+        return Ok((layout, Vec::new()));
+    }
+
+    let capacity = layout.array_length as usize;
+    let mut block_bits = 1;
+    while (1 << block_bits) < layout.segment_count() {
+        block_bits += 1;
+    }
+    let block = 1usize << block_bits;
+
+    let mut start = vec![0usize; block];
+    // Mixed keys grouped by their segments, then the peeled ones in the order of peeling
+    let mut order = vec![0u64; size + 1];
+    // Which of the three slots of a peeled key is its own
+    let mut own = vec![0u8; size];
+    let mut alone = vec![0u32; capacity];
+    // Keys of a slot (<< 2) and the xor of the indices of the slot among their slots
+    let mut counts = vec![0u8; capacity];
+    // Xor of the keys of a slot, the key itself, when it is alone
+    let mut xors = vec![0u64; capacity];
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..MAX_ATTEMPTS {
+        layout.seed = rng.gen();
+        order.fill(0);
+        order[size] = 1;
+        counts.fill(0);
+        xors.fill(0);
+
+        for (i, start) in start.iter_mut().enumerate() {
+            *start = (i * size) >> block_bits;
+        }
+        for key in keys {
+            let hash = mix(*key, layout.seed);
+            let mut segment = (hash >> (64 - block_bits)) as usize;
+            while order[start[segment]] != 0 {
+                segment = (segment + 1) & (block - 1);
+            }
+            order[start[segment]] = hash;
+            start[segment] += 1;
+        }
+
+        let mut overflow = false;
+        let mut duplicates = 0;
+        for &hash in &order[..size] {
+            let slots = layout.slots(hash);
+            for (i, &slot) in slots.iter().enumerate() {
+                counts[slot] = counts[slot].wrapping_add(4) ^ i as u8;
+                xors[slot] ^= hash;
+            }
+
+            let [h0, h1, h2] = slots;
+            if xors[h0] & xors[h1] & xors[h2] == 0
+                && slots
+                    .iter()
+                    .any(|&slot| xors[slot] == 0 && counts[slot] == 8)
+            {
+                duplicates += 1;
+                for (i, &slot) in slots.iter().enumerate() {
+                    counts[slot] = counts[slot].wrapping_sub(4) ^ i as u8;
+                    xors[slot] ^= hash;
+                }
+            }
+            overflow |= slots.iter().any(|&slot| counts[slot] < 4);
+        }
+        if overflow {
+            continue;
+        }
+
+        let mut queue = 0;
+        for (slot, count) in counts.iter().enumerate() {
+            alone[queue] = slot as u32;
+            queue += usize::from(count >> 2 == 1);
+        }
+
+        let mut peeled = 0;
+        while queue > 0 {
+            queue -= 1;
+            let slot = alone[queue] as usize;
+            if counts[slot] >> 2 != 1 {
+                continue;
+            }
+
+            let hash = xors[slot];
+            let found = counts[slot] & 3;
+            own[peeled] = found;
+            order[peeled] = hash;
+            peeled += 1;
+
+            let slots = layout.slots(hash);
+            for i in [1, 2] {
+                let index = (usize::from(found) + i) % 3;
+                let other = slots[index];
+                alone[queue] = other as u32;
+                queue += usize::from(counts[other] >> 2 == 2);
+                counts[other] = counts[other].wrapping_sub(4) ^ index as u8;
+                xors[other] ^= hash;
+            }
+        }
+
+        if peeled + duplicates != size {
+            continue;
+        }
+
+        let mut fingerprints = vec![0u16; capacity];
+        for (&hash, &found) in order[..peeled].iter().zip(&own).rev() {
+            let slots = layout.slots(hash);
+            let found = usize::from(found);
+            fingerprints[slots[found]] = fingerprint.of(hash)
+                ^ fingerprints[slots[(found + 1) % 3]]
+                ^ fingerprints[slots[(found + 2) % 3]];
+        }
+        return Ok((layout, fingerprints));
+    }
+
+    Err(io::Error::other("No seed lets the filter be built"))
+}
+
+/// The filter of a shard in the mapped file
+struct Shard {
+    layout: Layout,
+    fingerprints: Range<usize>,
+}
+
+/// A read-only filter of hashes with false positives, see the [module](self) docs
+pub struct FuseStore {
+    file_path: PathBuf,
+    header: FuseHeader,
+    map: Mmap,
+    shards: Vec<Shard>,
+}
+
+impl FuseStore {
+    /// Builds the filter of the store file into `path`, the file is replaced when the build completes.
+    /// Records of the store are read in order, a file whose header doesn't match its records isn't complete.
+    /// The build is blocking, it holds ~24 bytes per hash of the largest shard
+    pub fn build(
+        store: &LocalStore,
+        path: impl AsRef<Path>,
+        fingerprint: Fingerprint,
+    ) -> Result<FuseHeader, LocalStoreError> {
+        let path = path.as_ref();
+        let expected = store.header()?.map(|header| header.records);
+        let mut records = store
+            .open_records()?
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+
+        let temp_path = path.with_extension("tmp");
+        if temp_path.exists() {
+            remove_file(&temp_path)?;
+        }
+        let mut file = BufWriter::new(
+            OpenOptions::new()
+                .create_new(true)
+                .write(true)
+                .open(&temp_path)?,
+        );
+
+        // Placeholders, the header and the directory are written when the build completes
+        file.write_all(&[0u8; FILTERS_OFFSET as usize])?;
+
+        let mut directory = vec![FILTERS_OFFSET; SHARDS + 1];
+        let mut offset = FILTERS_OFFSET;
+        let mut keys = Vec::new();
+        let mut shard = 0;
+        let mut count = 0;
+
+        let write_shard = |keys: &mut Vec<u64>, file: &mut BufWriter<File>| -> io::Result<u64> {
+            let (layout, fingerprints) = build_filter(keys, fingerprint)?;
+            keys.clear();
+
+            file.write_all(&layout.to_bytes())?;
+            for f in &fingerprints {
+                file.write_all(&f.to_le_bytes()[..fingerprint.len()])?;
+            }
+            Ok((Layout::LEN + fingerprints.len() * fingerprint.len()) as u64)
+        };
+
+        loop {
+            let pwd = records.read()?;
+            let next = pwd.as_ref().map_or(SHARDS, |pwd| usize::from(pwd.hash[0]));
+            if next < shard {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "The records of the store aren't ordered",
+                )
+                .into());
+            }
+
+            while shard < next {
+                directory[shard] = offset;
+                offset += write_shard(&mut keys, &mut file)?;
+                shard += 1;
+            }
+
+            let Some(pwd) = pwd else {
+                break;
+            };
+            keys.push(key(&pwd.hash));
+            count += 1;
+        }
+        directory[SHARDS] = offset;
+
+        if expected.is_some_and(|expected| expected != count) {
+            drop(file);
+            remove_file(&temp_path)?;
+            return Err(LocalStoreError::InvalidHeader {
+                path: store.file_path().to_path_buf(),
+                reason: "The records don't match the header",
+            });
+        }
+
+        let header = FuseHeader {
+            version: FuseHeader::VERSION,
+            fingerprint,
+            records: count,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        };
+
+        let mut file = file.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut head = BufWriter::new(&mut file);
+        head.write_all(&header.to_bytes())?;
+        for offset in directory {
+            head.write_all(&offset.to_be_bytes())?;
+        }
+        head.flush()?;
+        drop(head);
+
+        file.sync_all()?;
+        replace(&temp_path, path, true)?;
+        sync_dir(path)?;
+        Ok(header)
+    }
+
+    /// Maps the filter file into memory and checks its header and directory
+    pub fn open(file_path: impl Into<PathBuf>) -> Result<Self, LocalStoreError> {
+        let file_path = file_path.into();
+        let invalid = |reason| LocalStoreError::InvalidHeader {
+            path: file_path.clone(),
+            reason,
+        };
+
+        let file = File::open(&file_path)?;
+        // SAFETY: a build writes a new file and renames it over the old one,
+        // so the mapped file isn't modified
+        let map = unsafe { Mmap::map(&file)? };
+        if (map.len() as u64) < FILTERS_OFFSET {
+            return Err(invalid("The file is shorter than the header"));
+        }
+
+        let header = FuseHeader::from_bytes(map[..HEADER_LEN as usize].try_into().unwrap())
+            .map_err(invalid)?;
+
+        let directory = map[HEADER_LEN as usize..FILTERS_OFFSET as usize]
+            .chunks_exact(8)
+            .map(|offset| u64::from_be_bytes(offset.try_into().expect("Offset is 8 bytes")))
+            .collect::<Vec<_>>();
+        if directory[SHARDS] != map.len() as u64 {
+            return Err(invalid("The directory doesn't match the file length"));
+        }
+
+        let shards = directory
+            .windows(2)
+            .map(|offsets| {
+                let (start, end) = (offsets[0] as usize, offsets[1] as usize);
+                if start < FILTERS_OFFSET as usize || end < start + Layout::LEN {
+                    return Err(invalid("The directory is damaged"));
+                }
+
+                let layout =
+                    Layout::from_bytes(map[start..start + Layout::LEN].try_into().unwrap());
+                let fingerprints = start + Layout::LEN..end;
+                if !layout.is_valid()
+                    || fingerprints.len() != layout.array_length as usize * header.fingerprint.len()
+                {
+                    return Err(invalid("The filter of a shard is damaged"));
+                }
+                Ok(Shard {
+                    layout,
+                    fingerprints,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            file_path,
+            header,
+            map,
+            shards,
+        })
+    }
+
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    pub fn header(&self) -> FuseHeader {
+        self.header
+    }
+
+    /// The hash is in the filter or it is a false positive
+    pub fn contains(&self, hash: &[u8; 20]) -> bool {
+        let shard = &self.shards[usize::from(hash[0])];
+        if shard.layout.array_length == 0 {
+            return false;
+        }
+
+        let mixed = mix(key(hash), shard.layout.seed);
+        let fingerprints = &self.map[shard.fingerprints.clone()];
+        let fingerprint = self.header.fingerprint;
+        shard
+            .layout
+            .slots(mixed)
+            .into_iter()
+            .fold(fingerprint.of(mixed), |f, slot| {
+                f ^ fingerprint.read(fingerprints, slot)
+            })
+            == 0
+    }
+}
+
+impl ReadStore for FuseStore {
+    type Error = LocalStoreError;
+
+    /// A hash which isn't in the set is found with the probability of [Fingerprint::false_positive_rate].
+    /// The lookup reads three fingerprints of the mapped file on the calling thread
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        Ok(self.contains(&val))
+    }
+
+    /// Unsupported, the filter doesn't keep the hashes
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        futures::stream::once(async {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "The filter doesn't keep the hashes",
+            )
+            .into())
+        })
+    }
+
+    async fn exists_many(&self, vals: &[[u8; 20]]) -> Result<Vec<bool>, Self::Error> {
+        Ok(vals.iter().map(|val| self.contains(val)).collect())
+    }
+
+    /// Unsupported, the filter doesn't keep counts
+    async fn exists_count(&self, _: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "The filter doesn't keep counts").into())
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        Ok(StoreMetadata {
+            records: Some(self.header.records),
+            updated_at: Some(self.header.created_at()),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use hex_literal::hex;
+    use pwned_pwd_core::{Chunk, Prefix};
+    use pwned_pwd_store::WriteStore;
+
+    use super::*;
+
+    #[test]
+    fn filter() {
+        let mut rng = rand::thread_rng();
+        for size in [0, 1, 2, 10, 1000, 100_000] {
+            let mut keys = (0..size).map(|_| rng.gen()).collect::<Vec<u64>>();
+            keys.extend_from_slice(&keys.clone()[..size.min(3)]);
+
+            let (layout, fingerprints) = build_filter(&keys, Fingerprint::U8).unwrap();
+            assert!(layout.is_valid());
+            assert_eq!(layout.array_length as usize, fingerprints.len());
+
+            let found = |key: u64| {
+                let hash = mix(key, layout.seed);
+                layout.array_length > 0 && layout.slots(hash).into_iter().fold(Fingerprint::U8.of(hash), |f, slot| f ^ fingerprints[slot]) == 0
+            };
+            assert!(keys.iter().all(|key| found(*key)), "{size} keys");
+            if size > 1000 {
+                assert!((fingerprints.len() as f64) < size as f64 * 1.2);
+                let false_positives = (0..10_000).filter(|_| found(rng.gen())).count();
+                assert!(false_positives < 80, "{false_positives} false positives");
+            }
+        }
+    }
+
+    /// A random hash of the prefix
+    fn hash(rng: &mut impl Rng, prefix: u32) -> [u8; 20] {
+        let mut hash: [u8; 20] = rng.gen();
+        let low = hash[2] & 0x0F;
+        Prefix::create(prefix).unwrap().write_prefix(&mut hash);
+        hash[2] |= low;
+        hash
+    }
+
+    #[tokio::test]
+    async fn build() {
+        let dir = temp_dir().join("pwned_pwd_fuse");
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = LocalStore::builder(dir.join("pwned")).build().unwrap();
+
+        let mut rng = rand::thread_rng();
+        let mut chunks = Vec::new();
+        for prefix in [0x00000, 0x21BD4, 0x21BD5, 0xFFFFF] {
+            let mut passwords = (0..500).map(|_| PwnedPwd { hash: hash(&mut rng, prefix), count: 1 }).collect::<Vec<_>>();
+            passwords.sort_unstable_by_key(|pwd| pwd.hash);
+            chunks.push(Chunk { prefix: Prefix::create(prefix).unwrap(), passwords });
+        }
+        store.save(futures::stream::iter(chunks.clone())).await.unwrap();
+
+        let path = dir.join("pwned.fuse");
+        let header = FuseStore::build(&store, &path, Fingerprint::U16).unwrap();
+        assert_eq!(2000, header.records);
+
+        let filter = FuseStore::open(&path).unwrap();
+        assert_eq!(header, filter.header());
+        assert!(filter.healthy().await.unwrap());
+        assert_eq!(Some(2000), filter.metadata().await.unwrap().records);
+
+        for chunk in &chunks {
+            let hashes = chunk.passwords.iter().map(|pwd| pwd.hash).collect::<Vec<_>>();
+            assert!(filter.exists_many(&hashes).await.unwrap().into_iter().all(|found| found));
+        }
+
+        let false_positives = (0..10_000).filter(|_| filter.contains(&hash(&mut rng, 0x21BD4))).count();
+        assert!(false_positives < 5, "{false_positives} false positives");
+        assert!(!filter.exists(hex!("7F00000000000000000000000000000000000000")).await.unwrap());
+        assert!(filter.exists_count(chunks[0].passwords[0].hash).await.is_err());
+
+        std::fs::write(&path, b"PWNEDBFF").unwrap();
+        assert!(matches!(FuseStore::open(&path), Err(LocalStoreError::InvalidHeader { .. })));
+    }
+}
@@ -0,0 +1,594 @@
+//! A Golomb-coded set of hashes
+//!
+//! [GolombStore] keeps only enough bits of every hash to tell it from the others
+//! with a configured false-positive rate: the hashes of a prefix are truncated,
+//! and the deltas between them are Rice-coded. With the default rate of 1 in ~1M
+//! a hash takes ~22 bits instead of 160, so the file is ~7 times smaller than
+//! [crate::format::RecordFormat::Hashes]. The hashes can't be read back, so the set
+//! answers only [ReadStore::exists] and can only be replaced by [WriteStore::save]
+//!
+//! The file is a header, a directory of bucket offsets of every prefix and the buckets.
+//! A bucket is the count of its hashes followed by the Rice-coded deltas
+
+use std::{
+    fs::{remove_file, File, OpenOptions},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{Stream, StreamExt};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+use pwned_pwd_store::{OrderRequirement, ReadStore, StoreMetadata, WriteStore};
+
+use crate::{header::HEADER_LEN, read_at::ReadAt, replace, sync_dir, LocalStoreError};
+
+const MAGIC: [u8; 8] = *b"PWNEDGCS";
+
+/// Every prefix and the end of the last bucket
+fn directory_entries() -> usize {
+    u32::from(Prefix::max()) as usize + 2
+}
+
+/// Where the buckets start
+fn buckets_offset() -> u64 {
+    HEADER_LEN + directory_entries() as u64 * 8
+}
+
+/// The false-positive rate is 1 in 2^bits, see [crate::builder::LocalStoreBuilder::build_golomb]
+pub const DEFAULT_FALSE_POSITIVE_BITS: u8 = 20;
+
+/// The greatest false-positive bits, truncated hashes must fit into 60 bits after the prefix
+pub const MAX_FALSE_POSITIVE_BITS: u8 = 32;
+
+/// Description of a set file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GolombHeader {
+    pub version: u16,
+
+    /// The false-positive rate is 1 in 2^bits
+    pub false_positive_bits: u8,
+    pub records: u64,
+
+    /// Seconds since the unix epoch when the file was written
+    pub created_at: u64,
+}
+
+impl GolombHeader {
+    pub const VERSION: u16 = 1;
+
+    pub fn created_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.created_at)
+    }
+
+    fn to_bytes(self) -> [u8; HEADER_LEN as usize] {
+        let mut bytes = [0u8; HEADER_LEN as usize];
+        bytes[0..8].copy_from_slice(&MAGIC);
+        bytes[8..10].copy_from_slice(&self.version.to_be_bytes());
+        bytes[10] = self.false_positive_bits;
+        bytes[16..24].copy_from_slice(&self.records.to_be_bytes());
+        bytes[24..32].copy_from_slice(&self.created_at.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; HEADER_LEN as usize]) -> Result<Self, &'static str> {
+        let u64_at = |i: usize| u64::from_be_bytes(bytes[i..i + 8].try_into().unwrap());
+
+        if bytes[0..8] != MAGIC {
+            return Err("The file has no header");
+        }
+
+        let header = Self {
+            version: u16::from_be_bytes([bytes[8], bytes[9]]),
+            false_positive_bits: bytes[10],
+            records: u64_at(16),
+            created_at: u64_at(24),
+        };
+
+        if header.version != Self::VERSION {
+            return Err("Unsupported version of the file");
+        }
+        Ok(header)
+    }
+}
+
+/// An opened set file
+struct GolombFile {
+    file: File,
+    header: GolombHeader,
+
+    /// Offsets of the buckets of every prefix and the end of the last one
+    directory: Vec<u64>,
+}
+
+impl GolombFile {
+    fn open(path: &Path) -> Result<Self, LocalStoreError> {
+        let invalid = |reason| LocalStoreError::InvalidHeader {
+            path: path.to_path_buf(),
+            reason,
+        };
+
+        let file = File::open(path)?;
+        if file.size()? < buckets_offset() {
+            return Err(invalid("The file is shorter than the header"));
+        }
+
+        let mut bytes = [0u8; HEADER_LEN as usize];
+        file.read_exact_at(&mut bytes, 0)?;
+        let header = GolombHeader::from_bytes(&bytes).map_err(invalid)?;
+
+        let mut bytes = vec![0u8; directory_entries() * 8];
+        file.read_exact_at(&mut bytes, HEADER_LEN)?;
+        let directory = bytes
+            .chunks_exact(8)
+            .map(|offset| u64::from_be_bytes(offset.try_into().expect("Offset is 8 bytes")))
+            .collect::<Vec<_>>();
+
+        if directory[directory_entries() - 1] != file.size()? {
+            return Err(invalid("The directory doesn't match the file length"));
+        }
+
+        Ok(Self {
+            file,
+            header,
+            directory,
+        })
+    }
+
+    fn contains(&self, val: &[u8; 20]) -> io::Result<bool> {
+        let prefix = u32::from(Prefix::from_sha1(val)) as usize;
+        let (start, end) = (self.directory[prefix], self.directory[prefix + 1]);
+        if start == end {
+            return Ok(false);
+        }
+
+        let mut bucket = vec![0u8; (end - start) as usize];
+        self.file.read_exact_at(&mut bucket, start)?;
+        Bucket::decode(&bucket, self.header.false_positive_bits)?.contains(val)
+    }
+}
+
+/// Truncated hashes of a prefix
+struct Bucket<'a> {
+    count: u32,
+    bits: u8,
+    false_positive_bits: u8,
+    deltas: BitReader<'a>,
+}
+
+impl<'a> Bucket<'a> {
+    /// Bits of a truncated hash, so a hash is mistaken for one of `count` hashes
+    /// with the probability of 1 in 2^false_positive_bits
+    fn bits(count: usize, false_positive_bits: u8) -> u8 {
+        false_positive_bits + count.next_power_of_two().trailing_zeros() as u8
+    }
+
+    /// The first bits of the hash after its prefix
+    fn truncate(hash: &[u8; 20], bits: u8) -> u64 {
+        let after_prefix = u64::from_be_bytes(hash[2..10].try_into().unwrap()) << 4;
+        after_prefix >> (64 - u32::from(bits))
+    }
+
+    /// Appends the bucket of the ordered hashes of a prefix to `out`.
+    /// Hashes equal after the truncation are coded as zero deltas
+    fn encode(hashes: &[[u8; 20]], false_positive_bits: u8, out: &mut Vec<u8>) {
+        let bits = Self::bits(hashes.len(), false_positive_bits);
+        out.extend_from_slice(&(hashes.len() as u32).to_be_bytes());
+
+        let mut writer = BitWriter::new(out);
+        let mut last = 0;
+        for hash in hashes {
+            let value = Self::truncate(hash, bits);
+            writer.rice(value - last, false_positive_bits);
+            last = value;
+        }
+        writer.finish();
+    }
+
+    fn decode(bucket: &'a [u8], false_positive_bits: u8) -> io::Result<Self> {
+        let (count, deltas) = bucket
+            .split_first_chunk::<4>()
+            .ok_or(io::ErrorKind::InvalidData)?;
+        let count = u32::from_be_bytes(*count);
+
+        Ok(Self {
+            count,
+            bits: Self::bits(count as usize, false_positive_bits),
+            false_positive_bits,
+            deltas: BitReader::new(deltas),
+        })
+    }
+
+    fn contains(mut self, hash: &[u8; 20]) -> io::Result<bool> {
+        let target = Self::truncate(hash, self.bits);
+        let mut value = 0;
+        for _ in 0..self.count {
+            value += self.deltas.rice(self.false_positive_bits)?;
+            if value >= target {
+                return Ok(value == target);
+            }
+        }
+        Ok(false)
+    }
+}
+
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    acc: u64,
+    len: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> Self {
+        Self {
+            out,
+            acc: 0,
+            len: 0,
+        }
+    }
+
+    /// Writes the lowest `bits` of the value, `bits` is at most 32
+    fn write(&mut self, value: u64, bits: u32) {
+        self.acc = (self.acc << bits) | (value & ((1 << bits) - 1));
+        self.len += bits;
+        while self.len >= 8 {
+            self.len -= 8;
+            self.out.push((self.acc >> self.len) as u8);
+        }
+    }
+
+    /// The quotient in unary, then the remainder in `k` bits
+    fn rice(&mut self, value: u64, k: u8) {
+        let mut quotient = value >> k;
+        while quotient >= 32 {
+            self.write(u64::from(u32::MAX), 32);
+            quotient -= 32;
+        }
+        self.write(((1 << quotient) - 1) << 1, quotient as u32 + 1);
+        self.write(value, u32::from(k));
+    }
+
+    /// Pads the last byte with zeros
+    fn finish(mut self) {
+        if self.len > 0 {
+            self.write(0, 8 - self.len);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn bit(&mut self) -> io::Result<u64> {
+        let byte = self
+            .data
+            .get(self.pos / 8)
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Ok(u64::from(bit))
+    }
+
+    fn rice(&mut self, k: u8) -> io::Result<u64> {
+        let mut quotient = 0;
+        while self.bit()? == 1 {
+            quotient += 1;
+        }
+
+        let mut remainder = 0;
+        for _ in 0..k {
+            remainder = (remainder << 1) | self.bit()?;
+        }
+        Ok((quotient << k) | remainder)
+    }
+}
+
+/// A read-only set of hashes with false positives, see the [module](self) docs
+pub struct GolombStore {
+    file_path: PathBuf,
+
+    /// Where a save is written before it replaces the file, None to write the file in place
+    temp_path: Option<PathBuf>,
+    false_positive_bits: u8,
+    sync: bool,
+
+    /// The file opened by the first lookup, it is dropped when a save replaces the file
+    opened: RwLock<Option<Arc<GolombFile>>>,
+}
+
+impl GolombStore {
+    pub(crate) fn new(
+        file_path: PathBuf,
+        temp_path: Option<PathBuf>,
+        false_positive_bits: u8,
+        sync: bool,
+    ) -> Self {
+        Self {
+            file_path,
+            temp_path,
+            false_positive_bits: false_positive_bits.clamp(1, MAX_FALSE_POSITIVE_BITS),
+            sync,
+            opened: RwLock::new(None),
+        }
+    }
+
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    /// The false-positive rate is 1 in 2^bits for the next save,
+    /// the current file keeps the rate it was saved with
+    pub fn false_positive_bits(&self) -> u8 {
+        self.false_positive_bits
+    }
+
+    /// Reads and checks the header of the file
+    pub fn header(&self) -> Result<GolombHeader, LocalStoreError> {
+        Ok(self.opened()?.header)
+    }
+
+    fn opened(&self) -> Result<Arc<GolombFile>, LocalStoreError> {
+        if let Some(opened) = &*self.opened.read().unwrap() {
+            return Ok(opened.clone());
+        }
+
+        let opened = Arc::new(GolombFile::open(&self.file_path)?);
+        *self.opened.write().unwrap() = Some(opened.clone());
+        Ok(opened)
+    }
+
+    /// Writes the set into the file at `path`
+    async fn write<S: Stream<Item = Chunk> + Unpin + Send>(
+        &self,
+        path: &Path,
+        mut s: S,
+    ) -> Result<(), LocalStoreError> {
+        if path.exists() {
+            remove_file(path)?;
+        }
+
+        let mut file = BufWriter::new(OpenOptions::new().create_new(true).write(true).open(path)?);
+
+        // Placeholders, the header and the directory are written when the save completes
+        file.write_all(&vec![0u8; buckets_offset() as usize])?;
+
+        let mut directory = vec![buckets_offset(); directory_entries()];
+        let mut offset = buckets_offset();
+        let mut records = 0;
+        let mut next_prefix = 0;
+        let mut bucket = Vec::new();
+
+        while let Some(chunk) = s.next().await {
+            let prefix = u32::from(chunk.prefix) as usize;
+            if prefix < next_prefix {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Chunks must be ordered by prefix",
+                )
+                .into());
+            }
+
+            let mut hashes = chunk
+                .passwords
+                .iter()
+                .map(|pwd| pwd.hash)
+                .collect::<Vec<_>>();
+            hashes.sort_unstable();
+            hashes.dedup();
+
+            directory[next_prefix..=prefix].fill(offset);
+            next_prefix = prefix + 1;
+
+            if !hashes.is_empty() {
+                bucket.clear();
+                Bucket::encode(&hashes, self.false_positive_bits, &mut bucket);
+                file.write_all(&bucket)?;
+                offset += bucket.len() as u64;
+                records += hashes.len() as u64;
+            }
+        }
+        directory[next_prefix..].fill(offset);
+
+        let header = GolombHeader {
+            version: GolombHeader::VERSION,
+            false_positive_bits: self.false_positive_bits,
+            records,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        };
+
+        let mut file = file.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut head = BufWriter::new(&mut file);
+        head.write_all(&header.to_bytes())?;
+        for offset in directory {
+            head.write_all(&offset.to_be_bytes())?;
+        }
+        head.flush()?;
+        drop(head);
+
+        if self.sync {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl ReadStore for GolombStore {
+    type Error = LocalStoreError;
+
+    /// A hash which isn't in the set is found with the probability of 1 in 2^false_positive_bits.
+    /// The search runs on the blocking thread pool of tokio
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        let opened = self.opened()?;
+        Ok(tokio::task::spawn_blocking(move || opened.contains(&val))
+            .await
+            .map_err(io::Error::other)??)
+    }
+
+    /// Unsupported, the set doesn't keep the hashes
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        futures::stream::once(async {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "The set doesn't keep the hashes",
+            )
+            .into())
+        })
+    }
+
+    /// Unsupported, the set doesn't keep counts
+    async fn exists_count(&self, _: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "The set doesn't keep counts").into())
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        let header = self.header()?;
+        Ok(StoreMetadata {
+            records: Some(header.records),
+            updated_at: Some(header.created_at()),
+            ..Default::default()
+        })
+    }
+
+    /// The file has a valid header and isn't empty
+    async fn healthy(&self) -> Result<bool, Self::Error> {
+        match self.header() {
+            Ok(header) => Ok(header.records > 0),
+            Err(LocalStoreError::InvalidHeader { .. }) => Ok(false),
+            Err(LocalStoreError::Io(e)) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl WriteStore for GolombStore {
+    /// Chunks must be ordered by prefix, hashes of a chunk may be unordered.
+    /// Counts are dropped. If the save is cancelled, the file is kept, unless
+    /// the store writes it in place
+    async fn save<S: Stream<Item = Chunk> + Unpin + Send>(&self, s: S) -> Result<(), Self::Error> {
+        let path = self.temp_path.as_deref().unwrap_or(&self.file_path);
+        let res = self.write(path, s).await;
+        *self.opened.write().unwrap() = None;
+        res?;
+
+        if self.temp_path.is_some() {
+            replace(path, &self.file_path, self.sync)?;
+        }
+        if self.sync {
+            sync_dir(&self.file_path)?;
+        }
+        Ok(())
+    }
+
+    /// Unsupported, the set doesn't keep the hashes to merge them with the stream
+    async fn merge<S: Stream<Item = Chunk> + Unpin + Send>(&self, _: S) -> Result<(), Self::Error> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "The set can only be saved").into())
+    }
+
+    /// Unsupported, the set doesn't keep the hashes to tell which one to remove
+    async fn remove(&self, _: [u8; 20]) -> Result<bool, Self::Error> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "The set can only be saved").into())
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        *self.opened.write().unwrap() = None;
+        for path in [Some(&self.file_path), self.temp_path.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            match remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn order_requirement() -> OrderRequirement {
+        OrderRequirement::Ordered
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use hex_literal::hex;
+    use rand::Rng;
+
+    use super::*;
+    use crate::LocalStore;
+
+    #[test]
+    fn bucket() {
+        let hashes = [
+            hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"),
+            hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"),
+            hex!("21BD4FF0328459B74EC3CC4ADCE47093DA97FD00"),
+        ];
+
+        let mut data = Vec::new();
+        Bucket::encode(&hashes, 4, &mut data);
+        assert_eq!(3, u32::from_be_bytes(data[..4].try_into().unwrap()));
+
+        for hash in hashes {
+            assert!(Bucket::decode(&data, 4).unwrap().contains(&hash).unwrap());
+        }
+        assert!(!Bucket::decode(&data, 4).unwrap().contains(&hex!("21BD4804DDDC80AE4683948C5A1C5903584D8087")).unwrap());
+    }
+
+    /// A random hash of the prefix
+    fn hash(rng: &mut impl Rng, prefix: u32) -> [u8; 20] {
+        let mut hash: [u8; 20] = rng.gen();
+        let low = hash[2] & 0x0F;
+        Prefix::create(prefix).unwrap().write_prefix(&mut hash);
+        hash[2] |= low;
+        hash
+    }
+
+    #[tokio::test]
+    async fn save() {
+        let dir = temp_dir().join("pwned_pwd_golomb");
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = LocalStore::builder(dir.join("pwned")).build_golomb(8).unwrap();
+
+        let mut rng = rand::thread_rng();
+        let mut chunks = Vec::new();
+        for prefix in [0x00000, 0x21BD4, 0xFFFFF] {
+            let passwords = (0..100).map(|_| PwnedPwd { hash: hash(&mut rng, prefix), count: 1 }).collect::<Vec<_>>();
+            chunks.push(Chunk { prefix: Prefix::create(prefix).unwrap(), passwords });
+        }
+
+        store.save(futures::stream::iter(chunks.clone())).await.unwrap();
+        assert!(store.healthy().await.unwrap());
+        assert_eq!(Some(300), store.metadata().await.unwrap().records);
+
+        for chunk in &chunks {
+            for pwd in &chunk.passwords {
+                assert!(store.exists(pwd.hash).await.unwrap());
+            }
+        }
+
+        let mut false_positives = 0;
+        for _ in 0..1000 {
+            false_positives += store.exists(hash(&mut rng, 0x21BD4)).await.unwrap() as u32;
+        }
+        assert!(false_positives < 20, "{false_positives} false positives");
+        assert!(!store.exists(hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+
+        assert!(store.merge(futures::stream::empty()).await.is_err());
+        store.clear().await.unwrap();
+        assert!(!store.healthy().await.unwrap());
+    }
+}
@@ -0,0 +1,239 @@
+//! Versioned header of a store file
+//!
+//! A file of bare records can't be told from any other file of the right size,
+//! and its layout can't evolve. [FileHeader] is written in front of the records
+//! when a save completes, so a file without a valid header is either foreign
+//! or left by an interrupted save
+
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{format::RecordFormat, read_at::ReadAt};
+
+/// Length of the header, the records start right after it
+pub const HEADER_LEN: u64 = 64;
+
+const MAGIC: [u8; 8] = *b"PWNEDPWD";
+
+/// Description of the records of a store file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHeader {
+    pub version: u16,
+    pub record_len: u16,
+    pub records: u64,
+
+    /// Seconds since the unix epoch when the file was written
+    pub created_at: u64,
+
+    /// FNV-1a of all the records
+    pub checksum: u64,
+}
+
+impl FileHeader {
+    pub const VERSION: u16 = 1;
+
+    pub(crate) fn new(format: RecordFormat, records: u64, checksum: u64) -> Self {
+        Self {
+            version: Self::VERSION,
+            record_len: format.record_len() as u16,
+            records,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            checksum,
+        }
+    }
+
+    /// The format of the records, None if the record length isn't known
+    pub fn format(&self) -> Option<RecordFormat> {
+        RecordFormat::of_record_len(u64::from(self.record_len))
+    }
+
+    pub fn created_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.created_at)
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN as usize] {
+        let mut bytes = [0u8; HEADER_LEN as usize];
+        bytes[0..8].copy_from_slice(&MAGIC);
+        bytes[8..10].copy_from_slice(&self.version.to_be_bytes());
+        bytes[10..12].copy_from_slice(&self.record_len.to_be_bytes());
+        bytes[16..24].copy_from_slice(&self.records.to_be_bytes());
+        bytes[24..32].copy_from_slice(&self.created_at.to_be_bytes());
+        bytes[32..40].copy_from_slice(&self.checksum.to_be_bytes());
+        bytes
+    }
+
+    /// Parses the header, the error is the reason why it is invalid
+    pub fn from_bytes(bytes: &[u8; HEADER_LEN as usize]) -> Result<Self, &'static str> {
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let u64_at = |i: usize| u64::from_be_bytes(bytes[i..i + 8].try_into().unwrap());
+
+        if bytes[0..8] != MAGIC {
+            return Err("The file has no header");
+        }
+
+        let header = Self {
+            version: u16_at(8),
+            record_len: u16_at(10),
+            records: u64_at(16),
+            created_at: u64_at(24),
+            checksum: u64_at(32),
+        };
+
+        if header.version != Self::VERSION {
+            return Err("Unsupported version of the file");
+        }
+
+        Ok(header)
+    }
+
+    /// Reads the header and checks it describes the data in the format
+    pub fn read<T: Read + Seek>(
+        data: &mut T,
+        format: RecordFormat,
+    ) -> io::Result<Result<Self, &'static str>> {
+        let len = data.seek(SeekFrom::End(0))?;
+        if len < HEADER_LEN {
+            return Ok(Err("The file is shorter than the header"));
+        }
+
+        let mut bytes = [0u8; HEADER_LEN as usize];
+        data.seek(SeekFrom::Start(0))?;
+        data.read_exact(&mut bytes)?;
+
+        Ok(Self::from_bytes(&bytes).and_then(|header| {
+            if u64::from(header.record_len) != format.record_len() {
+                Err("Record length doesn't match the format")
+            } else if format.records_in(len - HEADER_LEN) != Some(header.records) {
+                Err("Record count doesn't match the file length")
+            } else {
+                Ok(header)
+            }
+        }))
+    }
+}
+
+/// FNV-1a 64
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Checksum(u64);
+
+impl Default for Checksum {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Checksum {
+    /// Continues the checksum of the records which were already written
+    pub(crate) fn from_value(value: u64) -> Self {
+        Self(value)
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= u64::from(*b);
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub(crate) fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+/// The records of a file: positions are counted from the end of the header
+pub(crate) struct Body<T> {
+    inner: T,
+    offset: u64,
+}
+
+impl<T: Seek> Body<T> {
+    pub(crate) fn new(mut inner: T, offset: u64) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(offset))?;
+        Ok(Self { inner, offset })
+    }
+}
+
+impl<T: Read> Read for Body<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Seek> Seek for Body<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => SeekFrom::Start(pos + self.offset),
+            pos => pos,
+        };
+
+        self.inner
+            .seek(pos)?
+            .checked_sub(self.offset)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seek into the header"))
+    }
+}
+
+impl<T: ReadAt> ReadAt for Body<T> {
+    fn read_exact_at(&self, buf: &mut [u8], pos: u64) -> io::Result<()> {
+        self.inner.read_exact_at(buf, pos + self.offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.inner.size()?.saturating_sub(self.offset))
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn read() {
+        let header = FileHeader::new(RecordFormat::HashesWithCounts, 2, 42);
+        let mut data = header.to_bytes().to_vec();
+        data.extend_from_slice(&[1u8; 48]);
+
+        assert_eq!(Ok(header), FileHeader::read(&mut Cursor::new(&data), RecordFormat::HashesWithCounts).unwrap());
+        assert_eq!(Err("Record length doesn't match the format"), FileHeader::read(&mut Cursor::new(&data), RecordFormat::Hashes).unwrap());
+
+        data.push(0);
+        assert_eq!(Err("Record count doesn't match the file length"), FileHeader::read(&mut Cursor::new(&data), RecordFormat::HashesWithCounts).unwrap());
+
+        data[0] = 0;
+        assert_eq!(Err("The file has no header"), FileHeader::read(&mut Cursor::new(&data), RecordFormat::HashesWithCounts).unwrap());
+        assert_eq!(Err("The file is shorter than the header"), FileHeader::read(&mut Cursor::new(&[0u8; 20]), RecordFormat::Hashes).unwrap());
+    }
+
+    #[test]
+    fn body() {
+        let mut data = vec![0u8; HEADER_LEN as usize];
+        data.extend_from_slice(b"records");
+
+        let mut body = Body::new(Cursor::new(data), HEADER_LEN).unwrap();
+        assert_eq!(7, body.seek(SeekFrom::End(0)).unwrap());
+        assert_eq!(3, body.seek(SeekFrom::Start(3)).unwrap());
+
+        let mut rest = String::new();
+        body.read_to_string(&mut rest).unwrap();
+        assert_eq!("ords", rest);
+
+        let mut buf = [0u8; 3];
+        body.read_exact_at(&mut buf, 1).unwrap();
+        assert_eq!(b"eco", &buf);
+        assert_eq!(7, body.size().unwrap());
+    }
+
+    #[test]
+    fn checksum() {
+        let mut checksum = Checksum::default();
+        checksum.update(b"a");
+        assert_eq!(0xaf63dc4c8601ec8c, checksum.value());
+    }
+}
@@ -0,0 +1,151 @@
+//! In-memory index of prefixes
+//!
+//! A binary search over the whole file takes ~30 random reads on the full data set.
+//! [PrefixIndex] keeps the first record of every prefix (8 MiB of RAM), so a lookup
+//! searches only among the few hundred hashes of its prefix, which usually lie in one page.
+//! A file of [RecordFormat::Suffixes] keeps the index on disk as a directory of big-endian
+//! u32 record counts of every prefix after the records
+
+use std::{
+    io::{self, Read},
+    ops::Range,
+};
+
+use pwned_pwd_core::Prefix;
+
+use crate::{format::RecordFormat, read_at::ReadAt};
+
+/// Record ranges of all the prefixes of an ordered file of records
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixIndex {
+    /// `starts[p]` is the count of records with a prefix less than `p`
+    starts: Vec<u64>,
+}
+
+impl PrefixIndex {
+    /// Every prefix and the end of the last one
+    fn len() -> usize {
+        u32::from(Prefix::max()) as usize + 2
+    }
+
+    /// An index of an empty file
+    pub fn empty() -> Self {
+        Self {
+            starts: vec![0; Self::len()],
+        }
+    }
+
+    /// Length of the directory of a file
+    pub fn directory_len() -> u64 {
+        (Self::len() as u64 - 1) * 4
+    }
+
+    /// An index of the record counts of every prefix
+    pub fn from_counts(counts: impl IntoIterator<Item = u32>) -> Self {
+        let mut starts = Vec::with_capacity(Self::len());
+        starts.push(0);
+        for count in counts {
+            starts.push(starts[starts.len() - 1] + u64::from(count));
+        }
+        starts.resize(Self::len(), starts[starts.len() - 1]);
+
+        Self { starts }
+    }
+
+    /// Reads the directory at the end of the data
+    pub(crate) fn read_directory<T: ReadAt>(data: &T, format: RecordFormat) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid prefix directory");
+
+        let len = data.size()?;
+        let start = len.checked_sub(Self::directory_len()).ok_or_else(invalid)?;
+
+        let mut directory = vec![0u8; Self::directory_len() as usize];
+        data.read_exact_at(&mut directory, start)?;
+
+        let index = Self::from_counts(
+            directory
+                .chunks_exact(4)
+                .map(|count| u32::from_be_bytes(count.try_into().expect("Count is 4 bytes"))),
+        );
+
+        if index.records() * format.record_len() != start {
+            return Err(invalid());
+        }
+        Ok(index)
+    }
+
+    /// Scans the data, it is a sequential read of the whole file
+    pub fn build<T: Read>(data: &mut T, format: RecordFormat) -> io::Result<Self> {
+        let mut starts = vec![0u64; Self::len()];
+
+        while let Some(pwd) = format.read(data)? {
+            starts[u32::from(Prefix::from_sha1(&pwd.hash)) as usize + 1] += 1;
+        }
+
+        for i in 1..starts.len() {
+            starts[i] += starts[i - 1];
+        }
+
+        Ok(Self { starts })
+    }
+
+    /// Indexes of the records of the prefix
+    pub fn range(&self, prefix: Prefix) -> Range<u64> {
+        let i = u32::from(prefix) as usize;
+        self.starts[i]..self.starts[i + 1]
+    }
+
+    pub fn records(&self) -> u64 {
+        self.starts[self.starts.len() - 1]
+    }
+
+    /// The greatest prefix with records
+    pub fn last_prefix(&self) -> Option<Prefix> {
+        let records = self.records();
+        if records == 0 {
+            return None;
+        }
+
+        // The first end of a prefix which is the end of all the records
+        let end = self.starts[1..].partition_point(|start| *start < records);
+        Prefix::create(end as u32)
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::io::Cursor;
+
+    use hex_literal::hex;
+
+    use super::*;
+
+    #[test]
+    fn build() {
+        let index = PrefixIndex::build(&mut Cursor::new(hex!("
+            0000000C53D0B33029D7FE4FB08D3D1C9832D2ED
+            21BD4004DDDC80AE4683948C5A1C5903584D8087
+            21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED
+            21BD5004DDDC80AE4683948C5A1C5903584D8087
+            FFFFF004DDDC80AE4683948C5A1C5903584D8087
+        ")), RecordFormat::Hashes).unwrap();
+
+        assert_eq!(5, index.records());
+        assert_eq!(0..1, index.range(Prefix::create(0x00000).unwrap()));
+        assert_eq!(1..1, index.range(Prefix::create(0x00001).unwrap()));
+        assert_eq!(1..3, index.range(Prefix::create(0x21BD4).unwrap()));
+        assert_eq!(3..4, index.range(Prefix::create(0x21BD5).unwrap()));
+        assert_eq!(4..5, index.range(Prefix::max()));
+
+        assert_eq!(0..0, PrefixIndex::empty().range(Prefix::max()));
+        assert_eq!(Some(Prefix::max()), index.last_prefix());
+        assert_eq!(None, PrefixIndex::empty().last_prefix());
+
+        let index = PrefixIndex::from_counts([1, 0, 2]);
+        assert_eq!(3, index.records());
+        assert_eq!(1..3, index.range(Prefix::create(0x00002).unwrap()));
+        assert_eq!(3..3, index.range(Prefix::max()));
+        assert_eq!(Some(Prefix::create(0x00002).unwrap()), index.last_prefix());
+    }
+}
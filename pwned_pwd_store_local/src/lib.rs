@@ -1,12 +1,46 @@
 use std::cmp::Ordering;
-use std::fs::{remove_file, rename, File, OpenOptions};
-use std::io::{self, prelude::*, BufWriter};
-use std::path::PathBuf;
+use std::fs::{metadata, remove_file, rename, File, Metadata, OpenOptions};
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
+use cache::LookupCache;
+use checkpoint::{Checkpoint, Checkpoints};
+use format::{RecordFormat, Records};
+use futures::Stream;
 use futures::StreamExt;
-use futures::{future::BoxFuture, Stream};
-use pwned_pwd_core::PwnedPwd;
-use pwned_pwd_store::Store;
+use header::{Body, Checksum, FileHeader, HEADER_LEN};
+use index::PrefixIndex;
+use pwned_pwd_core::{
+    dump::{DumpError, DumpReader},
+    Chunk, Prefix, PwnedPwd, Suffix,
+};
+use pwned_pwd_store::{
+    progress::{SaveObserver, SaveProgress},
+    InvalidChunk, ReadStore, StoreMetadata, WriteStore,
+};
+use read_at::{LookupFile, ReadAt, ReadAtReader};
+use sampling::{SampleReport, SampleVerification};
+
+pub mod advisor;
+pub mod builder;
+pub mod cache;
+pub mod checkpoint;
+pub mod format;
+pub mod fuse;
+pub mod golomb;
+pub mod header;
+pub mod index;
+#[cfg(feature = "pool")]
+pub mod pool;
+pub mod read_at;
+pub mod read_only;
+pub mod sampling;
+pub mod sharded;
+pub mod verify;
+pub mod versioned;
 
 /// What should we do when pwned passwords file exists
 #[derive(Debug, Clone)]
@@ -21,7 +55,9 @@ pub enum ExistenceBehaviour {
     /// Downloads a file into the download_path then replace an original
     /// If the download_path is None then the file_path from a LocalStore will be used
     /// The download_path MUST be on the same mountpoint with a LocalStore.file_path
-    /// because an old file will be renamed into a new file after a download
+    /// because an old file will be renamed into a new file after a download.
+    /// [builder::LocalStoreBuilder::build] checks it where it can, a rename which still
+    /// crosses filesystems falls back to a copy next to the store file
     DownloadThenReplace { download_path: Option<PathBuf> },
 }
 
@@ -33,23 +69,210 @@ impl Default for ExistenceBehaviour {
     }
 }
 
+/// How much disk space a save reserves before it writes the records.
+/// A reserved file is less fragmented and a lack of space fails the save at the start
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Preallocation {
+    /// The file grows while the records are written
+    None,
+
+    /// The length of the current store file, a new version of the data set is about the same size
+    #[default]
+    CurrentLen,
+
+    /// The length of a file of the records
+    Records(u64),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum LocalStoreError {
+    #[error("Io error: {0}")]
+    Io(#[from] io::Error),
+
+    /// The disk is full. The partially written file is kept at `path`
+    /// and contains all the prefixes up to `last_prefix`
+    #[error("No space left after {written_bytes} bytes were written into '{}'", path.display())]
+    OutOfSpace {
+        written_bytes: u64,
+
+        /// How many bytes more are required, estimated from the saved prefixes
+        estimated_remaining: Option<u64>,
+
+        path: PathBuf,
+
+        /// The last prefix which was completely written
+        last_prefix: Option<Prefix>,
+    },
+
+    /// A path passed to [builder::LocalStoreBuilder] can't be used
+    #[error("Invalid path '{}': {reason}", path.display())]
+    InvalidPath { path: PathBuf, reason: &'static str },
+
+    /// The file isn't a store file of the configured format or it is left by an interrupted save
+    #[error("Invalid header of '{}': {reason}", path.display())]
+    InvalidHeader { path: PathBuf, reason: &'static str },
+
+    /// A text dump passed to [LocalStore::import_text] can't be read
+    #[error("Invalid dump: {0}")]
+    InvalidDump(#[from] DumpError),
+
+    /// A save stops at an invalid chunk, so the store file is kept unless it is written in place
+    #[error(transparent)]
+    InvalidChunk(#[from] InvalidChunk),
+
+    /// A saved or merged chunk doesn't follow the previous one, chunks must be ordered by prefix
+    #[error("The chunk of the prefix '{prefix}' follows the chunk of '{previous}'")]
+    UnorderedChunk { prefix: Prefix, previous: Prefix },
+}
+
 struct PwdFile {
     file: BufWriter<File>,
+    format: RecordFormat,
+    header: bool,
+    records: u64,
+    checksum: Checksum,
+    written: u64,
     path: PathBuf,
     move_on_complete_to: Option<PathBuf>,
+    last_prefix: Option<Prefix>,
+    sync: bool,
+
+    /// Record counts of the prefixes, if the format keeps a directory
+    directory: Option<Vec<u32>>,
+
+    /// Were the blocks reserved, the unused ones are released on completion
+    preallocated: bool,
+
+    /// None, if the save isn't resumable
+    checkpoints: Option<Checkpoints>,
 }
 
 impl PwdFile {
-    fn write(&mut self, pwd: PwnedPwd) -> io::Result<()> {
-        self.file.write_all(&pwd.sha1)
+    fn write(&mut self, pwd: &PwnedPwd) -> Result<(), LocalStoreError> {
+        let mut buf = [0u8; 24];
+        let record = self.format.encode(pwd, &mut buf);
+
+        self.file.write_all(record).map_err(|e| self.error(e))?;
+        if let Some(directory) = &mut self.directory {
+            directory[u32::from(Prefix::from_sha1(&pwd.hash)) as usize] += 1;
+        }
+        self.checksum.update(record);
+        self.records += 1;
+        self.written += record.len() as u64;
+        Ok(())
+    }
+
+    /// Bytes which have left the buffer
+    fn flushed(&self) -> u64 {
+        self.written - self.file.buffer().len() as u64
+    }
+
+    /// Checks that the chunk is ordered and follows the written ones,
+    /// so the file stays searchable
+    fn check(&self, chunk: &Chunk) -> Result<(), LocalStoreError> {
+        if let Some(previous) = self.last_prefix {
+            if u32::from(chunk.prefix) <= u32::from(previous) {
+                return Err(LocalStoreError::UnorderedChunk {
+                    prefix: chunk.prefix,
+                    previous,
+                });
+            }
+        }
+
+        Ok(InvalidChunk::check(chunk)?)
+    }
+
+    /// All the passwords of the prefix are written, a checkpoint is written if it is due
+    fn chunk_written(&mut self, prefix: Prefix) -> Result<(), LocalStoreError> {
+        self.last_prefix = Some(prefix);
+        if !self.checkpoints.as_mut().is_some_and(Checkpoints::due) {
+            return Ok(());
+        }
+
+        // The checkpoint must not point past the records on the disk
+        self.file.flush().map_err(|e| self.error(e))?;
+        if self.sync {
+            self.file.get_ref().sync_data()?;
+        }
+
+        let checkpoint = Checkpoint {
+            last_prefix: prefix,
+            written: self.written,
+            records: self.records,
+            checksum: self.checksum.value(),
+        };
+        Ok(checkpoint.write(&self.path, self.sync)?)
+    }
+
+    fn error(&self, e: io::Error) -> LocalStoreError {
+        if e.kind() != io::ErrorKind::StorageFull {
+            return e.into();
+        }
+
+        let written_bytes = self
+            .file
+            .get_ref()
+            .metadata()
+            .map(|m| m.len())
+            .unwrap_or_default();
+
+        let estimated_remaining = self.last_prefix.map(|last| {
+            let done = u64::from(u32::from(last)) + 1;
+            let total = u64::from(u32::from(Prefix::max())) + 1;
+            written_bytes / done * (total - done)
+        });
+
+        LocalStoreError::OutOfSpace {
+            written_bytes,
+            estimated_remaining,
+            path: self.path.clone(),
+            last_prefix: self.last_prefix,
+        }
     }
 
-    fn complete(mut self) -> io::Result<()> {
-        self.file.flush()?;
+    /// Writes the directory, flushes the records and writes the header. If `sync` is set, the file is synced
+    /// before it replaces the store file and the directory is synced after the rename,
+    /// so a power loss leaves either the old or the complete new file
+    fn complete(mut self) -> Result<(), LocalStoreError> {
+        for count in self.directory.take().unwrap_or_default() {
+            self.file
+                .write_all(&count.to_be_bytes())
+                .map_err(|e| self.error(e))?;
+        }
+        self.file.flush().map_err(|e| self.error(e))?;
+
+        if self.preallocated {
+            let file = self.file.get_ref();
+            file.set_len(file.metadata()?.len())?;
+        }
+
+        if self.header {
+            let header = FileHeader::new(self.format, self.records, self.checksum.value());
+            let file = self.file.get_mut();
+            file.seek(io::SeekFrom::Start(0))?;
+            file.write_all(&header.to_bytes())
+                .map_err(|e| self.error(e))?;
+        }
+
+        if self.sync {
+            self.file.get_ref().sync_all()?;
+        }
         drop(self.file);
 
-        if let Some(move_to) = self.move_on_complete_to {
-            rename(&self.path, &move_to)?;
+        if self.checkpoints.is_some() {
+            Checkpoint::remove(&self.path)?;
+        }
+
+        let path = match self.move_on_complete_to {
+            Some(move_to) => {
+                replace(&self.path, &move_to, self.sync)?;
+                move_to
+            }
+            None => self.path,
+        };
+
+        if self.sync {
+            sync_dir(&path)?;
         }
 
         Ok(())
@@ -60,81 +283,799 @@ pub struct LocalStore {
     file_path: PathBuf,
     existence_behaviour: ExistenceBehaviour,
     buff_capacity: Option<usize>,
+    format: RecordFormat,
+
+    /// Does the file start with a [FileHeader]
+    header: bool,
+
+    /// None, if lookups search the whole file
+    index: Option<RwLock<PrefixIndex>>,
+
+    /// Is a complete file synced to the disk before it replaces the store file
+    sync: bool,
+
+    preallocation: Preallocation,
+
+    /// Lookups bypass the page cache
+    direct_io: bool,
+
+    /// How often a save writes a checkpoint, None if saves aren't resumable
+    checkpoint_interval: Option<Duration>,
+
+    /// Results of the recent lookups
+    cache: Option<LookupCache>,
+
+    /// How often lookups check whether the file was replaced by someone else
+    reload_interval: Option<Duration>,
+
+    /// The file opened by the first lookup. It is reopened when the store changes the file,
+    /// a file replaced by someone else is read through the old handle until a reload
+    handle: Mutex<Option<Handle>>,
+}
+
+/// An opened store file
+struct Handle {
+    file: Arc<Body<LookupFile>>,
+    id: FileId,
+
+    /// When the path was last compared with the file
+    checked: Instant,
 }
 
 impl LocalStore {
     const DEFAULT_BUF_SIZE: usize = 8 * 1024;
 
-    fn open_write(&self) -> io::Result<PwdFile> {
-        let (path, move_on_complete_to) = match &self.existence_behaviour {
+    /// A store of the file at `file_path`
+    pub fn builder(file_path: impl Into<PathBuf>) -> builder::LocalStoreBuilder {
+        builder::LocalStoreBuilder::new(file_path.into())
+    }
+
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    pub fn format(&self) -> RecordFormat {
+        self.format
+    }
+
+    /// Records of the hash prefix, if the store is indexed
+    fn index_range(&self, val: &[u8; 20]) -> Option<Range<u64>> {
+        self.index
+            .as_ref()
+            .map(|index| index.read().unwrap().range(Prefix::from_sha1(val)))
+    }
+
+    /// Searches the hash within its prefix, if the store is indexed.
+    /// Returns the count of the found hash
+    fn lookup<T: ReadAt>(&self, data: &T, val: [u8; 20]) -> io::Result<Option<u32>> {
+        find(data, val, self.index_range(&val), self.format)
+    }
+
+    /// The cached handle of the file, it is opened once and shared by all lookups
+    fn handle(&self) -> io::Result<Arc<Body<LookupFile>>> {
+        if self.replaced(false)? {
+            self.refresh()?;
+        }
+
+        let mut handle = self.handle.lock().unwrap();
+        match &*handle {
+            Some(opened) => Ok(opened.file.clone()),
+            None => Ok(handle.insert(self.open_handle()?).file.clone()),
+        }
+    }
+
+    fn open_handle(&self) -> io::Result<Handle> {
+        let file = LookupFile::open(&self.file_path, self.direct_io)?;
+        Ok(Handle {
+            id: file_id(&file.file().metadata()?),
+            file: Arc::new(Body::new(file, self.offset())?),
+            checked: Instant::now(),
+        })
+    }
+
+    /// Is the file at the path not the opened one. Unless `force`, the path is checked
+    /// once per [builder::LocalStoreBuilder::with_reload_interval]
+    fn replaced(&self, force: bool) -> io::Result<bool> {
+        let interval = match (force, self.reload_interval) {
+            (true, _) => Duration::ZERO,
+            (false, Some(interval)) => interval,
+            (false, None) => return Ok(false),
+        };
+
+        let mut handle = self.handle.lock().unwrap();
+        if let Some(opened) = &mut *handle {
+            if opened.checked.elapsed() < interval {
+                return Ok(false);
+            }
+            opened.checked = Instant::now();
+        }
+
+        match metadata(&self.file_path) {
+            Ok(metadata) => Ok(handle
+                .as_ref()
+                .is_none_or(|opened| opened.id != file_id(&metadata))),
+            // The file is being replaced, keep the old one until the new one appears
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Switches to the file at the path, if it was replaced by someone else
+    /// since the store opened it. Returns true, if the file is switched
+    pub fn reload(&self) -> Result<bool, LocalStoreError> {
+        if !self.replaced(true)? {
+            return Ok(false);
+        }
+
+        // A new file with an invalid header is an error, lookups keep the old one
+        self.header()?;
+        self.refresh()?;
+        Ok(true)
+    }
+
+    /// Searches the hash in the cache, then in the file. Returns the count of the found hash
+    async fn search(&self, val: [u8; 20]) -> io::Result<Option<u32>> {
+        // The handle is taken first, a reload of a replaced file rebuilds the index
+        let file = self.handle()?;
+        if let Some(found) = self.cache.as_ref().and_then(|cache| cache.get(&val)) {
+            return Ok(found);
+        }
+
+        let (range, format) = (self.index_range(&val), self.format);
+        let found =
+            Self::read_blocking(file.clone(), move |file| find(file, val, range, format)).await?;
+
+        if let Some(cache) = &self.cache {
+            // The file could be changed during the search, its result must not outlive the change
+            let handle = self.handle.lock().unwrap();
+            if handle
+                .as_ref()
+                .is_some_and(|opened| Arc::ptr_eq(&opened.file, &file))
+            {
+                cache.put(val, found);
+            }
+        }
+        Ok(found)
+    }
+
+    /// Records of the file opened at the call, the stream doesn't borrow the store
+    fn records_stream(&self) -> impl Stream<Item = Result<PwnedPwd, LocalStoreError>> + Send {
+        let reader = self
+            .open_records()
+            .and_then(|records| records.ok_or(io::ErrorKind::NotFound.into()));
+
+        let mut reader = Some(reader);
+        futures::stream::iter(std::iter::from_fn(move || {
+            let mut file = match reader.take()? {
+                Ok(file) => file,
+                Err(e) => return Some(Err(e.into())),
+            };
+
+            match file.read() {
+                Ok(Some(pwd)) => {
+                    reader = Some(Ok(file));
+                    Some(Ok(pwd))
+                }
+                Ok(None) => None,
+                Err(e) => Some(Err(e.into())),
+            }
+        }))
+    }
+
+    /// Runs a search over the records of the handle on the blocking thread pool of tokio,
+    /// so disk reads don't stall the runtime
+    async fn read_blocking<R, F>(file: Arc<Body<LookupFile>>, f: F) -> io::Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Body<LookupFile>) -> io::Result<R> + Send + 'static,
+    {
+        tokio::task::spawn_blocking(move || f(&file))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Reopens the file and rebuilds the index after the file is changed.
+    /// The index is built from the opened file, so they always match
+    fn refresh(&self) -> io::Result<()> {
+        let opened = match self.open_handle() {
+            Ok(opened) => Some(opened),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        if let Some(index) = &self.index {
+            *index.write().unwrap() = match &opened {
+                Some(opened) if !self.format.is_flat() => {
+                    PrefixIndex::read_directory(&*opened.file, self.format)?
+                }
+                Some(opened) => PrefixIndex::build(
+                    &mut BufReader::with_capacity(
+                        self.buff_capacity.unwrap_or(Self::DEFAULT_BUF_SIZE),
+                        ReadAtReader::new(&*opened.file)?,
+                    ),
+                    self.format,
+                )?,
+                None => PrefixIndex::empty(),
+            };
+        }
+
+        let mut handle = self.handle.lock().unwrap();
+        *handle = opened;
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+        Ok(())
+    }
+
+    /// Where the records start
+    fn offset(&self) -> u64 {
+        if self.header {
+            HEADER_LEN
+        } else {
+            0
+        }
+    }
+
+    /// Reads and checks the header or returns None, if the store has no header
+    pub fn header(&self) -> Result<Option<FileHeader>, LocalStoreError> {
+        if !self.header {
+            return Ok(None);
+        }
+
+        let mut file = File::open(&self.file_path)?;
+        FileHeader::read(&mut file, self.format)?
+            .map(Some)
+            .map_err(|reason| LocalStoreError::InvalidHeader {
+                path: self.file_path.clone(),
+                reason,
+            })
+    }
+
+    /// Reads the whole file to compare the records with the checksum of the header.
+    /// Returns false, if the file is corrupted
+    pub fn verify_checksum(&self) -> Result<bool, LocalStoreError> {
+        let Some(header) = self.header()? else {
+            return Ok(true);
+        };
+
+        let mut records = self
+            .open_records()?
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        let mut buf = [0u8; 24];
+        let mut checksum = Checksum::default();
+        while let Some(pwd) = records.read()? {
+            checksum.update(self.format.encode(&pwd, &mut buf));
+        }
+
+        Ok(checksum.value() == header.checksum)
+    }
+
+    /// Opens the file of a save, which writes checkpoints if it is resumable
+    fn open_write(&self) -> Result<PwdFile, LocalStoreError> {
+        let (path, move_on_complete_to) = self.write_paths();
+        let mut pwd_file = self.open_write_at(path, move_on_complete_to)?;
+        pwd_file.checkpoints = self
+            .checkpoint_interval
+            .filter(|_| self.format.is_flat())
+            .map(Checkpoints::new);
+        Ok(pwd_file)
+    }
+
+    /// The file written by a save and where it is moved on completion
+    fn write_paths(&self) -> (PathBuf, Option<PathBuf>) {
+        match &self.existence_behaviour {
             ExistenceBehaviour::RemoveOldThenCreateNew => (self.file_path.clone(), None),
-            ExistenceBehaviour::DownloadThenReplace { download_path } => {
-                let path = download_path
-                    .as_deref()
-                    .map(|p| p.to_path_buf())
-                    .unwrap_or_else(|| self.file_path.with_file_name("download_tmp"));
-                (path, Some(self.file_path.clone()))
+            ExistenceBehaviour::DownloadThenReplace { .. } => {
+                (self.temp_path(), Some(self.file_path.clone()))
             }
+        }
+    }
+
+    /// The checkpoint left by an interrupted save, see [LocalStore::resume]
+    pub fn checkpoint(&self) -> Result<Option<Checkpoint>, LocalStoreError> {
+        Ok(Checkpoint::read(&self.write_paths().0)?)
+    }
+
+    /// Opens the file of an interrupted save truncated to the checkpoint.
+    /// None, if the file doesn't contain all the records of the checkpoint
+    fn open_resume(&self, checkpoint: &Checkpoint) -> Result<Option<PwdFile>, LocalStoreError> {
+        let (path, move_on_complete_to) = self.write_paths();
+        let mut file = match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
         };
 
+        let len = self.offset() + checkpoint.written;
+        if file.metadata()?.len() < len {
+            return Ok(None);
+        }
+        file.set_len(len)?;
+        file.seek(io::SeekFrom::End(0))?;
+
+        Ok(Some(PwdFile {
+            file: BufWriter::with_capacity(
+                self.buff_capacity.unwrap_or(Self::DEFAULT_BUF_SIZE),
+                file,
+            ),
+            format: self.format,
+            header: self.header,
+            records: checkpoint.records,
+            checksum: Checksum::from_value(checkpoint.checksum),
+            written: checkpoint.written,
+            path,
+            move_on_complete_to,
+            last_prefix: Some(checkpoint.last_prefix),
+            sync: self.sync,
+            directory: None,
+            preallocated: false,
+            checkpoints: self.checkpoint_interval.map(Checkpoints::new),
+        }))
+    }
+
+    /// Continues an interrupted save from its checkpoint: the written file is truncated
+    /// to the checkpoint and the chunks up to its prefix are skipped, so the stream may
+    /// start from the beginning or after [Checkpoint::last_prefix].
+    /// Without a checkpoint or the file of the save it is a [LocalStore::save]
+    pub async fn resume<S: Stream<Item = pwned_pwd_core::Chunk> + Unpin + Send>(
+        &self,
+        s: S,
+    ) -> Result<(), LocalStoreError> {
+        let pwd_file = match self.checkpoint()? {
+            Some(checkpoint) if self.format.is_flat() => self.open_resume(&checkpoint)?,
+            _ => None,
+        };
+        let Some(pwd_file) = pwd_file else {
+            return self.save(s).await;
+        };
+
+        let last = pwd_file.last_prefix.map_or(0, |last| u32::from(last) + 1);
+        let s = s.skip_while(move |chunk| futures::future::ready(u32::from(chunk.prefix) < last));
+        self.write_chunks(pwd_file, s, &()).await
+    }
+
+    /// Writes the stream into the file and replaces the store file with it
+    async fn write_chunks<S, O>(
+        &self,
+        mut pwd_file: PwdFile,
+        mut s: S,
+        observer: &O,
+    ) -> Result<(), LocalStoreError>
+    where
+        S: Stream<Item = pwned_pwd_core::Chunk> + Unpin + Send,
+        O: SaveObserver,
+    {
+        let mut progress = SaveProgress::default();
+
+        while let Some(chunk) = s.next().await {
+            pwd_file.check(&chunk)?;
+            let prefix = chunk.prefix;
+            progress.chunk(prefix, chunk.passwords.len());
+
+            for pwned_pwd in chunk {
+                pwd_file.write(&pwned_pwd)?;
+            }
+            pwd_file.chunk_written(prefix)?;
+
+            progress.bytes = Some(pwd_file.flushed());
+            observer.on_progress(&progress);
+        }
+
+        pwd_file.complete()?;
+        Ok(self.refresh()?)
+    }
+
+    /// Path of a file which replaces the store file on completion
+    fn temp_path(&self) -> PathBuf {
+        match &self.existence_behaviour {
+            ExistenceBehaviour::DownloadThenReplace {
+                download_path: Some(download_path),
+            } => download_path.clone(),
+            _ => self.file_path.with_file_name("download_tmp"),
+        }
+    }
+
+    /// Expected length of a saved file, the store file can be removed before the save
+    fn expected_len(&self) -> Option<u64> {
+        match self.preallocation {
+            Preallocation::None => None,
+            Preallocation::CurrentLen => metadata(&self.file_path).ok().map(|m| m.len()),
+            Preallocation::Records(records) => Some(
+                self.offset() + records * self.format.record_len() + self.format.directory_len(),
+            ),
+        }
+    }
+
+    fn open_write_at(
+        &self,
+        path: PathBuf,
+        move_on_complete_to: Option<PathBuf>,
+    ) -> Result<PwdFile, LocalStoreError> {
+        let expected_len = self.expected_len().filter(|len| *len > 0);
         if path.exists() {
             remove_file(&path)?
         }
+        // A checkpoint of an earlier save doesn't describe the new file
+        Checkpoint::remove(&path)?;
 
         let mut options = OpenOptions::new();
         options.create_new(true);
         options.write(true);
         options.read(true);
 
-        let file = BufWriter::with_capacity(
-            self.buff_capacity.unwrap_or(Self::DEFAULT_BUF_SIZE),
-            options.open(&path)?,
-        );
+        let mut file = options.open(&path)?;
+        if let Some(len) = expected_len {
+            preallocate(&file, len).map_err(|e| match e.kind() {
+                io::ErrorKind::StorageFull => LocalStoreError::OutOfSpace {
+                    written_bytes: 0,
+                    estimated_remaining: Some(len),
+                    path: path.clone(),
+                    last_prefix: None,
+                },
+                _ => e.into(),
+            })?;
+        }
+
+        if self.header {
+            // A placeholder, the header is written when the save completes
+            file.write_all(&[0u8; HEADER_LEN as usize])?;
+        }
+
+        let file =
+            BufWriter::with_capacity(self.buff_capacity.unwrap_or(Self::DEFAULT_BUF_SIZE), file);
 
         Ok(PwdFile {
             file,
+            format: self.format,
+            header: self.header,
+            records: 0,
+            checksum: Checksum::default(),
+            written: 0,
             path,
             move_on_complete_to,
+            last_prefix: None,
+            sync: self.sync,
+            directory: (!self.format.is_flat())
+                .then(|| vec![0; PrefixIndex::directory_len() as usize / 4]),
+            preallocated: expected_len.is_some(),
+            checkpoints: None,
         })
     }
 
-    fn open_read(&self) -> io::Result<File> {
+    /// Opens the records of the file
+    fn open_read(&self) -> io::Result<Body<File>> {
         let mut options = OpenOptions::new();
         options.read(true);
-        options.open(&self.file_path)
+        Body::new(options.open(&self.file_path)?, self.offset())
+    }
+
+    /// Opens the store file for merging, an absent file is treated as empty
+    fn open_merge(&self) -> io::Result<Option<BufReader<Body<File>>>> {
+        match self.open_read() {
+            Ok(file) => Ok(Some(BufReader::with_capacity(
+                self.buff_capacity.unwrap_or(Self::DEFAULT_BUF_SIZE),
+                file,
+            ))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Opens the records of the store file for a sequential read, an absent file is treated as empty
+    fn open_records(&self) -> io::Result<Option<Records<BufReader<Body<File>>>>> {
+        let Some(reader) = self.open_merge()? else {
+            return Ok(None);
+        };
+
+        let index = match self.format.is_flat() {
+            true => None,
+            false => Some(PrefixIndex::read_directory(reader.get_ref(), self.format)?),
+        };
+        Ok(Some(Records::new(reader, self.format, index)))
+    }
+
+    /// Checks random parts of the file within a time budget.
+    /// Cheap enough to run on every service start
+    /// Unsupported, if the format isn't flat
+    pub fn verify_sample(&self, verification: &SampleVerification) -> io::Result<SampleReport> {
+        if !self.format.is_flat() {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+
+        let mut file = self.open_read()?;
+        verification.verify_records(&mut file, &mut rand::thread_rng(), self.format.record_len())
+    }
+
+    /// Saves the official `HASH:COUNT` dump ordered by hash, see [DumpReader].
+    /// An invalid line cancels the save, so the file is left as described by [LocalStore::save].
+    /// The dump is read on the runtime thread like the file is written
+    pub async fn import_text(&self, path: impl AsRef<Path>) -> Result<(), LocalStoreError> {
+        let reader = DumpReader::new(BufReader::with_capacity(1024 * 1024, File::open(path)?));
+        let (failed, error) = futures::channel::oneshot::channel();
+
+        let chunks = futures::stream::unfold((reader, failed), |(mut reader, failed)| async {
+            match reader.next()? {
+                Ok(chunk) => Some((chunk, (reader, failed))),
+                Err(e) => {
+                    let _ = failed.send(e);
+                    futures::future::pending().await
+                }
+            }
+        });
+
+        tokio::select! {
+            biased;
+            Ok(e) = error => Err(e.into()),
+            res = self.save(Box::pin(chunks)) => res,
+        }
+    }
+
+    /// Writes the records as `HASH:COUNT` lines ordered by hash, like the official dump.
+    /// Counts are 0, if the format doesn't keep them. Returns the count of the lines
+    pub fn export_text<W: Write>(&self, writer: W) -> Result<u64, LocalStoreError> {
+        let mut records = self
+            .open_records()?
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        let mut writer = BufWriter::with_capacity(1024 * 1024, writer);
+
+        let mut lines = 0;
+        while let Some(pwd) = records.read()? {
+            writeln!(writer, "{:X}:{}", pwd, pwd.count)?;
+            lines += 1;
+        }
+
+        writer.flush()?;
+        Ok(lines)
+    }
+
+    /// Starts dedicated threads for lookups in the current file.
+    /// Unsupported, if the format isn't flat
+    #[cfg(feature = "pool")]
+    pub fn lookup_pool(&self, config: &pool::PoolConfig) -> io::Result<pool::LookupPool> {
+        if !self.format.is_flat() {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+
+        pool::LookupPool::new(&self.file_path, config, self.format, self.offset())
     }
 }
 
-/// A store which saves ordered password hashes as bytes into a file and searches in it with binary search
-impl Store for LocalStore {
-    type Error = std::io::Error;
+/// A store which saves ordered password hashes as bytes into a file and searches in it with binary search.
+/// If the disk becomes full during save, the save stops with [LocalStoreError::OutOfSpace]
+impl ReadStore for LocalStore {
+    type Error = LocalStoreError;
+
+    /// The search runs on the blocking thread pool of tokio, so it requires the tokio runtime
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        Ok(self.search(val).await?.is_some())
+    }
+
+    /// Counts are 0, if the format doesn't keep them
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        self.records_stream()
+    }
+
+    /// Sorts the hashes and finds them in one forward pass over the file,
+    /// within their prefixes, if the store is indexed
+    async fn exists_many(&self, vals: &[[u8; 20]]) -> Result<Vec<bool>, Self::Error> {
+        let format = self.format;
+        let file = self.handle()?;
+        let vals = vals
+            .iter()
+            .map(|val| (*val, self.index_range(val)))
+            .collect::<Vec<_>>();
+
+        Ok(Self::read_blocking(file, move |file| exists_many(file, &vals, format)).await?)
+    }
+
+    /// Unsupported, unless the format is [RecordFormat::HashesWithCounts]
+    async fn exists_count(&self, val: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        if !self.format.has_counts() {
+            return Err(
+                io::Error::new(io::ErrorKind::Unsupported, "The file doesn't keep counts").into(),
+            );
+        }
+
+        Ok(self.search(val).await?)
+    }
+
+    /// Reads the records of the prefix at once, they are found by the index
+    /// or by binary searches of the first records of the prefix and of the next one
+    async fn range(&self, prefix: Prefix) -> Result<Chunk, Self::Error> {
+        let file = self.handle()?;
+        let records = self
+            .index
+            .as_ref()
+            .map(|index| index.read().unwrap().range(prefix));
+        let format = self.format;
+
+        let passwords =
+            Self::read_blocking(file, move |file| read_range(file, prefix, records, format))
+                .await?;
+        Ok(Chunk { prefix, passwords })
+    }
 
-    fn save<
-        'a,
-        S: 'a + Stream<Item = pwned_pwd_core::Chunk> + std::marker::Unpin + std::marker::Send,
+    /// Records and the update time are taken from the header. A file without a header
+    /// is described by its length and modification time
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        if let Some(header) = self.header()? {
+            return Ok(StoreMetadata {
+                records: Some(header.records),
+                updated_at: Some(header.created_at()),
+                ..Default::default()
+            });
+        }
+
+        let metadata = std::fs::metadata(&self.file_path)?;
+
+        Ok(StoreMetadata {
+            records: Some(
+                metadata.len().saturating_sub(self.format.directory_len())
+                    / self.format.record_len(),
+            ),
+            updated_at: metadata.modified().ok(),
+            ..Default::default()
+        })
+    }
+
+    /// The prefix of the last hash in the file, a file of suffixes takes it from the directory. With [ExistenceBehaviour::RemoveOldThenCreateNew]
+    /// the file may be left by an interrupted save with the last prefix partially written,
+    /// so the prefix before it is returned, unless it is [Prefix::max]
+    async fn max_prefix(&self) -> Result<Option<Prefix>, Self::Error> {
+        if let (false, Some(index)) = (self.format.is_flat(), &self.index) {
+            return Ok(index.read().unwrap().last_prefix());
+        }
+
+        let mut file = match self.open_read() {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let len = self.format.record_len();
+        let records = file.seek(io::SeekFrom::End(0))? / len;
+        if records == 0 {
+            return Ok(None);
+        }
+
+        let mut last = [0u8; 20];
+        file.seek(io::SeekFrom::Start((records - 1) * len))?;
+        file.read_exact(&mut last)?;
+
+        let prefix = Prefix::from_sha1(&last);
+        Ok(match self.existence_behaviour {
+            ExistenceBehaviour::RemoveOldThenCreateNew if prefix != Prefix::max() => prefix.prev(),
+            _ => Some(prefix),
+        })
+    }
+
+    /// The file exists, has a valid header, isn't empty and consists of whole records
+    async fn healthy(&self) -> Result<bool, Self::Error> {
+        match self.header() {
+            Ok(Some(header)) => return Ok(header.records > 0),
+            Ok(None) => (),
+            Err(LocalStoreError::InvalidHeader { .. }) => return Ok(false),
+            Err(LocalStoreError::Io(e)) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        }
+
+        match std::fs::metadata(&self.file_path) {
+            Ok(metadata) => Ok(self
+                .format
+                .records_in(metadata.len())
+                .is_some_and(|records| records > 0)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl WriteStore for LocalStore {
+    /// If the save is cancelled (its future is dropped), with [ExistenceBehaviour::DownloadThenReplace]
+    /// the original file is kept and the partial temp file is left until the next save
+    /// (see [advisor::Issue::OrphanedTempFile]), with [ExistenceBehaviour::RemoveOldThenCreateNew]
+    /// the file contains the chunks written before the cancel.
+    /// A chunk which breaks the order stops the save with [LocalStoreError::InvalidChunk]
+    /// or [LocalStoreError::UnorderedChunk], the same way
+    async fn save<S: Stream<Item = pwned_pwd_core::Chunk> + Unpin + Send>(
+        &self,
+        s: S,
+    ) -> Result<(), Self::Error> {
+        self.save_observed(s, &()).await
+    }
+
+    /// Reports bytes flushed into the file
+    async fn save_observed<
+        S: Stream<Item = pwned_pwd_core::Chunk> + Unpin + Send,
+        O: SaveObserver,
     >(
-        &'a self,
+        &self,
+        s: S,
+        observer: &O,
+    ) -> Result<(), Self::Error> {
+        let pwd_file = self.open_write()?;
+        self.write_chunks(pwd_file, s, observer).await
+    }
+
+    /// Merge-joins the file with the stream into the temp file, then replaces the file.
+    /// The stream must be ordered like for [LocalStore::save]
+    async fn merge<S: Stream<Item = pwned_pwd_core::Chunk> + Unpin + Send>(
+        &self,
         mut s: S,
-    ) -> BoxFuture<'a, Result<(), Self::Error>> {
-        Box::pin(async move {
-            let mut pwd_file = self.open_write()?;
+    ) -> Result<(), Self::Error> {
+        let mut existing = self.open_records()?;
+        let mut pwd_file = self.open_write_at(self.temp_path(), Some(self.file_path.clone()))?;
+        let mut read_existing = || -> io::Result<Option<PwnedPwd>> {
+            Ok(match existing.as_mut() {
+                Some(existing) => existing.read()?,
+                None => None,
+            })
+        };
+        let mut next_existing = read_existing()?;
 
-            while let Some(chunk) = s.next().await {
-                for pwned_pwd in chunk {
-                    pwd_file.write(pwned_pwd)?;
+        while let Some(chunk) = s.next().await {
+            pwd_file.check(&chunk)?;
+            let prefix = chunk.prefix;
+            for pwned_pwd in chunk {
+                while let Some(pwd) = next_existing.take_if(|pwd| pwd.hash < pwned_pwd.hash) {
+                    pwd_file.write(&pwd)?;
+                    next_existing = read_existing()?;
                 }
+
+                if next_existing
+                    .as_ref()
+                    .is_some_and(|pwd| pwd.hash == pwned_pwd.hash)
+                {
+                    next_existing = read_existing()?;
+                }
+
+                pwd_file.write(&pwned_pwd)?;
             }
+            pwd_file.chunk_written(prefix)?;
+        }
 
-            pwd_file.complete()?;
-            Ok(())
-        })
+        while let Some(pwd) = next_existing {
+            pwd_file.write(&pwd)?;
+            next_existing = read_existing()?;
+        }
+
+        drop(existing);
+        pwd_file.complete()?;
+        Ok(self.refresh()?)
     }
 
-    fn exists<'a>(&'a self, val: [u8; 20]) -> BoxFuture<'a, Result<bool, Self::Error>> {
-        Box::pin(async move {
-            let mut file = self.open_read()?;
-            exists(&mut file, val)
-        })
+    /// Rewrites the file without the hash, so it is as slow as a save
+    async fn remove(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        if self.lookup(&*self.handle()?, val)?.is_none() {
+            return Ok(false);
+        }
+
+        let mut reader = self
+            .open_records()?
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        let mut pwd_file = self.open_write_at(self.temp_path(), Some(self.file_path.clone()))?;
+
+        while let Some(pwd) = reader.read()? {
+            if pwd.hash != val {
+                pwd_file.write(&pwd)?;
+            }
+        }
+
+        drop(reader);
+        pwd_file.complete()?;
+        self.refresh()?;
+        Ok(true)
+    }
+
+    /// Removes the file and the temp file
+    async fn clear(&self) -> Result<(), Self::Error> {
+        for path in [&self.file_path, &self.temp_path()] {
+            match remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+        Ok(self.refresh()?)
     }
 
     fn order_requirement() -> pwned_pwd_store::OrderRequirement {
@@ -142,31 +1083,265 @@ impl Store for LocalStore {
     }
 }
 
-fn exists<T: Seek + Read>(data: &mut T, x: [u8; 20]) -> Result<bool, std::io::Error> {
-    let mut size = data.seek(io::SeekFrom::End(0))? / 20;
-    let mut left = 0u64;
+/// Renames the file. Between filesystems the file is copied next to the target
+/// and the copy is renamed, so the target is replaced atomically anyway
+fn replace(from: &Path, to: &Path, sync: bool) -> io::Result<()> {
+    match rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => (),
+        res => return res,
+    }
+
+    tracing::warn!(
+        "'{}' is on another filesystem than '{}', it is copied",
+        from.display(),
+        to.display()
+    );
+
+    let copy = to.with_extension("copy");
+    std::fs::copy(from, &copy)?;
+    if sync {
+        File::open(&copy)?.sync_all()?;
+    }
+    rename(&copy, to)?;
+    remove_file(from)
+}
+
+/// Reserves the blocks without changing the length of the file,
+/// so a cancelled save doesn't leave zeroes after the records
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let len = libc::off_t::try_from(len).map_err(io::Error::other)?;
+    // SAFETY: the descriptor is owned by the file, which outlives the call
+    match unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) } {
+        0 => Ok(()),
+        _ => match io::Error::last_os_error() {
+            // The filesystem allocates on write
+            e if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+            e => Err(e),
+        },
+    }
+}
+
+/// Other systems allocate on write, growing the length in advance would leave
+/// zeroes after the records of a cancelled save
+#[cfg(not(target_os = "linux"))]
+fn preallocate(_: &File, _: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// Identity of a file, a file which replaced another one by a rename has a new identity
+#[cfg(unix)]
+type FileId = (u64, u64);
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> FileId {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+/// Identity of a file, a file which replaced another one is likely modified at another time
+#[cfg(not(unix))]
+type FileId = (Option<std::time::SystemTime>, u64);
+
+#[cfg(not(unix))]
+fn file_id(metadata: &Metadata) -> FileId {
+    (metadata.modified().ok(), metadata.len())
+}
+
+/// Persists the directory entry of the file
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// Directories can't be opened as files, a rename is persisted by the filesystem
+#[cfg(not(unix))]
+fn sync_dir(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Records of the prefix in the `records` range or, without it, between the bounds
+/// found by binary searches. A file of suffixes can be read only with its index
+fn read_range<T: ReadAt>(
+    data: &T,
+    prefix: Prefix,
+    records: Option<Range<u64>>,
+    format: RecordFormat,
+) -> io::Result<Vec<PwnedPwd>> {
+    let len = format.record_len();
+    let records = match records {
+        Some(records) => records,
+        None if format.is_flat() => {
+            let size = data.size()? / len;
+            let start = first_of(data, prefix, size, len)?;
+            let end = match prefix.next() {
+                Some(next) => first_of(data, next, size, len)?,
+                None => size,
+            };
+            start..end
+        }
+        None => return Err(io::ErrorKind::Unsupported.into()),
+    };
+
+    let mut buf = vec![0u8; ((records.end - records.start) * len) as usize];
+    data.read_exact_at(&mut buf, records.start * len)?;
+
+    buf.chunks_exact(len as usize)
+        .map(|record| match format.is_flat() {
+            true => {
+                let (hash, count) = record.split_at(RecordFormat::HASH_LEN);
+                Ok(PwnedPwd {
+                    hash: hash.try_into().expect("Hash is 20 bytes"),
+                    count: count.try_into().map_or(0, u32::from_be_bytes),
+                })
+            }
+            false => {
+                let suffix = Suffix::from_bytes(record.try_into().expect("Suffix is 18 bytes"))
+                    .ok_or(io::ErrorKind::InvalidData)?;
+                Ok(PwnedPwd {
+                    hash: prefix.with_suffix(&suffix),
+                    count: 0,
+                })
+            }
+        })
+        .collect()
+}
+
+/// Index of the first record of a flat file whose prefix isn't less than `prefix`
+fn first_of<T: ReadAt>(data: &T, prefix: Prefix, size: u64, len: u64) -> io::Result<u64> {
+    let mut left = 0;
     let mut right = size;
-    let mut buf = [0u8; 20];
+    let mut hash = [0u8; 20];
+
+    while left < right {
+        let mid = left + (right - left) / 2;
+        data.read_exact_at(&mut hash, mid * len)?;
+
+        match Prefix::from_sha1(&hash) < prefix {
+            true => left = mid + 1,
+            false => right = mid,
+        }
+    }
+
+    Ok(left)
+}
+
+/// Binary search among the records in the range or in the whole data.
+/// Returns the count of the found hash, 0 if the format doesn't keep counts
+fn find<T: ReadAt>(
+    data: &T,
+    x: [u8; 20],
+    records: Option<Range<u64>>,
+    format: RecordFormat,
+) -> io::Result<Option<u32>> {
+    let len = format.record_len();
+    let records = match records {
+        Some(records) => records,
+        None => 0..data.size()? / len,
+    };
+
+    let mut left = records.start;
+    let mut right = records.end;
+    let mut size = right - left;
+    let mut buf = [0u8; 24];
+    let buf = &mut buf[..len as usize];
+    let mut key = [0u8; 24];
+    let key = &format.encode(&PwnedPwd { hash: x, count: 0 }, &mut key)[..format.key_len()];
 
     while left < right {
         let mid = left + size / 2;
 
-        data.seek(io::SeekFrom::Start(mid * 20))?;
-        data.read_exact(&mut buf)?;
+        data.read_exact_at(buf, mid * len)?;
 
-        let cmp = (&buf).cmp(&x);
+        let (hash, count) = buf.split_at(format.key_len());
+        let cmp = hash.cmp(key);
 
         left = if cmp == Ordering::Less { mid + 1 } else { left };
         right = if cmp == Ordering::Greater { mid } else { right };
 
         if cmp == Ordering::Equal {
-            return Ok(true);
+            return Ok(Some(count.try_into().map_or(0, u32::from_be_bytes)));
         }
 
         size = right - left;
     }
 
-    Ok(false)
+    Ok(None)
+}
+
+/// Finds the hashes in one forward pass: they are sorted and the search of a hash gallops
+/// from the position of the previous one, so close hashes cost a few reads and
+/// a batch never reads a record twice. `records` of a hash are the records of its prefix,
+/// if the store is indexed
+fn exists_many<T: ReadAt>(
+    data: &T,
+    vals: &[([u8; 20], Option<Range<u64>>)],
+    format: RecordFormat,
+) -> io::Result<Vec<bool>> {
+    let len = format.record_len();
+    let size = data.size()? / len;
+    let key_len = format.key_len();
+
+    let mut order = (0..vals.len()).collect::<Vec<_>>();
+    order.sort_unstable_by_key(|i| vals[*i].0);
+
+    let mut res = vec![false; vals.len()];
+    let mut left = 0u64;
+    let mut buf = [0u8; 24];
+    let buf = &mut buf[..len as usize];
+    let mut key = [0u8; 24];
+
+    for i in order {
+        let (val, records) = &vals[i];
+        let key = &format.encode(
+            &PwnedPwd {
+                hash: *val,
+                count: 0,
+            },
+            &mut key,
+        )[..key_len];
+        let records = records.clone().unwrap_or(0..size);
+        let end = records.end.min(size);
+
+        // Gallops to a record which isn't less than the hash, the hash is in lo..hi
+        let mut lo = left.max(records.start);
+        let mut hi = lo;
+        let mut step = 1;
+        while hi < end {
+            data.read_exact_at(buf, hi * len)?;
+            if &buf[..key_len] >= key {
+                break;
+            }
+
+            lo = hi + 1;
+            hi = (hi + step).min(end);
+            step *= 2;
+        }
+
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            data.read_exact_at(buf, mid * len)?;
+
+            if &buf[..key_len] < key {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        if lo < end {
+            data.read_exact_at(buf, lo * len)?;
+            res[i] = &buf[..key_len] == key;
+        }
+        left = lo;
+    }
+
+    Ok(res)
 }
 
 #[cfg(test)]
@@ -176,9 +1351,14 @@ mod tests {
 
     use futures::SinkExt;
     use hex_literal::hex;
-    use pwned_pwd_core::{Chunk, Prefix};
+    use pwned_pwd_core::{Chunk, ChunkError, Prefix};
 
     use super::*;
+    use crate::cache::CacheSize;
+
+    fn exists<T: ReadAt>(data: &T, x: [u8; 20]) -> Result<bool, std::io::Error> {
+        Ok(find(data, x, None, RecordFormat::Hashes)?.is_some())
+    }
 
     #[test]
     fn exists_even_found() {
@@ -199,22 +1379,22 @@ mod tests {
             21BD403D9886FA118CE12F02212EEE72B3C3BD4A
         ");
 
-        let mut cursor = Cursor::new(data);
+        let cursor = Cursor::new(data);
 
-        assert!(exists(&mut cursor, hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD40110328459B74EC3CC4ADCE47093DA97FD0")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD4011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0D")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD401223249190CD4C2B5E2537329726EC5667")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD4021BFAACC3E46C4FC74BE8E7D2FDF7CF698")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD4026DC435DCAB3564A0FD64AD921D827E146")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD4026F2E5BA164D1B277D9AF5085249F414DB")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD402A437B1A6FA37515B549B5D830E838CCC4")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD402C77AFF03FC91842C503DB0BB83AB1BBE6")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD402CDE32C2D1295997B3CE1475C828BA20CE")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD402EE1FBAB40E737BDB81EDF820EB621B1A9")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD4030368B0426D8F5497810ACC3AAFE6FC5F1")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD403D9886FA118CE12F02212EEE72B3C3BD4A")).unwrap());
+        assert!(exists(&cursor, hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).unwrap());
+        assert!(exists(&cursor, hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED")).unwrap());
+        assert!(exists(&cursor, hex!("21BD40110328459B74EC3CC4ADCE47093DA97FD0")).unwrap());
+        assert!(exists(&cursor, hex!("21BD4011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0D")).unwrap());
+        assert!(exists(&cursor, hex!("21BD401223249190CD4C2B5E2537329726EC5667")).unwrap());
+        assert!(exists(&cursor, hex!("21BD4021BFAACC3E46C4FC74BE8E7D2FDF7CF698")).unwrap());
+        assert!(exists(&cursor, hex!("21BD4026DC435DCAB3564A0FD64AD921D827E146")).unwrap());
+        assert!(exists(&cursor, hex!("21BD4026F2E5BA164D1B277D9AF5085249F414DB")).unwrap());
+        assert!(exists(&cursor, hex!("21BD402A437B1A6FA37515B549B5D830E838CCC4")).unwrap());
+        assert!(exists(&cursor, hex!("21BD402C77AFF03FC91842C503DB0BB83AB1BBE6")).unwrap());
+        assert!(exists(&cursor, hex!("21BD402CDE32C2D1295997B3CE1475C828BA20CE")).unwrap());
+        assert!(exists(&cursor, hex!("21BD402EE1FBAB40E737BDB81EDF820EB621B1A9")).unwrap());
+        assert!(exists(&cursor, hex!("21BD4030368B0426D8F5497810ACC3AAFE6FC5F1")).unwrap());
+        assert!(exists(&cursor, hex!("21BD403D9886FA118CE12F02212EEE72B3C3BD4A")).unwrap());
     }
 
     #[test]
@@ -235,21 +1415,21 @@ mod tests {
             21BD4030368B0426D8F5497810ACC3AAFE6FC5F1
         ");
 
-        let mut cursor = Cursor::new(data);
+        let cursor = Cursor::new(data);
 
-        assert!(exists(&mut cursor, hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD40110328459B74EC3CC4ADCE47093DA97FD0")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD4011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0D")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD401223249190CD4C2B5E2537329726EC5667")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD4021BFAACC3E46C4FC74BE8E7D2FDF7CF698")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD4026DC435DCAB3564A0FD64AD921D827E146")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD4026F2E5BA164D1B277D9AF5085249F414DB")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD402A437B1A6FA37515B549B5D830E838CCC4")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD402C77AFF03FC91842C503DB0BB83AB1BBE6")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD402CDE32C2D1295997B3CE1475C828BA20CE")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD402EE1FBAB40E737BDB81EDF820EB621B1A9")).unwrap());
-        assert!(exists(&mut cursor, hex!("21BD4030368B0426D8F5497810ACC3AAFE6FC5F1")).unwrap());
+        assert!(exists(&cursor, hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).unwrap());
+        assert!(exists(&cursor, hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED")).unwrap());
+        assert!(exists(&cursor, hex!("21BD40110328459B74EC3CC4ADCE47093DA97FD0")).unwrap());
+        assert!(exists(&cursor, hex!("21BD4011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0D")).unwrap());
+        assert!(exists(&cursor, hex!("21BD401223249190CD4C2B5E2537329726EC5667")).unwrap());
+        assert!(exists(&cursor, hex!("21BD4021BFAACC3E46C4FC74BE8E7D2FDF7CF698")).unwrap());
+        assert!(exists(&cursor, hex!("21BD4026DC435DCAB3564A0FD64AD921D827E146")).unwrap());
+        assert!(exists(&cursor, hex!("21BD4026F2E5BA164D1B277D9AF5085249F414DB")).unwrap());
+        assert!(exists(&cursor, hex!("21BD402A437B1A6FA37515B549B5D830E838CCC4")).unwrap());
+        assert!(exists(&cursor, hex!("21BD402C77AFF03FC91842C503DB0BB83AB1BBE6")).unwrap());
+        assert!(exists(&cursor, hex!("21BD402CDE32C2D1295997B3CE1475C828BA20CE")).unwrap());
+        assert!(exists(&cursor, hex!("21BD402EE1FBAB40E737BDB81EDF820EB621B1A9")).unwrap());
+        assert!(exists(&cursor, hex!("21BD4030368B0426D8F5497810ACC3AAFE6FC5F1")).unwrap());
     }
 
     #[test]
@@ -270,33 +1450,33 @@ mod tests {
             21BD4030368B0426D8F5497810ACC3AAFE6FC5F1
         ");
 
-        let mut cursor = Cursor::new(data);
-        assert!(!exists(&mut cursor, hex!("21BD4004DDDC80AE4683948C5A1C5903584D8086")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4004DDDC80AE4683948C5A1C5903584D8088")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2EC")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2EE")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD40110328459B74EC3CC4ADCE47093DA97FCF")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD40110328459B74EC3CC4ADCE47093DA97FD1")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0C")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0E")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD401223249190CD4C2B5E2537329726EC5666")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD401223249190CD4C2B5E2537329726EC5668")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4021BFAACC3E46C4FC74BE8E7D2FDF7CF697")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4021BFAACC3E46C4FC74BE8E7D2FDF7CF699")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4026DC435DCAB3564A0FD64AD921D827E145")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4026DC435DCAB3564A0FD64AD921D827E147")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4026F2E5BA164D1B277D9AF5085249F414DA")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4026F2E5BA164D1B277D9AF5085249F414DC")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD402A437B1A6FA37515B549B5D830E838CCC3")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD402A437B1A6FA37515B549B5D830E838CCC5")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD402C77AFF03FC91842C503DB0BB83AB1BBE5")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD402C77AFF03FC91842C503DB0BB83AB1BBE7")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD402CDE32C2D1295997B3CE1475C828BA20CD")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD402CDE32C2D1295997B3CE1475C828BA20CF")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD402EE1FBAB40E737BDB81EDF820EB621B1A8")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD402EE1FBAB40E737BDB81EDF820EB621B1AA")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4030368B0426D8F5497810ACC3AAFE6FC5F0")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4030368B0426D8F5497810ACC3AAFE6FC5F2")).unwrap());
+        let cursor = Cursor::new(data);
+        assert!(!exists(&cursor, hex!("21BD4004DDDC80AE4683948C5A1C5903584D8086")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4004DDDC80AE4683948C5A1C5903584D8088")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2EC")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2EE")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD40110328459B74EC3CC4ADCE47093DA97FCF")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD40110328459B74EC3CC4ADCE47093DA97FD1")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0C")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0E")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD401223249190CD4C2B5E2537329726EC5666")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD401223249190CD4C2B5E2537329726EC5668")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4021BFAACC3E46C4FC74BE8E7D2FDF7CF697")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4021BFAACC3E46C4FC74BE8E7D2FDF7CF699")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4026DC435DCAB3564A0FD64AD921D827E145")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4026DC435DCAB3564A0FD64AD921D827E147")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4026F2E5BA164D1B277D9AF5085249F414DA")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4026F2E5BA164D1B277D9AF5085249F414DC")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD402A437B1A6FA37515B549B5D830E838CCC3")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD402A437B1A6FA37515B549B5D830E838CCC5")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD402C77AFF03FC91842C503DB0BB83AB1BBE5")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD402C77AFF03FC91842C503DB0BB83AB1BBE7")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD402CDE32C2D1295997B3CE1475C828BA20CD")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD402CDE32C2D1295997B3CE1475C828BA20CF")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD402EE1FBAB40E737BDB81EDF820EB621B1A8")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD402EE1FBAB40E737BDB81EDF820EB621B1AA")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4030368B0426D8F5497810ACC3AAFE6FC5F0")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4030368B0426D8F5497810ACC3AAFE6FC5F2")).unwrap());
     }
 
     #[test]
@@ -318,35 +1498,35 @@ mod tests {
             21BD403D9886FA118CE12F02212EEE72B3C3BD4A
         ");
 
-        let mut cursor = Cursor::new(data);
-        assert!(!exists(&mut cursor, hex!("21BD4004DDDC80AE4683948C5A1C5903584D8086")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4004DDDC80AE4683948C5A1C5903584D8088")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2EC")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2EE")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD40110328459B74EC3CC4ADCE47093DA97FCF")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD40110328459B74EC3CC4ADCE47093DA97FD1")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0C")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0E")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD401223249190CD4C2B5E2537329726EC5666")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD401223249190CD4C2B5E2537329726EC5668")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4021BFAACC3E46C4FC74BE8E7D2FDF7CF697")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4021BFAACC3E46C4FC74BE8E7D2FDF7CF699")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4026DC435DCAB3564A0FD64AD921D827E145")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4026DC435DCAB3564A0FD64AD921D827E147")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4026F2E5BA164D1B277D9AF5085249F414DA")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4026F2E5BA164D1B277D9AF5085249F414DC")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD402A437B1A6FA37515B549B5D830E838CCC3")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD402A437B1A6FA37515B549B5D830E838CCC5")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD402C77AFF03FC91842C503DB0BB83AB1BBE5")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD402C77AFF03FC91842C503DB0BB83AB1BBE7")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD402CDE32C2D1295997B3CE1475C828BA20CD")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD402CDE32C2D1295997B3CE1475C828BA20CF")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD402EE1FBAB40E737BDB81EDF820EB621B1A8")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD402EE1FBAB40E737BDB81EDF820EB621B1AA")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4030368B0426D8F5497810ACC3AAFE6FC5F0")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD4030368B0426D8F5497810ACC3AAFE6FC5F2")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD403D9886FA118CE12F02212EEE72B3C3BD49")).unwrap());
-        assert!(!exists(&mut cursor, hex!("21BD403D9886FA118CE12F02212EEE72B3C3BD4B")).unwrap());
+        let cursor = Cursor::new(data);
+        assert!(!exists(&cursor, hex!("21BD4004DDDC80AE4683948C5A1C5903584D8086")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4004DDDC80AE4683948C5A1C5903584D8088")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2EC")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2EE")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD40110328459B74EC3CC4ADCE47093DA97FCF")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD40110328459B74EC3CC4ADCE47093DA97FD1")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0C")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0E")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD401223249190CD4C2B5E2537329726EC5666")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD401223249190CD4C2B5E2537329726EC5668")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4021BFAACC3E46C4FC74BE8E7D2FDF7CF697")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4021BFAACC3E46C4FC74BE8E7D2FDF7CF699")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4026DC435DCAB3564A0FD64AD921D827E145")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4026DC435DCAB3564A0FD64AD921D827E147")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4026F2E5BA164D1B277D9AF5085249F414DA")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4026F2E5BA164D1B277D9AF5085249F414DC")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD402A437B1A6FA37515B549B5D830E838CCC3")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD402A437B1A6FA37515B549B5D830E838CCC5")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD402C77AFF03FC91842C503DB0BB83AB1BBE5")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD402C77AFF03FC91842C503DB0BB83AB1BBE7")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD402CDE32C2D1295997B3CE1475C828BA20CD")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD402CDE32C2D1295997B3CE1475C828BA20CF")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD402EE1FBAB40E737BDB81EDF820EB621B1A8")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD402EE1FBAB40E737BDB81EDF820EB621B1AA")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4030368B0426D8F5497810ACC3AAFE6FC5F0")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD4030368B0426D8F5497810ACC3AAFE6FC5F2")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD403D9886FA118CE12F02212EEE72B3C3BD49")).unwrap());
+        assert!(!exists(&cursor, hex!("21BD403D9886FA118CE12F02212EEE72B3C3BD4B")).unwrap());
     }
 
     #[tokio::test]
@@ -375,11 +1555,7 @@ mod tests {
         file.flush().expect("flush error");
         drop(file);
 
-        let store = LocalStore {
-            file_path: tmp_file_path,
-            existence_behaviour: Default::default(),
-            buff_capacity: None,
-        };
+        let store = LocalStore::builder(tmp_file_path).without_header().build().unwrap();
 
         assert!(store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
         assert!(store.exists(hex!("21BD401223249190CD4C2B5E2537329726EC5667")).await.unwrap());
@@ -388,25 +1564,305 @@ mod tests {
         assert!(!store.exists(hex!("21BD403D9886FA118CE12F02212EEE72B3C3BD4B")).await.unwrap());
     }
 
+    #[tokio::test]
+    async fn store_range() {
+        let dir = temp_dir().join("pwned_pwd_tests_store_range");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let pwd = |hash, count| PwnedPwd { hash, count };
+        let chunks = || futures::stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD3).unwrap(), passwords: vec![pwd(hex!("21BD3FFF08998514E6E8F28DBB4CA9F74EA5CAFA"), 1)] },
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![
+                pwd(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), 2),
+                pwd(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"), 3),
+            ] },
+            Chunk { prefix: Prefix::max(), passwords: vec![pwd(hex!("FFFFF9D7385261CA008A9777A93D86A6AB997F57"), 4)] },
+        ]);
+
+        for (builder, counted) in [
+            (LocalStore::builder(dir.join("flat")).with_format(RecordFormat::HashesWithCounts), true),
+            (LocalStore::builder(dir.join("indexed")).with_index(), false),
+            (LocalStore::builder(dir.join("suffixes")).with_format(RecordFormat::Suffixes), false),
+        ] {
+            let _ = std::fs::remove_file(builder.clone().build().unwrap().file_path());
+            let store = builder.build().unwrap();
+            store.save(chunks()).await.unwrap();
+
+            let count = |count| if counted { count } else { 0 };
+            assert_eq!(vec![
+                pwd(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count(2)),
+                pwd(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"), count(3)),
+            ], store.range(Prefix::create(0x21BD4).unwrap()).await.unwrap().passwords);
+            assert_eq!(1, store.range(Prefix::max()).await.unwrap().passwords.len());
+            assert!(store.range(Prefix::create(0x21BD5).unwrap()).await.unwrap().passwords.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn store_handle_refreshed() {
+        let dir = temp_dir().join("pwned_pwd_tests_store_handle");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("pwned"), hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).unwrap();
+
+        let store = LocalStore::builder(dir.join("pwned"))
+            .with_existence_behaviour(ExistenceBehaviour::DownloadThenReplace { download_path: None })
+            .without_header()
+            .build()
+            .unwrap();
+
+        assert!(store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert!(Arc::ptr_eq(&store.handle().unwrap(), &store.handle().unwrap()));
+
+        store.save(futures::stream::iter(vec![Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![
+            PwnedPwd { hash: hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087"), count: 1 },
+        ]}])).await.unwrap();
+
+        assert!(store.exists(hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert!(!store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn store_reload() {
+        let dir = temp_dir().join("pwned_pwd_tests_store_reload");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("pwned"), hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).unwrap();
+
+        let builder = LocalStore::builder(dir.join("pwned")).without_header().with_index();
+        let store = builder.clone().build().unwrap();
+        let watching = builder.with_reload_interval(Duration::ZERO).build().unwrap();
+
+        assert!(store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert!(!store.reload().unwrap());
+
+        std::fs::write(dir.join("download"), hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")).unwrap();
+        std::fs::rename(dir.join("download"), dir.join("pwned")).unwrap();
+
+        assert!(store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert!(store.reload().unwrap());
+        assert!(!store.reload().unwrap());
+        assert!(store.exists(hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert!(!store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+
+        assert!(watching.exists(hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn store_cache() {
+        let dir = temp_dir().join("pwned_pwd_tests_store_cache");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("pwned"), hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).unwrap();
+
+        let store = LocalStore::builder(dir.join("pwned"))
+            .without_header()
+            .with_cache(CacheSize::Entries(16))
+            .build()
+            .unwrap();
+
+        assert!(store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert!(!store.exists(hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+
+        // Written in place behind the store's back, the cached results are served
+        std::fs::write(dir.join("pwned"), hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")).unwrap();
+        assert!(store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert!(!store.exists(hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+
+        store.save(futures::stream::iter(vec![Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![
+            PwnedPwd { hash: hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087"), count: 1 },
+        ]}])).await.unwrap();
+
+        assert!(!store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert!(store.exists(hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn store_preallocated() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = temp_dir().join("pwned_pwd_tests_store_preallocated");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let file = File::create(dir.join("reserved")).unwrap();
+        preallocate(&file, 1 << 20).unwrap();
+        let metadata = file.metadata().unwrap();
+        assert_eq!(0, metadata.len());
+        assert!(metadata.blocks() == 0 || metadata.blocks() * 512 >= 1 << 20);
+
+        let store = LocalStore::builder(dir.join("pwned"))
+            .with_preallocation(Preallocation::Records(1 << 16))
+            .build()
+            .unwrap();
+
+        store.save(futures::stream::iter(vec![Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![
+            PwnedPwd { hash: hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087"), count: 1 },
+        ]}])).await.unwrap();
+
+        assert_eq!(HEADER_LEN + 20, std::fs::metadata(dir.join("pwned")).unwrap().len());
+        assert!(store.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn replace_across_filesystems() {
+        let dir = temp_dir().join("pwned_pwd_tests_replace");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("pwned"), b"old").unwrap();
+
+        // Another filesystem, if the system has one
+        let other = Path::new("/dev/shm").join("pwned_pwd_tests_replace");
+        let from = match std::fs::write(&other, b"new") {
+            Ok(()) => other,
+            Err(_) => dir.join("download"),
+        };
+        std::fs::write(&from, b"new").unwrap();
+
+        replace(&from, &dir.join("pwned"), true).unwrap();
+        assert_eq!(b"new", std::fs::read(dir.join("pwned")).unwrap().as_slice());
+        assert!(!from.exists());
+        assert!(!dir.join("pwned.copy").exists());
+    }
+
+    #[tokio::test]
+    async fn store_resume() {
+        let dir = temp_dir().join("pwned_pwd_tests_store_resume");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let store = LocalStore::builder(dir.join("pwned"))
+            .with_checkpoints(Duration::ZERO)
+            .without_sync()
+            .build()
+            .unwrap();
+
+        let chunks = || vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![
+                PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 },
+            ]},
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![
+                PwnedPwd { hash: hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087"), count: 1 },
+            ]},
+            Chunk { prefix: Prefix::create(0x21BD6).unwrap(), passwords: vec![
+                PwnedPwd { hash: hex!("21BD6004DDDC80AE4683948C5A1C5903584D8087"), count: 1 },
+            ]},
+        ];
+
+        // The save is interrupted after two prefixes
+        let interrupted = futures::stream::iter(chunks().into_iter().take(2)).chain(futures::stream::pending());
+        let save = tokio::time::timeout(Duration::from_millis(100), store.save(interrupted)).await;
+        assert!(save.is_err());
+
+        let checkpoint = store.checkpoint().unwrap().unwrap();
+        assert_eq!((Prefix::create(0x21BD5).unwrap(), 40, 2), (checkpoint.last_prefix, checkpoint.written, checkpoint.records));
+
+        store.resume(futures::stream::iter(chunks())).await.unwrap();
+        assert_eq!(None, store.checkpoint().unwrap());
+        assert_eq!(3, store.verify().unwrap().records);
+        assert!(store.verify().unwrap().is_ok());
+        assert!(store.exists(hex!("21BD6004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+
+        // Without a checkpoint it is a save
+        store.resume(futures::stream::iter(chunks().into_iter().skip(2))).await.unwrap();
+        assert!(!store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn store_save_unordered() {
+        let dir = temp_dir().join("pwned_pwd_tests_store_save_unordered");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("pwned"), hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).unwrap();
+
+        let store = LocalStore::builder(dir.join("pwned")).without_header().build().unwrap();
+        let chunk = |prefix: u32, hashes: &[[u8; 20]]| Chunk {
+            prefix: Prefix::create(prefix).unwrap(),
+            passwords: hashes.iter().map(|hash| PwnedPwd { hash: *hash, count: 1 }).collect(),
+        };
+
+        let err = store.save(futures::stream::iter(vec![
+            chunk(0x21BD5, &[hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")]),
+            chunk(0x21BD4, &[hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")]),
+        ])).await.unwrap_err();
+        assert!(matches!(err, LocalStoreError::UnorderedChunk { .. }), "{err}");
+
+        let err = store.save(futures::stream::iter(vec![chunk(0x21BD4, &[
+            hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"),
+            hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"),
+        ])])).await.unwrap_err();
+        assert!(matches!(err, LocalStoreError::InvalidChunk(InvalidChunk { error: ChunkError::Unordered { index: 1 }, .. })), "{err}");
+
+        let err = store.merge(futures::stream::iter(vec![
+            chunk(0x21BD5, &[hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")]),
+        ])).await.unwrap_err();
+        assert!(matches!(err, LocalStoreError::InvalidChunk(InvalidChunk { error: ChunkError::PrefixMismatch { .. }, .. })), "{err}");
+
+        assert!(store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+    }
+
+    #[test]
+    fn exists_many_found() {
+        let data = hex!("
+            21BD4004DDDC80AE4683948C5A1C5903584D8087
+            21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED
+            21BD40110328459B74EC3CC4ADCE47093DA97FD0
+            21BD4011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0D
+            21BD401223249190CD4C2B5E2537329726EC5667
+        ");
+
+        let cursor = Cursor::new(data);
+        assert_eq!(vec![true, false, true, false, true, true], exists_many(&cursor, &unindexed(&[
+            hex!("21BD401223249190CD4C2B5E2537329726EC5667"),
+            hex!("FFBD401223249190CD4C2B5E2537329726EC5667"),
+            hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"),
+            hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2EE"),
+            hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"),
+            hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"),
+        ]), RecordFormat::Hashes).unwrap());
+
+        assert_eq!(Vec::<bool>::new(), exists_many(&cursor, &[], RecordFormat::Hashes).unwrap());
+        assert_eq!(vec![false], exists_many(&Cursor::new(Vec::new()), &unindexed(&[hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")]), RecordFormat::Hashes).unwrap());
+    }
+
+    fn unindexed(vals: &[[u8; 20]]) -> Vec<([u8; 20], Option<Range<u64>>)> {
+        vals.iter().map(|val| (*val, None)).collect()
+    }
+
+    #[test]
+    fn exists_many_gallops() {
+        let hash = |i: u32| {
+            let mut hash = [0u8; 20];
+            hash[..4].copy_from_slice(&(i * 7919).to_be_bytes());
+            hash
+        };
+
+        let data = (0..1000).flat_map(|i| hash(i * 2)).collect::<Vec<_>>();
+        let cursor = Cursor::new(data.clone());
+        let index = PrefixIndex::build(&mut Cursor::new(data), RecordFormat::Hashes).unwrap();
+
+        let vals = (0..2000).rev().step_by(3).map(hash).collect::<Vec<_>>();
+        let expected = vals.iter().map(|val| exists(&cursor, *val).unwrap()).collect::<Vec<_>>();
+        assert!(expected.contains(&true) && expected.contains(&false));
+
+        let indexed = vals.iter().map(|val| (*val, Some(index.range(Prefix::from_sha1(val))))).collect::<Vec<_>>();
+        assert_eq!(expected, exists_many(&cursor, &unindexed(&vals), RecordFormat::Hashes).unwrap());
+        assert_eq!(expected, exists_many(&cursor, &indexed, RecordFormat::Hashes).unwrap());
+    }
+
     #[tokio::test]
     async fn store_save() {
         let (mut sender, receiver) = futures::channel::mpsc::channel::<Chunk>(256 * 1024);
 
         sender.send(Chunk {
-            prefix: Prefix::create(0x21DB4).unwrap(), passwords: vec![
-                PwnedPwd {sha1: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 10, },
-                PwnedPwd {sha1: hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"), count: 10, },
-                PwnedPwd {sha1: hex!("21BD40110328459B74EC3CC4ADCE47093DA97FD0"), count: 10, },
-                PwnedPwd {sha1: hex!("21BD4011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0D"), count: 10, },
+            prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![
+                PwnedPwd {hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 10, },
+                PwnedPwd {hash: hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"), count: 10, },
+                PwnedPwd {hash: hex!("21BD40110328459B74EC3CC4ADCE47093DA97FD0"), count: 10, },
+                PwnedPwd {hash: hex!("21BD4011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0D"), count: 10, },
             ]}
         ).await.unwrap();
 
         sender.send(Chunk {
-            prefix: Prefix::create(0x21DB5).unwrap(), passwords: vec![
-                PwnedPwd {sha1: hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087"), count: 11, },
-                PwnedPwd {sha1: hex!("21BD500C53D0B33029D7FE4FB08D3D1C9832D2ED"), count: 12, },
-                PwnedPwd {sha1: hex!("21BD50110328459B74EC3CC4ADCE47093DA97FD0"), count: 13, },
-                PwnedPwd {sha1: hex!("21BD5011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0D"), count: 14, },
+            prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![
+                PwnedPwd {hash: hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087"), count: 11, },
+                PwnedPwd {hash: hex!("21BD500C53D0B33029D7FE4FB08D3D1C9832D2ED"), count: 12, },
+                PwnedPwd {hash: hex!("21BD50110328459B74EC3CC4ADCE47093DA97FD0"), count: 13, },
+                PwnedPwd {hash: hex!("21BD5011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0D"), count: 14, },
             ]}
         ).await.unwrap();
 
@@ -419,14 +1875,14 @@ mod tests {
             remove_file(&tmp_file_path).unwrap();
         }
 
-        let store = LocalStore {
-            file_path: tmp_file_path,
-            existence_behaviour: Default::default(),
-            buff_capacity: None,
-        };
+        let store = LocalStore::builder(tmp_file_path).without_header().build().unwrap();
 
         store.save(receiver).await.expect("unable to save");
 
+        let metadata = store.metadata().await.unwrap();
+        assert_eq!(Some(8), metadata.records);
+        assert!(metadata.age().unwrap() < std::time::Duration::from_secs(60));
+
         let mut file = File::open(&store.file_path).expect("Unable to open the file");
         let mut file_data = Vec::new();
         file.read_to_end(&mut file_data).unwrap();
@@ -442,4 +1898,299 @@ mod tests {
             21BD5011CFFB38DFAD7E2FB4EE6ECED2ABCBBA0D
         "),file_data.as_slice());
     }
+
+    #[tokio::test]
+    async fn store_save_observed() {
+        let store = LocalStore::builder(temp_dir().join("pwned_pwd_tests_store_save_observed")).with_existence_behaviour(ExistenceBehaviour::RemoveOldThenCreateNew).with_buff_capacity(1).without_header().build().unwrap();
+
+        let pwd = |hash| PwnedPwd { hash, count: 1 };
+        let last = std::sync::Mutex::new(SaveProgress::default());
+
+        store.save_observed(futures::stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![
+                pwd(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")),
+                pwd(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED")),
+            ]},
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![
+                pwd(hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")),
+            ]},
+        ]), &|progress: &SaveProgress| *last.lock().unwrap() = *progress).await.unwrap();
+
+        assert_eq!(
+            SaveProgress { chunks: 2, records: 3, bytes: Some(60), last_prefix: Prefix::create(0x21BD5) },
+            last.into_inner().unwrap(),
+        );
+    }
+
+    #[tokio::test]
+    async fn store_save_cancelled() {
+        let dir = temp_dir().join("pwned_pwd_tests_store_save_cancelled");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let store = LocalStore::builder(dir.join("pwned")).without_header().build().unwrap();
+        std::fs::write(&store.file_path, hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).unwrap();
+
+        let token = pwned_pwd_store::cancel::CancelToken::new();
+        let chunks = futures::stream::iter(vec![Chunk { prefix: Prefix::create(0x00000).unwrap(), passwords: vec![
+            PwnedPwd { hash: hex!("0000000C53D0B33029D7FE4FB08D3D1C9832D2ED"), count: 1 },
+        ]}]).chain(futures::stream::pending());
+
+        let (saved, _) = tokio::join!(token.run(store.save(chunks)), async {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            token.cancel();
+        });
+        assert!(saved.is_err());
+        assert!(store.temp_path().exists());
+
+        assert_eq!(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087").as_slice(), std::fs::read(&store.file_path).unwrap().as_slice());
+        assert_eq!(Prefix::create(0x21BD4), store.max_prefix().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn store_counts() {
+        let dir = temp_dir().join("pwned_pwd_tests_store_counts");
+        std::fs::create_dir_all(&dir).unwrap();
+        let _ = remove_file(dir.join("pwned"));
+
+        let store = LocalStore::builder(dir.join("pwned"))
+            .with_format(RecordFormat::HashesWithCounts)
+            .with_index()
+            .build()
+            .unwrap();
+
+        let chunk = |passwords| Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords };
+        store.save(futures::stream::iter(vec![chunk(vec![
+            PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 10 },
+            PwnedPwd { hash: hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"), count: 3 },
+        ])])).await.unwrap();
+
+        assert_eq!(64 + 48, std::fs::metadata(store.file_path()).unwrap().len());
+        assert_eq!(Some(2), store.metadata().await.unwrap().records);
+        assert!(store.verify_checksum().unwrap());
+        assert_eq!(Some(10), store.exists_count(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert_eq!(None, store.exists_with_min_count(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"), 5).await.unwrap());
+        assert_eq!(None, store.exists_count(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2EE")).await.unwrap());
+        assert!(store.healthy().await.unwrap());
+
+        store.merge(futures::stream::iter(vec![chunk(vec![
+            PwnedPwd { hash: hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"), count: 7 },
+        ])])).await.unwrap();
+
+        let all = store.iter_all().map(|pwd| pwd.unwrap().count).collect::<Vec<_>>().await;
+        assert_eq!(vec![10, 7], all);
+        assert_eq!(vec![false, true], store.exists_many(&[
+            hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2EE"),
+            hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"),
+        ]).await.unwrap());
+
+        assert!(matches!(
+            LocalStore::builder(dir.join("pwned")).build(),
+            Err(LocalStoreError::InvalidHeader { reason: "Record length doesn't match the format", .. })
+        ));
+
+        let unsupported = LocalStore { format: RecordFormat::Hashes, header: false, index: None, ..store };
+        assert!(unsupported.exists_count(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn store_text() {
+        let dir = temp_dir().join("pwned_pwd_tests_store_text");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("dump.txt"), "21BD4004DDDC80AE4683948C5A1C5903584D8087:10\r\n21BD5004DDDC80AE4683948C5A1C5903584D8087:3\r\n").unwrap();
+        std::fs::write(dir.join("invalid.txt"), "21BD6004DDDC80AE4683948C5A1C5903584D8087:10\r\n21BD5004DDDC80AE4683948C5A1C5903584D8087:3\r\n").unwrap();
+
+        let store = LocalStore::builder(dir.join("pwned")).with_format(RecordFormat::HashesWithCounts).build().unwrap();
+        store.import_text(dir.join("dump.txt")).await.unwrap();
+        assert_eq!(Some(3), store.exists_count(hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+
+        assert!(matches!(
+            store.import_text(dir.join("invalid.txt")).await,
+            Err(LocalStoreError::InvalidDump(DumpError::Unordered { line: 2 }))
+        ));
+        assert_eq!(Some(10), store.exists_count(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+
+        let mut text = Vec::new();
+        assert_eq!(2, store.export_text(&mut text).unwrap());
+        assert_eq!("21BD4004DDDC80AE4683948C5A1C5903584D8087:10\n21BD5004DDDC80AE4683948C5A1C5903584D8087:3\n", String::from_utf8(text).unwrap());
+    }
+
+    #[tokio::test]
+    async fn store_suffixes() {
+        let dir = temp_dir().join("pwned_pwd_tests_store_suffixes");
+        std::fs::create_dir_all(&dir).unwrap();
+        let _ = remove_file(dir.join("pwned"));
+
+        let store = LocalStore::builder(dir.join("pwned")).with_format(RecordFormat::Suffixes).build().unwrap();
+        let pwd = |hash| PwnedPwd { hash, count: 0 };
+
+        store.save(futures::stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![
+                pwd(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")),
+                pwd(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED")),
+            ]},
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![
+                pwd(hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")),
+            ]},
+        ])).await.unwrap();
+
+        assert_eq!(64 + 3 * 18 + PrefixIndex::directory_len(), std::fs::metadata(store.file_path()).unwrap().len());
+        assert_eq!(Some(3), store.metadata().await.unwrap().records);
+        assert_eq!(Some(Prefix::create(0x21BD5).unwrap()), store.max_prefix().await.unwrap());
+        assert!(store.verify_checksum().unwrap());
+        assert!(store.healthy().await.unwrap());
+        assert!(store.exists(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED")).await.unwrap());
+        assert!(!store.exists(hex!("21BD500C53D0B33029D7FE4FB08D3D1C9832D2ED")).await.unwrap());
+        assert_eq!(vec![true, false, true], store.exists_many(&[
+            hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087"),
+            hex!("21BD4004DDDC80AE4683948C5A1C5903584D8088"),
+            hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"),
+        ]).await.unwrap());
+
+        store.merge(futures::stream::iter(vec![Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![
+            pwd(hex!("21BD4010DDDC80AE4683948C5A1C5903584D8087")),
+        ]}])).await.unwrap();
+        assert!(store.remove(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+
+        let all = store.iter_all().map(|pwd| pwd.unwrap().hash).collect::<Vec<_>>().await;
+        assert_eq!(vec![
+            hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"),
+            hex!("21BD4010DDDC80AE4683948C5A1C5903584D8087"),
+            hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087"),
+        ], all);
+
+        let reopened = LocalStore::builder(dir.join("pwned")).with_format(RecordFormat::Suffixes).build().unwrap();
+        assert!(reopened.exists(hex!("21BD4010DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert!(!reopened.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn store_header() {
+        let dir = temp_dir().join("pwned_pwd_tests_store_header");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pwned");
+
+        std::fs::write(&path, hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).unwrap();
+        assert!(matches!(
+            LocalStore::builder(&path).build(),
+            Err(LocalStoreError::InvalidHeader { reason: "The file is shorter than the header", .. })
+        ));
+
+        let store = LocalStore::builder(&path).without_header().build().unwrap();
+        assert_eq!(None, store.header().unwrap());
+        assert!(store.verify_checksum().unwrap());
+
+        let store = LocalStore { header: true, ..store };
+        store.save(futures::stream::iter(vec![Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![
+            PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 },
+            PwnedPwd { hash: hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"), count: 1 },
+        ]}])).await.unwrap();
+
+        let header = store.header().unwrap().unwrap();
+        assert_eq!((1, 20, 2), (header.version, header.record_len, header.records));
+        assert!(store.healthy().await.unwrap());
+        assert!(store.exists(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED")).await.unwrap());
+        assert_eq!(2, store.iter_all().collect::<Vec<_>>().await.len());
+        assert!(store.verify_checksum().unwrap());
+
+        let mut data = std::fs::read(&path).unwrap();
+        data[64] ^= 1;
+        std::fs::write(&path, &data).unwrap();
+        assert!(!store.verify_checksum().unwrap());
+
+        data[0] = 0;
+        std::fs::write(&path, &data).unwrap();
+        assert!(!store.healthy().await.unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn out_of_space() {
+        let path = PathBuf::from("/dev/full");
+        let mut pwd_file = PwdFile {
+            file: BufWriter::with_capacity(20, OpenOptions::new().write(true).open(&path).unwrap()),
+            format: RecordFormat::Hashes,
+            header: false,
+            records: 0,
+            checksum: Default::default(),
+            written: 0,
+            path: path.clone(),
+            move_on_complete_to: None,
+            last_prefix: None,
+            sync: false,
+            directory: None,
+            preallocated: false,
+            checkpoints: None,
+        };
+
+        pwd_file.chunk_written(Prefix::create(0x7FFFF).unwrap()).unwrap();
+        let pwd = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let err = pwd_file.write(&pwd).and_then(|_| pwd_file.write(&pwd)).unwrap_err();
+
+        match err {
+            LocalStoreError::OutOfSpace { written_bytes, estimated_remaining, path: err_path, last_prefix } => {
+                assert_eq!(0, written_bytes);
+                assert_eq!(Some(0), estimated_remaining);
+                assert_eq!(path, err_path);
+                assert_eq!(Some(Prefix::create(0x7FFFF).unwrap()), last_prefix);
+            }
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn store_merge() {
+        let dir = temp_dir().join("pwned_pwd_tests_store_merge");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let store = LocalStore::builder(dir.join("pwned")).with_existence_behaviour(ExistenceBehaviour::RemoveOldThenCreateNew).without_header().build().unwrap();
+
+        std::fs::write(&store.file_path, hex!("
+            21BD4004DDDC80AE4683948C5A1C5903584D8087
+            21BD40110328459B74EC3CC4ADCE47093DA97FD0
+            21BD5004DDDC80AE4683948C5A1C5903584D8087
+        ")).unwrap();
+
+        let pwd = |hash| PwnedPwd { hash, count: 1 };
+        store.merge(futures::stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x00000).unwrap(), passwords: vec![
+                pwd(hex!("0000000C53D0B33029D7FE4FB08D3D1C9832D2ED")),
+            ]},
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![
+                pwd(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED")),
+                pwd(hex!("21BD40110328459B74EC3CC4ADCE47093DA97FD0")),
+            ]},
+        ])).await.unwrap();
+
+        assert_eq!(hex!("
+            0000000C53D0B33029D7FE4FB08D3D1C9832D2ED
+            21BD4004DDDC80AE4683948C5A1C5903584D8087
+            21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED
+            21BD40110328459B74EC3CC4ADCE47093DA97FD0
+            21BD5004DDDC80AE4683948C5A1C5903584D8087
+        ").as_slice(), std::fs::read(&store.file_path).unwrap().as_slice());
+        assert!(!store.temp_path().exists());
+
+        let all = store.iter_all().map(|pwd| pwd.unwrap().hash).collect::<Vec<_>>().await;
+        assert_eq!(5, all.len());
+        assert_eq!(hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087"), all[4]);
+
+        assert!(store.remove(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED")).await.unwrap());
+        assert!(!store.remove(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED")).await.unwrap());
+        assert!(!store.exists(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED")).await.unwrap());
+        assert_eq!(Some(4), store.metadata().await.unwrap().records);
+        assert!(store.healthy().await.unwrap());
+        assert_eq!(Prefix::create(0x21BD4), store.max_prefix().await.unwrap());
+
+        store.clear().await.unwrap();
+        store.clear().await.unwrap();
+        assert!(!store.file_path.exists());
+        assert!(!store.healthy().await.unwrap());
+        assert_eq!(None, store.max_prefix().await.unwrap());
+        assert!(store.iter_all().next().await.unwrap().is_err());
+
+        store.merge(futures::stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![pwd(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"))] },
+        ])).await.unwrap();
+        assert_eq!(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED").as_slice(), std::fs::read(&store.file_path).unwrap().as_slice());
+    }
 }
@@ -0,0 +1,192 @@
+//! Dedicated lookup threads for extreme QPS deployments
+//!
+//! Every lookup of a [crate::LocalStore] is a few blocking disk reads. On large servers
+//! it is better to keep them away from the async runtime: [LookupPool] runs them
+//! on its own (optionally core-pinned) threads, each with its own file handle,
+//! and queries are dispatched through a lock-free queue
+
+use std::{
+    fs::File,
+    io,
+    path::Path,
+    thread::{self, JoinHandle},
+};
+
+use crossbeam_channel::{Sender, TrySendError};
+use futures::channel::oneshot;
+
+use crate::{format::RecordFormat, header::Body};
+
+/// Settings of a [LookupPool]
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    threads: usize,
+    cores: Option<Vec<usize>>,
+    queue_capacity: usize,
+}
+
+impl PoolConfig {
+    const DEFAULT_QUEUE_CAPACITY: usize = 64 * 1024;
+
+    pub fn new(threads: usize) -> Self {
+        Self {
+            threads: threads.max(1),
+            cores: None,
+            queue_capacity: Self::DEFAULT_QUEUE_CAPACITY,
+        }
+    }
+
+    /// Pin worker `i` to the core `cores[i % cores.len()]`
+    pub fn with_cores(mut self, cores: Vec<usize>) -> Self {
+        self.cores = Some(cores).filter(|cores| !cores.is_empty());
+        self
+    }
+
+    /// How many lookups may wait for a worker. When the queue is full,
+    /// lookups fail with [io::ErrorKind::WouldBlock] instead of piling up
+    pub fn with_queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.queue_capacity = queue_capacity.max(1);
+        self
+    }
+}
+
+type Job = ([u8; 20], oneshot::Sender<io::Result<bool>>);
+
+/// A pool of threads which search hashes in a pwned passwords file.
+///
+/// Workers keep the file open, so after the file is replaced by a new download
+/// the pool must be recreated to see the new data
+pub struct LookupPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl LookupPool {
+    pub(crate) fn new(
+        path: &Path,
+        config: &PoolConfig,
+        format: RecordFormat,
+        offset: u64,
+    ) -> io::Result<Self> {
+        let (sender, receiver) = crossbeam_channel::bounded::<Job>(config.queue_capacity);
+
+        let mut workers = Vec::with_capacity(config.threads);
+        for i in 0..config.threads {
+            let file = Body::new(File::open(path)?, offset)?;
+            let receiver = receiver.clone();
+            let core = config.cores.as_ref().map(|cores| cores[i % cores.len()]);
+
+            let worker = thread::Builder::new()
+                .name(format!("pwned-lookup-{}", i))
+                .spawn(move || {
+                    if let Some(id) = core {
+                        if !core_affinity::set_for_current(core_affinity::CoreId { id }) {
+                            tracing::warn!("Lookup worker {} can't be pinned to core {}", i, id);
+                        }
+                    }
+
+                    for (val, reply) in receiver {
+                        let found = crate::find(&file, val, None, format);
+                        let _ = reply.send(found.map(|count| count.is_some()));
+                    }
+                })?;
+
+            workers.push(worker);
+        }
+
+        Ok(Self {
+            sender: Some(sender),
+            workers,
+        })
+    }
+
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Lookups waiting for a worker
+    pub fn queued(&self) -> usize {
+        self.sender.as_ref().map_or(0, |sender| sender.len())
+    }
+
+    pub async fn exists(&self, val: [u8; 20]) -> io::Result<bool> {
+        let (reply, result) = oneshot::channel();
+        let sender = self.sender.as_ref().expect("Sender lives until drop");
+
+        match sender.try_send((val, reply)) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "Lookup queue is full",
+                ))
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "Lookup workers are stopped",
+                ))
+            }
+        }
+
+        result
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Lookup worker has panicked"))?
+    }
+}
+
+impl Drop for LookupPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use hex_literal::hex;
+
+    use super::*;
+
+    fn file(name: &str) -> std::path::PathBuf {
+        let path = temp_dir().join(name);
+        std::fs::write(&path, hex!("
+            21BD4004DDDC80AE4683948C5A1C5903584D8087
+            21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED
+            21BD40110328459B74EC3CC4ADCE47093DA97FD0
+        ")).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn exists() {
+        let path = file("pwned_pwd_pool_exists");
+        let pool = LookupPool::new(&path, &PoolConfig::new(2).with_cores(vec![0]), RecordFormat::Hashes, 0).unwrap();
+
+        assert_eq!(2, pool.threads());
+        assert!(pool.exists(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED")).await.unwrap());
+        assert!(!pool.exists(hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2EE")).await.unwrap());
+
+        drop(pool);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent() {
+        let path = file("pwned_pwd_pool_concurrent");
+        let pool = LookupPool::new(&path, &PoolConfig::new(4), RecordFormat::Hashes, 0).unwrap();
+
+        let lookups = (0..100).map(|_| pool.exists(hex!("21BD40110328459B74EC3CC4ADCE47093DA97FD0")));
+        let found = futures::future::join_all(lookups).await;
+
+        assert!(found.into_iter().all(|found| found.unwrap()));
+
+        drop(pool);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+//! Positioned reads
+//!
+//! A seek followed by a read moves the cursor of a handle, so concurrent lookups
+//! can't share it. [ReadAt] reads at an offset without a cursor (`pread`),
+//! so one open file can serve any number of lookups at once
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+/// A source of data which is read at offsets
+pub trait ReadAt {
+    /// Fills the buffer with the data at `pos`
+    fn read_exact_at(&self, buf: &mut [u8], pos: u64) -> io::Result<()>;
+
+    /// Length of the data
+    fn size(&self) -> io::Result<u64>;
+}
+
+impl ReadAt for File {
+    #[cfg(unix)]
+    fn read_exact_at(&self, buf: &mut [u8], pos: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, buf, pos)
+    }
+
+    #[cfg(windows)]
+    fn read_exact_at(&self, mut buf: &mut [u8], mut pos: u64) -> io::Result<()> {
+        use std::os::windows::fs::FileExt;
+
+        while !buf.is_empty() {
+            match self.seek_read(buf, pos) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    pos += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// There are no positioned reads, so the reads of a shared handle race
+    #[cfg(not(any(unix, windows)))]
+    fn read_exact_at(&self, buf: &mut [u8], pos: u64) -> io::Result<()> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let mut file = self;
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(buf)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+impl<T: AsRef<[u8]>> ReadAt for Cursor<T> {
+    fn read_exact_at(&self, buf: &mut [u8], pos: u64) -> io::Result<()> {
+        let data = self.get_ref().as_ref();
+        let src = usize::try_from(pos)
+            .ok()
+            .and_then(|pos| data.get(pos..pos.checked_add(buf.len())?))
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.get_ref().as_ref().len() as u64)
+    }
+}
+
+/// Alignment of the offsets, the lengths and the buffers of direct reads,
+/// a multiple of the logical block size of common devices
+const DIRECT_ALIGN: usize = 4096;
+
+/// A store file opened for lookups. A direct file bypasses the page cache (`O_DIRECT`),
+/// so its reads are made of whole aligned blocks
+pub(crate) struct LookupFile {
+    file: File,
+    direct: bool,
+}
+
+impl LookupFile {
+    /// Direct I/O is supported only by Linux, on other systems the file is cached
+    pub(crate) fn open(path: &Path, direct: bool) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+
+        #[cfg(target_os = "linux")]
+        if direct {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.custom_flags(libc::O_DIRECT);
+        }
+
+        Ok(Self {
+            file: options.open(path)?,
+            direct: direct && cfg!(target_os = "linux"),
+        })
+    }
+
+    pub(crate) fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Reads the aligned blocks around the range into an aligned buffer
+    fn read_direct(&self, buf: &mut [u8], pos: u64) -> io::Result<()> {
+        let start = pos - pos % DIRECT_ALIGN as u64;
+        let end = (pos + buf.len() as u64).next_multiple_of(DIRECT_ALIGN as u64);
+        let len = (end - start) as usize;
+
+        let mut blocks = vec![0u8; len + DIRECT_ALIGN];
+        let align = blocks.as_ptr().align_offset(DIRECT_ALIGN);
+        let blocks = &mut blocks[align..align + len];
+
+        // The last block of the file is short
+        let mut read = 0;
+        while read < len {
+            match read_at(&self.file, &mut blocks[read..], start + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let skip = (pos - start) as usize;
+        let src = blocks[..read]
+            .get(skip..skip + buf.len())
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, pos)
+}
+
+/// Unreachable, only Linux opens direct files
+#[cfg(not(unix))]
+fn read_at(file: &File, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+    file.read_exact_at(buf, pos).map(|_| buf.len())
+}
+
+impl ReadAt for LookupFile {
+    fn read_exact_at(&self, buf: &mut [u8], pos: u64) -> io::Result<()> {
+        match self.direct {
+            true => self.read_direct(buf, pos),
+            false => self.file.read_exact_at(buf, pos),
+        }
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        self.file.size()
+    }
+}
+
+impl Seek for LookupFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+/// A sequential reader of [ReadAt] data, the data is shared with the positioned reads
+pub(crate) struct ReadAtReader<'a, T> {
+    data: &'a T,
+    pos: u64,
+    size: u64,
+}
+
+impl<'a, T: ReadAt> ReadAtReader<'a, T> {
+    pub(crate) fn new(data: &'a T) -> io::Result<Self> {
+        Ok(Self {
+            data,
+            pos: 0,
+            size: data.size()?,
+        })
+    }
+}
+
+impl<T: ReadAt> Read for ReadAtReader<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min((self.size - self.pos) as usize);
+        self.data.read_exact_at(&mut buf[..len], self.pos)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use super::*;
+
+    #[test]
+    fn file() {
+        let path = temp_dir().join("pwned_pwd_read_at");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let file = File::open(&path).unwrap();
+
+        let mut buf = [0u8; 3];
+        file.read_exact_at(&mut buf, 4).unwrap();
+        assert_eq!(b"456", &buf);
+        assert_eq!(10, file.size().unwrap());
+        assert_eq!(io::ErrorKind::UnexpectedEof, file.read_exact_at(&mut buf, 8).unwrap_err().kind());
+
+        let cursor = Cursor::new(b"0123456789");
+        cursor.read_exact_at(&mut buf, 7).unwrap();
+        assert_eq!(b"789", &buf);
+        assert_eq!(io::ErrorKind::UnexpectedEof, cursor.read_exact_at(&mut buf, 8).unwrap_err().kind());
+    }
+
+    #[test]
+    fn lookup_file() {
+        let path = temp_dir().join("pwned_pwd_read_at_direct");
+        let data = (0..10000u32).map(|i| i as u8).collect::<Vec<_>>();
+        std::fs::write(&path, &data).unwrap();
+
+        for direct in [false, true] {
+            // A filesystem without direct I/O (tmpfs) refuses to open the file
+            let Ok(file) = LookupFile::open(&path, direct) else { continue };
+
+            let mut buf = [0u8; 20];
+            file.read_exact_at(&mut buf, 4090).unwrap();
+            assert_eq!(&data[4090..4110], &buf);
+            file.read_exact_at(&mut buf, 9980).unwrap();
+            assert_eq!(&data[9980..], &buf);
+            assert_eq!(io::ErrorKind::UnexpectedEof, file.read_exact_at(&mut buf, 9990).unwrap_err().kind());
+
+            let mut all = Vec::new();
+            ReadAtReader::new(&file).unwrap().read_to_end(&mut all).unwrap();
+            assert_eq!(data, all);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Lookups in a file produced elsewhere
+//!
+//! In a common deployment the data set is saved by a CI job or the official downloader,
+//! and an application server must never write it. [ReadOnlyStore] is a [LocalStore]
+//! without [pwned_pwd_store::WriteStore], so it can't replace or remove the file
+
+use std::{fs::File, io::Read, path::Path};
+
+use futures::Stream;
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+use pwned_pwd_store::{ReadStore, StoreMetadata};
+
+use crate::{
+    format::RecordFormat,
+    header::{FileHeader, HEADER_LEN},
+    LocalStore, LocalStoreError,
+};
+
+/// A store of an existing file, see [LocalStore::open_read_only]
+pub struct ReadOnlyStore(LocalStore);
+
+impl ReadOnlyStore {
+    pub(crate) fn new(store: LocalStore) -> Self {
+        Self(store)
+    }
+
+    pub fn file_path(&self) -> &Path {
+        self.0.file_path()
+    }
+
+    pub fn format(&self) -> RecordFormat {
+        self.0.format()
+    }
+
+    /// See [LocalStore::header]
+    pub fn header(&self) -> Result<Option<FileHeader>, LocalStoreError> {
+        self.0.header()
+    }
+
+    /// Switches to the file at the path, if it was replaced, see [LocalStore::reload]
+    pub fn reload(&self) -> Result<bool, LocalStoreError> {
+        self.0.reload()
+    }
+}
+
+impl LocalStore {
+    /// Opens the existing file with a header to look it up, the format is taken from the header.
+    /// Other settings are set by [crate::builder::LocalStoreBuilder::build_read_only]
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<ReadOnlyStore, LocalStoreError> {
+        let format = header_format(path.as_ref()).unwrap_or_default();
+        Self::builder(path.as_ref())
+            .with_format(format)
+            .build_read_only()
+    }
+}
+
+/// The format of the header of the file, None if it can't be read.
+/// The store checks the header once more when it is opened
+fn header_format(path: &Path) -> Option<RecordFormat> {
+    let mut bytes = [0u8; HEADER_LEN as usize];
+    File::open(path).ok()?.read_exact(&mut bytes).ok()?;
+    FileHeader::from_bytes(&bytes).ok()?.format()
+}
+
+impl ReadStore for ReadOnlyStore {
+    type Error = LocalStoreError;
+
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        self.0.exists(val).await
+    }
+
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        self.0.iter_all()
+    }
+
+    async fn exists_many(&self, vals: &[[u8; 20]]) -> Result<Vec<bool>, Self::Error> {
+        self.0.exists_many(vals).await
+    }
+
+    async fn exists_count(&self, val: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        self.0.exists_count(val).await
+    }
+
+    async fn range(&self, prefix: Prefix) -> Result<Chunk, Self::Error> {
+        self.0.range(prefix).await
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        self.0.metadata().await
+    }
+
+    async fn max_prefix(&self) -> Result<Option<Prefix>, Self::Error> {
+        self.0.max_prefix().await
+    }
+
+    async fn healthy(&self) -> Result<bool, Self::Error> {
+        self.0.healthy().await
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use hex_literal::hex;
+    use pwned_pwd_core::Chunk;
+    use pwned_pwd_store::WriteStore;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn open_read_only() {
+        let dir = temp_dir().join("pwned_pwd_read_only");
+        std::fs::create_dir_all(&dir).unwrap();
+        let _ = std::fs::remove_file(dir.join("absent"));
+
+        assert!(LocalStore::open_read_only(dir.join("absent")).is_err());
+        assert!(!dir.join("absent").exists());
+
+        LocalStore::builder(dir.join("pwned")).build().unwrap()
+            .save(futures::stream::iter(vec![Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![
+                PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 },
+            ]}]))
+            .await
+            .unwrap();
+
+        let store = LocalStore::open_read_only(dir.join("pwned")).unwrap();
+        assert!(store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert_eq!(Some(1), store.metadata().await.unwrap().records);
+        assert_eq!(RecordFormat::Hashes, store.format());
+
+        LocalStore::builder(dir.join("counts")).with_format(RecordFormat::HashesWithCounts).build().unwrap()
+            .save(futures::stream::iter(vec![Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![
+                PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 5 },
+            ]}]))
+            .await
+            .unwrap();
+
+        let store = LocalStore::open_read_only(dir.join("counts")).unwrap();
+        assert_eq!(RecordFormat::HashesWithCounts, store.format());
+        assert_eq!(Some(5), store.exists_count(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+
+        std::fs::write(dir.join("truncated"), [0u8; 30]).unwrap();
+        let err = LocalStore::builder(dir.join("truncated")).without_header().build_read_only().err().unwrap();
+        assert!(matches!(err, LocalStoreError::InvalidHeader { .. }));
+    }
+}
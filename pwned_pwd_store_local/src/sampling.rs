@@ -0,0 +1,263 @@
+//! Time-boxed partial verification of a pwned passwords file
+//!
+//! A full scan of a 40GB file takes long, so [SampleVerification] checks random
+//! windows of records for ordering and random prefixes for presence
+//! (every prefix of the haveibeenpwned data set contains passwords)
+
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    time::{Duration, Instant},
+};
+
+use pwned_pwd_core::Prefix;
+use rand::Rng;
+
+const HASH_LEN: usize = 20;
+
+/// Settings of a sampling verification
+#[derive(Debug, Clone)]
+pub struct SampleVerification {
+    /// How many random windows are checked for ordering
+    pub windows: usize,
+
+    /// How many records a window contains
+    pub window_len: usize,
+
+    /// How many random prefixes are checked for presence
+    pub prefixes: usize,
+
+    /// The verification stops when the budget is exhausted
+    pub budget: Duration,
+}
+
+impl Default for SampleVerification {
+    fn default() -> Self {
+        Self {
+            windows: 64,
+            window_len: 256,
+            prefixes: 64,
+            budget: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Result of a sampling verification
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleReport {
+    /// File length is a multiple of the record length
+    pub aligned: bool,
+
+    pub windows_checked: usize,
+
+    /// Windows with records which are not strictly increasing
+    pub unordered_windows: usize,
+
+    pub prefixes_checked: usize,
+
+    /// Prefixes without any record
+    pub missing_prefixes: Vec<Prefix>,
+
+    /// The budget was exhausted before all the checks were done
+    pub timed_out: bool,
+
+    /// From 0 to 1: share of the planned checks which were done and passed,
+    /// or 0 if any check failed
+    pub confidence: f64,
+}
+
+impl SampleReport {
+    pub fn is_ok(&self) -> bool {
+        self.aligned && self.unordered_windows == 0 && self.missing_prefixes.is_empty()
+    }
+}
+
+impl SampleVerification {
+    /// Verifies a file of 20-byte hashes
+    pub fn verify<T: Seek + Read>(
+        &self,
+        data: &mut T,
+        rng: &mut impl Rng,
+    ) -> io::Result<SampleReport> {
+        self.verify_records(data, rng, HASH_LEN as u64)
+    }
+
+    /// Verifies a file of records of `record_len` bytes which start with a hash
+    pub fn verify_records<T: Seek + Read>(
+        &self,
+        data: &mut T,
+        rng: &mut impl Rng,
+        record_len: u64,
+    ) -> io::Result<SampleReport> {
+        let started = Instant::now();
+        let len = data.seek(SeekFrom::End(0))?;
+        let records = len / record_len;
+
+        let mut report = SampleReport {
+            aligned: len % record_len == 0,
+            windows_checked: 0,
+            unordered_windows: 0,
+            prefixes_checked: 0,
+            missing_prefixes: Vec::new(),
+            timed_out: false,
+            confidence: 0.0,
+        };
+
+        let window_len = (self.window_len.max(2) as u64).min(records);
+        let mut window = vec![0u8; (window_len * record_len) as usize];
+
+        for _ in 0..self.windows {
+            if window_len < 2 {
+                break;
+            }
+
+            if started.elapsed() >= self.budget {
+                report.timed_out = true;
+                break;
+            }
+
+            let start = rng.gen_range(0..=records - window_len);
+            data.seek(SeekFrom::Start(start * record_len))?;
+            data.read_exact(&mut window)?;
+
+            let ordered = window
+                .chunks_exact(record_len as usize)
+                .zip(window.chunks_exact(record_len as usize).skip(1))
+                .all(|(a, b)| a[..HASH_LEN] < b[..HASH_LEN]);
+
+            report.windows_checked += 1;
+            if !ordered {
+                report.unordered_windows += 1;
+            }
+        }
+
+        for _ in 0..self.prefixes {
+            if started.elapsed() >= self.budget {
+                report.timed_out = true;
+                break;
+            }
+
+            let prefix = Prefix::random(rng);
+
+            report.prefixes_checked += 1;
+            if !has_prefix(data, records, record_len, prefix)? {
+                report.missing_prefixes.push(prefix);
+            }
+        }
+
+        let planned = if window_len < 2 { 0 } else { self.windows } + self.prefixes;
+        report.confidence = if !report.is_ok() {
+            0.0
+        } else if planned == 0 {
+            1.0
+        } else {
+            (report.windows_checked + report.prefixes_checked) as f64 / planned as f64
+        };
+
+        Ok(report)
+    }
+}
+
+/// Searches the first record which is greater or equal the prefix and checks it has the prefix
+fn has_prefix<T: Seek + Read>(
+    data: &mut T,
+    records: u64,
+    record_len: u64,
+    prefix: Prefix,
+) -> io::Result<bool> {
+    let mut first = [0u8; HASH_LEN];
+    prefix.write_prefix(&mut first);
+
+    let mut left = 0u64;
+    let mut right = records;
+    let mut buf = [0u8; HASH_LEN];
+
+    while left < right {
+        let mid = left + (right - left) / 2;
+
+        data.seek(SeekFrom::Start(mid * record_len))?;
+        data.read_exact(&mut buf)?;
+
+        if buf < first {
+            left = mid + 1;
+        } else {
+            right = mid;
+        }
+    }
+
+    if left == records {
+        return Ok(false);
+    }
+
+    data.seek(SeekFrom::Start(left * record_len))?;
+    data.read_exact(&mut buf)?;
+
+    Ok(Prefix::from_sha1(&buf) == prefix)
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::io::Cursor;
+
+    use hex_literal::hex;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn all_prefixes(mut corrupt: impl FnMut(u32, &mut [u8; 20])) -> Vec<u8> {
+        let mut data = Vec::new();
+        for prefix in Prefix::default() {
+            let mut record = hex!("0000000000000000000000000000000000000001");
+            prefix.write_prefix(&mut record);
+            corrupt(u32::from(prefix), &mut record);
+            data.extend_from_slice(&record);
+        }
+        data
+    }
+
+    #[test]
+    fn valid_file() {
+        let mut data = Cursor::new(all_prefixes(|_, _| ()));
+        let report = SampleVerification::default().verify(&mut data, &mut StdRng::seed_from_u64(1)).unwrap();
+
+        assert!(report.is_ok());
+        assert!(!report.timed_out);
+        assert_eq!(64, report.windows_checked);
+        assert_eq!(64, report.prefixes_checked);
+        assert_eq!(1.0, report.confidence);
+    }
+
+    #[test]
+    fn unordered_file() {
+        let mut data = Cursor::new(all_prefixes(|prefix, record| if prefix % 16 == 0 { record[0] = 0xFF } ));
+        let report = SampleVerification::default().verify(&mut data, &mut StdRng::seed_from_u64(1)).unwrap();
+
+        assert!(!report.is_ok());
+        assert_eq!(64, report.unordered_windows);
+        assert_eq!(0.0, report.confidence);
+    }
+
+    #[test]
+    fn missing_prefixes() {
+        let mut data = Cursor::new(hex!("
+            21BD4004DDDC80AE4683948C5A1C5903584D8087
+            21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED
+        ").to_vec());
+        let report = SampleVerification::default().verify(&mut data, &mut StdRng::seed_from_u64(1)).unwrap();
+
+        assert_eq!(64, report.windows_checked);
+        assert_eq!(0, report.unordered_windows);
+        assert_eq!(64, report.missing_prefixes.len());
+    }
+
+    #[test]
+    fn budget() {
+        let mut data = Cursor::new(all_prefixes(|_, _| ()));
+        let verification = SampleVerification { budget: Duration::ZERO, ..Default::default() };
+        let report = verification.verify(&mut data, &mut StdRng::seed_from_u64(1)).unwrap();
+
+        assert!(report.timed_out);
+        assert_eq!(0, report.windows_checked);
+        assert_eq!(0.0, report.confidence);
+    }
+}
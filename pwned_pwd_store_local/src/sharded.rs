@@ -0,0 +1,281 @@
+//! A store split into files by hash prefix
+//!
+//! [ShardedStore] keeps a directory of [LocalStore] files, each one holds a block
+//! of prefixes. Shards are saved concurrently, a stale shard can be saved again
+//! alone and an update renames many small files instead of one large
+
+use std::{future::Future, ops::RangeInclusive, sync::Arc};
+
+use futures::{channel::mpsc, SinkExt, Stream, StreamExt};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+use pwned_pwd_store::{OrderRequirement, ReadStore, StoreMetadata, WriteStore};
+use tokio::task::JoinSet;
+
+use crate::{LocalStore, LocalStoreError};
+
+/// Bits of a prefix which select a shard, so there are at most 256 shards
+pub const MAX_SHARD_BITS: u8 = 8;
+
+/// Bits of a prefix
+const PREFIX_BITS: u8 = 20;
+
+/// Chunks buffered for a shard while the shard writes
+const SHARD_BUFFER: usize = 16;
+
+/// Files of consecutive prefix blocks, see [crate::builder::LocalStoreBuilder::build_sharded]
+pub struct ShardedStore {
+    shard_bits: u8,
+    shards: Vec<Arc<LocalStore>>,
+}
+
+impl ShardedStore {
+    pub(crate) fn new(shard_bits: u8, shards: Vec<LocalStore>) -> Self {
+        Self {
+            shard_bits,
+            shards: shards.into_iter().map(Arc::new).collect(),
+        }
+    }
+
+    /// Name of the file of the shard, the index in hex
+    pub(crate) fn shard_name(shard_bits: u8, shard: usize) -> String {
+        format!(
+            "{:0width$x}",
+            shard,
+            width = (shard_bits as usize).div_ceil(4).max(1)
+        )
+    }
+
+    pub fn shards(&self) -> &[Arc<LocalStore>] {
+        &self.shards
+    }
+
+    /// Index of the shard which keeps the hashes of the prefix
+    pub fn shard_of(&self, prefix: Prefix) -> usize {
+        (u32::from(prefix) >> (PREFIX_BITS - self.shard_bits)) as usize
+    }
+
+    /// Prefixes kept by the shard
+    pub fn prefixes_of(&self, shard: usize) -> RangeInclusive<Prefix> {
+        let bits = PREFIX_BITS - self.shard_bits;
+        let first = (shard as u32) << bits;
+        let last = first | ((1 << bits) - 1);
+
+        Prefix::create(first).unwrap_or_else(Prefix::max)
+            ..=Prefix::create(last).unwrap_or_else(Prefix::max)
+    }
+
+    fn shard(&self, val: &[u8; 20]) -> &LocalStore {
+        &self.shards[self.shard_of(Prefix::from_sha1(val))]
+    }
+
+    /// Replaces the data of one shard, the stream must contain only its prefixes
+    pub async fn save_shard<S: Stream<Item = Chunk> + Unpin + Send>(
+        &self,
+        shard: usize,
+        s: S,
+    ) -> Result<(), LocalStoreError> {
+        let s = s.inspect(|chunk| debug_assert_eq!(shard, self.shard_of(chunk.prefix)));
+        self.shards[shard].save(s).await
+    }
+
+    /// Routes the chunks into the shards, which write them on their own tasks.
+    /// Every shard gets a stream, so a shard without chunks is written empty
+    async fn dispatch<S, F, Fut>(&self, mut s: S, write: F) -> Result<(), LocalStoreError>
+    where
+        S: Stream<Item = Chunk> + Unpin + Send,
+        F: Fn(Arc<LocalStore>, mpsc::Receiver<Chunk>) -> Fut,
+        Fut: Future<Output = Result<(), LocalStoreError>> + Send + 'static,
+    {
+        // Dropping the set aborts the writes, so a cancelled save leaves every shard
+        // in the state documented by [LocalStore::save]
+        let mut writes = JoinSet::new();
+        let mut senders = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            let (sender, receiver) = mpsc::channel(SHARD_BUFFER);
+            senders.push(sender);
+            writes.spawn(write(shard.clone(), receiver));
+        }
+
+        while let Some(chunk) = s.next().await {
+            let shard = self.shard_of(chunk.prefix);
+            if senders[shard].send(chunk).await.is_err() {
+                // The shard failed, its error is returned below
+                break;
+            }
+        }
+        drop(senders);
+
+        while let Some(res) = writes.join_next().await {
+            res.map_err(std::io::Error::other)??;
+        }
+        Ok(())
+    }
+}
+
+impl ReadStore for ShardedStore {
+    type Error = LocalStoreError;
+
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        self.shard(&val).exists(val).await
+    }
+
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        futures::stream::iter(&self.shards).flat_map(|shard| shard.iter_all())
+    }
+
+    /// Every shard searches its hashes in one pass
+    async fn exists_many(&self, vals: &[[u8; 20]]) -> Result<Vec<bool>, Self::Error> {
+        let mut by_shard = vec![Vec::new(); self.shards.len()];
+        for (i, val) in vals.iter().enumerate() {
+            by_shard[self.shard_of(Prefix::from_sha1(val))].push(i);
+        }
+
+        let mut res = vec![false; vals.len()];
+        for (shard, indices) in by_shard.into_iter().enumerate() {
+            if indices.is_empty() {
+                continue;
+            }
+
+            let shard_vals = indices.iter().map(|i| vals[*i]).collect::<Vec<_>>();
+            let found = self.shards[shard].exists_many(&shard_vals).await?;
+            for (i, found) in indices.into_iter().zip(found) {
+                res[i] = found;
+            }
+        }
+        Ok(res)
+    }
+
+    async fn exists_count(&self, val: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        self.shard(&val).exists_count(val).await
+    }
+
+    async fn range(&self, prefix: Prefix) -> Result<Chunk, Self::Error> {
+        self.shards[self.shard_of(prefix)].range(prefix).await
+    }
+
+    /// Records of all the shards, the update time of the oldest shard
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        let mut metadata = StoreMetadata {
+            records: Some(0),
+            ..Default::default()
+        };
+
+        for shard in &self.shards {
+            let shard = shard.metadata().await?;
+            metadata.records = metadata.records.zip(shard.records).map(|(a, b)| a + b);
+            metadata.updated_at = match (metadata.updated_at, shard.updated_at) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        }
+        Ok(metadata)
+    }
+
+    /// Every shard is healthy
+    async fn healthy(&self) -> Result<bool, Self::Error> {
+        for shard in &self.shards {
+            if !shard.healthy().await? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl WriteStore for ShardedStore {
+    /// Only the chunks of a shard must be ordered, chunks of different shards can be interleaved.
+    /// If the save is cancelled, every shard is left as described by [LocalStore::save]
+    async fn save<S: Stream<Item = Chunk> + Unpin + Send>(&self, s: S) -> Result<(), Self::Error> {
+        self.dispatch(s, |shard, chunks| async move { shard.save(chunks).await })
+            .await
+    }
+
+    async fn merge<S: Stream<Item = Chunk> + Unpin + Send>(&self, s: S) -> Result<(), Self::Error> {
+        self.dispatch(s, |shard, chunks| async move { shard.merge(chunks).await })
+            .await
+    }
+
+    async fn remove(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        self.shard(&val).remove(val).await
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        for shard in &self.shards {
+            shard.clear().await?;
+        }
+        Ok(())
+    }
+
+    fn order_requirement() -> OrderRequirement {
+        OrderRequirement::Ordered
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use hex_literal::hex;
+
+    use super::*;
+
+    fn chunk(prefix: u32, hashes: &[[u8; 20]]) -> Chunk {
+        Chunk {
+            prefix: Prefix::create(prefix).unwrap(),
+            passwords: hashes.iter().map(|hash| PwnedPwd { hash: *hash, count: 1 }).collect(),
+        }
+    }
+
+    #[test]
+    fn shard_of() {
+        let dir = temp_dir().join("pwned_pwd_sharded_of");
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = LocalStore::builder(&dir).build_sharded(4).unwrap();
+
+        assert_eq!(16, store.shards().len());
+        assert_eq!(dir.join("a"), store.shards()[10].file_path());
+        assert_eq!(0, store.shard_of(Prefix::create(0x0FFFF).unwrap()));
+        assert_eq!(15, store.shard_of(Prefix::max()));
+        assert_eq!(Prefix::create(0x10000).unwrap()..=Prefix::create(0x1FFFF).unwrap(), store.prefixes_of(1));
+        assert_eq!("00", ShardedStore::shard_name(8, 0));
+    }
+
+    #[tokio::test]
+    async fn save() {
+        let dir = temp_dir().join("pwned_pwd_sharded_save");
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = LocalStore::builder(&dir).build_sharded(1).unwrap();
+
+        store.save(futures::stream::iter(vec![
+            chunk(0x8BD40, &[hex!("8BD4004DDDC80AE4683948C5A1C5903584D8087A")]),
+            chunk(0x21BD4, &[hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")]),
+            chunk(0x21BD5, &[hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")]),
+        ])).await.unwrap();
+
+        assert!(store.exists(hex!("8BD4004DDDC80AE4683948C5A1C5903584D8087A")).await.unwrap());
+        assert!(store.exists(hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert!(!store.exists(hex!("21BD6004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert_eq!(vec![true, false, true], store.exists_many(&[
+            hex!("8BD4004DDDC80AE4683948C5A1C5903584D8087A"),
+            hex!("8BD4004DDDC80AE4683948C5A1C5903584D8087B"),
+            hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"),
+        ]).await.unwrap());
+        assert_eq!(Some(3), store.metadata().await.unwrap().records);
+
+        let all = store.iter_all().map(|pwd| pwd.unwrap().hash).collect::<Vec<_>>().await;
+        assert_eq!(vec![
+            hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"),
+            hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087"),
+            hex!("8BD4004DDDC80AE4683948C5A1C5903584D8087A"),
+        ], all);
+
+        store.save_shard(1, futures::stream::iter(vec![
+            chunk(0x9BD40, &[hex!("9BD4004DDDC80AE4683948C5A1C5903584D8087A")]),
+        ])).await.unwrap();
+
+        assert!(!store.exists(hex!("8BD4004DDDC80AE4683948C5A1C5903584D8087A")).await.unwrap());
+        assert!(store.exists(hex!("9BD4004DDDC80AE4683948C5A1C5903584D8087A")).await.unwrap());
+        assert!(store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+    }
+}
@@ -0,0 +1,162 @@
+//! Full verification of a store file
+//!
+//! [LocalStore::verify] reads every record, so it takes as long as a copy of the file,
+//! but unlike [crate::sampling] it tells exactly what is wrong with a dataset
+//! copied between hosts or left by a crash
+
+use std::{
+    fs::{metadata, File},
+    io,
+};
+
+use crate::{
+    header::{Checksum, FileHeader},
+    LocalStore, LocalStoreError,
+};
+
+/// Result of [LocalStore::verify]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Length of the file
+    pub len: u64,
+
+    /// The records (and the directory) fill the file without a remainder
+    pub aligned: bool,
+
+    /// The header or the reason why it is invalid, None if the store has no header
+    pub header: Option<Result<FileHeader, &'static str>>,
+
+    /// Records which were read
+    pub records: u64,
+
+    /// Records which are not greater than the previous one
+    pub unordered: u64,
+
+    /// Index of the first record which is not greater than the previous one
+    pub first_unordered: Option<u64>,
+
+    /// Do the records match the checksum of the header, None if there is no valid header
+    pub checksum: Option<bool>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.aligned
+            && self.header.as_ref().is_none_or(Result::is_ok)
+            && self.unordered == 0
+            && self.checksum != Some(false)
+    }
+}
+
+impl LocalStore {
+    /// Reads the whole file to check the header, the alignment, the strict order of the records
+    /// and the checksum. Records of a file of bare hashes are read up to the remainder,
+    /// a file with a prefix directory is read only if it is aligned
+    pub fn verify(&self) -> Result<VerifyReport, LocalStoreError> {
+        let len = metadata(&self.file_path)?.len();
+
+        let header = match self.header {
+            true => Some(FileHeader::read(
+                &mut File::open(&self.file_path)?,
+                self.format,
+            )?),
+            false => None,
+        };
+
+        let aligned = len
+            .checked_sub(self.offset())
+            .and_then(|len| self.format.records_in(len))
+            .is_some();
+
+        let mut report = VerifyReport {
+            len,
+            aligned,
+            header,
+            records: 0,
+            unordered: 0,
+            first_unordered: None,
+            checksum: None,
+        };
+
+        if !aligned && !self.format.is_flat() {
+            return Ok(report);
+        }
+
+        let Some(mut records) = self.open_records()? else {
+            return Ok(report);
+        };
+
+        let mut checksum = Checksum::default();
+        let mut buf = [0u8; 24];
+        let mut prev = None;
+        loop {
+            let pwd = match records.read() {
+                Ok(Some(pwd)) => pwd,
+                Ok(None) => break,
+                Err(e) if !aligned && e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            };
+
+            if prev.is_some_and(|prev| prev >= pwd.hash) {
+                report.unordered += 1;
+                report.first_unordered.get_or_insert(report.records);
+            }
+
+            checksum.update(self.format.encode(&pwd, &mut buf));
+            prev = Some(pwd.hash);
+            report.records += 1;
+        }
+
+        if let Some(Ok(header)) = &report.header {
+            report.checksum = Some(aligned && header.checksum == checksum.value());
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use hex_literal::hex;
+    use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+    use pwned_pwd_store::WriteStore;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn verify() {
+        let dir = temp_dir().join("pwned_pwd_verify");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let store = LocalStore::builder(dir.join("pwned")).build().unwrap();
+        store.save(futures::stream::iter(vec![Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![
+            PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 },
+            PwnedPwd { hash: hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"), count: 1 },
+        ]}])).await.unwrap();
+
+        let report = store.verify().unwrap();
+        assert!(report.is_ok());
+        assert_eq!((64 + 40, 2, Some(true)), (report.len, report.records, report.checksum));
+
+        std::fs::write(dir.join("bare"), hex!("
+            21BD4004DDDC80AE4683948C5A1C5903584D8087
+            21BD4004DDDC80AE4683948C5A1C5903584D8087
+            21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED
+            21BD40
+        ")).unwrap();
+
+        let report = LocalStore::builder(dir.join("bare")).without_header().build().unwrap().verify().unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(VerifyReport {
+            len: 63,
+            aligned: false,
+            header: None,
+            records: 3,
+            unordered: 1,
+            first_unordered: Some(1),
+            checksum: None,
+        }, report);
+    }
+}
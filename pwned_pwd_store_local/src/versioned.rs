@@ -0,0 +1,460 @@
+//! Versioned store files with an atomic switch
+//!
+//! [VersionedStore] saves every version of the data set into a new file
+//! (`pwned.v42.bin` for the store path `pwned.bin`) and switches to it by replacing
+//! a small manifest (`pwned.current`) with the number of the current version.
+//! Lookups keep using the previous version until the switch, and a few previous
+//! versions are kept, so a corrupt data set can be rolled back instantly
+
+use std::{
+    fmt,
+    fs::{read_dir, read_to_string, remove_file, rename, File},
+    io::{self, Write},
+    num::ParseIntError,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use futures::{Stream, StreamExt};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+use pwned_pwd_store::{OrderRequirement, ReadStore, StoreMetadata, WriteStore};
+
+use crate::{builder::LocalStoreBuilder, sync_dir, LocalStore, LocalStoreError};
+
+/// Number of a version of a [VersionedStore]. Its string form `v42` is the one of the
+/// file names, so it can be stored and compared outside of the store. A bare number is parsed too
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DatasetVersion(pub u64);
+
+impl DatasetVersion {
+    pub const FIRST: Self = Self(1);
+
+    pub const fn next(&self) -> Self {
+        Self(self.0 + 1)
+    }
+}
+
+impl fmt::Display for DatasetVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+impl FromStr for DatasetVersion {
+    type Err = ParseIntError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value.strip_prefix('v').unwrap_or(value).parse().map(Self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for DatasetVersion {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for DatasetVersion {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Versions of the files of the store path, see [LocalStoreBuilder::build_versioned]
+pub struct VersionedStore {
+    /// Settings of the version stores
+    builder: LocalStoreBuilder,
+    dir: PathBuf,
+    stem: String,
+    extension: Option<String>,
+
+    /// Previous versions kept after a save
+    retain: usize,
+
+    current: RwLock<Option<(DatasetVersion, Arc<LocalStore>)>>,
+
+    /// Saves of new versions one by one
+    writing: tokio::sync::Mutex<()>,
+}
+
+impl VersionedStore {
+    pub(crate) fn new(
+        builder: LocalStoreBuilder,
+        path: &Path,
+        retain: usize,
+    ) -> Result<Self, LocalStoreError> {
+        let stem = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| LocalStoreError::InvalidPath {
+                path: path.to_path_buf(),
+                reason: "The file name isn't valid UTF-8",
+            })?;
+
+        let store = Self {
+            builder,
+            dir: crate::builder::dir_of(path).to_path_buf(),
+            stem: stem.to_owned(),
+            extension: path
+                .extension()
+                .map(|extension| extension.to_string_lossy().into_owned()),
+            retain,
+            current: RwLock::new(None),
+            writing: Default::default(),
+        };
+
+        if let Some(version) = store.read_manifest()? {
+            let opened = store.open(version)?;
+            *store.current.write().unwrap() = Some((version, Arc::new(opened)));
+        }
+        Ok(store)
+    }
+
+    /// Path of the file of the version
+    pub fn version_path(&self, version: DatasetVersion) -> PathBuf {
+        let name = match &self.extension {
+            Some(extension) => format!("{}.{}.{}", self.stem, version, extension),
+            None => format!("{}.{}", self.stem, version),
+        };
+        self.dir.join(name)
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.current", self.stem))
+    }
+
+    fn read_manifest(&self) -> Result<Option<DatasetVersion>, LocalStoreError> {
+        let manifest = match read_to_string(self.manifest_path()) {
+            Ok(manifest) => manifest,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        manifest
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| LocalStoreError::InvalidPath {
+                path: self.manifest_path(),
+                reason: "The manifest isn't a version number",
+            })
+    }
+
+    /// The current version, None if nothing is saved yet
+    pub fn version(&self) -> Option<DatasetVersion> {
+        self.current
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|(version, _)| *version)
+    }
+
+    /// The store of the current version
+    pub fn current(&self) -> Result<Arc<LocalStore>, LocalStoreError> {
+        match &*self.current.read().unwrap() {
+            Some((_, store)) => Ok(store.clone()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "No version is saved").into()),
+        }
+    }
+
+    /// Versions which have files, in ascending order
+    pub fn versions(&self) -> Result<Vec<DatasetVersion>, LocalStoreError> {
+        let prefix = format!("{}.v", self.stem);
+        let suffix = self
+            .extension
+            .as_ref()
+            .map(|extension| format!(".{}", extension))
+            .unwrap_or_default();
+
+        let mut versions = Vec::new();
+        for entry in read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            let version = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(&prefix))
+                .and_then(|name| name.strip_suffix(&suffix))
+                .and_then(|version| version.parse().ok())
+                .map(DatasetVersion);
+
+            if let Some(version) = version {
+                versions.push(version);
+            }
+        }
+
+        versions.sort_unstable();
+        Ok(versions)
+    }
+
+    fn open(&self, version: DatasetVersion) -> Result<LocalStore, LocalStoreError> {
+        self.builder
+            .for_file(self.version_path(version), Default::default())
+            .build()
+    }
+
+    /// Makes the version current. The manifest is replaced atomically,
+    /// so a crash leaves either the old or the new version current
+    pub fn switch(&self, version: DatasetVersion) -> Result<(), LocalStoreError> {
+        if !self.version_path(version).exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "The version has no file").into());
+        }
+        let store = self.open(version)?;
+
+        let manifest = self.manifest_path();
+        let temp = manifest.with_extension("current.tmp");
+        let mut file = File::create(&temp)?;
+        file.write_all(version.0.to_string().as_bytes())?;
+        file.sync_all()?;
+        drop(file);
+        rename(&temp, &manifest)?;
+        sync_dir(&manifest)?;
+
+        *self.current.write().unwrap() = Some((version, Arc::new(store)));
+        Ok(())
+    }
+
+    /// Switches to the greatest version before the current one.
+    /// Returns the new current version or None, if there is no previous version
+    pub fn rollback(&self) -> Result<Option<DatasetVersion>, LocalStoreError> {
+        let Some(current) = self.version() else {
+            return Ok(None);
+        };
+
+        let previous = self
+            .versions()?
+            .into_iter()
+            .rev()
+            .find(|version| *version < current);
+
+        if let Some(previous) = previous {
+            self.switch(previous)?;
+        }
+        Ok(previous)
+    }
+
+    /// Removes the files of the versions except the current one and `retain` versions before it
+    fn prune(&self) -> Result<(), LocalStoreError> {
+        let Some(current) = self.version() else {
+            return Ok(());
+        };
+
+        let versions = self.versions()?;
+        let kept = versions
+            .iter()
+            .rev()
+            .filter(|version| **version < current)
+            .take(self.retain)
+            .copied()
+            .collect::<Vec<_>>();
+
+        for version in versions {
+            if version != current && !kept.contains(&version) {
+                remove_file(self.version_path(version))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a new version with `write`, then switches to it and prunes the old ones.
+    /// If `write` fails, the current version is kept
+    async fn write_version<F, Fut>(&self, write: F) -> Result<(), LocalStoreError>
+    where
+        F: FnOnce(LocalStore) -> Fut,
+        Fut: std::future::Future<Output = Result<(), LocalStoreError>>,
+    {
+        let _writing = self.writing.lock().await;
+
+        let version = self
+            .versions()?
+            .last()
+            .copied()
+            .max(self.version())
+            .map_or(DatasetVersion::FIRST, |version| version.next());
+        let store = self.open(version)?;
+        if let Err(e) = write(store).await {
+            let _ = remove_file(self.version_path(version));
+            return Err(e);
+        }
+
+        self.switch(version)?;
+        self.prune()
+    }
+}
+
+impl ReadStore for VersionedStore {
+    type Error = LocalStoreError;
+
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        self.current()?.exists(val).await
+    }
+
+    /// Records of the version current at the call
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        match self.current() {
+            Ok(store) => store.records_stream().left_stream(),
+            Err(e) => futures::stream::once(async { Err(e) }).right_stream(),
+        }
+    }
+
+    async fn exists_many(&self, vals: &[[u8; 20]]) -> Result<Vec<bool>, Self::Error> {
+        self.current()?.exists_many(vals).await
+    }
+
+    async fn exists_count(&self, val: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        self.current()?.exists_count(val).await
+    }
+
+    async fn range(&self, prefix: Prefix) -> Result<Chunk, Self::Error> {
+        self.current()?.range(prefix).await
+    }
+
+    /// The generation is the current version
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        let Some(version) = self.version() else {
+            return Ok(StoreMetadata::default());
+        };
+
+        Ok(StoreMetadata {
+            generation: Some(version.0),
+            ..self.current()?.metadata().await?
+        })
+    }
+
+    async fn healthy(&self) -> Result<bool, Self::Error> {
+        match self.version() {
+            Some(_) => self.current()?.healthy().await,
+            None => Ok(false),
+        }
+    }
+}
+
+impl WriteStore for VersionedStore {
+    /// Saves a new version. If the save fails or is cancelled, the current version is kept
+    async fn save<S: Stream<Item = Chunk> + Unpin + Send>(&self, s: S) -> Result<(), Self::Error> {
+        self.write_version(|store| async move { store.save(s).await })
+            .await
+    }
+
+    /// Copies the current version into a new one and merges the stream into it
+    async fn merge<S: Stream<Item = Chunk> + Unpin + Send>(&self, s: S) -> Result<(), Self::Error> {
+        let current = self.current().ok();
+        self.write_version(|store| async move {
+            if let Some(current) = current {
+                std::fs::copy(current.file_path(), store.file_path())?;
+                store.refresh()?;
+            }
+            store.merge(s).await
+        })
+        .await
+    }
+
+    /// Saves a new version without the hash
+    async fn remove(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        if !self.exists(val).await? {
+            return Ok(false);
+        }
+
+        let current = self.current()?;
+        self.write_version(|store| async move {
+            std::fs::copy(current.file_path(), store.file_path())?;
+            store.refresh()?;
+            store.remove(val).await.map(|_| ())
+        })
+        .await?;
+        Ok(true)
+    }
+
+    /// Removes the files of all the versions and the manifest
+    async fn clear(&self) -> Result<(), Self::Error> {
+        let _writing = self.writing.lock().await;
+
+        match remove_file(self.manifest_path()) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+        *self.current.write().unwrap() = None;
+
+        for version in self.versions()? {
+            remove_file(self.version_path(version))?;
+        }
+        Ok(())
+    }
+
+    fn order_requirement() -> OrderRequirement {
+        OrderRequirement::Ordered
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use hex_literal::hex;
+    use pwned_pwd_core::Prefix;
+
+    use super::*;
+
+    fn chunk(hash: [u8; 20]) -> futures::stream::Iter<std::vec::IntoIter<Chunk>> {
+        futures::stream::iter(vec![Chunk {
+            prefix: Prefix::from_sha1(&hash),
+            passwords: vec![PwnedPwd { hash, count: 1 }],
+        }])
+    }
+
+    #[tokio::test]
+    async fn versions() {
+        let dir = temp_dir().join("pwned_pwd_versioned");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let store = LocalStore::builder(dir.join("pwned.bin")).build_versioned(1).unwrap();
+        assert_eq!(None, store.version());
+        assert!(!store.healthy().await.unwrap());
+
+        store.save(chunk(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"))).await.unwrap();
+        store.save(chunk(hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087"))).await.unwrap();
+        store.merge(chunk(hex!("21BD6004DDDC80AE4683948C5A1C5903584D8087"))).await.unwrap();
+
+        assert_eq!(Some(DatasetVersion(3)), store.version());
+        assert_eq!(vec![DatasetVersion(2), DatasetVersion(3)], store.versions().unwrap());
+        assert_eq!(dir.join("pwned.v3.bin"), store.version_path(DatasetVersion(3)));
+        assert_eq!("3", std::fs::read_to_string(dir.join("pwned.current")).unwrap());
+        assert_eq!(Some(3), store.metadata().await.unwrap().generation);
+        assert!(store.exists(hex!("21BD5004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert!(store.exists(hex!("21BD6004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert!(!store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+        assert_eq!(2, store.iter_all().count().await);
+
+        assert_eq!(Some(DatasetVersion(2)), store.rollback().unwrap());
+        assert_eq!(None, store.rollback().unwrap());
+        assert!(!store.exists(hex!("21BD6004DDDC80AE4683948C5A1C5903584D8087")).await.unwrap());
+
+        let reopened = LocalStore::builder(dir.join("pwned.bin")).build_versioned(1).unwrap();
+        assert_eq!(Some(DatasetVersion(2)), reopened.version());
+
+        reopened.clear().await.unwrap();
+        assert!(store.versions().unwrap().is_empty());
+        assert!(!dir.join("pwned.current").exists());
+    }
+
+    #[test]
+    fn version_strings() {
+        assert_eq!("v42", DatasetVersion(42).to_string());
+        assert_eq!(Ok(DatasetVersion(42)), "v42".parse());
+        assert_eq!(Ok(DatasetVersion(42)), "42".parse());
+        assert!("v".parse::<DatasetVersion>().is_err());
+        assert!("w42".parse::<DatasetVersion>().is_err());
+        assert!(DatasetVersion(9) < DatasetVersion(10));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn version_serde() {
+        assert_eq!(r#""v42""#, serde_json::to_string(&DatasetVersion(42)).unwrap());
+        assert_eq!(DatasetVersion(42), serde_json::from_str::<DatasetVersion>(r#""v42""#).unwrap());
+    }
+}
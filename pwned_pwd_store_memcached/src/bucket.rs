@@ -0,0 +1,261 @@
+//! Layout of a data set in memcached
+//!
+//! Every prefix is an item `namespace:set:PREFIX` whose value packs the ordered records
+//! of the prefix, an 18-byte suffix and a big-endian 4-byte count each, so a lookup is
+//! a single `get` and a binary search. Empty prefixes have no items, a [Bitmap] of the prefixes
+//! with items tells them apart from evicted ones. A save writes the items of a new set and then
+//! switches `namespace:meta` to it, so frontends never read a half-written data set
+
+use pwned_pwd_core::{Prefix, PrefixStr, PwnedPwd, Suffix};
+
+const RECORD_LEN: usize = 22;
+
+/// Prefixes of a [Bitmap] item
+const GROUP_LEN: u32 = 0x1000;
+
+/// Key names of a namespace
+#[derive(Debug, Clone)]
+pub(crate) struct Keys {
+    namespace: String,
+}
+
+impl Keys {
+    pub(crate) fn new(namespace: String) -> Self {
+        Self { namespace }
+    }
+
+    pub(crate) fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// The item of the prefix in the set
+    pub(crate) fn bucket(&self, set: u64, prefix: &Prefix) -> String {
+        format!(
+            "{}:{set}:{}",
+            self.namespace,
+            PrefixStr::from(prefix).as_ref()
+        )
+    }
+
+    /// The [Bitmap] item of the group of prefixes in the set
+    pub(crate) fn bitmap(&self, set: u64, group: u32) -> String {
+        format!("{}:{set}:bitmap:{group:02X}", self.namespace)
+    }
+
+    /// The item of [Meta]
+    pub(crate) fn meta(&self) -> String {
+        format!("{}:meta", self.namespace)
+    }
+}
+
+/// Description of the data set, the value of the meta item
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Meta {
+    pub(crate) records: u64,
+    pub(crate) updated_at: u64,
+    pub(crate) generation: u64,
+
+    /// The set of the items of the data set, a save writes the next one
+    pub(crate) set: u64,
+
+    /// The set has the [Bitmap] items, so a missing bucket of a prefix in the bitmap was evicted
+    pub(crate) complete: bool,
+}
+
+impl Meta {
+    const LEN: usize = 33;
+
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::LEN);
+        bytes.extend_from_slice(&self.records.to_be_bytes());
+        bytes.extend_from_slice(&self.updated_at.to_be_bytes());
+        bytes.extend_from_slice(&self.generation.to_be_bytes());
+        bytes.extend_from_slice(&self.set.to_be_bytes());
+        bytes.push(u8::from(self.complete));
+        bytes
+    }
+
+    /// None, if the item isn't a meta item
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes = <&[u8; Self::LEN]>::try_from(bytes).ok()?;
+        let u64_at = |i: usize| u64::from_be_bytes(bytes[i..i + 8].try_into().unwrap());
+        Some(Self {
+            records: u64_at(0),
+            updated_at: u64_at(8),
+            generation: u64_at(16),
+            set: u64_at(24),
+            complete: bytes[32] != 0,
+        })
+    }
+}
+
+/// The prefixes with buckets, a bit per prefix. It is stored in items of 4096 prefixes,
+/// so a lookup of a prefix without a bucket reads 512 bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Bitmap(Vec<u8>);
+
+impl Bitmap {
+    const ITEM_LEN: usize = GROUP_LEN as usize / 8;
+
+    /// Count of the items
+    pub(crate) const GROUPS: u32 = 0x100000 / GROUP_LEN;
+
+    pub(crate) fn new() -> Self {
+        Self(vec![0; Self::GROUPS as usize * Self::ITEM_LEN])
+    }
+
+    /// The group of the prefix, the index of its item
+    pub(crate) fn group(prefix: &Prefix) -> u32 {
+        u32::from(*prefix) / GROUP_LEN
+    }
+
+    /// Adds the prefix. Returns false, if the bitmap already has it
+    pub(crate) fn insert(&mut self, prefix: &Prefix) -> bool {
+        let offset = u32::from(*prefix) as usize;
+        let (byte, bit) = (offset / 8, 0x80 >> (offset % 8));
+        let inserted = self.0[byte] & bit == 0;
+        self.0[byte] |= bit;
+        inserted
+    }
+
+    /// The items of the groups in order
+    pub(crate) fn items(&self) -> impl Iterator<Item = (u32, &[u8])> {
+        (0..).zip(self.0.chunks(Self::ITEM_LEN))
+    }
+
+    /// Whether the item of the group of the prefix has it, None if the value isn't an item
+    pub(crate) fn item_contains(item: &[u8], prefix: &Prefix) -> Option<bool> {
+        let (byte, bit) = Self::item_bit(item, prefix)?;
+        Some(item[byte] & bit != 0)
+    }
+
+    /// Adds the prefix to the item of its group. Returns false, if the value isn't an item
+    pub(crate) fn item_insert(item: &mut [u8], prefix: &Prefix) -> bool {
+        match Self::item_bit(item, prefix) {
+            Some((byte, bit)) => {
+                item[byte] |= bit;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn item_bit(item: &[u8], prefix: &Prefix) -> Option<(usize, u8)> {
+        if item.len() != Self::ITEM_LEN {
+            return None;
+        }
+        let offset = (u32::from(*prefix) % GROUP_LEN) as usize;
+        Some((offset / 8, 0x80 >> (offset % 8)))
+    }
+}
+
+/// The packed records of a prefix
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Bucket<'a>(&'a [[u8; RECORD_LEN]]);
+
+impl<'a> Bucket<'a> {
+    /// None, if the value isn't made of whole records
+    pub(crate) fn new(value: &'a [u8]) -> Option<Self> {
+        match value.as_chunks() {
+            (records, []) => Some(Self(records)),
+            _ => None,
+        }
+    }
+
+    /// Packs the ordered passwords of a prefix
+    pub(crate) fn encode(passwords: &[PwnedPwd]) -> Vec<u8> {
+        let mut value = Vec::with_capacity(passwords.len() * RECORD_LEN);
+        for pwd in passwords {
+            value.extend_from_slice(Suffix::from_sha1(&pwd.hash).as_bytes());
+            value.extend_from_slice(&pwd.count.to_be_bytes());
+        }
+        value
+    }
+
+    /// The count of the hash of the prefix, if the bucket has it
+    pub(crate) fn count(&self, hash: &[u8; 20]) -> Option<u32> {
+        let suffix = Suffix::from_sha1(hash);
+        let index = self
+            .0
+            .binary_search_by(|record| record[..18].cmp(suffix.as_bytes()))
+            .ok()?;
+        Some(count(&self.0[index]))
+    }
+
+    /// The passwords of the prefix in order, None if a record isn't a suffix
+    pub(crate) fn passwords(&self, prefix: &Prefix) -> Option<Vec<PwnedPwd>> {
+        self.0
+            .iter()
+            .map(|record| {
+                let suffix = Suffix::from_bytes(record[..18].try_into().unwrap())?;
+                Some(PwnedPwd {
+                    hash: prefix.with_suffix(&suffix),
+                    count: count(record),
+                })
+            })
+            .collect()
+    }
+}
+
+fn count(record: &[u8; RECORD_LEN]) -> u32 {
+    u32::from_be_bytes(record[18..].try_into().unwrap())
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use hex_literal::hex;
+
+    use super::*;
+
+    #[test]
+    fn layout() {
+        let keys = Keys::new("pwned".to_string());
+        let prefix = Prefix::create(0x21BD4).unwrap();
+        assert_eq!("pwned:3:21BD4", keys.bucket(3, &prefix));
+        assert_eq!("pwned:meta", keys.meta());
+        assert_eq!("pwned:3:bitmap:21", keys.bitmap(3, Bitmap::group(&prefix)));
+
+        let passwords = vec![
+            PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 },
+            PwnedPwd { hash: hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), count: 5 },
+        ];
+        let value = Bucket::encode(&passwords);
+        assert_eq!(44, value.len());
+
+        let bucket = Bucket::new(&value).unwrap();
+        assert_eq!(Some(5), bucket.count(&passwords[1].hash));
+        assert_eq!(None, bucket.count(&hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED")));
+        assert_eq!(Some(passwords), bucket.passwords(&prefix));
+        assert!(Bucket::new(&value[1..]).is_none());
+
+        let meta = Meta { records: 2, updated_at: 10, generation: 3, set: 2, complete: true };
+        assert_eq!(Some(meta), Meta::from_bytes(&meta.to_bytes()));
+        assert_eq!(None, Meta::from_bytes(&value));
+    }
+
+    #[test]
+    fn bitmap() {
+        let prefix = Prefix::create(0x21BD4).unwrap();
+        let other = Prefix::create(0x21BD5).unwrap();
+
+        let mut bitmap = Bitmap::new();
+        assert!(bitmap.insert(&prefix));
+        assert!(!bitmap.insert(&prefix));
+
+        let items = bitmap.items().collect::<Vec<_>>();
+        assert_eq!(256, items.len());
+        assert!(items.iter().all(|(_, item)| item.len() == 512));
+
+        let (group, item) = items[0x21];
+        assert_eq!(0x21, group);
+        assert_eq!(Some(true), Bitmap::item_contains(item, &prefix));
+        assert_eq!(Some(false), Bitmap::item_contains(item, &other));
+        assert_eq!(None, Bitmap::item_contains(&item[1..], &prefix));
+
+        let mut item = item.to_vec();
+        assert!(Bitmap::item_insert(&mut item, &other));
+        assert_eq!(Some(true), Bitmap::item_contains(&item, &other));
+        assert!(!Bitmap::item_insert(&mut item[1..], &other));
+    }
+}
@@ -0,0 +1,295 @@
+//! A client of the memcached text protocol
+//!
+//! Only the commands the store needs: `gets`, `set`, `add`, `cas` and `delete`.
+//! Keys are spread over the servers by their hash, connections are pooled per server,
+//! and the commands of a batch are pipelined over one connection
+
+use std::{collections::HashMap, io, sync::Mutex};
+
+use futures::future::try_join_all;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+};
+
+use crate::MemcachedStoreError;
+
+type Connection = BufStream<TcpStream>;
+
+/// Keys of a `gets` command, so the command line stays short
+const MAX_KEYS: usize = 100;
+
+/// A value and its CAS unique
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Item {
+    pub(crate) value: Vec<u8>,
+    pub(crate) cas: u64,
+}
+
+/// How a value is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Store {
+    Set,
+
+    /// Only if there is no item
+    Add,
+
+    /// Only if the item wasn't changed since it was read with the CAS unique
+    Cas(u64),
+}
+
+#[derive(Debug)]
+enum Reply {
+    Items(HashMap<String, Item>),
+    Status(String),
+}
+
+impl Reply {
+    fn unexpected(self) -> MemcachedStoreError {
+        MemcachedStoreError::UnexpectedReply(match self {
+            Reply::Items(_) => "VALUE".to_string(),
+            Reply::Status(status) => status,
+        })
+    }
+}
+
+struct Server {
+    addr: String,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl Server {
+    async fn connection(&self) -> io::Result<Connection> {
+        if let Some(conn) = self.idle.lock().unwrap().pop() {
+            return Ok(conn);
+        }
+
+        let stream = TcpStream::connect(&self.addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(BufStream::new(stream))
+    }
+
+    /// Sends the commands and reads a reply to each of them. A connection is reused
+    /// only after a complete exchange, so a dropped request doesn't leave a reply behind
+    async fn exchange(
+        &self,
+        request: &[u8],
+        commands: usize,
+    ) -> Result<Vec<Reply>, MemcachedStoreError> {
+        let mut conn = self.connection().await?;
+        conn.write_all(request).await?;
+        conn.flush().await?;
+
+        let mut replies = Vec::with_capacity(commands);
+        for _ in 0..commands {
+            replies.push(read_reply(&mut conn).await?);
+        }
+
+        self.idle.lock().unwrap().push(conn);
+        Ok(replies)
+    }
+}
+
+async fn read_line(conn: &mut Connection) -> Result<String, MemcachedStoreError> {
+    let mut line = String::new();
+    if conn.read_line(&mut line).await? == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    let line = line.trim_end_matches("\r\n").to_string();
+    if line == "ERROR" || line.starts_with("CLIENT_ERROR") || line.starts_with("SERVER_ERROR") {
+        return Err(MemcachedStoreError::Server(line));
+    }
+    Ok(line)
+}
+
+/// A status line or the items of `gets` up to `END`
+async fn read_reply(conn: &mut Connection) -> Result<Reply, MemcachedStoreError> {
+    let mut line = read_line(conn).await?;
+    if line != "END" && !line.starts_with("VALUE ") {
+        return Ok(Reply::Status(line));
+    }
+
+    let mut items = HashMap::new();
+    while line != "END" {
+        // VALUE <key> <flags> <bytes> <cas unique>
+        let header = match line.split(' ').collect::<Vec<_>>()[..] {
+            ["VALUE", key, _, len, cas] => len
+                .parse::<usize>()
+                .ok()
+                .zip(cas.parse::<u64>().ok())
+                .map(|(len, cas)| (key.to_string(), len, cas)),
+            _ => None,
+        };
+        let Some((key, len, cas)) = header else {
+            return Err(MemcachedStoreError::UnexpectedReply(line));
+        };
+
+        let mut value = vec![0u8; len + 2];
+        conn.read_exact(&mut value).await?;
+        if !value.ends_with(b"\r\n") {
+            return Err(MemcachedStoreError::UnexpectedReply(line));
+        }
+        value.truncate(len);
+
+        items.insert(key, Item { value, cas });
+        line = read_line(conn).await?;
+    }
+    Ok(Reply::Items(items))
+}
+
+fn store_command(request: &mut Vec<u8>, store: Store, key: &str, value: &[u8]) {
+    let line = match store {
+        Store::Set => format!("set {key} 0 0 {}\r\n", value.len()),
+        Store::Add => format!("add {key} 0 0 {}\r\n", value.len()),
+        Store::Cas(cas) => format!("cas {key} 0 0 {} {cas}\r\n", value.len()),
+    };
+    request.extend_from_slice(line.as_bytes());
+    request.extend_from_slice(value);
+    request.extend_from_slice(b"\r\n");
+}
+
+/// Was the value stored, false if the condition of [Store::Add] or [Store::Cas] failed
+fn stored(reply: Reply) -> Result<bool, MemcachedStoreError> {
+    match reply {
+        Reply::Status(status) if status == "STORED" => Ok(true),
+        Reply::Status(status)
+            if ["NOT_STORED", "EXISTS", "NOT_FOUND"].contains(&status.as_str()) =>
+        {
+            Ok(false)
+        }
+        reply => Err(reply.unexpected()),
+    }
+}
+
+pub(crate) struct Client {
+    servers: Vec<Server>,
+}
+
+impl Client {
+    pub(crate) fn new(servers: Vec<String>) -> Self {
+        assert!(!servers.is_empty(), "No memcached servers");
+        Self {
+            servers: servers
+                .into_iter()
+                .map(|addr| Server {
+                    addr,
+                    idle: Mutex::new(Vec::new()),
+                })
+                .collect(),
+        }
+    }
+
+    /// The index of the server of the key, FNV-1a of the key modulo the count of the servers
+    fn server(&self, key: &str) -> usize {
+        let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+        (hash % self.servers.len() as u64) as usize
+    }
+
+    /// Groups the indices of the keys by their servers
+    fn by_server<'a>(&self, keys: impl Iterator<Item = &'a str>) -> HashMap<usize, Vec<usize>> {
+        let mut groups = HashMap::<usize, Vec<usize>>::new();
+        for (i, key) in keys.enumerate() {
+            groups.entry(self.server(key)).or_default().push(i);
+        }
+        groups
+    }
+
+    /// The items of the keys which exist, every server is asked once
+    pub(crate) async fn get_many(
+        &self,
+        keys: &[String],
+    ) -> Result<HashMap<String, Item>, MemcachedStoreError> {
+        let requests = self
+            .by_server(keys.iter().map(String::as_str))
+            .into_iter()
+            .map(|(server, indices)| {
+                let mut request = Vec::new();
+                for batch in indices.chunks(MAX_KEYS) {
+                    request.extend_from_slice(b"gets");
+                    for i in batch {
+                        request.push(b' ');
+                        request.extend_from_slice(keys[*i].as_bytes());
+                    }
+                    request.extend_from_slice(b"\r\n");
+                }
+                let commands = indices.len().div_ceil(MAX_KEYS);
+                async move { self.servers[server].exchange(&request, commands).await }
+            });
+
+        let mut items = HashMap::with_capacity(keys.len());
+        for reply in try_join_all(requests).await?.into_iter().flatten() {
+            match reply {
+                Reply::Items(found) => items.extend(found),
+                reply => return Err(reply.unexpected()),
+            }
+        }
+        Ok(items)
+    }
+
+    pub(crate) async fn get(&self, key: &str) -> Result<Option<Item>, MemcachedStoreError> {
+        Ok(self.get_many(&[key.to_string()]).await?.remove(key))
+    }
+
+    /// Stores the value, false if the condition of [Store::Add] or [Store::Cas] failed
+    pub(crate) async fn store(
+        &self,
+        store: Store,
+        key: &str,
+        value: &[u8],
+    ) -> Result<bool, MemcachedStoreError> {
+        let mut request = Vec::with_capacity(key.len() + value.len() + 32);
+        store_command(&mut request, store, key, value);
+
+        let reply = self.servers[self.server(key)]
+            .exchange(&request, 1)
+            .await?
+            .pop()
+            .expect("A reply to the command");
+        stored(reply)
+    }
+
+    /// Sets all the values, a server is asked once
+    pub(crate) async fn set_many(
+        &self,
+        items: &[(String, Vec<u8>)],
+    ) -> Result<(), MemcachedStoreError> {
+        let requests = self
+            .by_server(items.iter().map(|(key, _)| key.as_str()))
+            .into_iter()
+            .map(|(server, indices)| {
+                let mut request = Vec::new();
+                for i in &indices {
+                    let (key, value) = &items[*i];
+                    store_command(&mut request, Store::Set, key, value);
+                }
+                async move { self.servers[server].exchange(&request, indices.len()).await }
+            });
+
+        for reply in try_join_all(requests).await?.into_iter().flatten() {
+            if !stored(reply)? {
+                return Err(MemcachedStoreError::UnexpectedReply(
+                    "NOT_STORED".to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the item, false if there is no such item
+    pub(crate) async fn delete(&self, key: &str) -> Result<bool, MemcachedStoreError> {
+        let reply = self.servers[self.server(key)]
+            .exchange(format!("delete {key}\r\n").as_bytes(), 1)
+            .await?
+            .pop()
+            .expect("A reply to the command");
+
+        match reply {
+            Reply::Status(status) if status == "DELETED" => Ok(true),
+            Reply::Status(status) if status == "NOT_FOUND" => Ok(false),
+            reply => Err(reply.unexpected()),
+        }
+    }
+}
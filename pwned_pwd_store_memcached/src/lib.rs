@@ -0,0 +1,784 @@
+//! Memcached backend
+//!
+//! Shops which already run memcached fleets share one memory-resident data set between
+//! many app servers. Every prefix is an item of packed records (see [bucket]), so a lookup
+//! is a single `get`, and a save writes a new set of items which replaces the data set
+//! when the save completes. The items of replaced sets aren't deleted, memcached evicts
+//! them first, as nobody reads them.
+//!
+//! Memcached may evict any item under memory pressure. A save writes the buckets of non-empty
+//! prefixes and a bitmap of them, so a lookup of an evicted bucket or bitmap item fails with
+//! [MemcachedStoreError::MissingBucket] instead of answering "not pwned"
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::RwLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bucket::{Bitmap, Bucket, Keys, Meta};
+use client::{Client, Store};
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+use pwned_pwd_store::{InvalidChunk, OrderRequirement, ReadStore, StoreMetadata, WriteStore};
+
+pub mod bucket;
+mod client;
+
+/// Attempts of a compare-and-swap update before it fails with [MemcachedStoreError::Conflict]
+const CAS_ATTEMPTS: u32 = 16;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MemcachedStoreError {
+    #[error("Io error: {0}")]
+    Io(#[from] io::Error),
+
+    /// The server replied with `ERROR`, `CLIENT_ERROR` or `SERVER_ERROR`,
+    /// e.g. a bucket is larger than the item size limit
+    #[error("Memcached error: {0}")]
+    Server(String),
+
+    /// The server replied with something the protocol doesn't allow here
+    #[error("Unexpected reply: '{0}'")]
+    UnexpectedReply(String),
+
+    /// A save stops at an invalid chunk, the data set isn't replaced
+    #[error(transparent)]
+    InvalidChunk(#[from] InvalidChunk),
+
+    /// A saved stream has two chunks of the prefix, the second one would replace the first
+    #[error("The prefix '{prefix}' is saved twice")]
+    DuplicateChunk { prefix: Prefix },
+
+    /// The bucket of the prefix or the bitmap item of its group was evicted,
+    /// the data set must be saved again
+    #[error("The bucket of the prefix '{prefix}' is missing")]
+    MissingBucket { prefix: Prefix },
+
+    /// The item of the prefix isn't a bucket, the key is used by something else
+    #[error("Invalid bucket of the prefix '{prefix}'")]
+    InvalidBucket { prefix: Prefix },
+
+    /// The meta or a bitmap item isn't written by the store, the key is used by something else
+    #[error("Invalid meta item '{key}'")]
+    InvalidMeta { key: String },
+
+    /// Other clients kept changing the item during a merge or a removal
+    #[error("The item '{key}' is changed concurrently")]
+    Conflict { key: String },
+}
+
+/// A store of SHA-1 hashes in memcached
+pub struct MemcachedStore {
+    client: Client,
+    keys: Keys,
+    concurrency: usize,
+    batch_size: usize,
+    meta_ttl: Duration,
+
+    /// The meta item and when it was read, lookups reuse it for `meta_ttl`
+    meta: RwLock<Option<(Instant, Option<Meta>)>>,
+}
+
+impl MemcachedStore {
+    pub const DEFAULT_NAMESPACE: &'static str = "pwned_pwd";
+
+    /// A store on the servers (`host:port`). Keys are spread over the servers by their hash,
+    /// so all the clients of a data set must list the same servers in the same order
+    ///
+    /// # Panics
+    ///
+    /// If there are no servers
+    pub fn new<S: Into<String>>(servers: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            client: Client::new(servers.into_iter().map(Into::into).collect()),
+            keys: Keys::new(Self::DEFAULT_NAMESPACE.to_string()),
+            concurrency: 16,
+            batch_size: 100,
+            meta_ttl: Duration::from_secs(1),
+            meta: RwLock::new(None),
+        }
+    }
+
+    /// Prefix of the keys, so several data sets share the servers.
+    /// It must not contain spaces or control characters
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.keys = Keys::new(namespace.into());
+        self
+    }
+
+    /// How many requests of a save, a merge or [ReadStore::iter_all] are in flight
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// How many buckets are written or read by one request to a server
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// How long lookups reuse the meta item, a save on another app server
+    /// is seen at most `meta_ttl` later
+    pub fn with_meta_ttl(mut self, meta_ttl: Duration) -> Self {
+        self.meta_ttl = meta_ttl;
+        self
+    }
+
+    pub fn namespace(&self) -> &str {
+        self.keys.namespace()
+    }
+
+    /// The meta item read at most `meta_ttl` ago
+    async fn cached_meta(&self) -> Result<Option<Meta>, MemcachedStoreError> {
+        if let Some((read, meta)) = *self.meta.read().unwrap() {
+            if read.elapsed() < self.meta_ttl {
+                return Ok(meta);
+            }
+        }
+        Ok(self.read_meta().await?.map(|(meta, _)| meta))
+    }
+
+    /// Reads the meta item and its CAS unique
+    async fn read_meta(&self) -> Result<Option<(Meta, u64)>, MemcachedStoreError> {
+        let key = self.keys.meta();
+        let meta = match self.client.get(&key).await? {
+            Some(item) => Some((
+                Meta::from_bytes(&item.value).ok_or(MemcachedStoreError::InvalidMeta { key })?,
+                item.cas,
+            )),
+            None => None,
+        };
+
+        *self.meta.write().unwrap() = Some((Instant::now(), meta.map(|(meta, _)| meta)));
+        Ok(meta)
+    }
+
+    /// Updates the meta item with compare-and-swap, a missing one is updated from the default
+    async fn update_meta(&self, update: impl Fn(Meta) -> Meta) -> Result<(), MemcachedStoreError> {
+        let key = self.keys.meta();
+        for _ in 0..CAS_ATTEMPTS {
+            let (meta, store) = match self.read_meta().await? {
+                Some((meta, cas)) => (update(meta), Store::Cas(cas)),
+                None => (update(Meta::default()), Store::Add),
+            };
+
+            if self.client.store(store, &key, &meta.to_bytes()).await? {
+                *self.meta.write().unwrap() = Some((Instant::now(), Some(meta)));
+                return Ok(());
+            }
+        }
+        Err(MemcachedStoreError::Conflict { key })
+    }
+
+    /// The buckets of the prefixes in the set, None for an empty one
+    async fn fetch(
+        &self,
+        meta: &Meta,
+        prefixes: &[Prefix],
+    ) -> Result<Vec<Option<Vec<u8>>>, MemcachedStoreError> {
+        let keys = prefixes
+            .iter()
+            .map(|prefix| self.keys.bucket(meta.set, prefix))
+            .collect::<Vec<_>>();
+        let mut items = self.client.get_many(&keys).await?;
+        let buckets = keys
+            .iter()
+            .map(|key| items.remove(key).map(|item| item.value))
+            .collect::<Vec<_>>();
+
+        if meta.complete {
+            let empty = prefixes
+                .iter()
+                .zip(&buckets)
+                .filter_map(|(prefix, bucket)| bucket.is_none().then_some(*prefix))
+                .collect::<Vec<_>>();
+            if let Some(prefix) = self.evicted(meta.set, &empty).await? {
+                return Err(MemcachedStoreError::MissingBucket { prefix });
+            }
+        }
+        Ok(buckets)
+    }
+
+    /// The first of the prefixes without buckets which is in the bitmap of the set
+    /// or whose bitmap item is missing, i.e. its bucket was evicted
+    async fn evicted(
+        &self,
+        set: u64,
+        prefixes: &[Prefix],
+    ) -> Result<Option<Prefix>, MemcachedStoreError> {
+        let mut keys = prefixes
+            .iter()
+            .map(|prefix| self.keys.bitmap(set, Bitmap::group(prefix)))
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys.dedup();
+        if keys.is_empty() {
+            return Ok(None);
+        }
+        let items = self.client.get_many(&keys).await?;
+
+        for prefix in prefixes {
+            let key = self.keys.bitmap(set, Bitmap::group(prefix));
+            let evicted = match items.get(&key) {
+                Some(item) => Bitmap::item_contains(&item.value, prefix)
+                    .ok_or(MemcachedStoreError::InvalidMeta { key })?,
+                None => true,
+            };
+            if evicted {
+                return Ok(Some(*prefix));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Adds the prefix to the bitmap item of its group with compare-and-swap
+    async fn mark_bucket(&self, set: u64, prefix: &Prefix) -> Result<(), MemcachedStoreError> {
+        let key = self.keys.bitmap(set, Bitmap::group(prefix));
+        for _ in 0..CAS_ATTEMPTS {
+            let Some(mut item) = self.client.get(&key).await? else {
+                return Err(MemcachedStoreError::MissingBucket { prefix: *prefix });
+            };
+            if !Bitmap::item_insert(&mut item.value, prefix) {
+                return Err(MemcachedStoreError::InvalidMeta { key });
+            }
+            if self
+                .client
+                .store(Store::Cas(item.cas), &key, &item.value)
+                .await?
+            {
+                return Ok(());
+            }
+        }
+        Err(MemcachedStoreError::Conflict { key })
+    }
+
+    /// [MemcachedStore::fetch] of the current data set for lookups
+    async fn lookup(
+        &self,
+        prefixes: &[Prefix],
+    ) -> Result<Vec<Option<Vec<u8>>>, MemcachedStoreError> {
+        let Some(meta) = self.cached_meta().await? else {
+            return Ok(vec![None; prefixes.len()]);
+        };
+
+        match self.fetch(&meta, prefixes).await {
+            // A save on another app server may have replaced the data set since the meta item was read
+            Err(MemcachedStoreError::MissingBucket { prefix }) => {
+                match self.read_meta().await?.map(|(meta, _)| meta) {
+                    Some(fresh) if fresh.set != meta.set => self.fetch(&fresh, prefixes).await,
+                    Some(_) => Err(MemcachedStoreError::MissingBucket { prefix }),
+                    None => Ok(vec![None; prefixes.len()]),
+                }
+            }
+            res => res,
+        }
+    }
+
+    /// The data set and its prefixes which may have buckets, the ones in the bitmap of a saved set
+    async fn stored(&self) -> Result<(Meta, Vec<Prefix>), MemcachedStoreError> {
+        let Some((meta, _)) = self.read_meta().await? else {
+            return Ok((Meta::default(), Vec::new()));
+        };
+        if !meta.complete {
+            return Ok((meta, Prefix::default().into_iter().collect()));
+        }
+
+        let keys = (0..Bitmap::GROUPS)
+            .map(|group| self.keys.bitmap(meta.set, group))
+            .collect::<Vec<_>>();
+        let items = self.client.get_many(&keys).await?;
+
+        let mut prefixes = Vec::new();
+        for prefix in Prefix::default() {
+            let key = &keys[Bitmap::group(&prefix) as usize];
+            let Some(item) = items.get(key) else {
+                return Err(MemcachedStoreError::MissingBucket { prefix });
+            };
+            let stored = Bitmap::item_contains(&item.value, &prefix)
+                .ok_or_else(|| MemcachedStoreError::InvalidMeta { key: key.clone() })?;
+            if stored {
+                prefixes.push(prefix);
+            }
+        }
+        Ok((meta, prefixes))
+    }
+
+    /// Reads the passwords of the prefixes in order
+    async fn read_batch(
+        &self,
+        meta: Meta,
+        prefixes: Vec<Prefix>,
+    ) -> Result<Vec<PwnedPwd>, MemcachedStoreError> {
+        let mut passwords = Vec::new();
+        for (prefix, value) in prefixes.iter().zip(self.fetch(&meta, &prefixes).await?) {
+            if let Some(value) = value {
+                passwords.extend(decode(*prefix, &value)?);
+            }
+        }
+        Ok(passwords)
+    }
+
+    /// Writes the buckets of the chunks into the set. Returns the count of the records
+    async fn write_buckets(
+        &self,
+        set: u64,
+        chunks: Vec<Chunk>,
+    ) -> Result<u64, MemcachedStoreError> {
+        let records = chunks
+            .iter()
+            .map(|chunk| chunk.passwords.len() as u64)
+            .sum();
+        let items = chunks
+            .iter()
+            .map(|chunk| {
+                (
+                    self.keys.bucket(set, &chunk.prefix),
+                    Bucket::encode(&chunk.passwords),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        self.client.set_many(&items).await?;
+        Ok(records)
+    }
+
+    /// Upserts the records of the chunk with compare-and-swap. Returns the count of the added records.
+    /// A new bucket of a saved set is added to the bitmap after it is written
+    async fn upsert_bucket(&self, meta: &Meta, chunk: Chunk) -> Result<u64, MemcachedStoreError> {
+        let key = self.keys.bucket(meta.set, &chunk.prefix);
+        for _ in 0..CAS_ATTEMPTS {
+            let (mut passwords, store) = match self.client.get(&key).await? {
+                Some(item) => (decode(chunk.prefix, &item.value)?, Store::Cas(item.cas)),
+                None if meta.complete => match self.evicted(meta.set, &[chunk.prefix]).await? {
+                    Some(prefix) => return Err(MemcachedStoreError::MissingBucket { prefix }),
+                    None => (Vec::new(), Store::Add),
+                },
+                None => (Vec::new(), Store::Add),
+            };
+            let created = matches!(store, Store::Add);
+
+            let before = passwords.len();
+            for pwd in &chunk.passwords {
+                match passwords.binary_search_by_key(&pwd.hash, |existing| existing.hash) {
+                    Ok(i) => passwords[i].count = pwd.count,
+                    Err(i) => passwords.insert(i, pwd.clone()),
+                }
+            }
+
+            if self
+                .client
+                .store(store, &key, &Bucket::encode(&passwords))
+                .await?
+            {
+                if created && meta.complete {
+                    self.mark_bucket(meta.set, &chunk.prefix).await?;
+                }
+                return Ok((passwords.len() - before) as u64);
+            }
+        }
+        Err(MemcachedStoreError::Conflict { key })
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn bucket(prefix: Prefix, value: &[u8]) -> Result<Bucket<'_>, MemcachedStoreError> {
+    Bucket::new(value).ok_or(MemcachedStoreError::InvalidBucket { prefix })
+}
+
+fn decode(prefix: Prefix, value: &[u8]) -> Result<Vec<PwnedPwd>, MemcachedStoreError> {
+    bucket(prefix, value)?
+        .passwords(&prefix)
+        .ok_or(MemcachedStoreError::InvalidBucket { prefix })
+}
+
+impl ReadStore for MemcachedStore {
+    type Error = MemcachedStoreError;
+
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        Ok(self.exists_count(val).await?.is_some())
+    }
+
+    /// Streams the buckets in the bitmap in order, reading up to the concurrency of batches ahead.
+    /// A save which replaces the data set meanwhile may fail the stream with [MemcachedStoreError::MissingBucket]
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        stream::once(self.stored())
+            .map_ok(move |(meta, prefixes)| {
+                stream::iter(prefixes)
+                    .chunks(self.batch_size)
+                    .map(move |batch| self.read_batch(meta, batch))
+                    .buffered(self.concurrency)
+            })
+            .try_flatten()
+            .map_ok(|passwords| stream::iter(passwords.into_iter().map(Ok)))
+            .try_flatten()
+    }
+
+    async fn exists_many(&self, vals: &[[u8; 20]]) -> Result<Vec<bool>, Self::Error> {
+        Ok(self
+            .exists_count_many(vals)
+            .await?
+            .into_iter()
+            .map(|count| count.is_some())
+            .collect())
+    }
+
+    /// Reads the buckets of all the hashes at once, every server is asked once
+    async fn exists_count_many(&self, vals: &[[u8; 20]]) -> Result<Vec<Option<u32>>, Self::Error> {
+        let mut prefixes = vals.iter().map(Prefix::from_sha1).collect::<Vec<_>>();
+        prefixes.sort_unstable_by_key(|prefix| u32::from(*prefix));
+        prefixes.dedup();
+
+        let buckets = prefixes
+            .iter()
+            .copied()
+            .zip(self.lookup(&prefixes).await?)
+            .filter_map(|(prefix, value)| Some((prefix, value?)))
+            .collect::<HashMap<_, _>>();
+
+        vals.iter()
+            .map(|val| {
+                let prefix = Prefix::from_sha1(val);
+                match buckets.get(&prefix) {
+                    Some(value) => Ok(bucket(prefix, value)?.count(val)),
+                    None => Ok(None),
+                }
+            })
+            .collect()
+    }
+
+    async fn exists_count(&self, val: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        let prefix = Prefix::from_sha1(&val);
+        match self.lookup(&[prefix]).await?.pop().flatten() {
+            Some(value) => Ok(bucket(prefix, &value)?.count(&val)),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads the bucket of the prefix
+    async fn range(&self, prefix: Prefix) -> Result<Chunk, Self::Error> {
+        let passwords = match self.lookup(&[prefix]).await?.pop().flatten() {
+            Some(value) => decode(prefix, &value)?,
+            None => Vec::new(),
+        };
+        Ok(Chunk { prefix, passwords })
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        let meta = self.read_meta().await?.map(|(meta, _)| meta);
+        Ok(StoreMetadata {
+            records: meta.map(|meta| meta.records),
+            updated_at: meta.map(|meta| UNIX_EPOCH + Duration::from_secs(meta.updated_at)),
+            generation: meta.map(|meta| meta.generation),
+            ..Default::default()
+        })
+    }
+
+    /// There is a data set and it isn't empty
+    async fn healthy(&self) -> Result<bool, Self::Error> {
+        Ok(self
+            .read_meta()
+            .await?
+            .is_some_and(|(meta, _)| meta.records > 0))
+    }
+}
+
+impl WriteStore for MemcachedStore {
+    fn order_requirement() -> OrderRequirement {
+        OrderRequirement::Unordered
+    }
+
+    /// Writes the buckets of the non-empty chunks of the stream and the bitmap of their prefixes
+    /// into a new set, then switches the meta item to it. If the future is dropped, the data set isn't replaced
+    async fn save<S: Stream<Item = Chunk> + Unpin + Send>(&self, s: S) -> Result<(), Self::Error> {
+        let set = self.read_meta().await?.map_or(0, |(meta, _)| meta.set + 1);
+        let mut saved = Bitmap::new();
+        let mut buckets = Bitmap::new();
+
+        let records = s
+            .map(|chunk| {
+                InvalidChunk::check(&chunk)?;
+                if !saved.insert(&chunk.prefix) {
+                    return Err(MemcachedStoreError::DuplicateChunk {
+                        prefix: chunk.prefix,
+                    });
+                }
+                if !chunk.passwords.is_empty() {
+                    buckets.insert(&chunk.prefix);
+                }
+                Ok(chunk)
+            })
+            .try_filter(|chunk| future::ready(!chunk.passwords.is_empty()))
+            .try_chunks(self.batch_size)
+            .map_err(|e| e.1)
+            .map_ok(|chunks| self.write_buckets(set, chunks))
+            .try_buffer_unordered(self.concurrency)
+            .try_fold(0, |records, written| future::ok(records + written))
+            .await?;
+
+        let items = buckets
+            .items()
+            .map(|(group, item)| (self.keys.bitmap(set, group), item.to_vec()))
+            .collect::<Vec<_>>();
+        stream::iter(items)
+            .chunks(self.batch_size)
+            .map(|items| async move { self.client.set_many(&items).await })
+            .buffer_unordered(self.concurrency)
+            .try_for_each(|_| future::ok(()))
+            .await?;
+
+        let updated_at = now();
+        self.update_meta(|meta| Meta {
+            records,
+            updated_at,
+            generation: meta.generation + 1,
+            set,
+            complete: true,
+        })
+        .await
+    }
+
+    /// Upserts the buckets with compare-and-swap, a merge into an empty store
+    /// creates only the buckets of the stream
+    async fn merge<S: Stream<Item = Chunk> + Unpin + Send>(&self, s: S) -> Result<(), Self::Error> {
+        let meta = self
+            .read_meta()
+            .await?
+            .map(|(meta, _)| meta)
+            .unwrap_or_default();
+
+        let added = s
+            .map(|chunk| {
+                InvalidChunk::check(&chunk)?;
+                Ok(self.upsert_bucket(&meta, chunk))
+            })
+            .try_buffer_unordered(self.concurrency)
+            .try_fold(0, |added, written| future::ok(added + written))
+            .await?;
+
+        let updated_at = now();
+        self.update_meta(|meta| Meta {
+            records: meta.records + added,
+            updated_at,
+            generation: meta.generation + 1,
+            ..meta
+        })
+        .await
+    }
+
+    async fn remove(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        let Some((meta, _)) = self.read_meta().await? else {
+            return Ok(false);
+        };
+
+        let prefix = Prefix::from_sha1(&val);
+        let key = self.keys.bucket(meta.set, &prefix);
+        for _ in 0..CAS_ATTEMPTS {
+            let item = match self.client.get(&key).await? {
+                Some(item) => item,
+                None if meta.complete => match self.evicted(meta.set, &[prefix]).await? {
+                    Some(prefix) => return Err(MemcachedStoreError::MissingBucket { prefix }),
+                    None => return Ok(false),
+                },
+                None => return Ok(false),
+            };
+
+            let mut passwords = decode(prefix, &item.value)?;
+            let Ok(i) = passwords.binary_search_by_key(&val, |pwd| pwd.hash) else {
+                return Ok(false);
+            };
+            passwords.remove(i);
+
+            if self
+                .client
+                .store(Store::Cas(item.cas), &key, &Bucket::encode(&passwords))
+                .await?
+            {
+                self.update_meta(|meta| Meta {
+                    records: meta.records.saturating_sub(1),
+                    ..meta
+                })
+                .await?;
+                return Ok(true);
+            }
+        }
+        Err(MemcachedStoreError::Conflict { key })
+    }
+
+    /// Deletes the meta item, memcached evicts the unreachable buckets
+    async fn clear(&self) -> Result<(), Self::Error> {
+        self.client.delete(&self.keys.meta()).await?;
+        *self.meta.write().unwrap() = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use hex_literal::hex;
+    use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream}, net::TcpListener};
+
+    use super::*;
+
+    /// Serves the commands of the client from a map, like a single memcached server
+    async fn serve() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let items = Arc::new(Mutex::new(HashMap::<String, (Vec<u8>, u64)>::new()));
+
+        tokio::spawn(async move {
+            while let Ok((connection, _)) = listener.accept().await {
+                let items = items.clone();
+                tokio::spawn(async move {
+                    let mut conn = BufStream::new(connection);
+                    let mut line = String::new();
+                    let mut cas = 0;
+                    while conn.read_line(&mut line).await.unwrap() > 0 {
+                        let parts = line.split_whitespace().map(str::to_string).collect::<Vec<_>>();
+                        line.clear();
+                        let reply = match parts[0].as_str() {
+                            "gets" => {
+                                let items = items.lock().unwrap();
+                                let mut reply = Vec::new();
+                                for key in &parts[1..] {
+                                    if let Some((value, cas)) = items.get(key) {
+                                        reply.extend_from_slice(format!("VALUE {key} 0 {} {cas}\r\n", value.len()).as_bytes());
+                                        reply.extend_from_slice(value);
+                                        reply.extend_from_slice(b"\r\n");
+                                    }
+                                }
+                                reply.extend_from_slice(b"END\r\n");
+                                reply
+                            }
+                            "delete" => match items.lock().unwrap().remove(&parts[1]) {
+                                Some(_) => b"DELETED\r\n".to_vec(),
+                                None => b"NOT_FOUND\r\n".to_vec(),
+                            },
+                            command => {
+                                let mut value = vec![0u8; parts[4].parse::<usize>().unwrap() + 2];
+                                conn.read_exact(&mut value).await.unwrap();
+                                value.truncate(value.len() - 2);
+
+                                let mut items = items.lock().unwrap();
+                                let current = items.get(&parts[1]).map(|(_, cas)| *cas);
+                                let stored = match (command, current) {
+                                    ("set", _) | ("add", None) => true,
+                                    ("cas", Some(current)) => current.to_string() == parts[5],
+                                    _ => false,
+                                };
+                                cas += 1;
+                                if stored {
+                                    items.insert(parts[1].clone(), (value, cas));
+                                }
+                                match stored {
+                                    true => b"STORED\r\n".to_vec(),
+                                    false => b"EXISTS\r\n".to_vec(),
+                                }
+                            }
+                        };
+                        conn.write_all(&reply).await.unwrap();
+                        conn.flush().await.unwrap();
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    /// A real server, if `PWNED_PWD_MEMCACHED` is set (e.g. `127.0.0.1:11211`)
+    async fn store(namespace: &str) -> MemcachedStore {
+        let addr = match std::env::var("PWNED_PWD_MEMCACHED") {
+            Ok(addr) => addr,
+            Err(_) => serve().await,
+        };
+        let store = MemcachedStore::new([addr]).with_namespace(namespace).with_batch_size(1000).with_meta_ttl(Duration::ZERO);
+        store.clear().await.unwrap();
+        store
+    }
+
+    #[tokio::test]
+    async fn save() {
+        let store = store("pwned_save").await;
+        assert!(!store.healthy().await.unwrap());
+
+        let first = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let second = PwnedPwd { hash: hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), count: 5 };
+        let other = PwnedPwd { hash: hex!("21BD5000F2D6B0E3CE3E9D1A0E9C3EB1E2A2A4AC"), count: 2 };
+
+        store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![other.clone()] },
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![first.clone(), second.clone()] },
+        ])).await.unwrap();
+
+        assert!(store.exists(first.hash).await.unwrap());
+        assert_eq!(Some(5), store.exists_count(second.hash).await.unwrap());
+        assert!(!store.exists(hex!("0000000C53D0B33029D7FE4FB08D3D1C9832D2ED")).await.unwrap());
+        assert_eq!(vec![true, false, true], store.exists_many(&[other.hash, hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"), first.hash]).await.unwrap());
+        assert_eq!(Some(3), store.metadata().await.unwrap().records);
+        assert_eq!(vec![first.clone(), second.clone(), other.clone()], store.iter_all().try_collect::<Vec<_>>().await.unwrap());
+        assert_eq!(vec![first.clone(), second.clone()], store.range(Prefix::create(0x21BD4).unwrap()).await.unwrap().passwords);
+        assert!(store.range(Prefix::max()).await.unwrap().passwords.is_empty());
+
+        let updated = PwnedPwd { hash: first.hash, count: 7 };
+        let added = PwnedPwd { hash: hex!("21BD400C53D0B33029D7FE4FB08D3D1C9832D2ED"), count: 3 };
+        store.merge(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![updated.clone(), added.clone()] },
+        ])).await.unwrap();
+        assert_eq!(Some(7), store.exists_count(first.hash).await.unwrap());
+        assert_eq!(Some(3), store.exists_count(added.hash).await.unwrap());
+        assert_eq!(Some(4), store.metadata().await.unwrap().records);
+        assert_eq!(Some(2), store.metadata().await.unwrap().generation);
+
+        assert!(store.remove(second.hash).await.unwrap());
+        assert!(!store.remove(second.hash).await.unwrap());
+        assert_eq!(Some(3), store.metadata().await.unwrap().records);
+
+        let set = store.read_meta().await.unwrap().unwrap().0.set;
+        let added = PwnedPwd { hash: hex!("1234500C53D0B33029D7FE4FB08D3D1C9832D2ED"), count: 1 };
+        store.merge(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x12345).unwrap(), passwords: vec![added.clone()] },
+        ])).await.unwrap();
+        assert!(store.exists(added.hash).await.unwrap());
+
+        let prefix = Prefix::create(0x12345).unwrap();
+        store.client.delete(&store.keys.bucket(set, &prefix)).await.unwrap();
+        assert!(matches!(store.exists(added.hash).await, Err(MemcachedStoreError::MissingBucket { prefix: missing }) if missing == prefix));
+        assert!(matches!(store.remove(added.hash).await, Err(MemcachedStoreError::MissingBucket { .. })));
+
+        let prefix = Prefix::create(0x00000).unwrap();
+        store.client.delete(&store.keys.bitmap(set, Bitmap::group(&prefix))).await.unwrap();
+        assert!(matches!(store.exists(hex!("0000000C53D0B33029D7FE4FB08D3D1C9832D2ED")).await, Err(MemcachedStoreError::MissingBucket { .. })));
+
+        let err = store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![first.clone()] },
+        ])).await.err().unwrap();
+        assert!(matches!(err, MemcachedStoreError::InvalidChunk(_)));
+        assert!(store.exists(first.hash).await.unwrap());
+
+        store.clear().await.unwrap();
+        assert!(!store.exists(first.hash).await.unwrap());
+        assert!(!store.healthy().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn merge_into_empty() {
+        let store = store("pwned_merge").await;
+        let pwd = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+
+        store.merge(stream::iter(vec![Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![pwd.clone()] }])).await.unwrap();
+        assert!(store.exists(pwd.hash).await.unwrap());
+        assert!(!store.exists(hex!("0000000C53D0B33029D7FE4FB08D3D1C9832D2ED")).await.unwrap());
+        assert_eq!(vec![pwd], store.iter_all().try_collect::<Vec<_>>().await.unwrap());
+    }
+}
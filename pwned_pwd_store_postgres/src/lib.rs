@@ -0,0 +1,460 @@
+//! PostgreSQL backend
+//!
+//! Keeps the data set next to an existing user database, so several services query it.
+//! A save streams the records with a binary `COPY` into a new table and swaps it with the
+//! current one, lookups are prepared statements cached by the connections of the pool
+
+use std::collections::{HashMap, HashSet};
+
+use deadpool_postgres::{Client, Pool, PoolError};
+use futures::{pin_mut, stream, Stream, StreamExt, TryStreamExt};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+use pwned_pwd_store::{InvalidChunk, OrderRequirement, ReadStore, StoreMetadata, WriteStore};
+use tokio_postgres::{binary_copy::BinaryCopyInWriter, types::Type, Row};
+
+#[derive(Debug, thiserror::Error)]
+pub enum PostgresStoreError {
+    #[error("Postgres error: {0}")]
+    Postgres(#[from] tokio_postgres::Error),
+
+    #[error("Pool error: {0}")]
+    Pool(#[from] PoolError),
+
+    /// A save stops at an invalid chunk and keeps the data, a merge keeps the batches committed before
+    #[error(transparent)]
+    InvalidChunk(#[from] InvalidChunk),
+
+    /// A row of the table isn't a SHA-1 hash with a count
+    #[error("Invalid record in the table")]
+    InvalidRecord,
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS pwned_pwd (hash BYTEA PRIMARY KEY, count BIGINT NOT NULL);
+CREATE TABLE IF NOT EXISTS pwned_pwd_meta (
+    id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+    records BIGINT NOT NULL,
+    updated_at TIMESTAMPTZ,
+    generation BIGINT NOT NULL
+);
+INSERT INTO pwned_pwd_meta (records, generation) VALUES (0, 0) ON CONFLICT DO NOTHING;
+";
+
+/// A save copies the records into this table, the key is added after the copy
+const SAVED_TABLE: &str = "
+DROP TABLE IF EXISTS pwned_pwd_saved;
+CREATE TABLE pwned_pwd_saved (hash BYTEA NOT NULL, count BIGINT NOT NULL);
+";
+
+const SWAP: &str = "
+DROP TABLE pwned_pwd;
+ALTER TABLE pwned_pwd_saved RENAME TO pwned_pwd;
+ALTER INDEX pwned_pwd_saved_pkey RENAME TO pwned_pwd_pkey;
+";
+
+/// A store of SHA-1 hashes in the `pwned_pwd` table, the schema is chosen by the `search_path`
+pub struct PostgresStore {
+    pool: Pool,
+    batch_size: usize,
+}
+
+impl PostgresStore {
+    pub fn new(pool: Pool) -> Self {
+        Self {
+            pool,
+            batch_size: 10_000,
+        }
+    }
+
+    /// How many records are upserted in a transaction by a merge,
+    /// and read at once by [ReadStore::iter_all]
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Creates the tables, if they don't exist
+    pub async fn migrate(&self) -> Result<(), PostgresStoreError> {
+        Ok(self.pool.get().await?.batch_execute(SCHEMA).await?)
+    }
+}
+
+fn record(row: &Row) -> Result<PwnedPwd, PostgresStoreError> {
+    Ok(PwnedPwd {
+        hash: row
+            .get::<_, &[u8]>(0)
+            .try_into()
+            .map_err(|_| PostgresStoreError::InvalidRecord)?,
+        count: count(row.get(1))?,
+    })
+}
+
+fn count(count: i64) -> Result<u32, PostgresStoreError> {
+    u32::try_from(count).map_err(|_| PostgresStoreError::InvalidRecord)
+}
+
+/// Upserts the batch in a transaction with the count of the added records
+async fn upsert(
+    client: &mut Client,
+    batch: impl Iterator<Item = PwnedPwd>,
+) -> Result<(), PostgresStoreError> {
+    let (hashes, counts): (Vec<_>, Vec<_>) = batch
+        .map(|pwd| (pwd.hash.to_vec(), i64::from(pwd.count)))
+        .unzip();
+
+    let tx = client.transaction().await?;
+    let added = tx
+        .query(
+            "INSERT INTO pwned_pwd (hash, count) SELECT * FROM UNNEST($1::bytea[], $2::bigint[])
+            ON CONFLICT (hash) DO UPDATE SET count = EXCLUDED.count
+            RETURNING xmax = 0",
+            &[&hashes, &counts],
+        )
+        .await?
+        .iter()
+        .filter(|row| row.get::<_, bool>(0))
+        .count();
+    tx.execute(
+        "UPDATE pwned_pwd_meta SET records = records + $1",
+        &[&(added as i64)],
+    )
+    .await?;
+    Ok(tx.commit().await?)
+}
+
+impl ReadStore for PostgresStore {
+    type Error = PostgresStoreError;
+
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        Ok(self.exists_count(val).await?.is_some())
+    }
+
+    /// Reads the table in order, a batch of records at a time
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        let pool = self.pool.clone();
+        let batch_size = self.batch_size;
+
+        stream::try_unfold(Some(Vec::new()), move |after| {
+            let pool = pool.clone();
+            async move {
+                let Some(after) = after else {
+                    return Ok::<_, PostgresStoreError>(None);
+                };
+
+                let client = pool.get().await?;
+                let stmt = client
+                    .prepare_cached(
+                        "SELECT hash, count FROM pwned_pwd WHERE hash > $1 ORDER BY hash LIMIT $2",
+                    )
+                    .await?;
+                let page = client
+                    .query(&stmt, &[&after, &(batch_size as i64)])
+                    .await?
+                    .iter()
+                    .map(record)
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let next = page
+                    .last()
+                    .filter(|_| page.len() == batch_size)
+                    .map(|pwd| pwd.hash.to_vec());
+                Ok(Some((stream::iter(page.into_iter().map(Ok)), next)))
+            }
+        })
+        .try_flatten()
+    }
+
+    /// Looks the hashes up with a single query
+    async fn exists_many(&self, vals: &[[u8; 20]]) -> Result<Vec<bool>, Self::Error> {
+        let client = self.pool.get().await?;
+        let stmt = client
+            .prepare_cached("SELECT hash FROM pwned_pwd WHERE hash = ANY($1)")
+            .await?;
+
+        let hashes = vals.iter().map(|val| val.as_slice()).collect::<Vec<_>>();
+        let found = client
+            .query(&stmt, &[&hashes])
+            .await?
+            .iter()
+            .map(|row| row.get::<_, Vec<u8>>(0))
+            .collect::<HashSet<_>>();
+
+        Ok(vals
+            .iter()
+            .map(|val| found.contains(val.as_slice()))
+            .collect())
+    }
+
+    /// Looks the counts up with a single query
+    async fn exists_count_many(&self, vals: &[[u8; 20]]) -> Result<Vec<Option<u32>>, Self::Error> {
+        let client = self.pool.get().await?;
+        let stmt = client
+            .prepare_cached("SELECT hash, count FROM pwned_pwd WHERE hash = ANY($1)")
+            .await?;
+
+        let hashes = vals.iter().map(|val| val.as_slice()).collect::<Vec<_>>();
+        let found = client
+            .query(&stmt, &[&hashes])
+            .await?
+            .iter()
+            .map(|row| Ok((row.get::<_, Vec<u8>>(0), count(row.get(1))?)))
+            .collect::<Result<HashMap<_, _>, Self::Error>>()?;
+
+        Ok(vals
+            .iter()
+            .map(|val| found.get(val.as_slice()).copied())
+            .collect())
+    }
+
+    async fn exists_count(&self, val: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        let client = self.pool.get().await?;
+        let stmt = client
+            .prepare_cached("SELECT count FROM pwned_pwd WHERE hash = $1")
+            .await?;
+
+        client
+            .query_opt(&stmt, &[&val.as_slice()])
+            .await?
+            .map(|row| count(row.get(0)))
+            .transpose()
+    }
+
+    /// Filters by the count in the query
+    async fn exists_with_min_count(
+        &self,
+        val: [u8; 20],
+        min_count: u32,
+    ) -> Result<Option<u32>, Self::Error> {
+        let client = self.pool.get().await?;
+        let stmt = client
+            .prepare_cached("SELECT count FROM pwned_pwd WHERE hash = $1 AND count >= $2")
+            .await?;
+
+        client
+            .query_opt(&stmt, &[&val.as_slice(), &i64::from(min_count)])
+            .await?
+            .map(|row| count(row.get(0)))
+            .transpose()
+    }
+
+    /// Selects the hashes from the first bytes of the prefix up to the ones of the next prefix
+    async fn range(&self, prefix: Prefix) -> Result<Chunk, Self::Error> {
+        let start = prefix.to_bytes();
+        // Longer than a hash, so every hash of the last prefix is less
+        let end = prefix
+            .next()
+            .map_or(vec![0xFF; 21], |next| next.to_bytes().to_vec());
+
+        let client = self.pool.get().await?;
+        let stmt = client
+            .prepare_cached(
+                "SELECT hash, count FROM pwned_pwd WHERE hash >= $1 AND hash < $2 ORDER BY hash",
+            )
+            .await?;
+
+        let passwords = client
+            .query(&stmt, &[&start.as_slice(), &end.as_slice()])
+            .await?
+            .iter()
+            .map(record)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Chunk { prefix, passwords })
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        let row = self
+            .pool
+            .get()
+            .await?
+            .query_one(
+                "SELECT records, updated_at, generation FROM pwned_pwd_meta",
+                &[],
+            )
+            .await?;
+
+        Ok(StoreMetadata {
+            records: Some(row.get::<_, i64>(0) as u64),
+            updated_at: row.get(1),
+            generation: Some(row.get::<_, i64>(2) as u64),
+            ..Default::default()
+        })
+    }
+}
+
+impl WriteStore for PostgresStore {
+    fn order_requirement() -> OrderRequirement {
+        OrderRequirement::Unordered
+    }
+
+    /// Copies the stream into a new table, adds the primary key and replaces the current table
+    /// in a transaction. A hash must not be repeated in the stream.
+    /// If the future is dropped, the data isn't changed and the next save drops the new table
+    async fn save<S: Stream<Item = Chunk> + Unpin + Send>(
+        &self,
+        mut s: S,
+    ) -> Result<(), Self::Error> {
+        let mut client = self.pool.get().await?;
+        client.batch_execute(SAVED_TABLE).await?;
+
+        let sink = client
+            .copy_in("COPY pwned_pwd_saved (hash, count) FROM STDIN BINARY")
+            .await?;
+        let writer = BinaryCopyInWriter::new(sink, &[Type::BYTEA, Type::INT8]);
+        pin_mut!(writer);
+
+        while let Some(chunk) = s.next().await {
+            InvalidChunk::check(&chunk)?;
+            for pwd in &chunk.passwords {
+                writer
+                    .as_mut()
+                    .write(&[&pwd.hash.as_slice(), &i64::from(pwd.count)])
+                    .await?;
+            }
+        }
+        let records = writer.finish().await?;
+
+        client
+            .batch_execute("ALTER TABLE pwned_pwd_saved ADD CONSTRAINT pwned_pwd_saved_pkey PRIMARY KEY (hash)")
+            .await?;
+
+        let tx = client.transaction().await?;
+        tx.batch_execute(SWAP).await?;
+        tx.execute(
+            "UPDATE pwned_pwd_meta SET records = $1, updated_at = now(), generation = generation + 1",
+            &[&(records as i64)],
+        )
+        .await?;
+        Ok(tx.commit().await?)
+    }
+
+    /// Upserts the stream in batched transactions, the record count is updated by every batch.
+    /// A hash must not be repeated in a batch
+    async fn merge<S: Stream<Item = Chunk> + Unpin + Send>(
+        &self,
+        mut s: S,
+    ) -> Result<(), Self::Error> {
+        let mut client = self.pool.get().await?;
+        let mut batch = Vec::with_capacity(self.batch_size);
+
+        loop {
+            let chunk = s.next().await;
+            if let Some(chunk) = &chunk {
+                InvalidChunk::check(chunk)?;
+            }
+
+            let last = chunk.is_none();
+            batch.extend(chunk.into_iter().flat_map(|chunk| chunk.passwords));
+
+            if batch.len() >= self.batch_size || (last && !batch.is_empty()) {
+                upsert(&mut client, batch.drain(..)).await?;
+            }
+
+            if last {
+                break;
+            }
+        }
+
+        client
+            .execute(
+                "UPDATE pwned_pwd_meta SET updated_at = now(), generation = generation + 1",
+                &[],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn remove(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+
+        let removed = tx
+            .execute("DELETE FROM pwned_pwd WHERE hash = $1", &[&val.as_slice()])
+            .await?
+            == 1;
+        if removed {
+            tx.execute("UPDATE pwned_pwd_meta SET records = records - 1", &[])
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(removed)
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        let mut client = self.pool.get().await?;
+        let tx = client.transaction().await?;
+        tx.batch_execute(
+            "TRUNCATE pwned_pwd;
+            UPDATE pwned_pwd_meta SET records = 0, updated_at = now(), generation = generation + 1;",
+        )
+        .await?;
+        Ok(tx.commit().await?)
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use deadpool_postgres::{Manager, Pool};
+    use hex_literal::hex;
+    use tokio_postgres::NoTls;
+
+    use super::*;
+
+    /// The tests need a database, its connection string is set by `PWNED_PWD_POSTGRES`.
+    /// They are ignored by default, `cargo test -- --ignored` runs them
+    async fn store() -> PostgresStore {
+        let config = std::env::var("PWNED_PWD_POSTGRES").expect("PWNED_PWD_POSTGRES").parse().unwrap();
+        let pool = Pool::builder(Manager::new(config, NoTls)).build().unwrap();
+
+        let store = PostgresStore::new(pool).with_batch_size(2);
+        store.migrate().await.unwrap();
+        store
+    }
+
+    #[tokio::test]
+    #[ignore = "needs PWNED_PWD_POSTGRES"]
+    async fn save_merge() {
+        let store = store().await;
+
+        let first = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let second = PwnedPwd { hash: hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), count: 5 };
+        let other = PwnedPwd { hash: hex!("21BD5000F2D6B0E3CE3E9D1A0E9C3EB1E2A2A4AC"), count: 2 };
+
+        store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![other.clone()] },
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![first.clone()] },
+        ])).await.unwrap();
+        let generation = store.metadata().await.unwrap().generation.unwrap();
+
+        assert!(store.exists(first.hash).await.unwrap());
+        assert_eq!(Some(2), store.exists_count(other.hash).await.unwrap());
+        assert_eq!(None, store.exists_with_min_count(first.hash, 2).await.unwrap());
+        assert_eq!(vec![true, false], store.exists_many(&[other.hash, second.hash]).await.unwrap());
+        assert_eq!(vec![first.clone(), other.clone()], store.iter_all().try_collect::<Vec<_>>().await.unwrap());
+        assert_eq!(vec![first.clone()], store.range(Prefix::create(0x21BD4).unwrap()).await.unwrap().passwords);
+        assert!(store.range(Prefix::max()).await.unwrap().passwords.is_empty());
+        assert_eq!(Some(2), store.metadata().await.unwrap().records);
+
+        store.merge(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![PwnedPwd { count: 7, ..first.clone() }, second.clone()] },
+        ])).await.unwrap();
+        assert_eq!(Some(7), store.exists_count(first.hash).await.unwrap());
+        assert_eq!(Some(3), store.metadata().await.unwrap().records);
+        assert_eq!(Some(generation + 1), store.metadata().await.unwrap().generation);
+
+        assert!(store.remove(other.hash).await.unwrap());
+        assert!(!store.remove(other.hash).await.unwrap());
+        assert_eq!(Some(2), store.metadata().await.unwrap().records);
+
+        let err = store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![first.clone()] },
+        ])).await.err().unwrap();
+        assert!(matches!(err, PostgresStoreError::InvalidChunk(_)));
+        assert!(store.exists(second.hash).await.unwrap());
+
+        store.clear().await.unwrap();
+        assert!(!store.exists(second.hash).await.unwrap());
+        assert_eq!(Some(0), store.metadata().await.unwrap().records);
+    }
+}
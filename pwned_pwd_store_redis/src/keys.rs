@@ -0,0 +1,121 @@
+//! Layout of a data set in Redis
+//!
+//! Every prefix is a hash bucket `namespace:{PREFIX}` of suffixes and counts. The hash tag
+//! spreads the buckets over the slots of a cluster, while the keys of the whole data set
+//! (`{namespace}:meta` and `{namespace}:prefixes`) share a slot, so they are updated atomically
+
+use pwned_pwd_core::{Prefix, PrefixStr};
+
+/// Key names of a namespace
+#[derive(Debug, Clone)]
+pub(crate) struct Keys {
+    namespace: String,
+}
+
+impl Keys {
+    pub(crate) fn new(namespace: String) -> Self {
+        Self { namespace }
+    }
+
+    pub(crate) fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// The hash of the prefix
+    pub(crate) fn bucket(&self, prefix: &Prefix) -> String {
+        format!(
+            "{}:{{{}}}",
+            self.namespace,
+            PrefixStr::from(prefix).as_ref()
+        )
+    }
+
+    /// The hash of [pwned_pwd_store::StoreMetadata]
+    pub(crate) fn meta(&self) -> String {
+        format!("{{{}}}:meta", self.namespace)
+    }
+
+    /// The bitmap of [PrefixSet]
+    pub(crate) fn prefixes(&self) -> String {
+        format!("{{{}}}:prefixes", self.namespace)
+    }
+}
+
+/// Prefixes which have a bucket, in the bit order of Redis `SETBIT`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PrefixSet(Vec<u8>);
+
+impl PrefixSet {
+    /// A bit of every possible prefix
+    const LEN: usize = 0x100000 / 8;
+
+    pub(crate) fn new() -> Self {
+        Self(vec![0; Self::LEN])
+    }
+
+    /// Reads a stored bitmap, which ends at the highest bit set by `SETBIT`
+    pub(crate) fn from_bytes(mut bytes: Vec<u8>) -> Self {
+        bytes.resize(Self::LEN, 0);
+        Self(bytes)
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Bit offset of the prefix
+    pub(crate) fn offset(prefix: &Prefix) -> usize {
+        u32::from(*prefix) as usize
+    }
+
+    /// Adds the prefix, false if it is already in the set
+    pub(crate) fn insert(&mut self, prefix: &Prefix) -> bool {
+        let (byte, mask) = Self::position(prefix);
+        let absent = self.0[byte] & mask == 0;
+        self.0[byte] |= mask;
+        absent
+    }
+
+    pub(crate) fn contains(&self, prefix: &Prefix) -> bool {
+        let (byte, mask) = Self::position(prefix);
+        self.0[byte] & mask != 0
+    }
+
+    /// The prefixes in order
+    pub(crate) fn iter(&self) -> impl Iterator<Item = Prefix> + '_ {
+        Prefix::default()
+            .up_to(Prefix::max())
+            .filter(|prefix| self.contains(prefix))
+    }
+
+    fn position(prefix: &Prefix) -> (usize, u8) {
+        let offset = Self::offset(prefix);
+        (offset / 8, 0x80 >> (offset % 8))
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout() {
+        let keys = Keys::new("pwned".to_string());
+        assert_eq!("pwned:{21BD4}", keys.bucket(&Prefix::create(0x21BD4).unwrap()));
+        assert_eq!("{pwned}:meta", keys.meta());
+        assert_eq!("{pwned}:prefixes", keys.prefixes());
+
+        let mut set = PrefixSet::new();
+        assert_eq!(0x20000, set.as_bytes().len());
+        assert!(set.insert(&Prefix::create(9).unwrap()));
+        assert!(!set.insert(&Prefix::create(9).unwrap()));
+        assert!(set.insert(&Prefix::max()));
+        assert_eq!(0x40, set.as_bytes()[1]);
+        assert_eq!(0x01, set.as_bytes()[0x1FFFF]);
+        assert_eq!(vec![Prefix::create(9).unwrap(), Prefix::max()], set.iter().collect::<Vec<_>>());
+
+        assert_eq!(set, PrefixSet::from_bytes(set.as_bytes().to_vec()));
+        assert_eq!(PrefixSet::new(), PrefixSet::from_bytes(vec![]));
+    }
+}
@@ -0,0 +1,564 @@
+//! Redis backend
+//!
+//! A fleet of stateless web frontends shares one data set in Redis or Redis Cluster.
+//! Every prefix is a hash of suffixes and counts (see [keys]), so a lookup is a single
+//! `HEXISTS`/`HGET`, and a save replaces the buckets with pipelined transactions
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
+use keys::{Keys, PrefixSet};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd, Suffix};
+use pwned_pwd_store::{InvalidChunk, OrderRequirement, ReadStore, StoreMetadata, WriteStore};
+use redis::{
+    aio::{ConnectionLike, ConnectionManager},
+    cluster::ClusterClient,
+    cluster_async::ClusterConnection,
+    AsyncCommands, IntoConnectionInfo, RedisError,
+};
+
+pub mod keys;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RedisStoreError {
+    #[error("Redis error: {0}")]
+    Redis(#[from] RedisError),
+
+    /// A save stops at an invalid chunk, the buckets written before are kept
+    #[error(transparent)]
+    InvalidChunk(#[from] InvalidChunk),
+
+    /// A saved stream has two chunks of the prefix, the second one would replace the first
+    #[error("The prefix '{prefix}' is saved twice")]
+    DuplicateChunk { prefix: Prefix },
+
+    /// A bucket has a field which isn't a suffix, the key is used by something else
+    #[error("Invalid record in the bucket of the prefix '{prefix}'")]
+    InvalidRecord { prefix: Prefix },
+}
+
+/// A store of SHA-1 hashes in Redis.
+/// `C` is a connection to a single server ([ConnectionManager]) or to a cluster ([ClusterConnection]),
+/// it is cloned for every request, so several requests are pipelined over one connection
+#[derive(Clone)]
+pub struct RedisStore<C = ConnectionManager> {
+    conn: C,
+    keys: Keys,
+    pipeline_depth: usize,
+}
+
+impl RedisStore {
+    /// Connects to a single server, the connection is restored after a failure
+    pub async fn connect(info: impl IntoConnectionInfo) -> Result<Self, RedisStoreError> {
+        let client = redis::Client::open(info)?;
+        Ok(Self::new(client.get_connection_manager().await?))
+    }
+}
+
+impl RedisStore<ClusterConnection> {
+    /// Connects to a cluster through its initial nodes
+    pub async fn connect_cluster<T: IntoConnectionInfo>(
+        nodes: impl IntoIterator<Item = T>,
+    ) -> Result<Self, RedisStoreError> {
+        let client = ClusterClient::new(nodes)?;
+        Ok(Self::new(client.get_async_connection().await?))
+    }
+}
+
+impl<C> RedisStore<C> {
+    pub const DEFAULT_NAMESPACE: &'static str = "pwned_pwd";
+
+    pub fn new(conn: C) -> Self {
+        Self {
+            conn,
+            keys: Keys::new(Self::DEFAULT_NAMESPACE.to_string()),
+            pipeline_depth: 64,
+        }
+    }
+
+    /// Prefix of the keys, so several data sets share a server.
+    /// It must not contain braces, they mark hash tags in Redis Cluster
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.keys = Keys::new(namespace.into());
+        self
+    }
+
+    /// How many requests of a save or a batch lookup are sent without waiting for the replies
+    pub fn with_pipeline_depth(mut self, pipeline_depth: usize) -> Self {
+        self.pipeline_depth = pipeline_depth.max(1);
+        self
+    }
+
+    pub fn namespace(&self) -> &str {
+        self.keys.namespace()
+    }
+}
+
+impl<C: ConnectionLike + Clone + Send + Sync + 'static> RedisStore<C> {
+    async fn prefixes(&self) -> Result<PrefixSet, RedisStoreError> {
+        let bitmap: Option<Vec<u8>> = self.conn.clone().get(self.keys.prefixes()).await?;
+        Ok(PrefixSet::from_bytes(bitmap.unwrap_or_default()))
+    }
+
+    /// The server time, so every frontend sees the same [StoreMetadata::updated_at]
+    async fn now(&self) -> Result<u64, RedisStoreError> {
+        let (secs, _micros): (u64, u64) = redis::cmd("TIME")
+            .query_async(&mut self.conn.clone())
+            .await?;
+        Ok(secs)
+    }
+
+    /// Replaces the bucket of the chunk in one transaction. Returns the count of the records
+    async fn replace_bucket(&self, chunk: Chunk) -> Result<u64, RedisStoreError> {
+        let key = self.keys.bucket(&chunk.prefix);
+        let fields = fields(&chunk);
+
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(&key).ignore();
+        if !fields.is_empty() {
+            pipe.hset_multiple(&key, &fields).ignore();
+        }
+        pipe.query_async::<()>(&mut self.conn.clone()).await?;
+
+        Ok(fields.len() as u64)
+    }
+
+    /// Upserts the records of the chunk. Returns the count of the added records
+    async fn upsert_bucket(&self, chunk: Chunk) -> Result<u64, RedisStoreError> {
+        let fields = fields(&chunk);
+        if fields.is_empty() {
+            return Ok(0);
+        }
+
+        // `HSET` returns the count of the new fields, unlike `HMSET`
+        let mut conn = self.conn.clone();
+        let added: u64 = redis::cmd("HSET")
+            .arg(self.keys.bucket(&chunk.prefix))
+            .arg(&fields)
+            .query_async(&mut conn)
+            .await?;
+        conn.setbit::<_, ()>(self.keys.prefixes(), PrefixSet::offset(&chunk.prefix), true)
+            .await?;
+
+        Ok(added)
+    }
+
+    /// Removes the buckets in parallel
+    async fn unlink(&self, prefixes: impl Iterator<Item = Prefix>) -> Result<(), RedisStoreError> {
+        stream::iter(prefixes)
+            .map(|prefix| {
+                let mut conn = self.conn.clone();
+                let key = self.keys.bucket(&prefix);
+                async move { conn.unlink::<_, ()>(key).await }
+            })
+            .buffer_unordered(self.pipeline_depth)
+            .try_for_each(|_| future::ok(()))
+            .await?;
+        Ok(())
+    }
+}
+
+/// Suffixes and counts of the chunk
+fn fields(chunk: &Chunk) -> Vec<(Vec<u8>, u32)> {
+    chunk
+        .passwords
+        .iter()
+        .map(|pwd| (Suffix::from_sha1(&pwd.hash).as_bytes().to_vec(), pwd.count))
+        .collect()
+}
+
+/// Reads the passwords of the bucket in order
+async fn read_bucket<C: ConnectionLike + Send>(
+    mut conn: C,
+    key: String,
+    prefix: Prefix,
+) -> Result<Vec<PwnedPwd>, RedisStoreError> {
+    let fields: Vec<(Vec<u8>, u32)> = conn.hgetall(key).await?;
+
+    let mut passwords = fields
+        .into_iter()
+        .map(|(suffix, count)| {
+            let suffix = <[u8; 18]>::try_from(suffix)
+                .ok()
+                .and_then(Suffix::from_bytes)
+                .ok_or(RedisStoreError::InvalidRecord { prefix })?;
+            Ok(PwnedPwd {
+                hash: prefix.with_suffix(&suffix),
+                count,
+            })
+        })
+        .collect::<Result<Vec<_>, RedisStoreError>>()?;
+    passwords.sort_unstable_by_key(|pwd| pwd.hash);
+
+    Ok(passwords)
+}
+
+impl<C: ConnectionLike + Clone + Send + Sync + 'static> ReadStore for RedisStore<C> {
+    type Error = RedisStoreError;
+
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        let key = self.keys.bucket(&Prefix::from_sha1(&val));
+        let suffix = Suffix::from_sha1(&val);
+        Ok(self
+            .conn
+            .clone()
+            .hexists(key, suffix.as_bytes().as_slice())
+            .await?)
+    }
+
+    /// Streams the buckets in order, reading up to the pipeline depth of them ahead
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        let conn = self.conn.clone();
+        let keys = self.keys.clone();
+        let pipeline_depth = self.pipeline_depth;
+
+        stream::once(self.prefixes())
+            .map_ok(move |prefixes| {
+                let (conn, keys) = (conn.clone(), keys.clone());
+                stream::iter(prefixes.iter().collect::<Vec<_>>())
+                    .map(move |prefix| read_bucket(conn.clone(), keys.bucket(&prefix), prefix))
+                    .buffered(pipeline_depth)
+            })
+            .try_flatten()
+            .map_ok(|passwords| stream::iter(passwords.into_iter().map(Ok)))
+            .try_flatten()
+    }
+
+    /// Checks the hashes in parallel, up to the pipeline depth at once
+    async fn exists_many(&self, vals: &[[u8; 20]]) -> Result<Vec<bool>, Self::Error> {
+        stream::iter(vals.iter().copied())
+            .map(|val| self.exists(val))
+            .buffered(self.pipeline_depth)
+            .try_collect()
+            .await
+    }
+
+    /// Looks the counts up in parallel, up to the pipeline depth at once
+    async fn exists_count_many(&self, vals: &[[u8; 20]]) -> Result<Vec<Option<u32>>, Self::Error> {
+        stream::iter(vals.iter().copied())
+            .map(|val| self.exists_count(val))
+            .buffered(self.pipeline_depth)
+            .try_collect()
+            .await
+    }
+
+    async fn exists_count(&self, val: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        let key = self.keys.bucket(&Prefix::from_sha1(&val));
+        let suffix = Suffix::from_sha1(&val);
+        Ok(self
+            .conn
+            .clone()
+            .hget(key, suffix.as_bytes().as_slice())
+            .await?)
+    }
+
+    /// Reads the bucket of the prefix
+    async fn range(&self, prefix: Prefix) -> Result<Chunk, Self::Error> {
+        let passwords = read_bucket(self.conn.clone(), self.keys.bucket(&prefix), prefix).await?;
+        Ok(Chunk { prefix, passwords })
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        let (records, updated_at, generation): (Option<u64>, Option<u64>, Option<u64>) =
+            redis::cmd("HMGET")
+                .arg(self.keys.meta())
+                .arg(&["records", "updated_at", "generation"])
+                .query_async(&mut self.conn.clone())
+                .await?;
+
+        Ok(StoreMetadata {
+            records,
+            updated_at: updated_at.map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+            generation,
+            ..Default::default()
+        })
+    }
+}
+
+impl<C: ConnectionLike + Clone + Send + Sync + 'static> WriteStore for RedisStore<C> {
+    fn order_requirement() -> OrderRequirement {
+        OrderRequirement::Unordered
+    }
+
+    /// Replaces every bucket of the stream in a transaction, then removes the buckets of the other prefixes.
+    /// The metadata is updated only by a complete save, and if the future is dropped,
+    /// the saved prefixes are of the new data set and the rest are of the old one
+    async fn save<S: Stream<Item = Chunk> + Unpin + Send>(&self, s: S) -> Result<(), Self::Error> {
+        let previous = self.prefixes().await?;
+        let mut saved = PrefixSet::new();
+
+        let records = s
+            .map(|chunk| {
+                InvalidChunk::check(&chunk)?;
+                if !saved.insert(&chunk.prefix) {
+                    return Err(RedisStoreError::DuplicateChunk {
+                        prefix: chunk.prefix,
+                    });
+                }
+                Ok(self.replace_bucket(chunk))
+            })
+            .try_buffer_unordered(self.pipeline_depth)
+            .try_fold(0, |records, written| future::ok(records + written))
+            .await?;
+
+        self.unlink(previous.iter().filter(|prefix| !saved.contains(prefix)))
+            .await?;
+
+        let meta = self.keys.meta();
+        redis::pipe()
+            .atomic()
+            .set(self.keys.prefixes(), saved.as_bytes())
+            .ignore()
+            .hset_multiple(
+                &meta,
+                &[("records", records), ("updated_at", self.now().await?)],
+            )
+            .ignore()
+            .hincr(&meta, "generation", 1)
+            .ignore()
+            .query_async::<()>(&mut self.conn.clone())
+            .await?;
+
+        Ok(())
+    }
+
+    async fn merge<S: Stream<Item = Chunk> + Unpin + Send>(&self, s: S) -> Result<(), Self::Error> {
+        let added = s
+            .map(|chunk| {
+                InvalidChunk::check(&chunk)?;
+                Ok(self.upsert_bucket(chunk))
+            })
+            .try_buffer_unordered(self.pipeline_depth)
+            .try_fold(0, |added, written| future::ok(added + written))
+            .await?;
+
+        let meta = self.keys.meta();
+        redis::pipe()
+            .atomic()
+            .hincr(&meta, "records", added)
+            .ignore()
+            .hset(&meta, "updated_at", self.now().await?)
+            .ignore()
+            .hincr(&meta, "generation", 1)
+            .ignore()
+            .query_async::<()>(&mut self.conn.clone())
+            .await?;
+
+        Ok(())
+    }
+
+    async fn remove(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        let key = self.keys.bucket(&Prefix::from_sha1(&val));
+        let suffix = Suffix::from_sha1(&val);
+
+        let mut conn = self.conn.clone();
+        let removed: bool = conn.hdel(key, suffix.as_bytes().as_slice()).await?;
+        if removed {
+            conn.hincr::<_, _, _, ()>(self.keys.meta(), "records", -1)
+                .await?;
+        }
+
+        Ok(removed)
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        self.unlink(self.prefixes().await?.iter()).await?;
+        self.conn
+            .clone()
+            .del::<_, ()>(&[self.keys.prefixes(), self.keys.meta()])
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use hex_literal::hex;
+    use redis::Value;
+    use redis_test::{MockCmd, MockRedisConnection};
+
+    use super::*;
+
+    fn store(commands: Vec<MockCmd>) -> RedisStore<MockRedisConnection> {
+        RedisStore::new(MockRedisConnection::new(commands)).with_namespace("pwned").with_pipeline_depth(1)
+    }
+
+    fn ok(commands: usize) -> Result<Vec<Value>, RedisError> {
+        Ok(vec![Value::Array(vec![Value::Okay; commands])])
+    }
+
+    #[tokio::test]
+    async fn save() {
+        let first = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let second = PwnedPwd { hash: hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), count: 5 };
+        let other = PwnedPwd { hash: hex!("21BD5000F2D6B0E3CE3E9D1A0E9C3EB1E2A2A4AC"), count: 2 };
+        let (prefix, next) = (Prefix::create(0x21BD4).unwrap(), Prefix::create(0x21BD5).unwrap());
+
+        let mut previous = PrefixSet::new();
+        previous.insert(&Prefix::create(9).unwrap());
+        previous.insert(&prefix);
+        let mut saved = PrefixSet::new();
+        saved.insert(&prefix);
+        saved.insert(&next);
+
+        let store = store(vec![
+            MockCmd::new(redis::cmd("GET").arg("{pwned}:prefixes"), Ok(previous.as_bytes().to_vec())),
+            MockCmd::with_values(
+                redis::pipe().atomic()
+                    .del("pwned:{21BD4}").ignore()
+                    .hset_multiple("pwned:{21BD4}", &fields(&Chunk { prefix, passwords: vec![first.clone(), second.clone()] })).ignore(),
+                ok(2),
+            ),
+            MockCmd::with_values(
+                redis::pipe().atomic()
+                    .del("pwned:{21BD5}").ignore()
+                    .hset_multiple("pwned:{21BD5}", &fields(&Chunk { prefix: next, passwords: vec![other.clone()] })).ignore(),
+                ok(2),
+            ),
+            MockCmd::new(redis::cmd("UNLINK").arg("pwned:{00009}"), Ok(1)),
+            MockCmd::new(redis::cmd("TIME"), Ok(Value::Array(vec![Value::BulkString(b"1700000000".to_vec()), Value::BulkString(b"0".to_vec())]))),
+            MockCmd::with_values(
+                redis::pipe().atomic()
+                    .set("{pwned}:prefixes", saved.as_bytes()).ignore()
+                    .hset_multiple("{pwned}:meta", &[("records", 3), ("updated_at", 1700000000)]).ignore()
+                    .hincr("{pwned}:meta", "generation", 1).ignore(),
+                ok(3),
+            ),
+            MockCmd::new(redis::cmd("HEXISTS").arg("pwned:{21BD4}").arg(Suffix::from_sha1(&second.hash).as_bytes().as_slice()), Ok(1)),
+            MockCmd::new(redis::cmd("HGET").arg("pwned:{21BD5}").arg(Suffix::from_sha1(&other.hash).as_bytes().as_slice()), Ok("2")),
+            MockCmd::new(redis::cmd("GET").arg("{pwned}:prefixes"), Ok(saved.as_bytes().to_vec())),
+            MockCmd::new(
+                redis::cmd("HGETALL").arg("pwned:{21BD4}"),
+                Ok(Value::Array(vec![
+                    Value::BulkString(Suffix::from_sha1(&second.hash).as_bytes().to_vec()), Value::BulkString(b"5".to_vec()),
+                    Value::BulkString(Suffix::from_sha1(&first.hash).as_bytes().to_vec()), Value::BulkString(b"1".to_vec()),
+                ])),
+            ),
+            MockCmd::new(
+                redis::cmd("HGETALL").arg("pwned:{21BD5}"),
+                Ok(Value::Array(vec![Value::BulkString(Suffix::from_sha1(&other.hash).as_bytes().to_vec()), Value::BulkString(b"2".to_vec())])),
+            ),
+            MockCmd::new(
+                redis::cmd("HMGET").arg("{pwned}:meta").arg(&["records", "updated_at", "generation"]),
+                Ok(Value::Array(vec![Value::BulkString(b"3".to_vec()), Value::BulkString(b"1700000000".to_vec()), Value::BulkString(b"1".to_vec())])),
+            ),
+        ]);
+
+        store.save(futures::stream::iter(vec![
+            Chunk { prefix, passwords: vec![first.clone(), second.clone()] },
+            Chunk { prefix: next, passwords: vec![other.clone()] },
+        ])).await.unwrap();
+
+        assert!(store.exists(second.hash).await.unwrap());
+        assert_eq!(Some(2), store.exists_count(other.hash).await.unwrap());
+        assert_eq!(vec![first, second, other], store.iter_all().try_collect::<Vec<_>>().await.unwrap());
+
+        let metadata = store.metadata().await.unwrap();
+        assert_eq!(Some(3), metadata.records);
+        assert_eq!(Some(1), metadata.generation);
+        assert_eq!(Some(UNIX_EPOCH + Duration::from_secs(1700000000)), metadata.updated_at);
+    }
+
+    #[tokio::test]
+    async fn merge() {
+        let first = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let second = PwnedPwd { hash: hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), count: 5 };
+        let prefix = Prefix::create(0x21BD4).unwrap();
+        let chunk = Chunk { prefix, passwords: vec![first, second] };
+
+        let store = store(vec![
+            MockCmd::new(redis::cmd("HSET").arg("pwned:{21BD4}").arg(fields(&chunk)), Ok(1)),
+            MockCmd::new(redis::cmd("SETBIT").arg("{pwned}:prefixes").arg(PrefixSet::offset(&prefix)).arg(1), Ok(1)),
+            MockCmd::new(redis::cmd("TIME"), Ok(Value::Array(vec![Value::BulkString(b"1700000000".to_vec()), Value::BulkString(b"0".to_vec())]))),
+            MockCmd::with_values(
+                redis::pipe().atomic()
+                    .hincr("{pwned}:meta", "records", 1).ignore()
+                    .hset("{pwned}:meta", "updated_at", 1700000000).ignore()
+                    .hincr("{pwned}:meta", "generation", 1).ignore(),
+                ok(3),
+            ),
+        ]);
+
+        store.merge(futures::stream::iter(vec![chunk, Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: Vec::new() }])).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn range() {
+        let first = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let second = PwnedPwd { hash: hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), count: 5 };
+
+        let store = store(vec![
+            MockCmd::new(
+                redis::cmd("HGETALL").arg("pwned:{21BD4}"),
+                Ok(Value::Array(vec![
+                    Value::BulkString(Suffix::from_sha1(&second.hash).as_bytes().to_vec()), Value::BulkString(b"5".to_vec()),
+                    Value::BulkString(Suffix::from_sha1(&first.hash).as_bytes().to_vec()), Value::BulkString(b"1".to_vec()),
+                ])),
+            ),
+            MockCmd::new(redis::cmd("HGETALL").arg("pwned:{FFFFF}"), Ok(Value::Array(Vec::new()))),
+        ]);
+
+        assert_eq!(vec![first, second], store.range(Prefix::create(0x21BD4).unwrap()).await.unwrap().passwords);
+        assert!(store.range(Prefix::max()).await.unwrap().passwords.is_empty());
+    }
+
+    #[tokio::test]
+    async fn remove() {
+        let pwd = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let suffix = Suffix::from_sha1(&pwd.hash);
+
+        let store = store(vec![
+            MockCmd::new(redis::cmd("HDEL").arg("pwned:{21BD4}").arg(suffix.as_bytes().as_slice()), Ok(1)),
+            MockCmd::new(redis::cmd("HINCRBY").arg("{pwned}:meta").arg("records").arg(-1), Ok(2)),
+            MockCmd::new(redis::cmd("HDEL").arg("pwned:{21BD4}").arg(suffix.as_bytes().as_slice()), Ok(0)),
+        ]);
+
+        assert!(store.remove(pwd.hash).await.unwrap());
+        assert!(!store.remove(pwd.hash).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn clear() {
+        let mut prefixes = PrefixSet::new();
+        prefixes.insert(&Prefix::create(9).unwrap());
+        prefixes.insert(&Prefix::create(0x21BD4).unwrap());
+
+        let store = store(vec![
+            MockCmd::new(redis::cmd("GET").arg("{pwned}:prefixes"), Ok(prefixes.as_bytes().to_vec())),
+            MockCmd::new(redis::cmd("UNLINK").arg("pwned:{00009}"), Ok(1)),
+            MockCmd::new(redis::cmd("UNLINK").arg("pwned:{21BD4}"), Ok(1)),
+            MockCmd::new(redis::cmd("DEL").arg(&["{pwned}:prefixes", "{pwned}:meta"]), Ok(2)),
+        ]);
+
+        store.clear().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn save_invalid() {
+        let pwd = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let prefix = Prefix::create(0x21BD4).unwrap();
+
+        let store = store(vec![
+            MockCmd::new(redis::cmd("GET").arg("{pwned}:prefixes"), Ok(Value::Nil)),
+            MockCmd::with_values(
+                redis::pipe().atomic()
+                    .del("pwned:{21BD4}").ignore()
+                    .hset_multiple("pwned:{21BD4}", &fields(&Chunk { prefix, passwords: vec![pwd.clone()] })).ignore(),
+                ok(2),
+            ),
+            MockCmd::new(redis::cmd("GET").arg("{pwned}:prefixes"), Ok(Value::Nil)),
+        ]);
+
+        let err = store.save(futures::stream::iter(vec![
+            Chunk { prefix, passwords: vec![pwd.clone()] },
+            Chunk { prefix, passwords: vec![pwd.clone()] },
+        ])).await.err().unwrap();
+        assert!(matches!(err, RedisStoreError::DuplicateChunk { .. }));
+
+        let err = store.save(futures::stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![pwd.clone()] },
+        ])).await.err().unwrap();
+        assert!(matches!(err, RedisStoreError::InvalidChunk(_)));
+    }
+}
@@ -0,0 +1,350 @@
+//! A store backed by a `pwned_pwd_server`
+//!
+//! [RemoteStore] checks hashes with `POST /exists` of the server. Concurrent
+//! [ReadStore::exists] calls are queued and sent together: a batch is sent, when it has
+//! [RemoteStore::with_max_batch] hashes or [RemoteStore::with_flush_interval] after its first
+//! hash, so a busy application makes one request for many login attempts.
+//! Counts and ranges are downloaded with `GET /range/{prefix}`
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd, NTLM_LEN, SHA1_LEN};
+use pwned_pwd_downloader::{ChunkSource, DownloadError, Downloader};
+use pwned_pwd_store::ReadStore;
+use reqwest::{header, Client};
+use tokio::sync::{mpsc, oneshot};
+use url::Url;
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum RemoteStoreError {
+    /// Errors are shared by the lookups of a batch
+    #[error("Request error: {0}")]
+    Request(Arc<reqwest::Error>),
+
+    #[error("Range download error: {0}")]
+    Download(Arc<DownloadError>),
+
+    /// The server answered with another count of results
+    #[error("Invalid response of the server")]
+    InvalidResponse,
+
+    /// The runtime of the batch was shut down before it was answered
+    #[error("The batch was dropped")]
+    Dropped,
+}
+
+impl From<reqwest::Error> for RemoteStoreError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Request(Arc::new(e))
+    }
+}
+
+impl From<DownloadError> for RemoteStoreError {
+    fn from(e: DownloadError) -> Self {
+        Self::Download(Arc::new(e))
+    }
+}
+
+/// A queued hash and where its result goes
+type Lookup<const N: usize> = ([u8; N], oneshot::Sender<Result<bool, RemoteStoreError>>);
+
+/// A read-only store which requests a server, see the [crate] docs
+pub struct RemoteStore<const N: usize = SHA1_LEN> {
+    client: Client,
+    exists_url: Url,
+    downloader: Downloader<N>,
+    max_batch: usize,
+    concurrency: usize,
+    flush_interval: Duration,
+
+    /// Lookups of [ReadStore::exists]. The batching task is spawned by the first one and
+    /// spawned again, if the runtime it was spawned on has been shut down
+    queue: Mutex<Option<mpsc::UnboundedSender<Lookup<N>>>>,
+}
+
+impl RemoteStore {
+    /// A store of SHA-1 hashes of the server at `base_url`, like `http://pwned.internal/`
+    pub fn new(base_url: Url) -> Self {
+        let downloader = Downloader::new(range_url(&base_url), 1);
+        Self::from_parts(exists_url(&base_url, None), downloader)
+    }
+}
+
+impl RemoteStore<NTLM_LEN> {
+    /// A store of NTLM hashes of the server at `base_url`, like `http://pwned.internal/`
+    pub fn ntlm(base_url: Url) -> Self {
+        let downloader = Downloader::ntlm(range_url(&base_url), 1);
+        Self::from_parts(exists_url(&base_url, Some("ntlm")), downloader)
+    }
+}
+
+impl<const N: usize> RemoteStore<N> {
+    /// Hashes of a request, unless [Self::with_max_batch] is set
+    pub const DEFAULT_MAX_BATCH: usize = 100;
+    /// Requests of [ReadStore::exists_many] at once, unless [Self::with_concurrency] is set
+    pub const DEFAULT_CONCURRENCY: usize = 4;
+    /// Wait of a batch, unless [Self::with_flush_interval] is set
+    pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_millis(5);
+
+    fn from_parts(exists_url: Url, downloader: Downloader<N>) -> Self {
+        Self {
+            client: Client::new(),
+            exists_url,
+            downloader,
+            max_batch: Self::DEFAULT_MAX_BATCH,
+            concurrency: Self::DEFAULT_CONCURRENCY,
+            flush_interval: Self::DEFAULT_FLUSH_INTERVAL,
+            queue: Mutex::new(None),
+        }
+    }
+
+    /// Hashes of a request, it shouldn't exceed the limit of the server
+    pub fn with_max_batch(mut self, max_batch: usize) -> Self {
+        self.max_batch = max_batch.max(1);
+        self
+    }
+
+    /// Requests which [ReadStore::exists_many] sends at once
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// How long a batch waits for more hashes after its first one
+    pub fn with_flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    pub fn downloader(&self) -> &Downloader<N> {
+        &self.downloader
+    }
+
+    fn queue(&self) -> mpsc::UnboundedSender<Lookup<N>> {
+        let mut queue = self.queue.lock().unwrap();
+        match &*queue {
+            Some(queue) if !queue.is_closed() => queue.clone(),
+            _ => {
+                let (sender, lookups) = mpsc::unbounded_channel();
+                tokio::spawn(batches(
+                    self.client.clone(),
+                    self.exists_url.clone(),
+                    self.max_batch,
+                    self.flush_interval,
+                    lookups,
+                ));
+                queue.insert(sender).clone()
+            }
+        }
+    }
+}
+
+fn range_url(base_url: &Url) -> Url {
+    base_url.join("range/").expect("Invalid range url")
+}
+
+fn exists_url(base_url: &Url, mode: Option<&str>) -> Url {
+    let mut url = base_url.join("exists").expect("Invalid exists url");
+    if let Some(mode) = mode {
+        url.query_pairs_mut().append_pair("mode", mode);
+    }
+    url
+}
+
+/// Collects the queued lookups into batches and sends them, until the store is dropped
+async fn batches<const N: usize>(
+    client: Client,
+    url: Url,
+    max_batch: usize,
+    flush_interval: Duration,
+    mut lookups: mpsc::UnboundedReceiver<Lookup<N>>,
+) {
+    while let Some(first) = lookups.recv().await {
+        let mut batch = vec![first];
+        let flush = tokio::time::sleep(flush_interval);
+        tokio::pin!(flush);
+
+        while batch.len() < max_batch {
+            tokio::select! {
+                lookup = lookups.recv() => match lookup {
+                    Some(lookup) => batch.push(lookup),
+                    None => break,
+                },
+                _ = &mut flush => break,
+            }
+        }
+
+        let client = client.clone();
+        let url = url.clone();
+        tokio::spawn(async move {
+            let (hashes, answers): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+            match exists(&client, &url, &hashes).await {
+                Ok(found) => {
+                    for (answer, found) in answers.into_iter().zip(found) {
+                        let _ = answer.send(Ok(found));
+                    }
+                }
+                Err(e) => {
+                    for answer in answers {
+                        let _ = answer.send(Err(e.clone()));
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// One `POST /exists` request
+async fn exists<const N: usize>(
+    client: &Client,
+    url: &Url,
+    hashes: &[[u8; N]],
+) -> Result<Vec<bool>, RemoteStoreError> {
+    let body = hashes.iter().map(hex::encode_upper).collect::<Vec<_>>();
+    let response = client
+        .post(url.clone())
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&body).expect("Hashes are serialized"))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let found = serde_json::from_slice::<Vec<bool>>(&response.bytes().await?)
+        .map_err(|_| RemoteStoreError::InvalidResponse)?;
+    if found.len() != hashes.len() {
+        return Err(RemoteStoreError::InvalidResponse);
+    }
+    Ok(found)
+}
+
+impl<const N: usize> ReadStore<N> for RemoteStore<N> {
+    type Error = RemoteStoreError;
+
+    /// Queues the hash into the next batch. If the batching task has stopped with its runtime
+    /// meanwhile, the lookup is queued again into a new one
+    async fn exists(&self, val: [u8; N]) -> Result<bool, Self::Error> {
+        let (answer, found) = oneshot::channel();
+        if let Err(mpsc::error::SendError(lookup)) = self.queue().send((val, answer)) {
+            self.queue()
+                .send(lookup)
+                .map_err(|_| RemoteStoreError::Dropped)?;
+        }
+        found.await.map_err(|_| RemoteStoreError::Dropped)?
+    }
+
+    /// Downloads the ranges of the server, they are streamed as they are downloaded,
+    /// so the passwords aren't ordered
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd<N>, Self::Error>> + Send {
+        self.downloader
+            .chunks(Prefix::default().into_iter())
+            .flat_map(|chunk| {
+                let passwords = match chunk {
+                    Ok(chunk) => chunk.passwords.into_iter().map(Ok).collect(),
+                    Err(e) => vec![Err(e.into())],
+                };
+                stream::iter(passwords)
+            })
+    }
+
+    /// Sends the hashes without the queue, in requests of at most [RemoteStore::with_max_batch]
+    /// hashes, [RemoteStore::with_concurrency] of them at once
+    async fn exists_many(&self, vals: &[[u8; N]]) -> Result<Vec<bool>, Self::Error> {
+        let found = stream::iter(vals.chunks(self.max_batch).map(<[_]>::to_vec))
+            .map(|hashes| async move { exists(&self.client, &self.exists_url, &hashes).await })
+            .buffered(self.concurrency)
+            .try_collect::<Vec<_>>()
+            .await?;
+        Ok(found.into_iter().flatten().collect())
+    }
+
+    /// The server answers `POST /exists` without counts, so the range of the hash is downloaded
+    async fn exists_count(&self, val: [u8; N]) -> Result<Option<u32>, Self::Error> {
+        let chunk = self.range(Prefix::from_hash(&val)).await?;
+        Ok(chunk
+            .passwords
+            .into_iter()
+            .find(|pwd| pwd.hash == val)
+            .map(|pwd| pwd.count))
+    }
+
+    async fn range(&self, prefix: Prefix) -> Result<Chunk<N>, Self::Error> {
+        Ok(self.downloader.download_prefix(prefix).await?)
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use pwned_pwd_test_utils::{serve_ranges, MockStore};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn batches_lookups() {
+        let store = MockStore::new().with_passwords([("password", 10), ("123456", 3)]);
+        let url = serve_ranges(store.clone()).await.unwrap().join("/").unwrap();
+        let remote = RemoteStore::new(url).with_max_batch(2).with_concurrency(1).with_flush_interval(Duration::from_secs(60));
+
+        let password = PwnedPwd::hash_password("password");
+        let other = PwnedPwd::hash_password("123456");
+        let unknown = PwnedPwd::hash_password("correct horse battery staple");
+
+        let (first, second) = tokio::join!(remote.exists(password), remote.exists(unknown));
+        assert!(first.unwrap());
+        assert!(!second.unwrap());
+        assert_eq!(1, store.calls());
+
+        assert_eq!(vec![false, true, true], remote.exists_many(&[unknown, other, password]).await.unwrap());
+        assert_eq!(3, store.calls());
+        assert!(remote.exists_many(&[]).await.unwrap().is_empty());
+
+        let concurrent = RemoteStore::new(remote.exists_url.join("/").unwrap()).with_max_batch(1).with_concurrency(2);
+        assert_eq!(vec![true, false, true], concurrent.exists_many(&[password, unknown, other]).await.unwrap());
+        assert_eq!(6, store.calls());
+
+        assert_eq!(Some(10), remote.exists_count(password).await.unwrap());
+        assert_eq!(None, remote.exists_count(unknown).await.unwrap());
+        assert_eq!(vec![PwnedPwd { hash: other, count: 3 }], remote.range(Prefix::from_sha1(&other)).await.unwrap().passwords);
+    }
+
+    #[tokio::test]
+    async fn flushes_after_interval() {
+        let store = MockStore::new().with_passwords([("password", 10)]);
+        let url = serve_ranges(store.clone()).await.unwrap().join("/").unwrap();
+        let remote = RemoteStore::new(url).with_flush_interval(Duration::from_millis(10));
+
+        assert!(remote.exists(PwnedPwd::hash_password("password")).await.unwrap());
+        assert!(!remote.exists([0; 20]).await.unwrap());
+        assert_eq!(2, store.calls());
+    }
+
+    #[tokio::test]
+    async fn shares_errors() {
+        let remote = RemoteStore::new("http://127.0.0.1:1/".parse().unwrap()).with_max_batch(2);
+
+        let (first, second) = tokio::join!(remote.exists([0; 20]), remote.exists([1; 20]));
+        assert!(matches!(first, Err(RemoteStoreError::Request(_))));
+        assert!(matches!(second, Err(RemoteStoreError::Request(_))));
+    }
+
+    #[test]
+    fn survives_runtime_shutdown() {
+        let server = tokio::runtime::Runtime::new().unwrap();
+        let store = MockStore::new().with_passwords([("password", 10)]);
+        let url = server.block_on(serve_ranges(store)).unwrap().join("/").unwrap();
+        let remote = RemoteStore::new(url);
+        let password = PwnedPwd::hash_password("password");
+
+        let first = tokio::runtime::Runtime::new().unwrap();
+        assert!(first.block_on(remote.exists(password)).unwrap());
+        drop(first);
+
+        let second = tokio::runtime::Runtime::new().unwrap();
+        assert!(second.block_on(remote.exists(password)).unwrap());
+        assert!(!second.block_on(remote.exists([0; 20])).unwrap());
+    }
+}
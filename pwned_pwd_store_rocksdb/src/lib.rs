@@ -0,0 +1,553 @@
+//! RocksDB backend
+//!
+//! Embedded durability without a hand-rolled file format: a hash is a key and its count is the value,
+//! so the sorted key space of RocksDB is the data set. A save writes the ordered stream into an SST file
+//! and ingests it into a new column family, which replaces the current one
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+use pwned_pwd_store::{InvalidChunk, OrderRequirement, ReadStore, StoreMetadata, WriteStore};
+use rocksdb::{
+    BoundColumnFamily, DBWithThreadMode, Direction, IngestExternalFileOptions, IteratorMode,
+    MultiThreaded, Options, SstFileWriter, WriteBatch, DEFAULT_COLUMN_FAMILY_NAME,
+};
+use tokio::sync::Mutex;
+
+type Db = DBWithThreadMode<MultiThreaded>;
+
+/// Keys of the default column family
+const CURRENT: &[u8] = b"current";
+const RECORDS: &[u8] = b"records";
+const UPDATED_AT: &[u8] = b"updated_at";
+const GENERATION: &[u8] = b"generation";
+
+#[derive(Debug, thiserror::Error)]
+pub enum RocksDbStoreError {
+    #[error("RocksDB error: {0}")]
+    RocksDb(#[from] rocksdb::Error),
+
+    #[error("Io error: {0}")]
+    Io(#[from] io::Error),
+
+    /// A blocking task with the database panicked or was cancelled
+    #[error("Blocking task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+
+    /// A save stops at an invalid chunk and keeps the data
+    #[error(transparent)]
+    InvalidChunk(#[from] InvalidChunk),
+
+    /// A saved chunk doesn't follow the previous one, chunks must be ordered by prefix
+    #[error("The chunk of the prefix '{prefix}' follows the chunk of '{previous}'")]
+    UnorderedChunk { prefix: Prefix, previous: Prefix },
+
+    /// A key isn't a SHA-1 hash or a value isn't a count, the database is used by something else
+    #[error("Invalid record in the database")]
+    InvalidRecord,
+}
+
+/// A store of SHA-1 hashes in a RocksDB database.
+/// The data set is in the column family named by the `current` key of the default one
+pub struct RocksDbStore {
+    db: Arc<Db>,
+    path: PathBuf,
+    current: Arc<RwLock<Option<String>>>,
+    batch_size: usize,
+
+    /// Saves, merges and removals update the metadata, so they are serialized
+    writing: Mutex<()>,
+}
+
+impl RocksDbStore {
+    /// Opens the database, it is created if it doesn't exist.
+    /// Column families left by interrupted saves are dropped
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, RocksDbStoreError> {
+        let path = path.into();
+
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+
+        let families = Db::list_cf(&options, &path)
+            .unwrap_or_else(|_| vec![DEFAULT_COLUMN_FAMILY_NAME.to_string()]);
+        let db = Db::open_cf(&options, &path, &families)?;
+
+        let current = db
+            .get(CURRENT)?
+            .map(String::from_utf8)
+            .transpose()
+            .map_err(|_| RocksDbStoreError::InvalidRecord)?;
+        for name in &families {
+            if name != DEFAULT_COLUMN_FAMILY_NAME && Some(name) != current.as_ref() {
+                db.drop_cf(name)?;
+            }
+        }
+
+        Ok(Self {
+            db: Arc::new(db),
+            path,
+            current: Arc::new(RwLock::new(current)),
+            batch_size: 10_000,
+            writing: Mutex::new(()),
+        })
+    }
+
+    /// How many records are upserted in a write batch by a merge,
+    /// and read at once by [ReadStore::iter_all]
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Runs `f` with the current column family on the blocking thread pool, None if there is no data set
+    async fn read<T, F>(&self, f: F) -> Result<Option<T>, RocksDbStoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Db, &Arc<BoundColumnFamily>) -> Result<T, RocksDbStoreError> + Send + 'static,
+    {
+        let db = self.db.clone();
+        let current = self.current.read().unwrap().clone();
+
+        tokio::task::spawn_blocking(move || {
+            match current.as_deref().and_then(|name| db.cf_handle(name)) {
+                Some(cf) => f(&db, &cf).map(Some),
+                None => Ok(None),
+            }
+        })
+        .await?
+    }
+
+    /// Runs `f` with the database on the blocking thread pool
+    async fn blocking<T, F>(&self, f: F) -> Result<T, RocksDbStoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Db, &RwLock<Option<String>>) -> Result<T, RocksDbStoreError> + Send + 'static,
+    {
+        let db = self.db.clone();
+        let current = self.current.clone();
+        tokio::task::spawn_blocking(move || f(&db, &current)).await?
+    }
+}
+
+fn number(db: &Db, key: &[u8]) -> Result<Option<u64>, RocksDbStoreError> {
+    db.get_pinned(key)?
+        .map(|value| {
+            <[u8; 8]>::try_from(&*value)
+                .map(u64::from_be_bytes)
+                .map_err(|_| RocksDbStoreError::InvalidRecord)
+        })
+        .transpose()
+}
+
+fn count(value: &[u8]) -> Result<u32, RocksDbStoreError> {
+    <[u8; 4]>::try_from(value)
+        .map(u32::from_be_bytes)
+        .map_err(|_| RocksDbStoreError::InvalidRecord)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The column family of the data set, it is created if there is no data set
+fn data_cf(db: &Db, current: &RwLock<Option<String>>) -> Result<String, RocksDbStoreError> {
+    if let Some(name) = current.read().unwrap().clone() {
+        return Ok(name);
+    }
+
+    let name = format!("data.{}", number(db, GENERATION)?.unwrap_or_default() + 1);
+    db.create_cf(&name, &Options::default())?;
+    db.put(CURRENT, &name)?;
+    *current.write().unwrap() = Some(name.clone());
+    Ok(name)
+}
+
+impl ReadStore for RocksDbStore {
+    type Error = RocksDbStoreError;
+
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        Ok(self.exists_count(val).await?.is_some())
+    }
+
+    /// Iterates the column family in order, a batch of records at a time
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        let batch_size = self.batch_size;
+
+        stream::try_unfold(Some(Vec::new()), move |after| async move {
+            let Some(after) = after else {
+                return Ok::<_, RocksDbStoreError>(None);
+            };
+
+            let page = self
+                .read(move |db, cf| {
+                    let mut page = Vec::with_capacity(batch_size);
+                    for item in db.iterator_cf(cf, IteratorMode::From(&after, Direction::Forward)) {
+                        let (key, value) = item?;
+                        if *key == *after.as_slice() {
+                            continue;
+                        }
+                        if page.len() == batch_size {
+                            break;
+                        }
+
+                        page.push(PwnedPwd {
+                            hash: <[u8; 20]>::try_from(&*key)
+                                .map_err(|_| RocksDbStoreError::InvalidRecord)?,
+                            count: count(&value)?,
+                        });
+                    }
+                    Ok(page)
+                })
+                .await?
+                .unwrap_or_default();
+
+            let next = page
+                .last()
+                .filter(|_| page.len() == batch_size)
+                .map(|pwd| pwd.hash.to_vec());
+            Ok(Some((stream::iter(page.into_iter().map(Ok)), next)))
+        })
+        .try_flatten()
+    }
+
+    /// Iterates the keys of the prefix only
+    async fn range(&self, prefix: Prefix) -> Result<Chunk, Self::Error> {
+        let start = prefix.to_bytes();
+        let end = prefix.next().map(|next| next.to_bytes());
+
+        let passwords = self
+            .read(move |db, cf| {
+                let mut passwords = Vec::new();
+                for item in db.iterator_cf(cf, IteratorMode::From(&start, Direction::Forward)) {
+                    let (key, value) = item?;
+                    if end.is_some_and(|end| *key >= end[..]) {
+                        break;
+                    }
+
+                    passwords.push(PwnedPwd {
+                        hash: <[u8; 20]>::try_from(&*key)
+                            .map_err(|_| RocksDbStoreError::InvalidRecord)?,
+                        count: count(&value)?,
+                    });
+                }
+                Ok(passwords)
+            })
+            .await?
+            .unwrap_or_default();
+
+        Ok(Chunk { prefix, passwords })
+    }
+
+    /// Looks the hashes up with a batched multi-get
+    async fn exists_many(&self, vals: &[[u8; 20]]) -> Result<Vec<bool>, Self::Error> {
+        let vals = vals.to_vec();
+        let len = vals.len();
+
+        Ok(self
+            .read(move |db, cf| {
+                db.batched_multi_get_cf(cf, &vals, false)
+                    .into_iter()
+                    .map(|value| Ok::<_, RocksDbStoreError>(value?.is_some()))
+                    .collect()
+            })
+            .await?
+            .unwrap_or_else(|| vec![false; len]))
+    }
+
+    async fn exists_count(&self, val: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        Ok(self
+            .read(move |db, cf| {
+                db.get_pinned_cf(cf, val)?
+                    .map(|value| count(&value))
+                    .transpose()
+            })
+            .await?
+            .flatten())
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        self.blocking(|db, _| {
+            Ok(StoreMetadata {
+                records: Some(number(db, RECORDS)?.unwrap_or_default()),
+                updated_at: number(db, UPDATED_AT)?
+                    .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+                generation: number(db, GENERATION)?,
+                ..Default::default()
+            })
+        })
+        .await
+    }
+}
+
+impl WriteStore for RocksDbStore {
+    fn order_requirement() -> OrderRequirement {
+        OrderRequirement::Ordered
+    }
+
+    /// Writes the stream into an SST file, ingests it into a new column family and switches to it.
+    /// If the future is dropped, the data isn't changed
+    async fn save<S: Stream<Item = Chunk> + Unpin + Send>(
+        &self,
+        mut s: S,
+    ) -> Result<(), Self::Error> {
+        let _writing = self.writing.lock().await;
+
+        let generation = self.metadata().await?.generation.unwrap_or_default() + 1;
+        let name = format!("data.{generation}");
+        let sst = self.path.join(format!("save.{generation}.sst"));
+
+        let options = Options::default();
+        let mut writer = SstFileWriter::create(&options);
+        writer.open(&sst)?;
+
+        let mut records = 0u64;
+        let mut previous: Option<Prefix> = None;
+        while let Some(chunk) = s.next().await {
+            InvalidChunk::check(&chunk)?;
+            if let Some(previous) =
+                previous.filter(|previous| u32::from(*previous) >= u32::from(chunk.prefix))
+            {
+                return Err(RocksDbStoreError::UnorderedChunk {
+                    prefix: chunk.prefix,
+                    previous,
+                });
+            }
+            previous = Some(chunk.prefix);
+
+            for pwd in &chunk.passwords {
+                writer.put(pwd.hash, pwd.count.to_be_bytes())?;
+            }
+            records += chunk.passwords.len() as u64;
+        }
+
+        // An SST file can't be empty
+        if records > 0 {
+            writer.finish()?;
+        }
+        drop(writer);
+
+        let ingested = sst.clone();
+        self.blocking(move |db, current| {
+            db.create_cf(&name, &Options::default())?;
+            if records > 0 {
+                let cf = db
+                    .cf_handle(&name)
+                    .ok_or(RocksDbStoreError::InvalidRecord)?;
+                let mut ingest = IngestExternalFileOptions::default();
+                ingest.set_move_files(true);
+                db.ingest_external_file_cf_opts(&cf, &ingest, vec![&ingested])?;
+            }
+
+            let mut batch = WriteBatch::default();
+            batch.put(CURRENT, &name);
+            batch.put(RECORDS, records.to_be_bytes());
+            batch.put(UPDATED_AT, now().to_be_bytes());
+            batch.put(GENERATION, generation.to_be_bytes());
+            db.write(batch)?;
+
+            let previous = current.write().unwrap().replace(name);
+            if let Some(previous) = previous {
+                db.drop_cf(&previous)?;
+            }
+            Ok(())
+        })
+        .await?;
+
+        match std::fs::remove_file(&sst) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Upserts the stream in write batches, the record count is updated by every batch
+    async fn merge<S: Stream<Item = Chunk> + Unpin + Send>(
+        &self,
+        mut s: S,
+    ) -> Result<(), Self::Error> {
+        let _writing = self.writing.lock().await;
+        let mut batch = Vec::with_capacity(self.batch_size);
+
+        loop {
+            let chunk = s.next().await;
+            if let Some(chunk) = &chunk {
+                InvalidChunk::check(chunk)?;
+            }
+
+            let last = chunk.is_none();
+            batch.extend(chunk.into_iter().flat_map(|chunk| chunk.passwords));
+
+            if batch.len() >= self.batch_size || (last && !batch.is_empty()) {
+                let passwords = std::mem::replace(&mut batch, Vec::with_capacity(self.batch_size));
+                self.blocking(move |db, current| {
+                    let name = data_cf(db, current)?;
+                    let cf = db
+                        .cf_handle(&name)
+                        .ok_or(RocksDbStoreError::InvalidRecord)?;
+
+                    let mut write = WriteBatch::default();
+                    let mut added = 0;
+                    for pwd in &passwords {
+                        if db.get_pinned_cf(&cf, pwd.hash)?.is_none() {
+                            added += 1;
+                        }
+                        write.put_cf(&cf, pwd.hash, pwd.count.to_be_bytes());
+                    }
+
+                    let records = number(db, RECORDS)?.unwrap_or_default() + added;
+                    write.put(RECORDS, records.to_be_bytes());
+                    Ok(db.write(write)?)
+                })
+                .await?;
+            }
+
+            if last {
+                break;
+            }
+        }
+
+        self.blocking(|db, _| {
+            let generation = number(db, GENERATION)?.unwrap_or_default() + 1;
+
+            let mut batch = WriteBatch::default();
+            batch.put(UPDATED_AT, now().to_be_bytes());
+            batch.put(GENERATION, generation.to_be_bytes());
+            Ok(db.write(batch)?)
+        })
+        .await
+    }
+
+    async fn remove(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        let _writing = self.writing.lock().await;
+
+        Ok(self
+            .read(move |db, cf| {
+                if db.get_pinned_cf(cf, val)?.is_none() {
+                    return Ok(false);
+                }
+
+                let records = number(db, RECORDS)?.unwrap_or_default().saturating_sub(1);
+                let mut batch = WriteBatch::default();
+                batch.delete_cf(cf, val);
+                batch.put(RECORDS, records.to_be_bytes());
+                db.write(batch)?;
+                Ok(true)
+            })
+            .await?
+            .unwrap_or_default())
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        let _writing = self.writing.lock().await;
+
+        self.blocking(|db, current| {
+            let generation = number(db, GENERATION)?.unwrap_or_default() + 1;
+
+            let mut batch = WriteBatch::default();
+            batch.delete(CURRENT);
+            batch.put(RECORDS, 0u64.to_be_bytes());
+            batch.put(UPDATED_AT, now().to_be_bytes());
+            batch.put(GENERATION, generation.to_be_bytes());
+            db.write(batch)?;
+
+            let previous = current.write().unwrap().take();
+            if let Some(previous) = previous {
+                db.drop_cf(&previous)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use hex_literal::hex;
+
+    use super::*;
+
+    fn open(name: &str) -> RocksDbStore {
+        let path = temp_dir().join("pwned_pwd_rocksdb").join(name);
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        RocksDbStore::open(path).unwrap().with_batch_size(2)
+    }
+
+    #[tokio::test]
+    async fn save() {
+        let store = open("save");
+        assert!(!store.healthy().await.unwrap());
+
+        let first = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let second = PwnedPwd { hash: hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), count: 5 };
+        let other = PwnedPwd { hash: hex!("21BD5000F2D6B0E3CE3E9D1A0E9C3EB1E2A2A4AC"), count: 2 };
+
+        store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![first.clone(), second.clone()] },
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![other.clone()] },
+        ])).await.unwrap();
+
+        assert!(store.exists(first.hash).await.unwrap());
+        assert_eq!(Some(2), store.exists_count(other.hash).await.unwrap());
+        assert_eq!(vec![true, false], store.exists_many(&[second.hash, [0; 20]]).await.unwrap());
+        assert_eq!(vec![first.clone(), second.clone(), other.clone()], store.iter_all().try_collect::<Vec<_>>().await.unwrap());
+        assert_eq!(vec![first.clone(), second.clone()], store.range(Prefix::create(0x21BD4).unwrap()).await.unwrap().passwords);
+        assert!(store.range(Prefix::max()).await.unwrap().passwords.is_empty());
+        assert_eq!(Some(3), store.metadata().await.unwrap().records);
+        assert_eq!(Some(1), store.metadata().await.unwrap().generation);
+
+        let err = store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![other.clone()] },
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![first.clone()] },
+        ])).await.err().unwrap();
+        assert!(matches!(err, RocksDbStoreError::UnorderedChunk { .. }));
+        assert!(store.exists(second.hash).await.unwrap());
+
+        let path = store.path().to_path_buf();
+        drop(store);
+        let store = RocksDbStore::open(path).unwrap();
+        assert_eq!(Some(5), store.exists_count(second.hash).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn merge_remove_clear() {
+        let store = open("merge");
+
+        let first = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let second = PwnedPwd { hash: hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), count: 5 };
+
+        store.merge(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![first.clone()] },
+        ])).await.unwrap();
+        store.merge(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![PwnedPwd { count: 7, ..first.clone() }, second.clone()] },
+        ])).await.unwrap();
+
+        assert_eq!(Some(7), store.exists_count(first.hash).await.unwrap());
+        assert_eq!(Some(2), store.metadata().await.unwrap().records);
+
+        assert!(store.remove(first.hash).await.unwrap());
+        assert!(!store.remove(first.hash).await.unwrap());
+        assert_eq!(Some(1), store.metadata().await.unwrap().records);
+
+        store.clear().await.unwrap();
+        assert!(!store.exists(second.hash).await.unwrap());
+        assert_eq!(Some(0), store.metadata().await.unwrap().records);
+    }
+}
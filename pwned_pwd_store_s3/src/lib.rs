@@ -0,0 +1,593 @@
+//! Object store backend
+//!
+//! A serverless deployment has no disk for the data set, but it can read the store file of
+//! `pwned_pwd_store_local` from S3 or any other object store: a lookup is a binary search driven
+//! by byte-range GETs. The search reads single records until the range of the hash fits a window,
+//! which is fetched at once, so a lookup in the full data set takes ~18 round trips.
+//! A [PrefixIndex] narrows the range down to the prefix, so a lookup is one GET
+
+use std::{
+    ops::Range,
+    sync::{Arc, RwLock},
+};
+
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use object_store::{path::Path, GetOptions, GetRange, ObjectStore};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd, Suffix};
+use pwned_pwd_store::{ReadStore, StoreMetadata};
+use pwned_pwd_store_local::{
+    format::RecordFormat,
+    header::{FileHeader, HEADER_LEN},
+    index::PrefixIndex,
+};
+
+pub use object_store;
+
+#[derive(Debug, thiserror::Error)]
+pub enum S3StoreError {
+    #[error("Object store error: {0}")]
+    ObjectStore(#[from] object_store::Error),
+
+    /// The object isn't a store file of the configured format
+    #[error("Invalid header of '{path}': {reason}")]
+    InvalidHeader { path: Path, reason: &'static str },
+
+    /// The object was replaced after it was opened, see [S3Store::reload]
+    #[error("The object '{path}' was replaced")]
+    Replaced { path: Path },
+
+    #[error("The file doesn't keep counts")]
+    NoCounts,
+
+    /// A record isn't a suffix, the file is damaged
+    #[error("Invalid record in '{path}'")]
+    InvalidRecord { path: Path },
+}
+
+/// The opened version of the object
+struct Object {
+    /// Reads fail, if the object doesn't match it anymore
+    e_tag: Option<String>,
+    header: FileHeader,
+
+    /// The directory of a file of suffixes or the index set by [S3Store::with_index]
+    index: Option<Arc<PrefixIndex>>,
+}
+
+/// A read-only store of a store file in an object store
+pub struct S3Store {
+    store: Arc<dyn ObjectStore>,
+    path: Path,
+    format: RecordFormat,
+    object: RwLock<Arc<Object>>,
+
+    /// Records which are fetched at once, instead of reading their middle one
+    window: u64,
+    concurrency: usize,
+}
+
+impl S3Store {
+    /// Opens the object with a header, a file of suffixes is opened with its directory
+    pub async fn open(
+        store: Arc<dyn ObjectStore>,
+        path: impl Into<Path>,
+        format: RecordFormat,
+    ) -> Result<Self, S3StoreError> {
+        let path = path.into();
+        let object = open(&*store, &path, format).await?;
+
+        Ok(Self {
+            store,
+            path,
+            format,
+            object: RwLock::new(Arc::new(object)),
+            window: 4096,
+            concurrency: 16,
+        })
+    }
+
+    /// Sets the index of the object, e.g. built by [PrefixIndex::build] from the local copy which was
+    /// uploaded, or by [S3Store::build_index]. A reload of a replaced object drops it
+    pub fn with_index(self, index: PrefixIndex) -> Self {
+        {
+            let mut object = self.object.write().unwrap();
+            *object = Arc::new(Object {
+                e_tag: object.e_tag.clone(),
+                header: object.header,
+                index: Some(Arc::new(index)),
+            });
+        }
+        self
+    }
+
+    /// How many records are fetched at once by the end of a search and by [ReadStore::iter_all]
+    pub fn with_window(mut self, records: u64) -> Self {
+        self.window = records.max(1);
+        self
+    }
+
+    /// How many lookups of [ReadStore::exists_many] run at once
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn format(&self) -> RecordFormat {
+        self.format
+    }
+
+    pub fn header(&self) -> FileHeader {
+        self.object().header
+    }
+
+    /// Reads the whole object once to index it
+    pub async fn build_index(&self) -> Result<PrefixIndex, S3StoreError> {
+        let object = self.object();
+        if let Some(index) = &object.index {
+            return Ok(PrefixIndex::clone(index));
+        }
+
+        let mut counts = vec![0u32; Prefix::count() as usize];
+        if object.header.records == 0 {
+            return Ok(PrefixIndex::from_counts(counts));
+        }
+
+        let end = HEADER_LEN + object.header.records * self.format.record_len();
+        let options = GetOptions {
+            if_match: object.e_tag.clone(),
+            range: Some(GetRange::Bounded(HEADER_LEN as usize..end as usize)),
+            ..Default::default()
+        };
+        let mut bytes = self
+            .store
+            .get_opts(&self.path, options)
+            .await
+            .map_err(|e| replaced(e, &self.path))?
+            .into_stream();
+
+        let mut carry = Vec::new();
+        while let Some(chunk) = bytes.try_next().await? {
+            carry.extend_from_slice(&chunk);
+            let records = carry.chunks_exact(self.format.record_len() as usize);
+            let rest = records.remainder().len();
+            for record in records {
+                counts[u32::from(Prefix::from_hash(record)) as usize] += 1;
+            }
+            carry.drain(..carry.len() - rest);
+        }
+
+        Ok(PrefixIndex::from_counts(counts))
+    }
+
+    /// Switches to the object at the path, if it was replaced. Returns true, if it was
+    pub async fn reload(&self) -> Result<bool, S3StoreError> {
+        let e_tag = self.store.head(&self.path).await?.e_tag;
+        if e_tag.is_some() && e_tag == self.object().e_tag {
+            return Ok(false);
+        }
+
+        let object = open(&*self.store, &self.path, self.format).await?;
+        *self.object.write().unwrap() = Arc::new(object);
+        Ok(true)
+    }
+
+    fn object(&self) -> Arc<Object> {
+        self.object.read().unwrap().clone()
+    }
+
+    /// Reads the records of the object
+    async fn read(&self, object: &Object, records: Range<u64>) -> Result<Vec<u8>, S3StoreError> {
+        let len = self.format.record_len();
+        let bytes = HEADER_LEN + records.start * len..HEADER_LEN + records.end * len;
+        read(&*self.store, &self.path, object.e_tag.clone(), bytes).await
+    }
+
+    /// The count of the hash, 0 if the format doesn't keep counts
+    async fn search(&self, val: [u8; 20]) -> Result<Option<u32>, S3StoreError> {
+        let object = self.object();
+        let key = key(self.format, &val);
+        let key = &key[..self.format.key_len()];
+        let len = self.format.record_len() as usize;
+
+        let mut range = match &object.index {
+            Some(index) => index.range(Prefix::from_sha1(&val)),
+            None => 0..object.header.records,
+        };
+
+        while range.end - range.start > self.window {
+            let mid = range.start + (range.end - range.start) / 2;
+            let record = self.read(&object, mid..mid + 1).await?;
+            match record[..key.len()].cmp(key) {
+                std::cmp::Ordering::Less => range.start = mid + 1,
+                std::cmp::Ordering::Greater => range.end = mid,
+                std::cmp::Ordering::Equal => return Ok(Some(count(&record, key.len()))),
+            }
+        }
+
+        if range.is_empty() {
+            return Ok(None);
+        }
+
+        let records = self.read(&object, range).await?;
+        let records = records.chunks_exact(len).collect::<Vec<_>>();
+        Ok(records
+            .binary_search_by(|record| record[..key.len()].cmp(key))
+            .ok()
+            .map(|i| count(records[i], key.len())))
+    }
+
+    /// The first record which isn't less than the key, by a search of the whole object
+    async fn position(&self, object: &Object, key: &[u8]) -> Result<u64, S3StoreError> {
+        let len = self.format.record_len() as usize;
+        let mut range = 0..object.header.records;
+
+        while range.end - range.start > self.window {
+            let mid = range.start + (range.end - range.start) / 2;
+            let record = self.read(object, mid..mid + 1).await?;
+            match &record[..key.len()] < key {
+                true => range.start = mid + 1,
+                false => range.end = mid,
+            }
+        }
+
+        if range.is_empty() {
+            return Ok(range.start);
+        }
+
+        let records = self.read(object, range.clone()).await?;
+        let less = records
+            .chunks_exact(len)
+            .take_while(|record| &record[..key.len()] < key)
+            .count();
+        Ok(range.start + less as u64)
+    }
+}
+
+/// Opens the version of the object at the path and checks its header
+async fn open(
+    store: &dyn ObjectStore,
+    path: &Path,
+    format: RecordFormat,
+) -> Result<Object, S3StoreError> {
+    let invalid = |reason| S3StoreError::InvalidHeader {
+        path: path.clone(),
+        reason,
+    };
+
+    let meta = store.head(path).await?;
+    let size = meta.size as u64;
+    if size < HEADER_LEN {
+        return Err(invalid("The file is shorter than the header"));
+    }
+
+    let bytes = read(store, path, meta.e_tag.clone(), 0..HEADER_LEN).await?;
+    let header = FileHeader::from_bytes(bytes.as_slice().try_into().expect("Header is read"))
+        .map_err(invalid)?;
+    if u64::from(header.record_len) != format.record_len() {
+        return Err(invalid("Record length doesn't match the format"));
+    }
+    if format.records_in(size - HEADER_LEN) != Some(header.records) {
+        return Err(invalid("Record count doesn't match the file length"));
+    }
+
+    let index = match format.is_flat() {
+        true => None,
+        false => {
+            let directory = read(
+                store,
+                path,
+                meta.e_tag.clone(),
+                size - format.directory_len()..size,
+            )
+            .await?;
+            let index = PrefixIndex::from_counts(
+                directory
+                    .chunks_exact(4)
+                    .map(|count| u32::from_be_bytes(count.try_into().expect("Count is 4 bytes"))),
+            );
+            if index.records() != header.records {
+                return Err(invalid("Invalid prefix directory"));
+            }
+            Some(Arc::new(index))
+        }
+    };
+
+    Ok(Object {
+        e_tag: meta.e_tag,
+        header,
+        index,
+    })
+}
+
+/// Reads the bytes of the version of the object
+async fn read(
+    store: &dyn ObjectStore,
+    path: &Path,
+    e_tag: Option<String>,
+    bytes: Range<u64>,
+) -> Result<Vec<u8>, S3StoreError> {
+    let options = GetOptions {
+        if_match: e_tag,
+        range: Some(GetRange::Bounded(bytes.start as usize..bytes.end as usize)),
+        ..Default::default()
+    };
+
+    let result = store
+        .get_opts(path, options)
+        .await
+        .map_err(|e| replaced(e, path))?;
+    Ok(result.bytes().await?.to_vec())
+}
+
+/// A failed precondition of a read means the object doesn't match the opened version
+fn replaced(e: object_store::Error, path: &Path) -> S3StoreError {
+    match e {
+        object_store::Error::Precondition { .. } => S3StoreError::Replaced { path: path.clone() },
+        e => e.into(),
+    }
+}
+
+/// The part of the hash which is compared with records, see [RecordFormat::key_len]
+fn key(format: RecordFormat, val: &[u8; 20]) -> [u8; 20] {
+    match format.is_flat() {
+        true => *val,
+        false => {
+            let mut key = [0u8; 20];
+            key[..RecordFormat::SUFFIX_LEN].copy_from_slice(Suffix::from_sha1(val).as_bytes());
+            key
+        }
+    }
+}
+
+fn count(record: &[u8], key_len: usize) -> u32 {
+    record[key_len..].try_into().map_or(0, u32::from_be_bytes)
+}
+
+impl ReadStore for S3Store {
+    type Error = S3StoreError;
+
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        Ok(self.search(val).await?.is_some())
+    }
+
+    /// Reads the object a window of records at a time, in order
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        let object = self.object();
+        let len = self.format.record_len() as usize;
+        let key_len = self.format.key_len();
+
+        stream::try_unfold((0, Prefix::default()), move |(start, mut prefix)| {
+            let object = object.clone();
+            async move {
+                let end = object.header.records.min(start + self.window);
+                if start >= end {
+                    return Ok::<_, S3StoreError>(None);
+                }
+
+                let records = self.read(&object, start..end).await?;
+                let mut page = Vec::with_capacity(records.len() / len);
+                for (i, record) in (start..).zip(records.chunks_exact(len)) {
+                    let hash = match &object.index {
+                        Some(index) if !self.format.is_flat() => {
+                            while index.range(prefix).end <= i {
+                                prefix =
+                                    prefix.next().ok_or_else(|| S3StoreError::InvalidRecord {
+                                        path: self.path.clone(),
+                                    })?;
+                            }
+                            let suffix = record[..key_len]
+                                .try_into()
+                                .ok()
+                                .and_then(Suffix::from_bytes)
+                                .ok_or_else(|| S3StoreError::InvalidRecord {
+                                    path: self.path.clone(),
+                                })?;
+                            prefix.with_suffix(&suffix)
+                        }
+                        _ => record[..RecordFormat::HASH_LEN]
+                            .try_into()
+                            .expect("Hash is 20 bytes"),
+                    };
+                    page.push(Ok(PwnedPwd {
+                        hash,
+                        count: count(record, key_len),
+                    }));
+                }
+
+                Ok(Some((stream::iter(page), (end, prefix))))
+            }
+        })
+        .try_flatten()
+    }
+
+    /// Reads the records of the prefix at once, they are found by the index or by searches of a flat
+    /// object without one
+    async fn range(&self, prefix: Prefix) -> Result<Chunk, Self::Error> {
+        let object = self.object();
+        let records = match &object.index {
+            Some(index) => index.range(prefix),
+            None => {
+                let start = self.position(&object, &prefix.to_bytes()).await?;
+                let end = match prefix.next() {
+                    Some(next) => self.position(&object, &next.to_bytes()).await?,
+                    None => object.header.records,
+                };
+                start..end
+            }
+        };
+        if records.is_empty() {
+            return Ok(Chunk {
+                prefix,
+                passwords: Vec::new(),
+            });
+        }
+
+        let len = self.format.record_len() as usize;
+        let key_len = self.format.key_len();
+        let invalid = || S3StoreError::InvalidRecord {
+            path: self.path.clone(),
+        };
+        let passwords = self
+            .read(&object, records)
+            .await?
+            .chunks_exact(len)
+            .map(|record| {
+                let hash = match self.format.is_flat() {
+                    true => record[..RecordFormat::HASH_LEN]
+                        .try_into()
+                        .expect("Hash is 20 bytes"),
+                    false => record[..key_len]
+                        .try_into()
+                        .ok()
+                        .and_then(Suffix::from_bytes)
+                        .map(|suffix| prefix.with_suffix(&suffix))
+                        .ok_or_else(invalid)?,
+                };
+                Ok(PwnedPwd {
+                    hash,
+                    count: count(record, key_len),
+                })
+            })
+            .collect::<Result<_, S3StoreError>>()?;
+
+        Ok(Chunk { prefix, passwords })
+    }
+
+    /// Runs the lookups concurrently
+    async fn exists_many(&self, vals: &[[u8; 20]]) -> Result<Vec<bool>, Self::Error> {
+        stream::iter(vals.iter().copied())
+            .map(|val| self.exists(val))
+            .buffered(self.concurrency)
+            .try_collect()
+            .await
+    }
+
+    async fn exists_count(&self, val: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        if !self.format.has_counts() {
+            return Err(S3StoreError::NoCounts);
+        }
+
+        self.search(val).await
+    }
+
+    /// Records and the update time are taken from the header
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        let header = self.header();
+        Ok(StoreMetadata {
+            records: Some(header.records),
+            updated_at: Some(header.created_at()),
+            ..Default::default()
+        })
+    }
+
+    /// The prefix of the last hash, an indexed object takes it from the index
+    async fn max_prefix(&self) -> Result<Option<Prefix>, Self::Error> {
+        let object = self.object();
+        if let Some(index) = &object.index {
+            return Ok(index.last_prefix());
+        }
+
+        let Some(last) = object.header.records.checked_sub(1) else {
+            return Ok(None);
+        };
+        let record = self.read(&object, last..last + 1).await?;
+        Ok(Some(Prefix::from_hash(&record)))
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use hex_literal::hex;
+    use object_store::{memory::InMemory, PutPayload};
+    use pwned_pwd_store::WriteStore;
+    use pwned_pwd_store_local::LocalStore;
+
+    use super::*;
+
+    const HASHES: [([u8; 20], u32); 4] = [
+        (hex!("0000000C53D0B33029D7FE4FB08D3D1C9832D2ED"), 3),
+        (hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), 1),
+        (hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), 5),
+        (hex!("21BD5000F2D6B0E3CE3E9D1A0E9C3EB1E2A2A4AC"), 2),
+    ];
+
+    /// Saves a store file of the format and uploads it
+    async fn upload(store: &InMemory, name: &str, format: RecordFormat) -> Path {
+        let file = temp_dir().join(format!("pwned_pwd_s3_{name}"));
+        let _ = std::fs::remove_file(&file);
+        let chunks = HASHES.map(|(hash, count)| Chunk { prefix: Prefix::from_sha1(&hash), passwords: vec![PwnedPwd { hash, count }] });
+        let local = LocalStore::builder(&file).with_format(format).build().unwrap();
+        local.save(stream::iter(vec![
+            chunks[0].clone(),
+            chunks[1].clone().merge(chunks[2].clone()).unwrap(),
+            chunks[3].clone(),
+        ])).await.unwrap();
+
+        let path = Path::from(name);
+        store.put(&path, PutPayload::from(std::fs::read(&file).unwrap())).await.unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn lookups() {
+        let memory = Arc::new(InMemory::new());
+        let path = upload(&memory, "counts", RecordFormat::HashesWithCounts).await;
+        let store = S3Store::open(memory.clone(), path, RecordFormat::HashesWithCounts).await.unwrap().with_window(1);
+
+        for (hash, count) in HASHES {
+            assert_eq!(Some(count), store.exists_count(hash).await.unwrap());
+        }
+        assert!(!store.exists(hex!("21BD4004DDDC80AE4683948C5A1C5903584D8088")).await.unwrap());
+        assert_eq!(vec![true, false], store.exists_many(&[HASHES[3].0, [0; 20]]).await.unwrap());
+        assert_eq!(Some(4), store.metadata().await.unwrap().records);
+        assert_eq!(Some(Prefix::create(0x21BD5).unwrap()), store.max_prefix().await.unwrap());
+
+        let all = store.iter_all().try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(HASHES.map(|(hash, count)| PwnedPwd { hash, count }).to_vec(), all);
+        for window in [1, 4] {
+            let store = S3Store::open(memory.clone(), Path::from("counts"), RecordFormat::HashesWithCounts).await.unwrap().with_window(window);
+            assert_eq!(all[1..3], store.range(Prefix::create(0x21BD4).unwrap()).await.unwrap().passwords);
+            assert_eq!(all[..1], store.range(Prefix::default()).await.unwrap().passwords);
+            assert!(store.range(Prefix::create(0x21BD3).unwrap()).await.unwrap().passwords.is_empty());
+            assert!(store.range(Prefix::max()).await.unwrap().passwords.is_empty());
+        }
+
+        let index = store.build_index().await.unwrap();
+        assert_eq!(1..3, index.range(Prefix::create(0x21BD4).unwrap()));
+        let store = store.with_index(index).with_window(2);
+        assert_eq!(Some(5), store.exists_count(HASHES[2].0).await.unwrap());
+
+        let err = S3Store::open(memory, Path::from("counts"), RecordFormat::Hashes).await.err().unwrap();
+        assert!(matches!(err, S3StoreError::InvalidHeader { .. }));
+    }
+
+    #[tokio::test]
+    async fn suffixes_and_reload() {
+        let memory = Arc::new(InMemory::new());
+        let path = upload(&memory, "suffixes", RecordFormat::Suffixes).await;
+        let store = S3Store::open(memory.clone(), path.clone(), RecordFormat::Suffixes).await.unwrap().with_window(1);
+
+        for (hash, _) in HASHES {
+            assert!(store.exists(hash).await.unwrap());
+        }
+        assert!(matches!(store.exists_count(HASHES[0].0).await, Err(S3StoreError::NoCounts)));
+        let all = store.iter_all().map_ok(|pwd| pwd.hash).try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(HASHES.map(|(hash, _)| hash).to_vec(), all);
+        let range = store.range(Prefix::create(0x21BD4).unwrap()).await.unwrap();
+        assert_eq!(vec![HASHES[1].0, HASHES[2].0], range.passwords.iter().map(|pwd| pwd.hash).collect::<Vec<_>>());
+        assert!(store.range(Prefix::max()).await.unwrap().passwords.is_empty());
+
+        assert!(!store.reload().await.unwrap());
+        upload(&memory, "suffixes", RecordFormat::Suffixes).await;
+        assert!(matches!(store.exists(HASHES[0].0).await, Err(S3StoreError::Replaced { .. })));
+        assert!(store.reload().await.unwrap());
+        assert!(store.exists(HASHES[0].0).await.unwrap());
+    }
+}
@@ -0,0 +1,543 @@
+//! Sled backend
+//!
+//! An embedded key-value store without C dependencies: a hash is a key and its count is the value
+//! of a sled tree. A save inserts the stream into a new tree in batches, which replaces the current one
+
+use std::{
+    ops::Bound,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+use pwned_pwd_store::{InvalidChunk, OrderRequirement, ReadStore, StoreMetadata, WriteStore};
+use sled::{
+    transaction::{abort, ConflictableTransactionError, TransactionError, TransactionalTree},
+    Batch, Db, IVec, Transactional, Tree,
+};
+use tokio::sync::Mutex;
+
+/// Keys of the default tree
+const CURRENT: &[u8] = b"current";
+const RECORDS: &[u8] = b"records";
+const UPDATED_AT: &[u8] = b"updated_at";
+const GENERATION: &[u8] = b"generation";
+
+/// Prefix of the names of data set trees
+const DATA: &[u8] = b"data.";
+
+#[derive(Debug, thiserror::Error)]
+pub enum SledStoreError {
+    #[error("Sled error: {0}")]
+    Sled(#[from] sled::Error),
+
+    /// A blocking task with the database panicked or was cancelled
+    #[error("Blocking task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+
+    /// A save stops at an invalid chunk and keeps the data
+    #[error(transparent)]
+    InvalidChunk(#[from] InvalidChunk),
+
+    /// A key isn't a SHA-1 hash or a value isn't a count, the database is used by something else
+    #[error("Invalid record in the database")]
+    InvalidRecord,
+}
+
+impl From<TransactionError<SledStoreError>> for SledStoreError {
+    fn from(e: TransactionError<SledStoreError>) -> Self {
+        match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        }
+    }
+}
+
+/// A store of SHA-1 hashes in a sled database.
+/// The data set is the tree named by the `current` key of the default one
+pub struct SledStore {
+    db: Db,
+    current: Arc<RwLock<Option<Tree>>>,
+    batch_size: usize,
+
+    /// Saves, merges and removals update the metadata, so they are serialized
+    writing: Mutex<()>,
+}
+
+impl SledStore {
+    /// Opens the database, it is created if it doesn't exist.
+    /// Trees left by interrupted saves are dropped
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, SledStoreError> {
+        let db = sled::open(path)?;
+
+        let current = db.get(CURRENT)?;
+        for name in db.tree_names() {
+            if name.starts_with(DATA) && Some(&name) != current.as_ref() {
+                db.drop_tree(name)?;
+            }
+        }
+        let current = current.map(|name| db.open_tree(name)).transpose()?;
+
+        Ok(Self {
+            db,
+            current: Arc::new(RwLock::new(current)),
+            batch_size: 10_000,
+            writing: Mutex::new(()),
+        })
+    }
+
+    /// How many records are inserted in a batch by a save or a merge,
+    /// and read at once by [ReadStore::iter_all]
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Runs `f` with the current tree on the blocking thread pool, None if there is no data set
+    async fn read<T, F>(&self, f: F) -> Result<Option<T>, SledStoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Tree) -> Result<T, SledStoreError> + Send + 'static,
+    {
+        let current = self.current.read().unwrap().clone();
+        match current {
+            Some(tree) => Ok(Some(tokio::task::spawn_blocking(move || f(&tree)).await??)),
+            None => Ok(None),
+        }
+    }
+
+    /// Runs `f` with the database on the blocking thread pool
+    async fn blocking<T, F>(&self, f: F) -> Result<T, SledStoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Db, &RwLock<Option<Tree>>) -> Result<T, SledStoreError> + Send + 'static,
+    {
+        let db = self.db.clone();
+        let current = self.current.clone();
+        tokio::task::spawn_blocking(move || f(&db, &current)).await?
+    }
+}
+
+fn number(value: Option<IVec>) -> Result<Option<u64>, SledStoreError> {
+    value
+        .map(|value| {
+            <[u8; 8]>::try_from(&*value)
+                .map(u64::from_be_bytes)
+                .map_err(|_| SledStoreError::InvalidRecord)
+        })
+        .transpose()
+}
+
+fn count(value: &[u8]) -> Result<u32, SledStoreError> {
+    <[u8; 4]>::try_from(value)
+        .map(u32::from_be_bytes)
+        .map_err(|_| SledStoreError::InvalidRecord)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Adds `delta` to the record count in a transaction over the default tree
+fn add_records(
+    meta: &TransactionalTree,
+    delta: i64,
+) -> Result<(), ConflictableTransactionError<SledStoreError>> {
+    let records = match number(meta.get(RECORDS)?) {
+        Ok(records) => records.unwrap_or_default(),
+        Err(e) => return abort(e),
+    };
+    meta.insert(RECORDS, &records.saturating_add_signed(delta).to_be_bytes())?;
+    Ok(())
+}
+
+/// The tree of the data set, it is created if there is no data set
+fn data_tree(db: &Db, current: &RwLock<Option<Tree>>) -> Result<Tree, SledStoreError> {
+    if let Some(tree) = current.read().unwrap().clone() {
+        return Ok(tree);
+    }
+
+    let generation = number(db.get(GENERATION)?)?.unwrap_or_default() + 1;
+    let name = format!("data.{generation}");
+    let tree = db.open_tree(&name)?;
+    db.insert(CURRENT, name.as_bytes())?;
+    *current.write().unwrap() = Some(tree.clone());
+    Ok(tree)
+}
+
+impl ReadStore for SledStore {
+    type Error = SledStoreError;
+
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        Ok(self.exists_count(val).await?.is_some())
+    }
+
+    /// Iterates the tree in order, a batch of records at a time
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        let batch_size = self.batch_size;
+
+        stream::try_unfold(Some(Vec::new()), move |after| async move {
+            let Some(after) = after else {
+                return Ok::<_, SledStoreError>(None);
+            };
+
+            let page = self
+                .read(move |tree| {
+                    let mut page = Vec::with_capacity(batch_size);
+                    for item in tree.range(after.as_slice()..) {
+                        let (key, value) = item?;
+                        if *key == *after.as_slice() {
+                            continue;
+                        }
+                        if page.len() == batch_size {
+                            break;
+                        }
+
+                        page.push(PwnedPwd {
+                            hash: <[u8; 20]>::try_from(&*key)
+                                .map_err(|_| SledStoreError::InvalidRecord)?,
+                            count: count(&value)?,
+                        });
+                    }
+                    Ok(page)
+                })
+                .await?
+                .unwrap_or_default();
+
+            let next = page
+                .last()
+                .filter(|_| page.len() == batch_size)
+                .map(|pwd| pwd.hash.to_vec());
+            Ok(Some((stream::iter(page.into_iter().map(Ok)), next)))
+        })
+        .try_flatten()
+    }
+
+    /// Looks the hashes up in a single blocking task
+    async fn exists_many(&self, vals: &[[u8; 20]]) -> Result<Vec<bool>, Self::Error> {
+        let vals = vals.to_vec();
+        let len = vals.len();
+
+        Ok(self
+            .read(move |tree| vals.iter().map(|val| Ok(tree.contains_key(val)?)).collect())
+            .await?
+            .unwrap_or_else(|| vec![false; len]))
+    }
+
+    async fn exists_count(&self, val: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        Ok(self
+            .read(move |tree| tree.get(val)?.map(|value| count(&value)).transpose())
+            .await?
+            .flatten())
+    }
+
+    /// Reads the keys from the first bytes of the prefix up to the ones of the next prefix
+    async fn range(&self, prefix: Prefix) -> Result<Chunk, Self::Error> {
+        let start = prefix.to_bytes();
+        let end = prefix.next().map(|next| next.to_bytes());
+
+        let passwords = self
+            .read(move |tree| {
+                let end = end
+                    .as_ref()
+                    .map_or(Bound::Unbounded, |end| Bound::Excluded(&end[..]));
+                tree.range::<&[u8], _>((Bound::Included(&start[..]), end))
+                    .map(|item| {
+                        let (key, value) = item?;
+                        Ok(PwnedPwd {
+                            hash: <[u8; 20]>::try_from(&*key)
+                                .map_err(|_| SledStoreError::InvalidRecord)?,
+                            count: count(&value)?,
+                        })
+                    })
+                    .collect()
+            })
+            .await?
+            .unwrap_or_default();
+        Ok(Chunk { prefix, passwords })
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        self.blocking(|db, _| {
+            Ok(StoreMetadata {
+                records: Some(number(db.get(RECORDS)?)?.unwrap_or_default()),
+                updated_at: number(db.get(UPDATED_AT)?)?
+                    .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+                generation: number(db.get(GENERATION)?)?,
+                ..Default::default()
+            })
+        })
+        .await
+    }
+}
+
+impl WriteStore for SledStore {
+    fn order_requirement() -> OrderRequirement {
+        OrderRequirement::Unordered
+    }
+
+    /// Inserts the stream into a new tree in batches and switches to it.
+    /// If the future is dropped, the data isn't changed
+    async fn save<S: Stream<Item = Chunk> + Unpin + Send>(
+        &self,
+        mut s: S,
+    ) -> Result<(), Self::Error> {
+        let _writing = self.writing.lock().await;
+
+        let generation = self.metadata().await?.generation.unwrap_or_default() + 1;
+        let name = format!("data.{generation}");
+        let tree = {
+            let name = name.clone();
+            self.blocking(move |db, _| {
+                // A tree of the generation is left by a dropped save
+                db.drop_tree(&name)?;
+                Ok(db.open_tree(&name)?)
+            })
+            .await?
+        };
+
+        let mut records = 0u64;
+        let mut batch = Vec::with_capacity(self.batch_size);
+        loop {
+            let chunk = s.next().await;
+            if let Some(chunk) = &chunk {
+                InvalidChunk::check(chunk)?;
+            }
+
+            let last = chunk.is_none();
+            batch.extend(chunk.into_iter().flat_map(|chunk| chunk.passwords));
+
+            if batch.len() >= self.batch_size || (last && !batch.is_empty()) {
+                let passwords = std::mem::replace(&mut batch, Vec::with_capacity(self.batch_size));
+                let tree = tree.clone();
+                records += self
+                    .blocking(move |_, _| {
+                        let mut batch = Batch::default();
+                        for pwd in &passwords {
+                            batch.insert(&pwd.hash, &pwd.count.to_be_bytes());
+                        }
+                        tree.apply_batch(batch)?;
+                        Ok(passwords.len() as u64)
+                    })
+                    .await?;
+            }
+
+            if last {
+                break;
+            }
+        }
+
+        self.blocking(move |db, current| {
+            let mut batch = Batch::default();
+            batch.insert(CURRENT, name.as_bytes());
+            batch.insert(RECORDS, &records.to_be_bytes());
+            batch.insert(UPDATED_AT, &now().to_be_bytes());
+            batch.insert(GENERATION, &generation.to_be_bytes());
+            db.apply_batch(batch)?;
+
+            let previous = current.write().unwrap().replace(tree);
+            if let Some(previous) = previous {
+                db.drop_tree(previous.name())?;
+            }
+            Ok(())
+        })
+        .await?;
+
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    /// Upserts the stream in transactions, the record count is updated by every batch
+    async fn merge<S: Stream<Item = Chunk> + Unpin + Send>(
+        &self,
+        mut s: S,
+    ) -> Result<(), Self::Error> {
+        let _writing = self.writing.lock().await;
+        let mut batch = Vec::with_capacity(self.batch_size);
+
+        loop {
+            let chunk = s.next().await;
+            if let Some(chunk) = &chunk {
+                InvalidChunk::check(chunk)?;
+            }
+
+            let last = chunk.is_none();
+            batch.extend(chunk.into_iter().flat_map(|chunk| chunk.passwords));
+
+            if batch.len() >= self.batch_size || (last && !batch.is_empty()) {
+                let passwords = std::mem::replace(&mut batch, Vec::with_capacity(self.batch_size));
+                self.blocking(move |db, current| {
+                    let tree = data_tree(db, current)?;
+
+                    (&tree, &**db).transaction(|(data, meta)| {
+                        let mut added = 0;
+                        for pwd in &passwords {
+                            if data.insert(&pwd.hash, &pwd.count.to_be_bytes())?.is_none() {
+                                added += 1;
+                            }
+                        }
+                        add_records(meta, added)
+                    })?;
+                    Ok(())
+                })
+                .await?;
+            }
+
+            if last {
+                break;
+            }
+        }
+
+        self.blocking(|db, _| {
+            let generation = number(db.get(GENERATION)?)?.unwrap_or_default() + 1;
+
+            let mut batch = Batch::default();
+            batch.insert(UPDATED_AT, &now().to_be_bytes());
+            batch.insert(GENERATION, &generation.to_be_bytes());
+            db.apply_batch(batch)?;
+            Ok(())
+        })
+        .await?;
+
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    async fn remove(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        let _writing = self.writing.lock().await;
+        let db = self.db.clone();
+
+        Ok(self
+            .read(move |tree| {
+                Ok((tree, &*db).transaction(|(data, meta)| {
+                    if data.remove(&val)?.is_none() {
+                        return Ok(false);
+                    }
+                    add_records(meta, -1)?;
+                    Ok(true)
+                })?)
+            })
+            .await?
+            .unwrap_or_default())
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        let _writing = self.writing.lock().await;
+
+        self.blocking(|db, current| {
+            let generation = number(db.get(GENERATION)?)?.unwrap_or_default() + 1;
+
+            let mut batch = Batch::default();
+            batch.remove(CURRENT);
+            batch.insert(RECORDS, &0u64.to_be_bytes());
+            batch.insert(UPDATED_AT, &now().to_be_bytes());
+            batch.insert(GENERATION, &generation.to_be_bytes());
+            db.apply_batch(batch)?;
+
+            let previous = current.write().unwrap().take();
+            if let Some(previous) = previous {
+                db.drop_tree(previous.name())?;
+            }
+            Ok(())
+        })
+        .await?;
+
+        self.db.flush_async().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use hex_literal::hex;
+
+    use super::*;
+
+    fn open(name: &str) -> (SledStore, std::path::PathBuf) {
+        let path = temp_dir().join("pwned_pwd_sled").join(name);
+        let _ = std::fs::remove_dir_all(&path);
+        (SledStore::open(&path).unwrap().with_batch_size(2), path)
+    }
+
+    #[tokio::test]
+    async fn save() {
+        let (store, path) = open("save");
+        assert!(!store.healthy().await.unwrap());
+
+        let first = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let second = PwnedPwd { hash: hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), count: 5 };
+        let other = PwnedPwd { hash: hex!("21BD5000F2D6B0E3CE3E9D1A0E9C3EB1E2A2A4AC"), count: 2 };
+
+        store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![other.clone()] },
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![first.clone(), second.clone()] },
+        ])).await.unwrap();
+
+        assert!(store.exists(first.hash).await.unwrap());
+        assert_eq!(Some(2), store.exists_count(other.hash).await.unwrap());
+        assert_eq!(vec![true, false], store.exists_many(&[second.hash, [0; 20]]).await.unwrap());
+        assert_eq!(vec![first.clone(), second.clone(), other.clone()], store.iter_all().try_collect::<Vec<_>>().await.unwrap());
+        assert_eq!(vec![first.clone(), second.clone()], store.range(Prefix::create(0x21BD4).unwrap()).await.unwrap().passwords);
+        assert!(store.range(Prefix::max()).await.unwrap().passwords.is_empty());
+        assert_eq!(Some(3), store.metadata().await.unwrap().records);
+        assert_eq!(Some(1), store.metadata().await.unwrap().generation);
+
+        let err = store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![first.clone()] },
+        ])).await.err().unwrap();
+        assert!(matches!(err, SledStoreError::InvalidChunk(_)));
+        assert!(store.exists(second.hash).await.unwrap());
+
+        store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![second.clone()] },
+        ])).await.unwrap();
+        assert!(!store.exists(first.hash).await.unwrap());
+
+        drop(store);
+        // The flusher thread of sled releases the lock of the directory a bit later
+        let mut attempts = 0;
+        let store = loop {
+            attempts += 1;
+            match SledStore::open(&path) {
+                Err(SledStoreError::Sled(sled::Error::Io(_))) if attempts < 100 => tokio::time::sleep(Duration::from_millis(10)).await,
+                res => break res.unwrap(),
+            }
+        };
+        assert_eq!(Some(5), store.exists_count(second.hash).await.unwrap());
+        assert_eq!(Some(1), store.metadata().await.unwrap().records);
+    }
+
+    #[tokio::test]
+    async fn merge_remove_clear() {
+        let (store, _) = open("merge");
+
+        let first = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let second = PwnedPwd { hash: hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), count: 5 };
+
+        store.merge(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![first.clone()] },
+        ])).await.unwrap();
+        store.merge(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![PwnedPwd { count: 7, ..first.clone() }, second.clone()] },
+        ])).await.unwrap();
+
+        assert_eq!(Some(7), store.exists_count(first.hash).await.unwrap());
+        assert_eq!(Some(2), store.metadata().await.unwrap().records);
+
+        assert!(store.remove(first.hash).await.unwrap());
+        assert!(!store.remove(first.hash).await.unwrap());
+        assert_eq!(Some(1), store.metadata().await.unwrap().records);
+
+        store.clear().await.unwrap();
+        assert!(!store.exists(second.hash).await.unwrap());
+        assert_eq!(Some(0), store.metadata().await.unwrap().records);
+    }
+}
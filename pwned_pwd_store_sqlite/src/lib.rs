@@ -0,0 +1,458 @@
+//! SQLite backend
+//!
+//! For small self-contained apps which already ship SQLite and don't want a custom file format.
+//! The data set is a single `WITHOUT ROWID` table keyed by the hash, so a lookup is one index search.
+//! The database is in WAL mode, lookups use their own connection and aren't blocked by a save
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd};
+use pwned_pwd_store::{InvalidChunk, OrderRequirement, ReadStore, StoreMetadata, WriteStore};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Transaction};
+
+#[derive(Debug, thiserror::Error)]
+pub enum SqliteStoreError {
+    #[error("Sqlite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    /// A blocking task with a connection panicked or was cancelled
+    #[error("Blocking task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+
+    /// A save stops at an invalid chunk and keeps the data, a merge keeps the batches committed before
+    #[error(transparent)]
+    InvalidChunk(#[from] InvalidChunk),
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS pwned (hash BLOB PRIMARY KEY, count INTEGER NOT NULL) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS pwned_meta (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    records INTEGER NOT NULL,
+    updated_at INTEGER,
+    generation INTEGER NOT NULL
+);
+INSERT OR IGNORE INTO pwned_meta (id, records, updated_at, generation) VALUES (0, 0, NULL, 0);
+";
+
+/// A save fills this table and replaces `pwned` with it at the end
+const SAVED_TABLE: &str = "
+DROP TABLE IF EXISTS pwned_saved;
+CREATE TABLE pwned_saved (hash BLOB PRIMARY KEY, count INTEGER NOT NULL) WITHOUT ROWID;
+";
+
+type Shared = Arc<Mutex<Connection>>;
+
+/// A store of SHA-1 hashes in a SQLite database
+pub struct SqliteStore {
+    reader: Shared,
+    writer: Shared,
+    batch_size: usize,
+}
+
+impl SqliteStore {
+    /// Opens the database, it is created if it doesn't exist
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, SqliteStoreError> {
+        let writer = Connection::open(path.as_ref())?;
+        writer.pragma_update(None, "journal_mode", "WAL")?;
+        writer.pragma_update(None, "synchronous", "NORMAL")?;
+        writer.execute_batch(SCHEMA)?;
+
+        let reader = Connection::open_with_flags(
+            path.as_ref(),
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+
+        Ok(Self {
+            reader: Arc::new(Mutex::new(reader)),
+            writer: Arc::new(Mutex::new(writer)),
+            batch_size: 10_000,
+        })
+    }
+
+    /// How many records are written in a transaction by a save or a merge,
+    /// and read at once by [ReadStore::iter_all]
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+}
+
+/// Runs `f` with the connection on the blocking thread pool
+async fn blocking<T, F>(conn: &Shared, f: F) -> Result<T, SqliteStoreError>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Connection) -> Result<T, SqliteStoreError> + Send + 'static,
+{
+    let conn = conn.clone();
+    tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap())).await?
+}
+
+/// The first 20 bits of hashes of the prefix, padded by zeroes.
+/// Blobs are compared as bytes, so hashes of the prefix aren't less than it
+fn prefix_bytes(prefix: Prefix) -> [u8; 3] {
+    let bits = u32::from(prefix) << 4;
+    [(bits >> 16) as u8, (bits >> 8) as u8, bits as u8]
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Reads the stream into batches of at least `batch_size` records and writes every batch by `write`
+async fn write_batches<S, F>(
+    conn: &Shared,
+    mut s: S,
+    batch_size: usize,
+    write: F,
+) -> Result<u64, SqliteStoreError>
+where
+    S: Stream<Item = Chunk> + Unpin + Send,
+    F: Fn(&Transaction, &[PwnedPwd]) -> Result<u64, SqliteStoreError> + Copy + Send + 'static,
+{
+    let mut written = 0;
+    let mut batch = Vec::with_capacity(batch_size);
+
+    loop {
+        let chunk = s.next().await;
+        if let Some(chunk) = &chunk {
+            InvalidChunk::check(chunk)?;
+        }
+
+        let last = chunk.is_none();
+        batch.extend(chunk.into_iter().flat_map(|chunk| chunk.passwords));
+
+        if batch.len() >= batch_size || (last && !batch.is_empty()) {
+            let passwords = std::mem::replace(&mut batch, Vec::with_capacity(batch_size));
+            written += blocking(conn, move |conn| {
+                let tx = conn.transaction()?;
+                let written = write(&tx, &passwords)?;
+                tx.commit()?;
+                Ok(written)
+            })
+            .await?;
+        }
+
+        if last {
+            return Ok(written);
+        }
+    }
+}
+
+impl ReadStore for SqliteStore {
+    type Error = SqliteStoreError;
+
+    async fn exists(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        Ok(self.exists_count(val).await?.is_some())
+    }
+
+    /// Streams the table in order, a batch of records at a time
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd, Self::Error>> + Send {
+        let reader = self.reader.clone();
+        let batch_size = self.batch_size;
+
+        stream::try_unfold(Some(Vec::new()), move |after| {
+            let reader = reader.clone();
+            async move {
+                let Some(after) = after else {
+                    return Ok::<_, SqliteStoreError>(None);
+                };
+
+                let page = blocking(&reader, move |conn| {
+                    let mut stmt = conn.prepare_cached(
+                        "SELECT hash, count FROM pwned WHERE hash > ?1 ORDER BY hash LIMIT ?2",
+                    )?;
+                    let page = stmt
+                        .query_map(params![after, batch_size as i64], |row| {
+                            Ok(PwnedPwd {
+                                hash: row.get(0)?,
+                                count: row.get(1)?,
+                            })
+                        })?
+                        .collect::<Result<Vec<_>, _>>()?;
+                    Ok(page)
+                })
+                .await?;
+
+                let next = page
+                    .last()
+                    .filter(|_| page.len() == batch_size)
+                    .map(|pwd| pwd.hash.to_vec());
+                Ok(Some((stream::iter(page.into_iter().map(Ok)), next)))
+            }
+        })
+        .try_flatten()
+    }
+
+    async fn exists_many(&self, vals: &[[u8; 20]]) -> Result<Vec<bool>, Self::Error> {
+        let vals = vals.to_vec();
+        blocking(&self.reader, move |conn| {
+            let mut stmt = conn.prepare_cached("SELECT 1 FROM pwned WHERE hash = ?1")?;
+            Ok(vals
+                .iter()
+                .map(|val| stmt.exists([val]))
+                .collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+
+    async fn exists_count(&self, val: [u8; 20]) -> Result<Option<u32>, Self::Error> {
+        blocking(&self.reader, move |conn| {
+            Ok(conn
+                .prepare_cached("SELECT count FROM pwned WHERE hash = ?1")?
+                .query_row([val], |row| row.get(0))
+                .optional()?)
+        })
+        .await
+    }
+
+    /// Filters by the count in the query
+    async fn exists_with_min_count(
+        &self,
+        val: [u8; 20],
+        min_count: u32,
+    ) -> Result<Option<u32>, Self::Error> {
+        blocking(&self.reader, move |conn| {
+            Ok(conn
+                .prepare_cached("SELECT count FROM pwned WHERE hash = ?1 AND count >= ?2")?
+                .query_row(params![val, min_count], |row| row.get(0))
+                .optional()?)
+        })
+        .await
+    }
+
+    /// Selects the hashes between the first bytes of the prefix and of the next one
+    async fn range(&self, prefix: Prefix) -> Result<Chunk, Self::Error> {
+        let start = prefix_bytes(prefix);
+        let end = prefix.next().map(prefix_bytes);
+
+        let passwords = blocking(&self.reader, move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT hash, count FROM pwned WHERE hash >= ?1 AND (?2 IS NULL OR hash < ?2) ORDER BY hash",
+            )?;
+            let passwords = stmt
+                .query_map(params![start, end], |row| {
+                    Ok(PwnedPwd {
+                        hash: row.get(0)?,
+                        count: row.get(1)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(passwords)
+        })
+        .await?;
+        Ok(Chunk { prefix, passwords })
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        blocking(&self.reader, |conn| {
+            let (records, updated_at, generation) = conn.query_row(
+                "SELECT records, updated_at, generation FROM pwned_meta",
+                [],
+                |row| {
+                    Ok((
+                        row.get::<_, u64>(0)?,
+                        row.get::<_, Option<u64>>(1)?,
+                        row.get::<_, u64>(2)?,
+                    ))
+                },
+            )?;
+
+            Ok(StoreMetadata {
+                records: Some(records),
+                updated_at: updated_at.map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+                generation: Some(generation),
+                ..Default::default()
+            })
+        })
+        .await
+    }
+}
+
+impl WriteStore for SqliteStore {
+    fn order_requirement() -> OrderRequirement {
+        OrderRequirement::Unordered
+    }
+
+    /// Writes the stream into a new table in batched transactions and replaces the data
+    /// with it in the last one. A hash must not be repeated in the stream.
+    /// If the future is dropped, the data isn't changed and the next save drops the new table
+    async fn save<S: Stream<Item = Chunk> + Unpin + Send>(&self, s: S) -> Result<(), Self::Error> {
+        blocking(&self.writer, |conn| Ok(conn.execute_batch(SAVED_TABLE)?)).await?;
+
+        let records = write_batches(&self.writer, s, self.batch_size, |tx, passwords| {
+            let mut stmt =
+                tx.prepare_cached("INSERT INTO pwned_saved (hash, count) VALUES (?1, ?2)")?;
+            for pwd in passwords {
+                stmt.execute(params![pwd.hash, pwd.count])?;
+            }
+            Ok(passwords.len() as u64)
+        })
+        .await?;
+
+        blocking(&self.writer, move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute_batch("DROP TABLE pwned; ALTER TABLE pwned_saved RENAME TO pwned;")?;
+            tx.execute(
+                "UPDATE pwned_meta SET records = ?1, updated_at = ?2, generation = generation + 1",
+                params![records, now()],
+            )?;
+            Ok(tx.commit()?)
+        })
+        .await
+    }
+
+    /// Upserts the stream in batched transactions, the record count is updated by every batch
+    async fn merge<S: Stream<Item = Chunk> + Unpin + Send>(&self, s: S) -> Result<(), Self::Error> {
+        write_batches(&self.writer, s, self.batch_size, |tx, passwords| {
+            let mut insert =
+                tx.prepare_cached("INSERT OR IGNORE INTO pwned (hash, count) VALUES (?1, ?2)")?;
+            let mut update = tx.prepare_cached("UPDATE pwned SET count = ?2 WHERE hash = ?1")?;
+
+            let mut added = 0;
+            for pwd in passwords {
+                if insert.execute(params![pwd.hash, pwd.count])? == 1 {
+                    added += 1;
+                } else {
+                    update.execute(params![pwd.hash, pwd.count])?;
+                }
+            }
+
+            tx.execute("UPDATE pwned_meta SET records = records + ?1", [added])?;
+            Ok(added)
+        })
+        .await?;
+
+        blocking(&self.writer, |conn| {
+            conn.execute(
+                "UPDATE pwned_meta SET updated_at = ?1, generation = generation + 1",
+                [now()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove(&self, val: [u8; 20]) -> Result<bool, Self::Error> {
+        blocking(&self.writer, move |conn| {
+            let tx = conn.transaction()?;
+            let removed = tx.execute("DELETE FROM pwned WHERE hash = ?1", [val])? == 1;
+            if removed {
+                tx.execute("UPDATE pwned_meta SET records = records - 1", [])?;
+            }
+            tx.commit()?;
+            Ok(removed)
+        })
+        .await
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        blocking(&self.writer, |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM pwned", [])?;
+            tx.execute(
+                "UPDATE pwned_meta SET records = 0, updated_at = ?1, generation = generation + 1",
+                [now()],
+            )?;
+            Ok(tx.commit()?)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use std::env::temp_dir;
+
+    use hex_literal::hex;
+
+    use super::*;
+
+    fn open(name: &str) -> SqliteStore {
+        let dir = temp_dir().join("pwned_pwd_sqlite");
+        std::fs::create_dir_all(&dir).unwrap();
+        for ext in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(dir.join(format!("{name}{ext}")));
+        }
+        SqliteStore::open(dir.join(name)).unwrap().with_batch_size(2)
+    }
+
+    #[tokio::test]
+    async fn save() {
+        let store = open("save.db");
+        assert_eq!(Some(0), store.metadata().await.unwrap().records);
+        assert!(!store.healthy().await.unwrap());
+
+        let first = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let second = PwnedPwd { hash: hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), count: 5 };
+        let other = PwnedPwd { hash: hex!("21BD5000F2D6B0E3CE3E9D1A0E9C3EB1E2A2A4AC"), count: 2 };
+
+        store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![other.clone()] },
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![first.clone(), second.clone()] },
+        ])).await.unwrap();
+
+        assert!(store.exists(first.hash).await.unwrap());
+        assert_eq!(Some(5), store.exists_count(second.hash).await.unwrap());
+        assert_eq!(None, store.exists_with_min_count(first.hash, 2).await.unwrap());
+        assert_eq!(Some(5), store.exists_with_min_count(second.hash, 2).await.unwrap());
+        assert_eq!(vec![true, false], store.exists_many(&[other.hash, [0; 20]]).await.unwrap());
+        assert_eq!(vec![first.clone(), second.clone(), other.clone()], store.iter_all().try_collect::<Vec<_>>().await.unwrap());
+        assert_eq!(vec![first.clone(), second.clone()], store.range(Prefix::create(0x21BD4).unwrap()).await.unwrap().passwords);
+        assert!(store.range(Prefix::max()).await.unwrap().passwords.is_empty());
+
+        let metadata = store.metadata().await.unwrap();
+        assert_eq!(Some(3), metadata.records);
+        assert_eq!(Some(1), metadata.generation);
+        assert!(metadata.updated_at.is_some());
+
+        let err = store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD5).unwrap(), passwords: vec![first.clone()] },
+        ])).await.err().unwrap();
+        assert!(matches!(err, SqliteStoreError::InvalidChunk(_)));
+        assert_eq!(Some(3), store.metadata().await.unwrap().records);
+
+        store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![second.clone()] },
+        ])).await.unwrap();
+        assert!(!store.exists(first.hash).await.unwrap());
+        assert_eq!(Some(1), store.metadata().await.unwrap().records);
+    }
+
+    #[tokio::test]
+    async fn merge_remove_clear() {
+        let store = open("merge.db");
+
+        let first = PwnedPwd { hash: hex!("21BD4004DDDC80AE4683948C5A1C5903584D8087"), count: 1 };
+        let second = PwnedPwd { hash: hex!("21BD400E47EFC33E7C8EE6B3B4BDBB8E3E9A73A6"), count: 5 };
+
+        store.save(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![first.clone()] },
+        ])).await.unwrap();
+        store.merge(stream::iter(vec![
+            Chunk { prefix: Prefix::create(0x21BD4).unwrap(), passwords: vec![PwnedPwd { count: 7, ..first.clone() }, second.clone()] },
+        ])).await.unwrap();
+
+        assert_eq!(Some(7), store.exists_count(first.hash).await.unwrap());
+        assert_eq!(Some(5), store.exists_count(second.hash).await.unwrap());
+        assert_eq!(Some(2), store.metadata().await.unwrap().records);
+        assert_eq!(Some(2), store.metadata().await.unwrap().generation);
+
+        assert!(store.remove(first.hash).await.unwrap());
+        assert!(!store.remove(first.hash).await.unwrap());
+        assert_eq!(Some(1), store.metadata().await.unwrap().records);
+
+        store.clear().await.unwrap();
+        assert!(!store.exists(second.hash).await.unwrap());
+        assert_eq!(Some(0), store.metadata().await.unwrap().records);
+    }
+}
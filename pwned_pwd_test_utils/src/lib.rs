@@ -0,0 +1,49 @@
+//! Test doubles for applications which use the pwned passwords crates
+//!
+//! Integrations can be unit-tested without the network or the real data set:
+//! [MockStore] replaces a store, [FakeSource] replaces a downloader where a
+//! [pwned_pwd_downloader::ChunkSource] is accepted and [serve_ranges] runs a local
+//! range API for code which needs a real [pwned_pwd_downloader::Downloader] url
+
+use pwned_pwd_server::RangeServer;
+use pwned_pwd_store::dyn_store::DynReadStore;
+use tokio::net::TcpListener;
+use url::Url;
+
+pub mod mock_store;
+pub mod source;
+
+pub use mock_store::{MockError, MockStore, Scripted};
+pub use source::{FakeError, FakeSource};
+
+/// Serves the range API of the store on a random local port until the runtime stops.
+/// Returns the base url for a downloader, like `http://127.0.0.1:PORT/range/`
+pub async fn serve_ranges(store: impl DynReadStore + 'static) -> std::io::Result<Url> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/range/", listener.local_addr()?)
+        .parse()
+        .expect("Invalid url");
+
+    let router = RangeServer::new(store).router();
+    tokio::spawn(async move { axum::serve(listener, router).await });
+    Ok(url)
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use pwned_pwd_core::{Prefix, PwnedPwd};
+    use pwned_pwd_downloader::Downloader;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_store() {
+        let store = MockStore::new().with_passwords([("password", 10)]);
+        let downloader = Downloader::new(serve_ranges(store.clone()).await.unwrap(), 1);
+
+        let hash = PwnedPwd::hash_password("password");
+        let chunk = downloader.download_prefix(Prefix::from_sha1(&hash)).await.unwrap();
+        assert_eq!(store.records(), chunk.passwords);
+    }
+}
@@ -0,0 +1,265 @@
+//! An in-memory store with scriptable calls
+//!
+//! [MockStore] answers from a map of hashes. Calls may be delayed, fail or hang,
+//! either every time ([MockStore::with_latency]) or one by one in a script
+//! ([MockStore::push]), so timeouts, fallbacks and retries of an application
+//! can be tested without a real backend
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use futures::{Stream, StreamExt};
+use pwned_pwd_core::{Chunk, HashKind, PwnedPwd, SHA1_LEN};
+use pwned_pwd_store::{OrderRequirement, ReadStore, StoreMetadata, WriteStore};
+
+/// What the next call of a [MockStore] does instead of answering at once
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Scripted {
+    /// Fails with [MockError]
+    Fail,
+
+    /// Never completes
+    Hang,
+
+    /// Answers after the delay
+    Delay(Duration),
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Scripted failure of the mock store")]
+pub struct MockError;
+
+struct Inner<const N: usize> {
+    records: Mutex<BTreeMap<[u8; N], u32>>,
+    script: Mutex<VecDeque<Scripted>>,
+    latency: Mutex<Option<Duration>>,
+    calls: AtomicU64,
+    generation: AtomicU64,
+    updated_at: Mutex<Option<SystemTime>>,
+}
+
+/// A store of `N`-byte hashes in memory. Clones share the data and the script
+#[derive(Clone)]
+pub struct MockStore<const N: usize = SHA1_LEN> {
+    inner: Arc<Inner<N>>,
+}
+
+impl<const N: usize> Default for MockStore<N> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                records: Mutex::new(BTreeMap::new()),
+                script: Mutex::new(VecDeque::new()),
+                latency: Mutex::new(None),
+                calls: AtomicU64::new(0),
+                generation: AtomicU64::new(0),
+                updated_at: Mutex::new(None),
+            }),
+        }
+    }
+}
+
+impl MockStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds SHA-1 hashes of the plaintext passwords with their counts
+    pub fn with_passwords<'a>(self, passwords: impl IntoIterator<Item = (&'a str, u32)>) -> Self {
+        self.with_records(passwords.into_iter().map(|(password, count)| PwnedPwd {
+            hash: PwnedPwd::hash_password(password),
+            count,
+        }))
+    }
+}
+
+impl<const N: usize> MockStore<N> {
+    pub fn with_records(self, records: impl IntoIterator<Item = PwnedPwd<N>>) -> Self {
+        self.insert(records);
+        self
+    }
+
+    /// Every call is delayed, after the scripted behaviour
+    pub fn with_latency(self, latency: Duration) -> Self {
+        *self.inner.latency.lock().unwrap() = Some(latency);
+        self
+    }
+
+    /// Scripts the behaviour of the next call which isn't scripted yet.
+    /// Every call except [ReadStore::iter_all] takes a step of the script
+    pub fn push(&self, scripted: Scripted) {
+        self.inner.script.lock().unwrap().push_back(scripted);
+    }
+
+    /// Calls made so far, including failed ones
+    pub fn calls(&self) -> u64 {
+        self.inner.calls.load(Ordering::Relaxed)
+    }
+
+    /// The data in the order of hashes
+    pub fn records(&self) -> Vec<PwnedPwd<N>> {
+        self.inner
+            .records
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(hash, count)| PwnedPwd {
+                hash: *hash,
+                count: *count,
+            })
+            .collect()
+    }
+
+    fn insert(&self, records: impl IntoIterator<Item = PwnedPwd<N>>) {
+        let mut map = self.inner.records.lock().unwrap();
+        map.extend(records.into_iter().map(|pwd| (pwd.hash, pwd.count)));
+    }
+
+    fn saved(&self) {
+        self.inner.generation.fetch_add(1, Ordering::Relaxed);
+        *self.inner.updated_at.lock().unwrap() = Some(SystemTime::now());
+    }
+
+    /// Plays the next step of the script and the latency
+    async fn call(&self) -> Result<(), MockError> {
+        self.inner.calls.fetch_add(1, Ordering::Relaxed);
+        let scripted = self.inner.script.lock().unwrap().pop_front();
+        match scripted {
+            Some(Scripted::Fail) => return Err(MockError),
+            Some(Scripted::Hang) => futures::future::pending().await,
+            Some(Scripted::Delay(delay)) => tokio::time::sleep(delay).await,
+            None => {}
+        }
+
+        let latency = *self.inner.latency.lock().unwrap();
+        if let Some(latency) = latency {
+            tokio::time::sleep(latency).await;
+        }
+        Ok(())
+    }
+
+    fn count(&self, val: &[u8; N]) -> Option<u32> {
+        self.inner.records.lock().unwrap().get(val).copied()
+    }
+}
+
+impl<const N: usize> ReadStore<N> for MockStore<N> {
+    type Error = MockError;
+
+    async fn exists(&self, val: [u8; N]) -> Result<bool, Self::Error> {
+        self.call().await?;
+        Ok(self.count(&val).is_some())
+    }
+
+    fn iter_all(&self) -> impl Stream<Item = Result<PwnedPwd<N>, Self::Error>> + Send {
+        futures::stream::iter(self.records()).map(Ok)
+    }
+
+    /// A single call, like a backend which looks a batch up at once
+    async fn exists_many(&self, vals: &[[u8; N]]) -> Result<Vec<bool>, Self::Error> {
+        self.call().await?;
+        Ok(vals.iter().map(|val| self.count(val).is_some()).collect())
+    }
+
+    async fn exists_count(&self, val: [u8; N]) -> Result<Option<u32>, Self::Error> {
+        self.call().await?;
+        Ok(self.count(&val))
+    }
+
+    /// A single call, like a backend which looks a batch up at once
+    async fn exists_count_many(&self, vals: &[[u8; N]]) -> Result<Vec<Option<u32>>, Self::Error> {
+        self.call().await?;
+        Ok(vals.iter().map(|val| self.count(val)).collect())
+    }
+
+    async fn metadata(&self) -> Result<StoreMetadata, Self::Error> {
+        self.call().await?;
+        Ok(StoreMetadata {
+            records: Some(self.inner.records.lock().unwrap().len() as u64),
+            updated_at: *self.inner.updated_at.lock().unwrap(),
+            generation: Some(self.inner.generation.load(Ordering::Relaxed)),
+            kind: HashKind::of_len(N).unwrap_or_default(),
+        })
+    }
+}
+
+impl<const N: usize> WriteStore<N> for MockStore<N> {
+    fn order_requirement() -> OrderRequirement {
+        OrderRequirement::Unordered
+    }
+
+    async fn save<S: Stream<Item = Chunk<N>> + Unpin + Send>(
+        &self,
+        s: S,
+    ) -> Result<(), Self::Error> {
+        self.call().await?;
+        let chunks = s.collect::<Vec<_>>().await;
+        self.inner.records.lock().unwrap().clear();
+        self.insert(chunks.into_iter().flat_map(|chunk| chunk.passwords));
+        self.saved();
+        Ok(())
+    }
+
+    async fn merge<S: Stream<Item = Chunk<N>> + Unpin + Send>(
+        &self,
+        s: S,
+    ) -> Result<(), Self::Error> {
+        self.call().await?;
+        let chunks = s.collect::<Vec<_>>().await;
+        self.insert(chunks.into_iter().flat_map(|chunk| chunk.passwords));
+        self.saved();
+        Ok(())
+    }
+
+    async fn remove(&self, val: [u8; N]) -> Result<bool, Self::Error> {
+        self.call().await?;
+        Ok(self.inner.records.lock().unwrap().remove(&val).is_some())
+    }
+
+    async fn clear(&self) -> Result<(), Self::Error> {
+        self.call().await?;
+        self.inner.records.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use pwned_pwd_core::Prefix;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn scripted() {
+        let store = MockStore::new().with_passwords([("password", 10)]);
+        let hash = PwnedPwd::hash_password("password");
+        store.push(Scripted::Fail);
+        store.push(Scripted::Hang);
+
+        assert_eq!(Err(MockError), store.exists(hash).await);
+        assert!(tokio::time::timeout(Duration::from_millis(10), store.exists(hash)).await.is_err());
+        assert_eq!(Ok(Some(10)), store.exists_count(hash).await);
+        assert_eq!(3, store.calls());
+    }
+
+    #[tokio::test]
+    async fn save_and_range() {
+        let store = MockStore::new();
+        let pwd = PwnedPwd { hash: PwnedPwd::hash_password("qwerty"), count: 2 };
+        let prefix = Prefix::from_sha1(&pwd.hash);
+        store.save(futures::stream::iter([Chunk { prefix, passwords: vec![pwd.clone()] }])).await.unwrap();
+
+        assert_eq!(vec![pwd.clone()], store.range(prefix).await.unwrap().passwords);
+        let metadata = store.metadata().await.unwrap();
+        assert_eq!((Some(1), Some(1)), (metadata.records, metadata.generation));
+        assert!(store.remove(pwd.hash).await.unwrap());
+        assert!(!store.healthy().await.unwrap());
+    }
+}
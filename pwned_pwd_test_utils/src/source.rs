@@ -0,0 +1,125 @@
+//! A chunk source with fixed ranges
+//!
+//! [FakeSource] yields the chunks it was given (empty ones for other prefixes).
+//! Prefixes may fail or be delayed, so out-of-order completion and broken
+//! downloads can be reproduced. For synthetic data of realistic size see
+//! [pwned_pwd_downloader::simulation::SimulatedSource]
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::{stream::BoxStream, StreamExt};
+use pwned_pwd_core::{Chunk, Prefix, PwnedPwd, SHA1_LEN};
+use pwned_pwd_downloader::ChunkSource;
+
+/// Prefixes downloaded at once, like workers of a downloader
+const PARALLEL: usize = 16;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Scripted failure of the prefix {}", prefix.as_prefix_str().as_ref())]
+pub struct FakeError {
+    pub prefix: Prefix,
+}
+
+/// A [ChunkSource] of fixed `N`-byte hashes
+#[derive(Debug, Clone, Default)]
+pub struct FakeSource<const N: usize = SHA1_LEN> {
+    chunks: Arc<BTreeMap<Prefix, Chunk<N>>>,
+    failures: Arc<HashSet<Prefix>>,
+    delays: Arc<HashMap<Prefix, Duration>>,
+}
+
+impl FakeSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<const N: usize> FakeSource<N> {
+    /// Adds records to the ranges of their prefixes
+    pub fn with_records(mut self, records: impl IntoIterator<Item = PwnedPwd<N>>) -> Self {
+        let chunks = Arc::make_mut(&mut self.chunks);
+        for pwd in records {
+            let prefix = Prefix::from_hash(&pwd.hash);
+            let chunk = chunks.entry(prefix).or_insert_with(|| Chunk {
+                prefix,
+                passwords: Vec::new(),
+            });
+            let at = chunk
+                .passwords
+                .partition_point(|other| other.hash < pwd.hash);
+            chunk.passwords.insert(at, pwd);
+        }
+        self
+    }
+
+    /// The prefix fails with [FakeError]
+    pub fn with_failure(mut self, prefix: Prefix) -> Self {
+        Arc::make_mut(&mut self.failures).insert(prefix);
+        self
+    }
+
+    /// The prefix is yielded after the delay, the following ones may overtake it
+    pub fn with_delay(mut self, prefix: Prefix, delay: Duration) -> Self {
+        Arc::make_mut(&mut self.delays).insert(prefix, delay);
+        self
+    }
+
+    /// The range of the prefix, ignoring failures and delays
+    pub fn chunk(&self, prefix: Prefix) -> Chunk<N> {
+        self.chunks.get(&prefix).cloned().unwrap_or(Chunk {
+            prefix,
+            passwords: Vec::new(),
+        })
+    }
+
+    async fn download(self, prefix: Prefix) -> Result<Chunk<N>, FakeError> {
+        if let Some(delay) = self.delays.get(&prefix) {
+            tokio::time::sleep(*delay).await;
+        }
+        match self.failures.contains(&prefix) {
+            true => Err(FakeError { prefix }),
+            false => Ok(self.chunk(prefix)),
+        }
+    }
+}
+
+impl<const N: usize> ChunkSource<N> for FakeSource<N> {
+    type Error = FakeError;
+
+    fn chunks<Prefixes: Iterator<Item = Prefix> + Send + 'static>(
+        &self,
+        prefixes: Prefixes,
+    ) -> BoxStream<'static, Result<Chunk<N>, Self::Error>> {
+        let source = self.clone();
+        futures::stream::iter(prefixes)
+            .map(move |prefix| source.clone().download(prefix))
+            .buffer_unordered(PARALLEL)
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn chunks() {
+        let pwd = PwnedPwd { hash: PwnedPwd::hash_password("password"), count: 10 };
+        let prefix = Prefix::from_sha1(&pwd.hash);
+        let source = FakeSource::new()
+            .with_records([pwd.clone()])
+            .with_delay(Prefix::default(), Duration::from_millis(20))
+            .with_failure(Prefix::create(2).unwrap());
+
+        let chunks = source.chunks(Prefix::default().up_to(Prefix::create(2).unwrap()).chain([prefix])).collect::<Vec<_>>().await;
+        assert_eq!(4, chunks.len());
+        assert!(chunks.contains(&Err(FakeError { prefix: Prefix::create(2).unwrap() })));
+        assert_eq!(Prefix::default(), chunks[3].as_ref().unwrap().prefix);
+        assert_eq!(vec![pwd], source.chunk(prefix).passwords);
+    }
+}
@@ -0,0 +1,23 @@
+//! The JavaScript API
+
+use wasm_bindgen::prelude::*;
+
+use crate::OnlineChecker;
+
+/// How many times the password was pwned, 0 if it wasn't. The default range API
+/// is requested without `baseUrl`
+#[wasm_bindgen(js_name = pwnedCount)]
+pub async fn pwned_count(
+    password: String,
+    base_url: Option<String>,
+    padding: bool,
+) -> Result<u32, JsError> {
+    let mut checker = match base_url {
+        Some(base_url) => OnlineChecker::with_base_url(base_url.parse()?),
+        None => OnlineChecker::new(),
+    };
+    if padding {
+        checker = checker.with_padding();
+    }
+    Ok(checker.check_password(&password).await?.unwrap_or(0))
+}
@@ -0,0 +1,146 @@
+//! Online checks which compile to `wasm32-unknown-unknown`
+//!
+//! The downloader and the stores need tokio and a file system, a browser or a worker
+//! has neither. [OnlineChecker] requests a single range with `fetch` (through reqwest)
+//! and finds the hash in it with the parser of the core crate, so only the prefix of
+//! the hash leaves the page. On wasm the checker is also exported to JavaScript
+
+use pwned_pwd_core::{Chunk, ParseError, Parser, Prefix, PwnedPwd};
+use url::Url;
+
+#[cfg(target_arch = "wasm32")]
+mod bindings;
+
+/// Haveibeenpwned range api url
+pub const DEFAULT_BASE_URL: &str = "https://api.pwnedpasswords.com/range/";
+
+#[derive(thiserror::Error, Debug)]
+pub enum OnlineError {
+    #[error("Request error: {0}")]
+    Request(#[from] reqwest::Error),
+
+    #[error("Invalid range: {0}")]
+    Parse(#[from] ParseError),
+}
+
+/// Requests ranges of SHA-1 hashes
+#[derive(Debug, Clone)]
+pub struct OnlineChecker {
+    base_url: Url,
+    padding: bool,
+    client: reqwest::Client,
+}
+
+impl Default for OnlineChecker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OnlineChecker {
+    /// A checker of [DEFAULT_BASE_URL]
+    pub fn new() -> Self {
+        Self::with_base_url(DEFAULT_BASE_URL.parse().expect("Invalid default url"))
+    }
+
+    /// A checker of another range API, e.g. an internal mirror
+    pub fn with_base_url(base_url: Url) -> Self {
+        Self {
+            base_url,
+            padding: false,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Ask for padded ranges (`Add-Padding: true`), so the size of a response
+    /// doesn't reveal the prefix. Padding records are dropped
+    pub fn with_padding(mut self) -> Self {
+        self.padding = true;
+        self
+    }
+
+    /// Records of the range
+    pub async fn range(&self, prefix: Prefix) -> Result<Chunk, OnlineError> {
+        let url = self
+            .base_url
+            .join(prefix.as_prefix_str().as_ref())
+            .expect("Invalid url");
+        let mut request = self.client.get(url);
+        if self.padding {
+            request = request.header("Add-Padding", "true");
+        }
+
+        let content = request.send().await?.error_for_status()?.text().await?;
+        let parser = Parser::new(prefix);
+        let mut passwords = Vec::new();
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            let pwd = parser.parse(line)?;
+            if pwd.count > 0 {
+                passwords.push(pwd);
+            }
+        }
+        Ok(Chunk { prefix, passwords })
+    }
+
+    /// How many times the hash was pwned, None if it wasn't
+    pub async fn count(&self, hash: [u8; 20]) -> Result<Option<u32>, OnlineError> {
+        let chunk = self.range(Prefix::from_sha1(&hash)).await?;
+        Ok(chunk
+            .passwords
+            .iter()
+            .find(|pwd| pwd.hash == hash)
+            .map(|pwd| pwd.count))
+    }
+
+    /// Hashes the password and checks it, the plaintext doesn't leave the page
+    pub async fn check_password(&self, password: &str) -> Result<Option<u32>, OnlineError> {
+        self.count(PwnedPwd::hash_password(password)).await
+    }
+}
+
+#[cfg(test)]
+#[rustfmt::skip]
+mod tests {
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    use super::*;
+
+    /// Answers with a record of `password` and a padding record, requests are sent back
+    async fn serve() -> (Url, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/range/", listener.local_addr().unwrap()).parse().unwrap();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Ok((mut connection, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = connection.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                tx.send(String::from_utf8_lossy(&request).into_owned()).unwrap();
+
+                let body = "1E4C9B93F3F0682250B6CF8331B7EE68FD8:10\r\n000000005AD76BD555C1D6D771DE417A4B8:0\r\n";
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len());
+                connection.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    #[tokio::test]
+    async fn check() {
+        let (url, mut requests) = serve().await;
+        let checker = OnlineChecker::with_base_url(url).with_padding();
+
+        assert_eq!(Some(10), checker.check_password("password").await.unwrap());
+        let request = requests.recv().await.unwrap();
+        assert!(request.starts_with("GET /range/5BAA6 "), "{request}");
+        assert!(request.to_lowercase().contains("add-padding: true"), "{request}");
+
+        let range = checker.range(Prefix::create(0x5BAA6).unwrap()).await.unwrap();
+        assert_eq!(1, range.passwords.len());
+        assert_eq!(None, checker.check_password("correct horse battery staple").await.unwrap());
+    }
+}